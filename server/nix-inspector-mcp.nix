@@ -6,6 +6,7 @@
 }:
 with lib; let
   cfg = config.services.nix-inspector-mcp;
+  tomlFormat = pkgs.formats.toml {};
 in {
   options.services.nix-inspector-mcp = {
     enable = mkEnableOption "Nix Inspector MCP Server";
@@ -27,6 +28,62 @@ in {
       default = "/var/log/nix-inspector-mcp/server.log";
       description = "Path to the log file.";
     };
+
+    settings = mkOption {
+      type = tomlFormat.type;
+      default = {};
+      example = {
+        cache_max_bytes = 134217728;
+        rate_limits.tools.eval = 10;
+      };
+      description = ''
+        Settings written to the server's TOML config file. Options of this
+        module take precedence over the same settings here.
+      '';
+    };
+
+    accessPolicy = mkOption {
+      type = types.nullOr tomlFormat.type;
+      default = null;
+      example = {
+        flake_roots = ["/etc/nixos"];
+        remote_flakes = false;
+      };
+      description = ''
+        Access policy restricting which tools may run and which flakes,
+        store paths and files they may use. Without a policy every tool but
+        `collect_garbage` is allowed; with one, tools that build or delete
+        are disabled unless `allow_mutating` is set. `collect_garbage` also
+        needs `allow_collect_garbage`.
+      '';
+    };
+
+    rateLimit = mkOption {
+      type = types.ints.unsigned;
+      default = 100;
+      description = "Requests per minute each client session may make; 0 disables the limit.";
+    };
+
+    maxNixProcesses = mkOption {
+      type = types.ints.unsigned;
+      default = 4;
+      description = ''
+        Tool calls that may run nix at once. Evaluations can use a lot of
+        memory; calls beyond the limit are refused with a retry hint.
+        0 disables the limit.
+      '';
+    };
+
+    tokensFile = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      example = "/run/secrets/nix-inspector-mcp-tokens.toml";
+      description = ''
+        TOML file with the roles and bearer tokens of network clients. It is
+        passed to the service as a systemd credential and should not be in
+        the Nix store.
+      '';
+    };
   };
 
   config = mkIf cfg.enable {
@@ -39,7 +96,12 @@ in {
         LOG_LEVEL = cfg.logLevel;
         LOG_FILE = cfg.logFile;
         NIX_PATH = "/nix/var/nix/profiles/per-user/root/channels";
-        RUST_LOG = "debug";
+        NIX_INSPECTOR_CONFIG = tomlFormat.generate "nix-inspector-mcp.toml" cfg.settings;
+        RATE_LIMIT_PER_MINUTE = toString cfg.rateLimit;
+        MAX_NIX_PROCESSES = toString cfg.maxNixProcesses;
+      }
+      // optionalAttrs (cfg.accessPolicy != null) {
+        ACCESS_POLICY_FILE = tomlFormat.generate "access-policy.toml" cfg.accessPolicy;
       };
 
       serviceConfig = {
@@ -50,6 +112,7 @@ in {
         RuntimeDirectory = "nix-inspector-mcp";
         StateDirectory = "nix-inspector-mcp";
         CacheDirectory = "nix-inspector-mcp";
+        LogsDirectory = "nix-inspector-mcp";
         User = "nix-inspector-mcp";
         Group = "nix-inspector-mcp";
         DynamicUser = true;
@@ -62,6 +125,9 @@ in {
           "/nix/var/nix/daemon-socket"
         ];
         SupplementaryGroups = ["nix-users"];
+      }
+      // optionalAttrs (cfg.tokensFile != null) {
+        LoadCredential = "nix-inspector-mcp-tokens:${toString cfg.tokensFile}";
       };
     };
   };
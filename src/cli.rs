@@ -0,0 +1,317 @@
+//! Subcommands running a single tool from the shell
+//!
+//! Each subcommand builds the parameters of an MCP tool and runs it through
+//! [`SystemAnalyzer::call`], so the JSON it prints is what a client gets
+//! back from the server for the same call.
+
+use clap::{Subcommand, ValueEnum};
+use mcp_rust_sdk::error::Error as McpError;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+use crate::inspector::system::SystemAnalyzer;
+
+/// Exit status when the tool fails
+pub const EXIT_TOOL_FAILED: i32 = 1;
+
+/// Exit status for unusable arguments, as for configuration mistakes
+pub const EXIT_USAGE: i32 = 2;
+
+/// Formats `flake-graph` adds to the DOT graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphFormat {
+    Dot,
+    Svg,
+    Mermaid,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Show the nix version, system and channels (get_system_info)
+    SystemInfo,
+    /// Show the inputs, outputs and dev shells of a flake (analyze_dev_flake)
+    FlakeInfo {
+        #[arg(default_value = ".")]
+        flake: PathBuf,
+    },
+    /// Graph the dependency structure of a flake (generate_flake_graph)
+    FlakeGraph(FlakeGraphArgs),
+    /// Graph the runtime closure of an installable (get_dependency_graph)
+    DependencyGraph(DependencyGraphArgs),
+    /// Explain why a closure contains a path (why_depends)
+    WhyDepends(WhyDependsArgs),
+    /// Break down the size of a closure (analyze_closure_size)
+    ClosureSize(ClosureSizeArgs),
+    /// Compare two derivations (diff_derivations)
+    DiffDerivations(DiffDerivationsArgs),
+    /// Build the checks of a flake (flake_check)
+    FlakeCheck(FlakeCheckArgs),
+    /// Evaluate a Nix expression (eval)
+    Eval(EvalArgs),
+    /// Search the packages of a flake's nixpkgs (search_packages)
+    SearchPackages(SearchPackagesArgs),
+    /// Scan a closure for known vulnerabilities (scan_vulnerabilities)
+    ScanVulnerabilities(ScanVulnerabilitiesArgs),
+    /// Export a software bill of materials (export_sbom)
+    Sbom(SbomArgs),
+    /// Check the licenses of a closure against a policy (audit_licenses)
+    Licenses(LicensesArgs),
+    /// Run any tool with parameters given as a JSON object
+    Call {
+        tool: String,
+        #[arg(default_value = "{}")]
+        params: String,
+    },
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct FlakeGraphArgs {
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<GraphFormat>,
+    /// Flake directory; the graph is generated from within it
+    #[arg(default_value = ".")]
+    #[serde(skip)]
+    pub flake: PathBuf,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct DependencyGraphArgs {
+    #[arg(default_value = ".")]
+    pub installable: String,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct WhyDependsArgs {
+    pub installable: String,
+    /// Store path, store path name or package name
+    pub target: String,
+    /// Only the k shortest chains
+    #[arg(short)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<usize>,
+    /// Report the file offsets carrying each hash
+    #[arg(long)]
+    pub precise: bool,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct ClosureSizeArgs {
+    pub installable: String,
+    /// Maximum size of the whole closure in bytes
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<u64>,
+    /// Maximum unique contribution of a single path in bytes
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_budget: Option<u64>,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top: Option<usize>,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct DiffDerivationsArgs {
+    pub left: String,
+    pub right: String,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct FlakeCheckArgs {
+    #[arg(default_value = ".")]
+    pub flake: String,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// Checks to run, separated by commas; all of them by default
+    #[arg(long, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checks: Option<Vec<String>>,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_log_lines: Option<usize>,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct EvalArgs {
+    pub expression: String,
+    /// Flake whose outputs are in scope as `flake`, `inputs` and `pkgs`
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flake: Option<String>,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct SearchPackagesArgs {
+    pub query: String,
+    #[arg(long, default_value = ".")]
+    pub flake: String,
+    /// Flake input providing nixpkgs
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Re-evaluate nixpkgs even if an index exists
+    #[arg(long)]
+    pub rebuild: bool,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct ScanVulnerabilitiesArgs {
+    /// Installable or store path; the running system by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub installable: Option<String>,
+    /// System generation to scan when no installable is given
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    /// NVD JSON feed, or a directory of feeds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<String>,
+    /// Whitelist in vulnix's TOML format
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub whitelist: Option<String>,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct SbomArgs {
+    pub installable: String,
+    /// `cyclonedx` or `spdx`
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Describe the build closure rather than the runtime closure
+    #[arg(long)]
+    pub build_closure: bool,
+}
+
+#[derive(Debug, clap::Args, Serialize)]
+pub struct LicensesArgs {
+    pub installable: String,
+    /// Policy file, `license-policy.toml` in the state directory by default
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+}
+
+impl Command {
+    /// The tool the subcommand runs and its parameters
+    pub fn request(&self) -> Result<(&str, Value), serde_json::Error> {
+        Ok(match self {
+            Command::SystemInfo => ("get_system_info", json!({})),
+            Command::FlakeInfo { .. } => ("analyze_dev_flake", json!({})),
+            Command::FlakeGraph(args) => ("generate_flake_graph", serde_json::to_value(args)?),
+            Command::DependencyGraph(args) => ("get_dependency_graph", serde_json::to_value(args)?),
+            Command::WhyDepends(args) => ("why_depends", serde_json::to_value(args)?),
+            Command::ClosureSize(args) => ("analyze_closure_size", serde_json::to_value(args)?),
+            Command::DiffDerivations(args) => ("diff_derivations", serde_json::to_value(args)?),
+            Command::FlakeCheck(args) => ("flake_check", serde_json::to_value(args)?),
+            Command::Eval(args) => ("eval", serde_json::to_value(args)?),
+            Command::SearchPackages(args) => ("search_packages", serde_json::to_value(args)?),
+            Command::ScanVulnerabilities(args) => ("scan_vulnerabilities", serde_json::to_value(args)?),
+            Command::Sbom(args) => ("export_sbom", serde_json::to_value(args)?),
+            Command::Licenses(args) => ("audit_licenses", serde_json::to_value(args)?),
+            Command::Call { tool, params } => (tool.as_str(), serde_json::from_str(params)?),
+        })
+    }
+
+    /// Directory the tool runs in, for tools working on the flake in the
+    /// current directory
+    pub fn working_dir(&self) -> Option<&Path> {
+        match self {
+            Command::FlakeInfo { flake } | Command::FlakeGraph(FlakeGraphArgs { flake, .. }) => Some(flake),
+            _ => None,
+        }
+    }
+}
+
+/// The JSON-RPC `error` object a client would receive
+fn error_json(err: McpError) -> Value {
+    match err {
+        McpError::Protocol { code, message, data } => json!({ "code": i32::from(code), "message": message, "data": data }),
+        other => json!({ "message": other.to_string() }),
+    }
+}
+
+/// Runs a subcommand, printing the result to stdout or the error to
+/// stderr, and returns the exit status
+pub async fn run(command: &Command, analyzer: &SystemAnalyzer) -> i32 {
+    let (tool, params) = match command.request() {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("nix-inspector-mcp: invalid parameters: {}", e);
+            return EXIT_USAGE;
+        }
+    };
+    if let Some(dir) = command.working_dir() {
+        if let Err(e) = std::env::set_current_dir(dir) {
+            eprintln!("nix-inspector-mcp: cannot enter {}: {}", dir.display(), e);
+            return EXIT_USAGE;
+        }
+    }
+
+    let session = analyzer.start_local_session();
+    let (output, status) = match analyzer.call(&session, tool, Some(params)).await {
+        Ok(result) => (result, 0),
+        Err(err) => (json!({ "error": error_json(err) }), EXIT_TOOL_FAILED),
+    };
+    let output = serde_json::to_string_pretty(&output).unwrap_or_default();
+    if status == 0 {
+        println!("{}", output);
+    } else {
+        eprintln!("{}", output);
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Args;
+    use clap::Parser;
+
+    fn command(args: &[&str]) -> Command {
+        let args = Args::try_parse_from(std::iter::once("nix-inspector-mcp").chain(args.iter().copied())).unwrap();
+        args.command.unwrap()
+    }
+
+    #[test]
+    fn test_requests() {
+        let graph = command(&["flake-graph", "--format", "mermaid", "./"]);
+        assert_eq!(graph.request().unwrap(), ("generate_flake_graph", json!({ "format": "mermaid" })));
+        assert_eq!(graph.working_dir(), Some(Path::new("./")));
+
+        let closure = command(&["closure-size", ".#default", "--budget", "1000000"]);
+        assert_eq!(
+            closure.request().unwrap(),
+            ("analyze_closure_size", json!({ "installable": ".#default", "budget": 1000000 }))
+        );
+        assert_eq!(closure.working_dir(), None);
+
+        let check = command(&["flake-check", "--checks", "fmt,tests"]);
+        assert_eq!(check.request().unwrap(), ("flake_check", json!({ "flake": ".", "checks": ["fmt", "tests"] })));
+
+        let call = command(&["call", "get_option", r#"{"name": "services.nginx.enable"}"#]);
+        assert_eq!(call.request().unwrap(), ("get_option", json!({ "name": "services.nginx.enable" })));
+        assert!(command(&["call", "get_option", "{"]).request().is_err());
+
+        // Without a subcommand the server runs
+        assert!(Args::try_parse_from(["nix-inspector-mcp", "--no-cache"]).unwrap().command.is_none());
+    }
+}
@@ -0,0 +1,378 @@
+//! Configuration of the `nix-inspector-mcp` binary
+//!
+//! Settings come from command line flags, then environment variables, then
+//! an optional TOML file, then defaults. Everything is validated before the
+//! server starts so mistakes fail with a clear message instead of at the
+//! first request.
+
+use clap::{Parser, ValueEnum};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::cli::Command;
+use crate::inspector::auth::{Authenticator, TOKENS_CREDENTIAL};
+use crate::inspector::cache::{ResponseCache, CACHE_DIR, DEFAULT_MAX_BYTES};
+use crate::inspector::limits::RateLimitConfig;
+use crate::inspector::policy::{AccessPolicy, ACCESS_POLICY_FILE};
+use crate::inspector::state::state_dir;
+use crate::inspector::system::{AnalyzerSettings, SystemAnalyzer, SystemAnalyzerError};
+
+/// Providers the server can serve
+pub const PROVIDERS: &[&str] = &["system"];
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")]
+    Read { path: PathBuf, source: std::io::Error },
+    #[error("Invalid config file {path}: {message}")]
+    Parse { path: PathBuf, message: String },
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+    #[error("Failed to set up the system analyzer: {0}")]
+    Analyzer(#[from] SystemAnalyzerError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// JSON-RPC over stdin and stdout
+    Stdio,
+}
+
+/// Log levels, accepting the names the NixOS module uses as well
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    #[value(alias = "warning")]
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl<'de> Deserialize<'de> for LogLevel {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        LogLevel::from_str(&name, true).map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// Command line flags. Each can also be set by the environment variable
+/// shown in `--help`. Without a subcommand the server runs.
+#[derive(Debug, Default, Parser)]
+#[command(name = "nix-inspector-mcp", version, about = "A Model Context Protocol server for inspecting Nix systems and flakes")]
+pub struct Args {
+    /// TOML config file
+    #[arg(long, env = "NIX_INSPECTOR_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Transport to serve MCP over
+    #[arg(long, env = "NIX_INSPECTOR_TRANSPORT", value_enum)]
+    pub transport: Option<Transport>,
+    /// The nix binary; found on PATH by default
+    #[arg(long, env = "NIX_INSPECTOR_NIX_PATH")]
+    pub nix_path: Option<PathBuf>,
+    /// Providers to serve, separated by commas
+    #[arg(long, env = "NIX_INSPECTOR_PROVIDERS", value_delimiter = ',')]
+    pub providers: Option<Vec<String>>,
+    /// Directory for cached nix command output
+    #[arg(long, env = "NIX_INSPECTOR_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// Do not cache nix command output
+    #[arg(long)]
+    pub no_cache: bool,
+    /// Log level; `RUST_LOG` applies when unset
+    #[arg(long, env = "LOG_LEVEL", value_enum, ignore_case = true)]
+    pub log_level: Option<LogLevel>,
+    /// File to write logs to instead of stderr
+    #[arg(long, env = "LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+    /// Access policy file
+    #[arg(long, env = "ACCESS_POLICY_FILE")]
+    pub policy_file: Option<PathBuf>,
+    /// Requests per minute and session; 0 for no limit
+    #[arg(long, env = "RATE_LIMIT_PER_MINUTE")]
+    pub rate_limit: Option<u32>,
+    /// Nix processes running at once; 0 for no limit
+    #[arg(long, env = "MAX_NIX_PROCESSES")]
+    pub max_nix_processes: Option<usize>,
+    /// Run a single tool and print its result instead of serving MCP
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Contents of the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub transport: Option<Transport>,
+    pub nix_path: Option<PathBuf>,
+    pub providers: Option<Vec<String>>,
+    pub cache_dir: Option<PathBuf>,
+    /// Largest size of the response cache in bytes
+    pub cache_max_bytes: Option<u64>,
+    pub cache: Option<bool>,
+    pub log_level: Option<LogLevel>,
+    pub log_file: Option<PathBuf>,
+    pub policy_file: Option<PathBuf>,
+    pub rate_limits: Option<RateLimitConfig>,
+}
+
+impl FileConfig {
+    /// Reads a config file. Relative paths in it are taken relative to the
+    /// file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read { path: path.to_path_buf(), source })?;
+        let mut config: Self = toml::from_str(&contents)
+            .map_err(|e| ConfigError::Parse { path: path.to_path_buf(), message: e.to_string() })?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        for file in [&mut config.nix_path, &mut config.cache_dir, &mut config.log_file, &mut config.policy_file]
+            .into_iter()
+            .flatten()
+        {
+            if file.is_relative() {
+                *file = base.join(&*file);
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Validated settings
+#[derive(Debug)]
+pub struct Config {
+    pub transport: Transport,
+    pub nix_path: Option<PathBuf>,
+    pub providers: Vec<String>,
+    /// Persistent data such as search indexes and the audit log
+    pub state_dir: PathBuf,
+    /// Response cache directory, `None` when caching is off
+    pub cache_dir: Option<PathBuf>,
+    pub cache_max_bytes: u64,
+    pub log_level: Option<LogLevel>,
+    pub log_file: Option<PathBuf>,
+    pub policy: Option<AccessPolicy>,
+    /// Bearer tokens of network clients
+    pub tokens_file: Option<PathBuf>,
+    pub rate_limits: RateLimitConfig,
+}
+
+impl Config {
+    /// Combines flags, which already include environment overrides, with
+    /// the config file they name, and validates the result
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+        Self::resolve(args, file)
+    }
+
+    pub fn resolve(args: Args, file: FileConfig) -> Result<Self, ConfigError> {
+        let transport = args.transport.or(file.transport).unwrap_or(Transport::Stdio);
+
+        let nix_path = args.nix_path.or(file.nix_path);
+        if let Some(path) = &nix_path {
+            if !is_executable(path) {
+                return Err(ConfigError::Invalid(format!("nix path {} is not an executable file", path.display())));
+            }
+        }
+
+        let providers = args.providers.or(file.providers).unwrap_or_else(|| vec!["system".to_string()]);
+        if providers.is_empty() {
+            return Err(ConfigError::Invalid("no providers are enabled".to_string()));
+        }
+        if let Some(unknown) = providers.iter().find(|p| !PROVIDERS.contains(&p.as_str())) {
+            return Err(ConfigError::Invalid(format!(
+                "unknown provider {}; available: {}",
+                unknown,
+                PROVIDERS.join(", ")
+            )));
+        }
+
+        let state_dir = state_dir();
+        let caching = !args.no_cache && file.cache.unwrap_or(true);
+        let cache_dir = caching.then(|| args.cache_dir.or(file.cache_dir).unwrap_or_else(|| state_dir.join(CACHE_DIR)));
+        if let Some(dir) = &cache_dir {
+            fs::create_dir_all(dir)
+                .map_err(|e| ConfigError::Invalid(format!("cannot create cache directory {}: {}", dir.display(), e)))?;
+        }
+
+        let log_file = args.log_file.or(file.log_file);
+        if let Some(path) = &log_file {
+            open_log_file(path)?;
+        }
+
+        // An explicitly configured policy must exist; the default one is optional
+        let policy_file = args
+            .policy_file
+            .or(file.policy_file)
+            .or_else(|| Some(state_dir.join(ACCESS_POLICY_FILE)).filter(|path| path.exists()));
+        let policy = match policy_file {
+            Some(path) => Some(AccessPolicy::load(&path).map_err(|e| {
+                ConfigError::Invalid(format!("cannot load access policy {}: {}", path.display(), e))
+            })?),
+            None => None,
+        };
+
+        // systemd passes the token file as a credential
+        let tokens_file = std::env::var_os("CREDENTIALS_DIRECTORY")
+            .map(|dir| PathBuf::from(dir).join(TOKENS_CREDENTIAL))
+            .filter(|path| path.exists());
+
+        let mut rate_limits = file.rate_limits.unwrap_or_default();
+        if let Some(rate) = args.rate_limit {
+            rate_limits.requests_per_minute = rate;
+        }
+        if let Some(max) = args.max_nix_processes {
+            rate_limits.max_nix_processes = max;
+        }
+
+        Ok(Self {
+            transport,
+            nix_path,
+            providers,
+            state_dir,
+            cache_dir,
+            cache_max_bytes: file.cache_max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            log_level: args.log_level.or(file.log_level),
+            log_file,
+            policy,
+            tokens_file,
+            rate_limits,
+        })
+    }
+
+    /// Sets up logging to stderr or the log file. Stdout carries the
+    /// protocol and is never logged to. Without a configured level,
+    /// `RUST_LOG` applies as usual.
+    pub fn init_logging(&self) -> Result<(), ConfigError> {
+        let mut builder = env_logger::Builder::new();
+        match self.log_level {
+            Some(level) => {
+                builder.filter_level(level.into());
+            }
+            None => {
+                builder.filter_level(LevelFilter::Info).parse_env("RUST_LOG");
+            }
+        }
+        if let Some(path) = &self.log_file {
+            builder.target(env_logger::Target::Pipe(Box::new(open_log_file(path)?)));
+        }
+        builder
+            .try_init()
+            .map_err(|e| ConfigError::Invalid(format!("cannot set up logging: {}", e)))
+    }
+
+    /// Builds the analyzer with the configured nix, state, cache, policy,
+    /// tokens and limits
+    pub fn system_analyzer(&self) -> Result<SystemAnalyzer, ConfigError> {
+        let authenticator = match &self.tokens_file {
+            Some(path) => Some(Authenticator::load(path).map_err(|e| {
+                ConfigError::Invalid(format!("cannot load tokens {}: {}", path.display(), e))
+            })?),
+            None => None,
+        };
+        Ok(SystemAnalyzer::with_settings(AnalyzerSettings {
+            nix_path: self.nix_path.as_ref().map(|path| path.to_string_lossy().to_string()),
+            state_dir: self.state_dir.clone(),
+            cache: self.cache_dir.clone().map(|dir| ResponseCache::new(dir, self.cache_max_bytes)),
+            policy: self.policy.clone().unwrap_or_else(AccessPolicy::unrestricted),
+            authenticator,
+            rate_limits: self.rate_limits.clone(),
+        })?)
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+fn open_log_file(path: &Path) -> Result<fs::File, ConfigError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| ConfigError::Invalid(format!("cannot open log file {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("nix-inspector-mcp").chain(flags.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let file: FileConfig = toml::from_str(&format!(
+            "cache_dir = \"{}\"\nlog_level = \"WARNING\"\nproviders = [\"system\"]\n\n[rate_limits]\nrequests_per_minute = 10\ntools = {{ eval = 2 }}\n",
+            dir.join("file-cache").display()
+        ))
+        .unwrap();
+        let cache = dir.join("flag-cache");
+        let config = Config::resolve(
+            args(&["--cache-dir", cache.to_str().unwrap(), "--log-level", "debug", "--max-nix-processes", "1"]),
+            file,
+        )
+        .unwrap();
+        assert_eq!(config.transport, Transport::Stdio);
+        assert_eq!(config.cache_dir.as_deref(), Some(cache.as_path()));
+        assert!(cache.is_dir());
+        assert_eq!(config.log_level, Some(LogLevel::Debug));
+        assert_eq!(config.rate_limits.requests_per_minute, 10);
+        assert_eq!(config.rate_limits.tools.get("eval"), Some(&2));
+        assert_eq!(config.rate_limits.max_nix_processes, 1);
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let invalid = |flags: &[&str]| match Config::resolve(args(flags), FileConfig::default()) {
+            Err(ConfigError::Invalid(message)) => message,
+            other => panic!("expected an invalid configuration, got {:?}", other),
+        };
+        assert!(invalid(&["--providers", "system,flake", "--no-cache"]).contains("unknown provider flake"));
+        assert!(invalid(&["--nix-path", "/nonexistent/nix", "--no-cache"]).contains("not an executable"));
+        assert!(invalid(&["--policy-file", "/nonexistent/policy.toml", "--no-cache"]).contains("access policy"));
+
+        assert!(Args::try_parse_from(["nix-inspector-mcp", "--transport", "carrier-pigeon"]).is_err());
+        assert!(Args::try_parse_from(["nix-inspector-mcp", "--bind", "127.0.0.1:8080"]).is_err());
+        assert!(Args::try_parse_from(["nix-inspector-mcp", "--log-level", "WARNING"]).is_ok());
+        assert!(toml::from_str::<FileConfig>("transprot = \"stdio\"").is_err());
+    }
+
+    #[test]
+    fn test_file_relative_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("config.toml");
+        fs::write(&path, "policy_file = \"policies/access.toml\"\ncache = false\n").unwrap();
+        let file = FileConfig::load(&path).unwrap();
+        assert_eq!(file.policy_file, Some(dir.join("policies/access.toml")));
+
+        fs::write(&path, "log_level = \"loud\"").unwrap();
+        assert!(matches!(FileConfig::load(&path), Err(ConfigError::Parse { .. })));
+        assert!(matches!(FileConfig::load(&dir.join("missing.toml")), Err(ConfigError::Read { .. })));
+    }
+}
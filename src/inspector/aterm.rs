@@ -0,0 +1,308 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use super::derivation::{Derivation, DerivationOutput};
+use super::system::SystemAnalyzerError;
+
+/// Environment variable holding structured attributes (`__structuredAttrs = true`)
+pub const STRUCTURED_ATTRS_KEY: &str = "__json";
+
+/// Cursor over the bytes of an ATerm-encoded derivation
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input: input.as_bytes(),
+            position: 0,
+        }
+    }
+
+    fn error(&self, message: &str) -> SystemAnalyzerError {
+        SystemAnalyzerError::ParseError(format!("Invalid derivation at byte {}: {}", self.position, message))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), SystemAnalyzerError> {
+        if self.input[self.position..].starts_with(expected.as_bytes()) {
+            self.position += expected.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", expected)))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, SystemAnalyzerError> {
+        self.expect("\"")?;
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.position += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    let escaped = self
+                        .input
+                        .get(self.position + 1)
+                        .copied()
+                        .ok_or_else(|| self.error("unterminated escape"))?;
+                    bytes.push(match escaped {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        other => other,
+                    });
+                    self.position += 2;
+                }
+                Some(byte) => {
+                    bytes.push(byte);
+                    self.position += 1;
+                }
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("string is not valid UTF-8"))
+    }
+
+    /// Parses a bracketed, comma separated list
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, SystemAnalyzerError>,
+    ) -> Result<Vec<T>, SystemAnalyzerError> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            match self.peek() {
+                Some(b',') => self.position += 1,
+                Some(b']') => {
+                    self.position += 1;
+                    return Ok(items);
+                }
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, SystemAnalyzerError> {
+        self.list(Self::string)
+    }
+}
+
+/// Parses an ATerm-encoded store derivation
+pub fn parse_derivation(input: &str) -> Result<Derivation, SystemAnalyzerError> {
+    let mut parser = Parser::new(input);
+    parser.expect("Derive(")?;
+
+    let outputs = parser.list(|p| {
+        p.expect("(")?;
+        let name = p.string()?;
+        p.expect(",")?;
+        let path = p.string()?;
+        p.expect(",")?;
+        let hash_algo = p.string()?;
+        p.expect(",")?;
+        let hash = p.string()?;
+        p.expect(")")?;
+        Ok((
+            name,
+            DerivationOutput {
+                path,
+                hash_algo: (!hash_algo.is_empty()).then_some(hash_algo),
+                hash: (!hash.is_empty()).then_some(hash),
+            },
+        ))
+    })?;
+    parser.expect(",")?;
+
+    let input_drvs = parser.list(|p| {
+        p.expect("(")?;
+        let path = p.string()?;
+        p.expect(",")?;
+        let outputs = p.strings()?;
+        p.expect(")")?;
+        Ok((path, outputs.into_iter().collect::<BTreeSet<_>>()))
+    })?;
+    parser.expect(",")?;
+    let input_srcs = parser.strings()?;
+    parser.expect(",")?;
+    let system = parser.string()?;
+    parser.expect(",")?;
+    let builder = parser.string()?;
+    parser.expect(",")?;
+    let args = parser.strings()?;
+    parser.expect(",")?;
+    let env = parser.list(|p| {
+        p.expect("(")?;
+        let key = p.string()?;
+        p.expect(",")?;
+        let value = p.string()?;
+        p.expect(")")?;
+        Ok((key, value))
+    })?;
+    parser.expect(")")?;
+
+    if parser.position != parser.input.len() {
+        return Err(parser.error("trailing data"));
+    }
+
+    Ok(Derivation {
+        outputs: outputs.into_iter().collect(),
+        input_srcs: input_srcs.into_iter().collect(),
+        input_drvs: input_drvs.into_iter().collect(),
+        system,
+        builder,
+        args,
+        env: env.into_iter().collect(),
+    })
+}
+
+/// Reads and parses a `.drv` file from the store
+pub fn read_derivation(path: &Path) -> Result<Derivation, SystemAnalyzerError> {
+    let contents = std::fs::read_to_string(path)?;
+    parse_derivation(&contents)
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_list<T>(out: &mut String, items: impl IntoIterator<Item = T>, mut item: impl FnMut(&mut String, T)) {
+    out.push('[');
+    for (i, value) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        item(out, value);
+    }
+    out.push(']');
+}
+
+impl Derivation {
+    /// Serializes the derivation in the ATerm format used by the store
+    pub fn to_aterm(&self) -> String {
+        let mut out = String::from("Derive(");
+        write_list(&mut out, &self.outputs, |out, (name, output)| {
+            out.push('(');
+            write_string(out, name);
+            out.push(',');
+            write_string(out, &output.path);
+            out.push(',');
+            write_string(out, output.hash_algo.as_deref().unwrap_or_default());
+            out.push(',');
+            write_string(out, output.hash.as_deref().unwrap_or_default());
+            out.push(')');
+        });
+        out.push(',');
+        write_list(&mut out, &self.input_drvs, |out, (path, outputs)| {
+            out.push('(');
+            write_string(out, path);
+            out.push(',');
+            write_list(out, outputs, |out, name| write_string(out, name));
+            out.push(')');
+        });
+        out.push(',');
+        write_list(&mut out, &self.input_srcs, |out, path| write_string(out, path));
+        out.push(',');
+        write_string(&mut out, &self.system);
+        out.push(',');
+        write_string(&mut out, &self.builder);
+        out.push(',');
+        write_list(&mut out, &self.args, |out, arg| write_string(out, arg));
+        out.push(',');
+        write_list(&mut out, &self.env, |out, (key, value)| {
+            out.push('(');
+            write_string(out, key);
+            out.push(',');
+            write_string(out, value);
+            out.push(')');
+        });
+        out.push(')');
+        out
+    }
+
+    /// Structured attributes of derivations built with `__structuredAttrs`
+    pub fn structured_attrs(&self) -> Option<BTreeMap<String, Value>> {
+        let json = self.env.get(STRUCTURED_ATTRS_KEY)?;
+        serde_json::from_str(json).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = include_str!("../../tests/fixtures/hello.drv");
+    const STRUCTURED: &str = include_str!("../../tests/fixtures/structured-attrs.drv");
+
+    #[test]
+    fn test_parse_hello() {
+        let drv = parse_derivation(HELLO).unwrap();
+        assert_eq!(drv.name(), Some("hello-2.12.1"));
+        assert_eq!(drv.system, "x86_64-linux");
+        assert_eq!(drv.args, vec!["-e", "/nix/store/v6x3cs394jgqfbi0a42pam708flxaphh-default-builder.sh"]);
+        assert_eq!(drv.outputs["out"].path, "/nix/store/9p3b5f4xrc4z8sjg3xq4kk4hqpk2rmlw-hello-2.12.1");
+        assert_eq!(drv.input_drvs.len(), 3);
+        assert!(drv.input_drvs.values().all(|outputs| outputs.contains("out")));
+        assert!(drv.env["preConfigure"].contains('\n'));
+        assert!(drv.structured_attrs().is_none());
+    }
+
+    #[test]
+    fn test_parse_fixed_output() {
+        let drv = parse_derivation(
+            r#"Derive([("out","/nix/store/1b9p07z77phvv2hf6gm9f28syp39f1ag-hello-2.12.1.tar.gz","sha256","8d99142afd92576f30b0cd7cb42a8dc6809998bc5d607d88761f512e26c7db20")],[],[],"x86_64-linux","builtin:fetchurl",[],[("name","hello-2.12.1.tar.gz"),("out","/nix/store/1b9p07z77phvv2hf6gm9f28syp39f1ag-hello-2.12.1.tar.gz")])"#,
+        )
+        .unwrap();
+        assert_eq!(drv.outputs["out"].hash_algo.as_deref(), Some("sha256"));
+        assert!(drv.outputs["out"].hash.is_some());
+        assert_eq!(drv.builder, "builtin:fetchurl");
+    }
+
+    #[test]
+    fn test_structured_attrs() {
+        let drv = parse_derivation(STRUCTURED).unwrap();
+        let attrs = drv.structured_attrs().unwrap();
+        assert_eq!(attrs["pname"], "jq");
+        assert_eq!(attrs["outputs"], serde_json::json!(["bin", "out"]));
+        assert_eq!(drv.outputs.len(), 2);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for fixture in [HELLO, STRUCTURED] {
+            let drv = parse_derivation(fixture).unwrap();
+            assert_eq!(drv.to_aterm(), fixture);
+        }
+    }
+
+    #[test]
+    fn test_rejects_malformed_input() {
+        assert!(parse_derivation("Derive([").is_err());
+        assert!(parse_derivation(&format!("{} ", HELLO)).is_err());
+        assert!(parse_derivation("Derive(\"unterminated").is_err());
+    }
+}
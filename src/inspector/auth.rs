@@ -0,0 +1,309 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::policy::{AccessDenial, AccessPolicy};
+use super::system::SystemAnalyzerError;
+use super::util::{unix_now, utc_timestamp};
+
+/// Name of the token file when passed with systemd's `LoadCredential`
+pub const TOKENS_CREDENTIAL: &str = "nix-inspector-mcp-tokens";
+
+/// Log of refused calls and failed logins in the state directory
+pub const AUDIT_LOG: &str = "audit.log";
+
+/// A client token. The token is given either as is or, to keep it out of
+/// the file, as the hex SHA-256 of the token.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenEntry {
+    /// Name of the client the token identifies
+    pub client: String,
+    pub role: String,
+    pub token: Option<String>,
+    pub token_sha256: Option<String>,
+}
+
+/// Contents of the token file: roles, each an access policy, and the
+/// tokens granting them
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenFile {
+    pub roles: BTreeMap<String, AccessPolicy>,
+    pub tokens: Vec<TokenEntry>,
+}
+
+/// Who is calling. Local sessions, such as over stdio, have no role and are
+/// only bound by the server's access policy.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Identity {
+    pub client: String,
+    pub role: Option<String>,
+}
+
+impl Identity {
+    pub fn local() -> Self {
+        Self {
+            client: "local".to_string(),
+            role: None,
+        }
+    }
+}
+
+/// A client connection and who it belongs to
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: String,
+    pub identity: Identity,
+}
+
+impl Session {
+    pub fn new(identity: Identity) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            identity,
+        }
+    }
+}
+
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+fn parse_hex_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Compares digests in constant time
+fn digests_equal(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Maps bearer tokens to identities and roles to access policies
+pub struct Authenticator {
+    tokens: Vec<([u8; 32], Identity)>,
+    roles: BTreeMap<String, AccessPolicy>,
+}
+
+impl Authenticator {
+    pub fn parse(contents: &str) -> Result<Self, SystemAnalyzerError> {
+        let invalid = |message: String| SystemAnalyzerError::ParseError(format!("invalid token file: {}", message));
+        let file: TokenFile = toml::from_str(contents).map_err(|e| invalid(e.to_string()))?;
+
+        let mut clients = HashSet::new();
+        let mut tokens = Vec::new();
+        for entry in file.tokens {
+            if !clients.insert(entry.client.clone()) {
+                return Err(invalid(format!("client {} is listed twice", entry.client)));
+            }
+            if !file.roles.contains_key(&entry.role) {
+                return Err(invalid(format!("client {} has unknown role {}", entry.client, entry.role)));
+            }
+            let digest = match (&entry.token, &entry.token_sha256) {
+                (Some(token), None) if !token.is_empty() => sha256(token.as_bytes()),
+                (None, Some(hex)) => parse_hex_digest(hex)
+                    .ok_or_else(|| invalid(format!("token_sha256 of client {} is not a SHA-256 digest", entry.client)))?,
+                _ => return Err(invalid(format!("client {} needs exactly one of token and token_sha256", entry.client))),
+            };
+            tokens.push((digest, Identity { client: entry.client, role: Some(entry.role) }));
+        }
+        Ok(Self { tokens, roles: file.roles })
+    }
+
+    /// Loads a token file. Relative directories in roles are taken
+    /// relative to the file.
+    pub fn load(path: &Path) -> Result<Self, SystemAnalyzerError> {
+        let mut authenticator = Self::parse(&fs::read_to_string(path)?)?;
+        for policy in authenticator.roles.values_mut() {
+            policy.resolve_dirs(path.parent().unwrap_or(Path::new("/")))?;
+        }
+        Ok(authenticator)
+    }
+
+    /// Identifies the client presenting an `Authorization` header value
+    pub fn authenticate(&self, authorization: &str) -> Option<Identity> {
+        let token = authorization.strip_prefix("Bearer ")?.trim();
+        let digest = sha256(token.as_bytes());
+        // Every entry is compared so the time taken does not reveal which
+        let mut found = None;
+        for (expected, identity) in &self.tokens {
+            if digests_equal(expected, &digest) {
+                found = Some(identity.clone());
+            }
+        }
+        found
+    }
+
+    /// Access policy of a role
+    pub fn role_policy(&self, role: &str) -> Option<&AccessPolicy> {
+        self.roles.get(role)
+    }
+
+    /// Checks a call made in a session against the policy of its role.
+    /// Local sessions have no role and pass.
+    pub fn check(&self, session: &Session, tool: &str, params: Option<&Value>, base: &Path) -> Result<(), AccessDenial> {
+        match session.identity.role.as_deref().and_then(|role| self.role_policy(role)) {
+            Some(policy) => policy.check(tool, params, base),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the store directory of every role's policy
+    pub fn with_store_dir(mut self, store_dir: &str) -> Self {
+        self.roles = std::mem::take(&mut self.roles)
+            .into_iter()
+            .map(|(name, policy)| (name, policy.with_store_dir(store_dir)))
+            .collect();
+        self
+    }
+}
+
+/// A refused call or failed login
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    pub timestamp: String,
+    pub session: Option<&'a str>,
+    pub client: Option<&'a str>,
+    pub role: Option<&'a str>,
+    /// Tool that was refused; `None` for failed logins
+    pub tool: Option<&'a str>,
+    pub reason: String,
+}
+
+/// Appends audit entries to a file as JSON lines
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Records an entry. Failing to write it is logged rather than failing
+    /// the call, which is refused either way.
+    pub fn record(&self, session: Option<&Session>, tool: Option<&str>, reason: &str) {
+        let entry = AuditEntry {
+            timestamp: utc_timestamp(unix_now()),
+            session: session.map(|s| s.id.as_str()),
+            client: session.map(|s| s.identity.client.as_str()),
+            role: session.and_then(|s| s.identity.role.as_deref()),
+            tool,
+            reason: reason.to_string(),
+        };
+        let result = (|| {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)
+        })();
+        if let Err(e) = result {
+            log::warn!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspector::policy::DenialReason;
+    use serde_json::json;
+
+    const TOKENS: &str = r#"
+[roles.reader]
+flake_roots = ["/srv/flakes"]
+
+[roles.builder]
+allow_mutating = true
+tools = ["nix_build", "get_build_log"]
+
+[[tokens]]
+client = "ci"
+role = "builder"
+token = "ci-secret"
+
+[[tokens]]
+client = "assistant"
+role = "reader"
+# printf %s assistant-secret | sha256sum
+token_sha256 = "513dee7ded8843f7391dccaa3d68d7f2f422d12c485eb4bbc448c5beb298d2ea"
+"#;
+
+    #[test]
+    fn test_authenticate() {
+        let auth = Authenticator::parse(TOKENS).unwrap();
+        let ci = auth.authenticate("Bearer ci-secret").unwrap();
+        assert_eq!(ci, Identity { client: "ci".to_string(), role: Some("builder".to_string()) });
+        assert_eq!(auth.authenticate("Bearer assistant-secret").unwrap().client, "assistant");
+        assert!(auth.authenticate("Bearer wrong").is_none());
+        assert!(auth.authenticate("ci-secret").is_none());
+
+        let builder = auth.role_policy("builder").unwrap();
+        assert!(builder.check("nix_build", None, Path::new("/")).is_ok());
+        assert!(builder.check("eval", None, Path::new("/")).is_err());
+        let reader = auth.role_policy("reader").unwrap();
+        let params = json!({ "installable": "/home/alice/app#x" });
+        assert!(reader.check("analyze_closure_size", Some(&params), Path::new("/")).is_err());
+    }
+
+    #[test]
+    fn test_sessions_by_role() {
+        let auth = Authenticator::parse(TOKENS).unwrap();
+        let ci = Session::new(auth.authenticate("Bearer ci-secret").unwrap());
+        let assistant = Session::new(auth.authenticate("Bearer assistant-secret").unwrap());
+        let local = Session::new(Identity::local());
+
+        let params = json!({ "installable": "/srv/flakes/app#default" });
+        assert!(auth.check(&ci, "nix_build", Some(&params), Path::new("/")).is_ok());
+        let denial = auth.check(&assistant, "nix_build", Some(&params), Path::new("/")).unwrap_err();
+        assert_eq!(denial.reason, DenialReason::Mutating);
+        assert!(auth.check(&local, "nix_build", Some(&params), Path::new("/")).is_ok());
+
+        assert!(auth.check(&assistant, "analyze_closure_size", Some(&params), Path::new("/")).is_ok());
+        let denial = auth.check(&ci, "analyze_closure_size", Some(&params), Path::new("/")).unwrap_err();
+        assert_eq!(denial.reason, DenialReason::ToolDisabled);
+    }
+
+    #[test]
+    fn test_invalid_token_files() {
+        let role = "[roles.r]\n";
+        assert!(Authenticator::parse(&format!("{role}[[tokens]]\nclient = \"a\"\nrole = \"x\"\ntoken = \"t\"")).is_err());
+        assert!(Authenticator::parse(&format!("{role}[[tokens]]\nclient = \"a\"\nrole = \"r\"")).is_err());
+        assert!(Authenticator::parse(&format!("{role}[[tokens]]\nclient = \"a\"\nrole = \"r\"\ntoken_sha256 = \"abc\"")).is_err());
+        let twice = format!("{role}[[tokens]]\nclient = \"a\"\nrole = \"r\"\ntoken = \"t\"\n[[tokens]]\nclient = \"a\"\nrole = \"r\"\ntoken = \"u\"");
+        assert!(Authenticator::parse(&twice).is_err());
+        assert!(Authenticator::parse("[roles.r]\nallow_build = true").is_err());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let log = AuditLog::new(dir.join(AUDIT_LOG));
+        let session = Session::new(Identity { client: "ci".to_string(), role: Some("builder".to_string()) });
+        log.record(Some(&session), Some("collect_garbage"), "tool collect_garbage is not enabled");
+        log.record(None, None, "invalid bearer token");
+
+        let contents = fs::read_to_string(dir.join(AUDIT_LOG)).unwrap();
+        let entries: Vec<Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["client"], "ci");
+        assert_eq!(entries[0]["role"], "builder");
+        assert_eq!(entries[0]["tool"], "collect_garbage");
+        assert_eq!(entries[1]["session"], Value::Null);
+        assert_eq!(entries[1]["reason"], "invalid bearer token");
+    }
+}
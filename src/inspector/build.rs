@@ -0,0 +1,445 @@
+use mcp_rust_sdk::types::Progress;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use super::logs::{summarize_failure, FailureSummary};
+use super::store::store_path_name;
+use super::system::SystemAnalyzerError;
+
+/// Log lines kept per derivation for failure reports
+pub const DEFAULT_LOG_LINES: usize = 50;
+
+/// Prefix of structured log lines in `--log-format internal-json` output
+const INTERNAL_JSON_PREFIX: &str = "@nix ";
+
+// Activity types, see `ActivityType` in Nix's `logging.hh`
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+
+// Result types, see `ResultType` in Nix's `logging.hh`
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_SET_PHASE: u64 = 104;
+const RES_PROGRESS: u64 = 105;
+const RES_SET_EXPECTED: u64 = 106;
+const RES_POST_BUILD_LOG_LINE: u64 = 107;
+
+/// Parameters of the `nix_build` method
+#[derive(Debug, Deserialize)]
+pub struct BuildParams {
+    pub installable: String,
+    #[serde(default)]
+    pub max_log_lines: Option<usize>,
+}
+
+/// Outcome of a build
+#[derive(Debug, Default, Serialize)]
+pub struct BuildResult {
+    pub success: bool,
+    pub outputs: Vec<BuiltDerivation>,
+    pub built: u64,
+    pub downloaded: u64,
+    pub failures: Vec<BuildFailure>,
+    /// Derivations not built because one of their dependencies failed
+    pub dependency_failures: Vec<String>,
+    /// Wall clock build time per derivation built, in milliseconds
+    pub build_times_ms: BTreeMap<String, u64>,
+    /// Error messages printed by Nix
+    pub errors: Vec<String>,
+}
+
+/// A derivation and its output paths, from `nix build --json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuiltDerivation {
+    #[serde(rename = "drvPath")]
+    pub drv_path: String,
+    #[serde(default)]
+    pub outputs: BTreeMap<String, String>,
+}
+
+/// A derivation that failed to build
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildFailure {
+    pub drv_path: String,
+    pub phase: Option<String>,
+    pub log_tail: Vec<String>,
+    pub summary: FailureSummary,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Counter {
+    done: u64,
+    expected: u64,
+    running: u64,
+    failed: u64,
+}
+
+#[derive(Debug)]
+struct BuildActivity {
+    drv_path: String,
+    started: Instant,
+    phase: Option<String>,
+    log: VecDeque<String>,
+}
+
+/// Follows the activity stream of `--log-format internal-json`
+#[derive(Debug)]
+pub struct BuildTracker {
+    max_log_lines: usize,
+    activity_types: HashMap<u64, u64>,
+    builds: HashMap<u64, BuildActivity>,
+    finished: HashMap<String, BuildActivity>,
+    build_times: HashMap<String, Duration>,
+    counters: HashMap<u64, Counter>,
+    downloads: u64,
+    errors: Vec<String>,
+    last_progress: Option<(u64, Option<u64>, String)>,
+}
+
+impl BuildTracker {
+    pub fn new(max_log_lines: usize) -> Self {
+        Self {
+            max_log_lines,
+            activity_types: HashMap::new(),
+            builds: HashMap::new(),
+            finished: HashMap::new(),
+            build_times: HashMap::new(),
+            counters: HashMap::new(),
+            downloads: 0,
+            errors: Vec::new(),
+            last_progress: None,
+        }
+    }
+
+    /// Consumes one line of stderr, returning a progress update if the
+    /// overall progress changed.
+    pub fn handle_line(&mut self, line: &str) -> Option<Progress> {
+        let event: Value = serde_json::from_str(line.strip_prefix(INTERNAL_JSON_PREFIX)?).ok()?;
+        let id = event.get("id").and_then(Value::as_u64).unwrap_or_default();
+        let kind = event.get("type").and_then(Value::as_u64).unwrap_or_default();
+        let fields = event.get("fields").and_then(Value::as_array).cloned().unwrap_or_default();
+        let field_str = |i: usize| fields.get(i).and_then(Value::as_str).map(str::to_string);
+        let field_u64 = |i: usize| fields.get(i).and_then(Value::as_u64).unwrap_or_default();
+
+        match event.get("action").and_then(Value::as_str)? {
+            "start" => {
+                self.activity_types.insert(id, kind);
+                match kind {
+                    ACT_BUILD => {
+                        self.builds.insert(
+                            id,
+                            BuildActivity {
+                                drv_path: field_str(0).unwrap_or_default(),
+                                started: Instant::now(),
+                                phase: None,
+                                log: VecDeque::new(),
+                            },
+                        );
+                    }
+                    ACT_FILE_TRANSFER => self.downloads += 1,
+                    _ => {}
+                }
+            }
+            "stop" => {
+                if let Some(build) = self.builds.remove(&id) {
+                    self.build_times.insert(build.drv_path.clone(), build.started.elapsed());
+                    self.finished.insert(build.drv_path.clone(), build);
+                }
+            }
+            "result" => match kind {
+                RES_BUILD_LOG_LINE | RES_POST_BUILD_LOG_LINE => {
+                    if let (Some(build), Some(line)) = (self.builds.get_mut(&id), field_str(0)) {
+                        build.log.push_back(line);
+                        while build.log.len() > self.max_log_lines {
+                            build.log.pop_front();
+                        }
+                    }
+                }
+                RES_SET_PHASE => {
+                    if let Some(build) = self.builds.get_mut(&id) {
+                        build.phase = field_str(0);
+                    }
+                }
+                RES_PROGRESS => {
+                    let counter = self.counters.entry(id).or_default();
+                    counter.done = field_u64(0);
+                    counter.expected = field_u64(1);
+                    counter.running = field_u64(2);
+                    counter.failed = field_u64(3);
+                }
+                RES_SET_EXPECTED => {
+                    // fields: [activity type, expected]
+                    let counter = self.counters.entry(id).or_default();
+                    counter.expected = counter.expected.max(field_u64(1));
+                }
+                _ => {}
+            },
+            "msg" => {
+                // Level 0 is `lvlError`
+                if event.get("level").and_then(Value::as_u64) == Some(0) {
+                    if let Some(msg) = event.get("msg").and_then(Value::as_str) {
+                        self.errors.push(strip_ansi(msg));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        self.progress()
+    }
+
+    fn counter(&self, activity_type: u64) -> Counter {
+        self.counters
+            .iter()
+            .filter(|(id, _)| self.activity_types.get(id) == Some(&activity_type))
+            .fold(Counter::default(), |total, (_, c)| Counter {
+                done: total.done + c.done,
+                expected: total.expected + c.expected,
+                running: total.running + c.running,
+                failed: total.failed + c.failed,
+            })
+    }
+
+    fn progress(&mut self) -> Option<Progress> {
+        let builds = self.counter(ACT_BUILDS);
+        let copies = self.counter(ACT_COPY_PATHS);
+        let done = builds.done + copies.done;
+        let expected = builds.expected + copies.expected;
+
+        let mut message = format!(
+            "{}/{} derivations built, {}/{} paths downloaded",
+            builds.done, builds.expected, copies.done, copies.expected
+        );
+        if builds.failed > 0 {
+            message.push_str(&format!(", {} failed", builds.failed));
+        }
+        let mut running: Vec<&BuildActivity> = self.builds.values().collect();
+        running.sort_by(|a, b| a.drv_path.cmp(&b.drv_path));
+        if let Some(build) = running.first() {
+            message.push_str(&format!("; building {}", store_path_name(&build.drv_path)));
+            if let Some(phase) = &build.phase {
+                message.push_str(&format!(" ({})", phase));
+            }
+        }
+
+        let total = (expected > 0).then_some(expected);
+        let current = (done, total, message);
+        if self.last_progress.as_ref() == Some(&current) {
+            return None;
+        }
+        self.last_progress = Some(current.clone());
+        Some(Progress {
+            progress: current.0,
+            total: current.1,
+            message: Some(current.2),
+        })
+    }
+
+    /// Builds the final result once Nix exited
+    pub fn finish(mut self, success: bool, outputs: Vec<BuiltDerivation>) -> BuildResult {
+        let builds = self.counter(ACT_BUILDS);
+        let copies = self.counter(ACT_COPY_PATHS);
+
+        // Nix reports failed builds as `builder for '<drv>' failed ...`
+        // and skipped ones as `1 dependencies of derivation '<drv>' failed to build`
+        let dependency_failures = self
+            .errors
+            .iter()
+            .filter_map(|error| quoted_derivation(error, "dependencies of derivation '"))
+            .collect();
+        let mut failures = Vec::new();
+        for error in &self.errors {
+            let Some(drv_path) = quoted_derivation(error, "builder for '") else {
+                continue;
+            };
+            let activity = self
+                .finished
+                .remove(&drv_path)
+                .or_else(|| {
+                    let id = self.builds.iter().find(|(_, b)| b.drv_path == drv_path).map(|(id, _)| *id)?;
+                    self.builds.remove(&id)
+                });
+            let log_tail: Vec<String> = activity.as_ref().map(|a| a.log.iter().cloned().collect()).unwrap_or_default();
+            let mut summary = summarize_failure(&log_tail.join("\n"));
+            // The activity knows the phase even if its header scrolled out of the tail
+            if let Some(phase) = activity.and_then(|a| a.phase) {
+                summary.phase = Some(phase);
+            }
+            failures.push(BuildFailure {
+                drv_path,
+                phase: summary.phase.clone(),
+                log_tail,
+                summary,
+            });
+        }
+
+        BuildResult {
+            success,
+            outputs,
+            built: builds.done,
+            downloaded: copies.done.max(self.downloads),
+            failures,
+            dependency_failures,
+            build_times_ms: self
+                .build_times
+                .iter()
+                .map(|(drv, time)| (drv.clone(), time.as_millis() as u64))
+                .collect(),
+            errors: self.errors,
+        }
+    }
+}
+
+/// Extracts the derivation quoted after `marker` in an error message
+fn quoted_derivation(message: &str, marker: &str) -> Option<String> {
+    let start = message.find(marker)? + marker.len();
+    let end = message[start..].find('\'')? + start;
+    Some(message[start..end].to_string())
+}
+
+/// Removes ANSI escape sequences from Nix messages
+pub fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            if chars.peek() == Some(&'[') {
+                chars.next();
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Runs a `nix` command with `--log-format internal-json`, reporting
+/// progress as the activity stream on stderr advances. Stdout is
+/// returned once the command exits.
+pub async fn stream_build<F>(
+    nix_cmd: &str,
+    args: &[&str],
+    max_log_lines: usize,
+    mut on_progress: F,
+) -> Result<(bool, String, BuildTracker), SystemAnalyzerError>
+where
+    F: FnMut(Progress),
+{
+    let mut child = Command::new(nix_cmd)
+        .args(args)
+        .args(["--log-format", "internal-json"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| SystemAnalyzerError::NixCommandError(e.to_string()))?;
+
+    let stdout = child.stdout.take();
+    let stdout_task = tokio::spawn(async move {
+        let mut buffer = String::new();
+        if let Some(stdout) = stdout {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                buffer.push_str(&line);
+                buffer.push('\n');
+            }
+        }
+        buffer
+    });
+
+    let mut tracker = BuildTracker::new(max_log_lines);
+    if let Some(stderr) = child.stderr.take() {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(line) = lines.next_line().await? {
+            if let Some(progress) = tracker.handle_line(&line) {
+                on_progress(progress);
+            }
+        }
+    }
+
+    let status = child.wait().await?;
+    let stdout = stdout_task
+        .await
+        .map_err(|e| SystemAnalyzerError::NixCommandError(e.to_string()))?;
+    Ok((status.success(), stdout, tracker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DRV: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12.1.drv";
+
+    fn feed(tracker: &mut BuildTracker, events: &[Value]) -> Vec<Progress> {
+        events
+            .iter()
+            .filter_map(|event| tracker.handle_line(&format!("@nix {}", event)))
+            .collect()
+    }
+
+    #[test]
+    fn test_tracker_reports_progress() {
+        let mut tracker = BuildTracker::new(2);
+        let updates = feed(
+            &mut tracker,
+            &[
+                serde_json::json!({"action": "start", "id": 1, "type": ACT_BUILDS, "fields": []}),
+                serde_json::json!({"action": "result", "id": 1, "type": RES_PROGRESS, "fields": [0, 2, 0, 0]}),
+                serde_json::json!({"action": "start", "id": 2, "type": ACT_BUILD, "fields": [DRV, "", 1, 1]}),
+                serde_json::json!({"action": "result", "id": 2, "type": RES_SET_PHASE, "fields": ["buildPhase"]}),
+                serde_json::json!({"action": "result", "id": 2, "type": RES_BUILD_LOG_LINE, "fields": ["gcc -c hello.c"]}),
+                serde_json::json!({"action": "result", "id": 1, "type": RES_PROGRESS, "fields": [1, 2, 1, 0]}),
+            ],
+        );
+        let last = updates.last().unwrap();
+        assert_eq!(last.progress, 1);
+        assert_eq!(last.total, Some(2));
+        let message = last.message.as_deref().unwrap();
+        assert!(message.contains("1/2 derivations built"));
+        assert!(message.contains("building hello-2.12.1.drv (buildPhase)"));
+
+        // Log lines alone do not change the progress
+        assert!(tracker
+            .handle_line(&format!("@nix {}", serde_json::json!({"action": "result", "id": 2, "type": RES_BUILD_LOG_LINE, "fields": ["x"]})))
+            .is_none());
+        assert!(tracker.handle_line("plain text").is_none());
+    }
+
+    #[test]
+    fn test_tracker_collects_failures() {
+        let mut tracker = BuildTracker::new(2);
+        feed(
+            &mut tracker,
+            &[
+                serde_json::json!({"action": "start", "id": 2, "type": ACT_BUILD, "fields": [DRV, "", 1, 1]}),
+                serde_json::json!({"action": "result", "id": 2, "type": RES_SET_PHASE, "fields": ["checkPhase"]}),
+                serde_json::json!({"action": "result", "id": 2, "type": RES_BUILD_LOG_LINE, "fields": ["one"]}),
+                serde_json::json!({"action": "result", "id": 2, "type": RES_BUILD_LOG_LINE, "fields": ["two"]}),
+                serde_json::json!({"action": "result", "id": 2, "type": RES_BUILD_LOG_LINE, "fields": ["three"]}),
+                serde_json::json!({"action": "stop", "id": 2}),
+                serde_json::json!({"action": "msg", "level": 0, "msg": format!("\u{1b}[31;1merror:\u{1b}[0m builder for '{}' failed with exit code 2", DRV)}),
+                serde_json::json!({"action": "msg", "level": 0, "msg": "error: 1 dependencies of derivation '/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-app.drv' failed to build"}),
+            ],
+        );
+        let result = tracker.finish(false, Vec::new());
+        assert!(!result.success);
+        assert_eq!(result.failures.len(), 1);
+        assert_eq!(result.failures[0].drv_path, DRV);
+        assert_eq!(result.failures[0].phase.as_deref(), Some("checkPhase"));
+        assert_eq!(result.failures[0].log_tail, vec!["two", "three"]);
+        assert!(result.errors[0].starts_with("error: builder for"));
+        assert_eq!(result.dependency_failures, vec!["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-app.drv"]);
+        assert!(result.build_times_ms.contains_key(DRV));
+    }
+}
@@ -0,0 +1,438 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use super::flake::local_flake_dir;
+use super::system::SystemAnalyzerError;
+use super::util::unix_now;
+
+/// Directory below the state directory holding cached responses
+pub const CACHE_DIR: &str = "cache";
+
+/// Total size of cached responses before the least recently used are evicted
+pub const DEFAULT_MAX_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Parameters of the `invalidate_cache` method
+#[derive(Debug, Default, Deserialize)]
+pub struct InvalidateCacheParams {
+    /// Only drop the responses for this flake, all responses by default
+    #[serde(default)]
+    pub flake: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub directory: String,
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheInvalidation {
+    pub removed: usize,
+    pub stats: CacheStats,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    command: Vec<String>,
+    created: i64,
+    output: String,
+}
+
+fn sha256_hex(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        // Length prefixes keep `["ab", "c"]` and `["a", "bc"]` apart
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hex(hasher)
+}
+
+fn hex(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, b| {
+            use std::fmt::Write;
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+}
+
+/// Whether a flake reference pins its contents, e.g. by revision
+fn is_locked(flake: &str) -> bool {
+    let (path, query) = flake.split_once('?').unwrap_or((flake, ""));
+    query.split('&').any(|pair| pair.starts_with("rev=") || pair.starts_with("narHash="))
+        || path
+            .rsplit('/')
+            .next()
+            .is_some_and(|rev| rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Hash of the NAR serialisation of a path, the `narHash` Nix gives a
+/// `path:` flake copied from it
+fn nar_hash(path: &Path) -> io::Result<String> {
+    fn string(hasher: &mut Sha256, s: &[u8]) {
+        hasher.update((s.len() as u64).to_le_bytes());
+        hasher.update(s);
+        hasher.update(&[0; 8][..(8 - s.len() % 8) % 8]);
+    }
+
+    fn node(hasher: &mut Sha256, path: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        string(hasher, b"(");
+        string(hasher, b"type");
+        if metadata.is_symlink() {
+            string(hasher, b"symlink");
+            string(hasher, b"target");
+            string(hasher, fs::read_link(path)?.as_os_str().as_bytes());
+        } else if metadata.is_dir() {
+            string(hasher, b"directory");
+            let mut names = fs::read_dir(path)?.map(|entry| entry.map(|e| e.file_name())).collect::<io::Result<Vec<_>>>()?;
+            names.sort();
+            for name in names {
+                string(hasher, b"entry");
+                string(hasher, b"(");
+                string(hasher, b"name");
+                string(hasher, name.as_bytes());
+                string(hasher, b"node");
+                node(hasher, &path.join(&name))?;
+                string(hasher, b")");
+            }
+        } else {
+            string(hasher, b"regular");
+            if metadata.permissions().mode() & 0o100 != 0 {
+                string(hasher, b"executable");
+                string(hasher, b"");
+            }
+            string(hasher, b"contents");
+            let len = metadata.len();
+            hasher.update(len.to_le_bytes());
+            let copied = io::copy(&mut fs::File::open(path)?, hasher)?;
+            if copied != len {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, format!("{} changed while hashing", path.display())));
+            }
+            hasher.update(&[0; 8][..(8 - len as usize % 8) % 8]);
+        }
+        string(hasher, b")");
+        Ok(())
+    }
+
+    let mut hasher = Sha256::new();
+    string(&mut hasher, b"nix-archive-1");
+    node(&mut hasher, path)?;
+    Ok(hex(hasher))
+}
+
+/// State of the files git tracks in the checkout containing `dir`, which
+/// are the files Nix copies for a flake in it: the staged blobs, and the
+/// contents of tracked files changed since. `None` outside of a checkout.
+fn git_tracked_state(dir: &Path) -> Option<Vec<u8>> {
+    let git = |dir: &Path, args: &[&str]| {
+        let output = Command::new("git").arg("-C").arg(dir).args(args).output().ok()?;
+        output.status.success().then_some(output.stdout)
+    };
+    let top = git(dir, &["rev-parse", "--show-toplevel"])?;
+    let top = PathBuf::from(String::from_utf8(top).ok()?.trim_end());
+    let mut state = git(&top, &["ls-files", "--stage", "-z"])?;
+    for changed in git(&top, &["diff", "--name-only", "-z"])?.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+        state.push(0);
+        state.extend_from_slice(changed);
+        // Deleted files leave just their name
+        if let Ok(contents) = fs::read(top.join(OsStr::from_bytes(changed))) {
+            state.extend_from_slice(sha256_hex(&[&contents]).as_bytes());
+        }
+    }
+    Some(state)
+}
+
+/// Fingerprint of a local flake: its location, the contents of its lock
+/// file, and the source Nix would copy. In a git checkout that is the state
+/// of the tracked files, as for `git+file:` flakes; otherwise, or for
+/// `path:` references, the NAR hash of the whole directory.
+fn local_fingerprint(dir: &Path, path_fetcher: bool) -> Option<String> {
+    if !dir.join("flake.nix").is_file() {
+        return None;
+    }
+    // Outputs such as `nix flake metadata` mention where the flake is
+    let dir = fs::canonicalize(dir).ok()?;
+    let dir = dir.as_path();
+    let lock = fs::read(dir.join("flake.lock")).unwrap_or_default();
+    let source = match git_tracked_state(dir).filter(|_| !path_fetcher) {
+        Some(state) => [b"git".as_slice(), &state].concat(),
+        None => [b"path".as_slice(), nar_hash(dir).ok()?.as_bytes()].concat(),
+    };
+    Some(sha256_hex(&[b"local", dir.as_os_str().as_encoded_bytes(), &lock, &source]))
+}
+
+/// Identifies the contents of a flake. Local flakes are fingerprinted from
+/// disk; remote flakes only when locked. Unlocked references such as
+/// `github:NixOS/nixpkgs` can change at any time and have no fingerprint.
+pub fn flake_fingerprint(flake: &str, base: &Path) -> Option<String> {
+    match local_flake_dir(flake, base) {
+        Some(dir) => local_fingerprint(&dir, flake.starts_with("path:")),
+        None if is_locked(flake) => Some(sha256_hex(&[b"locked", flake.as_bytes()])),
+        None => None,
+    }
+}
+
+/// Outputs of nix commands, persisted by flake fingerprint and command line.
+/// A changed flake gets a new fingerprint, so its old responses are never
+/// read again and age out under the size limit.
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl ResponseCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    fn entry_path(&self, fingerprint: &str, command: &[&str]) -> PathBuf {
+        let parts: Vec<&[u8]> = command.iter().map(|part| part.as_bytes()).collect();
+        self.dir.join(fingerprint).join(format!("{}.json", sha256_hex(&parts)))
+    }
+
+    /// The cached output of a command run against a flake
+    pub fn get(&self, fingerprint: &str, command: &[&str]) -> Option<String> {
+        let path = self.entry_path(fingerprint, command);
+        let entry: CacheEntry = serde_json::from_slice(&fs::read(&path).ok()?).ok()?;
+        // The modification time orders entries for eviction
+        if let Ok(file) = fs::File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(entry.output)
+    }
+
+    /// Stores the output of a command, evicting the least recently used
+    /// entries beyond the size limit
+    pub fn put(&self, fingerprint: &str, command: &[&str], output: &str) -> Result<(), SystemAnalyzerError> {
+        let entry = CacheEntry {
+            command: command.iter().map(|part| part.to_string()).collect(),
+            created: unix_now(),
+            output: output.to_string(),
+        };
+        let contents = serde_json::to_vec(&entry)?;
+        if contents.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        let path = self.entry_path(fingerprint, command);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, &path)?;
+        self.evict()
+    }
+
+    /// Entries with their size and last use, oldest first
+    fn entries(&self) -> Result<Vec<(SystemTime, u64, PathBuf)>, SystemAnalyzerError> {
+        let mut entries = Vec::new();
+        if !self.dir.is_dir() {
+            return Ok(entries);
+        }
+        for group in fs::read_dir(&self.dir)? {
+            let group = group?.path();
+            if !group.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&group)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    let metadata = fs::metadata(&path)?;
+                    entries.push((metadata.modified()?, metadata.len(), path));
+                }
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn evict(&self) -> Result<(), SystemAnalyzerError> {
+        let entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            total -= size;
+            if let Some(group) = path.parent() {
+                // Only succeeds once the group is empty
+                let _ = fs::remove_dir(group);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the responses of one fingerprint, or all responses, returning
+    /// the number of entries removed
+    pub fn invalidate(&self, fingerprint: Option<&str>) -> Result<usize, SystemAnalyzerError> {
+        let entries = self.entries()?;
+        let mut removed = 0;
+        for (_, _, path) in entries {
+            let group = path.parent().and_then(Path::file_name).map(|name| name.to_string_lossy().to_string());
+            if fingerprint.is_none() || group.as_deref() == fingerprint {
+                fs::remove_file(&path)?;
+                removed += 1;
+                if let Some(group) = path.parent() {
+                    let _ = fs::remove_dir(group);
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn stats(&self) -> Result<CacheStats, SystemAnalyzerError> {
+        let entries = self.entries()?;
+        Ok(CacheStats {
+            directory: self.dir.to_string_lossy().to_string(),
+            entries: entries.len(),
+            bytes: entries.iter().map(|(_, size, _)| size).sum(),
+            max_bytes: self.max_bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flake_fingerprint() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("modules")).unwrap();
+        fs::write(dir.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        fs::write(dir.join("flake.lock"), "{\"version\": 7}").unwrap();
+        fs::write(dir.join("modules/default.nix"), "{ }").unwrap();
+
+        let base = dir.parent().unwrap();
+        let relative = format!("./{}", dir.file_name().unwrap().to_string_lossy());
+        let fingerprint = flake_fingerprint(&relative, base).unwrap();
+        assert_eq!(flake_fingerprint(&format!("path:{}", dir.display()), Path::new("/")), Some(fingerprint.clone()));
+        // Nix copies hidden files too
+        fs::write(dir.join(".envrc"), "use flake").unwrap();
+        let fingerprint = {
+            let hidden = flake_fingerprint(&relative, base).unwrap();
+            assert_ne!(hidden, fingerprint);
+            hidden
+        };
+        // Lock file changes are part of it
+        fs::write(dir.join("flake.lock"), "{\"version\": 7, \"root\": \"root\"}").unwrap();
+        let relocked = flake_fingerprint(&relative, base).unwrap();
+        assert_ne!(relocked, fingerprint);
+        // And so are other files
+        fs::write(dir.join("modules/default.nix"), "{ imports = [ ]; }").unwrap();
+        assert_ne!(flake_fingerprint(&relative, base).unwrap(), relocked);
+
+        assert!(flake_fingerprint("./missing", base).is_none());
+        assert!(flake_fingerprint("github:NixOS/nixpkgs/nixos-unstable", base).is_none());
+        assert!(flake_fingerprint("github:NixOS/nixpkgs/b06025f1533a1e07b6db3e75151caa155d1c7eb3", base).is_some());
+        assert!(flake_fingerprint("git+https://example.org/repo?rev=b06025f1533a1e07b6db3e75151caa155d1c7eb3", base).is_some());
+    }
+
+    #[test]
+    fn test_git_flake_fingerprint() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        fs::write(dir.join(".envrc"), "use flake").unwrap();
+        let git = |args: &[&str]| assert!(Command::new("git").arg("-C").arg(dir).args(args).status().unwrap().success());
+        git(&["init", "--quiet"]);
+        git(&["add", "flake.nix", ".envrc"]);
+
+        let flake = dir.to_string_lossy().to_string();
+        let fingerprint = flake_fingerprint(&flake, Path::new("/")).unwrap();
+        // Untracked files are not copied by Nix
+        fs::write(dir.join("target/out"), "build output").unwrap();
+        assert_eq!(flake_fingerprint(&flake, Path::new("/")), Some(fingerprint.clone()));
+        // But a `path:` reference copies them
+        assert_ne!(flake_fingerprint(&format!("path:{}", flake), Path::new("/")), Some(fingerprint.clone()));
+        // Changes to tracked files count, staged or not, hidden or not
+        fs::write(dir.join(".envrc"), "use flake .#dev").unwrap();
+        let changed = flake_fingerprint(&flake, Path::new("/")).unwrap();
+        assert_ne!(changed, fingerprint);
+        git(&["add", ".envrc"]);
+        let staged = flake_fingerprint(&flake, Path::new("/")).unwrap();
+        assert_ne!(staged, fingerprint);
+        fs::write(dir.join(".envrc"), "use flake .#ci").unwrap();
+        assert_ne!(flake_fingerprint(&flake, Path::new("/")).unwrap(), staged);
+    }
+
+    #[test]
+    fn test_get_and_put() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cache = ResponseCache::new(dir.to_path_buf(), DEFAULT_MAX_BYTES);
+        let command = ["/bin/nix", "flake", "show", "--json"];
+        assert_eq!(cache.get("aaaa", &command), None);
+
+        cache.put("aaaa", &command, "{\"packages\": {}}").unwrap();
+        assert_eq!(cache.get("aaaa", &command).as_deref(), Some("{\"packages\": {}}"));
+        // Other fingerprints and arguments miss
+        assert_eq!(cache.get("bbbb", &command), None);
+        assert_eq!(cache.get("aaaa", &["/bin/nix", "flake", "show"]), None);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.entries, 1);
+        assert!(stats.bytes > 0);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let output = "x".repeat(100);
+        // Room for two entries of about 150 bytes
+        let cache = ResponseCache::new(dir.to_path_buf(), 350);
+        for (i, fingerprint) in ["aaaa", "bbbb", "cccc"].iter().enumerate() {
+            if i == 2 {
+                // Using the oldest entry makes the second one the least recently used
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                assert!(cache.get("aaaa", &["show"]).is_some());
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            cache.put(fingerprint, &["show"], &output).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert!(cache.get("aaaa", &["show"]).is_some());
+        assert!(cache.get("bbbb", &["show"]).is_none());
+        assert!(cache.get("cccc", &["show"]).is_some());
+        assert!(!dir.join("bbbb").exists());
+
+        // Entries larger than the cache are not stored
+        cache.put("dddd", &["show"], &"x".repeat(400)).unwrap();
+        assert!(cache.get("dddd", &["show"]).is_none());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cache = ResponseCache::new(dir.to_path_buf(), DEFAULT_MAX_BYTES);
+        cache.put("aaaa", &["metadata"], "{}").unwrap();
+        cache.put("aaaa", &["show"], "{}").unwrap();
+        cache.put("bbbb", &["show"], "{}").unwrap();
+
+        assert_eq!(cache.invalidate(Some("aaaa")).unwrap(), 2);
+        assert!(cache.get("aaaa", &["show"]).is_none());
+        assert!(cache.get("bbbb", &["show"]).is_some());
+        assert_eq!(cache.invalidate(None).unwrap(), 1);
+        assert_eq!(cache.stats().unwrap().entries, 0);
+    }
+}
@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::build::BuildResult;
+use super::logs::FailureSummary;
+use super::util::default_flake;
+
+/// Parameters of the `flake_check` method
+#[derive(Debug, Deserialize)]
+pub struct FlakeCheckParams {
+    #[serde(default = "default_flake")]
+    pub flake: String,
+    /// System whose checks run, the current system by default
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Names of `checks.<system>.*` to run, all of them by default
+    #[serde(default)]
+    pub checks: Option<Vec<String>>,
+    #[serde(default)]
+    pub max_log_lines: Option<usize>,
+}
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    /// Built successfully during this run
+    Passed,
+    /// Already built or substituted, so known to pass
+    Cached,
+    Failed,
+    /// Not built because a dependency failed
+    DependencyFailed,
+}
+
+/// A row of the check table
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub drv_path: String,
+    pub status: CheckStatus,
+    pub duration_ms: Option<u64>,
+    pub log_excerpt: Vec<String>,
+    pub summary: Option<FailureSummary>,
+}
+
+/// Results of all selected checks
+#[derive(Debug, Serialize)]
+pub struct FlakeCheckReport {
+    pub flake: String,
+    pub system: String,
+    pub success: bool,
+    pub passed: usize,
+    pub failed: usize,
+    pub checks: Vec<CheckResult>,
+    /// Errors printed by Nix that are not tied to a check, e.g. evaluation errors
+    pub errors: Vec<String>,
+}
+
+/// Nix expression mapping each check to its derivation path
+pub const CHECK_DRV_PATHS: &str = "builtins.mapAttrs (_: check: check.drvPath)";
+
+/// Restricts the checks of a flake to the requested names
+pub fn select_checks(
+    available: BTreeMap<String, String>,
+    requested: Option<&[String]>,
+) -> Result<BTreeMap<String, String>, Vec<String>> {
+    let Some(requested) = requested else {
+        return Ok(available);
+    };
+    let unknown: Vec<String> = requested
+        .iter()
+        .filter(|name| !available.contains_key(*name))
+        .cloned()
+        .collect();
+    if !unknown.is_empty() {
+        return Err(unknown);
+    }
+    Ok(available
+        .into_iter()
+        .filter(|(name, _)| requested.contains(name))
+        .collect())
+}
+
+/// Builds the check table from the result of building all checks with
+/// `--keep-going`
+pub fn check_report(
+    flake: &str,
+    system: &str,
+    checks: &BTreeMap<String, String>,
+    build: BuildResult,
+) -> FlakeCheckReport {
+    let mut results: Vec<CheckResult> = checks
+        .iter()
+        .map(|(name, drv_path)| {
+            let failure = build.failures.iter().find(|f| &f.drv_path == drv_path);
+            let duration_ms = build.build_times_ms.get(drv_path).copied();
+            let status = if failure.is_some() {
+                CheckStatus::Failed
+            } else if build.dependency_failures.contains(drv_path) {
+                CheckStatus::DependencyFailed
+            } else if duration_ms.is_some() {
+                CheckStatus::Passed
+            } else {
+                CheckStatus::Cached
+            };
+            CheckResult {
+                name: name.clone(),
+                drv_path: drv_path.clone(),
+                status,
+                duration_ms,
+                log_excerpt: failure.map(|f| f.log_tail.clone()).unwrap_or_default(),
+                summary: failure.map(|f| f.summary.clone()),
+            }
+        })
+        .collect();
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // Failures of dependencies are reported with the checks they block
+    let failed_drvs: Vec<&str> = build.failures.iter().map(|f| f.drv_path.as_str()).collect();
+    let errors = build
+        .errors
+        .into_iter()
+        .filter(|error| {
+            !failed_drvs.iter().any(|drv| error.contains(drv))
+                && !checks.values().any(|drv| error.contains(drv.as_str()))
+        })
+        .collect();
+
+    let passed = results
+        .iter()
+        .filter(|r| matches!(r.status, CheckStatus::Passed | CheckStatus::Cached))
+        .count();
+    FlakeCheckReport {
+        flake: flake.to_string(),
+        system: system.to_string(),
+        success: build.success && passed == results.len(),
+        passed,
+        failed: results.len() - passed,
+        checks: results,
+        errors,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspector::build::BuildFailure;
+    use crate::inspector::logs::summarize_failure;
+
+    fn checks() -> BTreeMap<String, String> {
+        [
+            ("clippy", "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-clippy.drv"),
+            ("fmt", "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-fmt.drv"),
+            ("tests", "/nix/store/cccccccccccccccccccccccccccccccc-tests.drv"),
+            ("integration", "/nix/store/dddddddddddddddddddddddddddddddd-integration.drv"),
+        ]
+        .into_iter()
+        .map(|(name, drv)| (name.to_string(), drv.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn test_select_checks() {
+        let selected = select_checks(checks(), Some(&["fmt".to_string()])).unwrap();
+        assert_eq!(selected.keys().collect::<Vec<_>>(), vec!["fmt"]);
+        assert_eq!(select_checks(checks(), None).unwrap().len(), 4);
+        assert_eq!(
+            select_checks(checks(), Some(&["lint".to_string()])).unwrap_err(),
+            vec!["lint"]
+        );
+    }
+
+    #[test]
+    fn test_check_report() {
+        let checks = checks();
+        let build = BuildResult {
+            success: false,
+            failures: vec![BuildFailure {
+                drv_path: checks["tests"].clone(),
+                phase: Some("checkPhase".to_string()),
+                log_tail: vec!["test result: FAILED".to_string()],
+                summary: summarize_failure("test result: FAILED"),
+            }],
+            dependency_failures: vec![checks["integration"].clone()],
+            build_times_ms: [(checks["clippy"].clone(), 1200), (checks["tests"].clone(), 300)]
+                .into_iter()
+                .collect(),
+            errors: vec![
+                format!("error: builder for '{}' failed with exit code 101", checks["tests"]),
+                format!("error: 1 dependencies of derivation '{}' failed to build", checks["integration"]),
+                "error: build of '/nix/store/x.drv' failed".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let report = check_report(".", "x86_64-linux", &checks, build);
+        let status: Vec<(&str, CheckStatus)> = report.checks.iter().map(|c| (c.name.as_str(), c.status)).collect();
+        assert_eq!(
+            status,
+            vec![
+                ("clippy", CheckStatus::Passed),
+                ("fmt", CheckStatus::Cached),
+                ("integration", CheckStatus::DependencyFailed),
+                ("tests", CheckStatus::Failed),
+            ]
+        );
+        assert_eq!(report.checks[0].duration_ms, Some(1200));
+        assert_eq!(report.checks[3].log_excerpt, vec!["test result: FAILED"]);
+        assert_eq!((report.passed, report.failed), (2, 2));
+        assert!(!report.success);
+        assert_eq!(report.errors, vec!["error: build of '/nix/store/x.drv' failed"]);
+    }
+}
@@ -0,0 +1,844 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+
+use super::store::{parse_drv_name, store_path_basename, store_path_hash, store_path_name};
+use super::system::SystemAnalyzerError;
+
+/// Upper bound on the number of chains returned when no `k` is given
+pub const MAX_CHAINS: usize = 256;
+
+/// Upper bound on the number of hash occurrences reported per reference
+const MAX_OCCURRENCES: usize = 16;
+
+/// Bytes of context shown on each side of a hash occurrence
+const CONTEXT_BYTES: usize = 24;
+
+/// Files are scanned in chunks of this size rather than read whole
+const SCAN_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Number of entries in each ranking when no `top` is given
+pub const DEFAULT_TOP: usize = 20;
+
+/// Reference graph of a runtime closure, as reported by `nix path-info --recursive`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DependencyGraph {
+    pub roots: Vec<String>,
+    pub nodes: Vec<String>,
+    pub edges: Vec<(String, String)>,
+    /// NAR size of every node in bytes
+    pub sizes: BTreeMap<String, u64>,
+}
+
+/// A single entry of `nix path-info --json` output
+#[derive(Debug, Clone, Deserialize)]
+pub struct PathInfo {
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub references: Vec<String>,
+    #[serde(default, rename = "narSize")]
+    pub nar_size: u64,
+    #[serde(default)]
+    pub deriver: Option<String>,
+    /// `sha256:<base32>` or, from newer Nix, an SRI hash
+    #[serde(default, rename = "narHash")]
+    pub nar_hash: Option<String>,
+}
+
+/// Dominator tree of a closure, rooted at a virtual node above all roots
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DominatorTree {
+    /// Immediate dominator of every node; `None` for nodes only dominated by the virtual root
+    pub immediate: BTreeMap<String, Option<String>>,
+    /// Bytes that would leave the closure if the node were dropped
+    pub retained_size: BTreeMap<String, u64>,
+}
+
+/// Parameters of the `analyze_closure_size` method
+#[derive(Debug, Deserialize)]
+pub struct ClosureSizeParams {
+    pub installable: String,
+    /// Maximum size of the whole closure in bytes
+    #[serde(default)]
+    pub budget: Option<u64>,
+    /// Maximum unique contribution of a single path in bytes
+    #[serde(default)]
+    pub path_budget: Option<u64>,
+    #[serde(default)]
+    pub top: Option<usize>,
+}
+
+/// Size breakdown of a closure
+#[derive(Debug, Serialize)]
+pub struct ClosureSizeReport {
+    pub roots: Vec<String>,
+    pub paths: usize,
+    pub total_size: u64,
+    pub budget: Option<u64>,
+    pub over_budget: bool,
+    pub by_self_size: Vec<ClosureMember>,
+    pub by_unique_size: Vec<ClosureMember>,
+    pub by_package: Vec<PackageGroup>,
+    /// Paths whose unique contribution exceeds the path budget
+    pub offenders: Vec<ClosureMember>,
+}
+
+/// Size information for a single closure member
+#[derive(Debug, Clone, Serialize)]
+pub struct ClosureMember {
+    pub path: String,
+    pub name: String,
+    pub self_size: u64,
+    pub unique_size: u64,
+    pub dominator: Option<String>,
+}
+
+/// Size information aggregated by package name
+#[derive(Debug, Clone, Serialize)]
+pub struct PackageGroup {
+    pub name: String,
+    pub paths: usize,
+    pub self_size: u64,
+    pub unique_size: u64,
+}
+
+/// Parameters of the `why_depends` method
+#[derive(Debug, Deserialize)]
+pub struct WhyDependsParams {
+    pub installable: String,
+    pub target: String,
+    #[serde(default)]
+    pub k: Option<usize>,
+    #[serde(default)]
+    pub precise: bool,
+}
+
+/// Explanation of why `root` depends on `target`
+#[derive(Debug, Serialize)]
+pub struct WhyDepends {
+    pub root: String,
+    pub target: String,
+    pub depends: bool,
+    pub truncated: bool,
+    pub chains: Vec<DependencyChain>,
+    pub dot_graph: String,
+}
+
+/// One reference chain from the root to a target path
+#[derive(Debug, Serialize)]
+pub struct DependencyChain {
+    pub paths: Vec<String>,
+    pub links: Vec<ReferenceLink>,
+}
+
+/// A single reference between two store paths in a chain
+#[derive(Debug, Serialize)]
+pub struct ReferenceLink {
+    pub from: String,
+    pub to: String,
+    pub occurrences: Vec<HashOccurrence>,
+}
+
+/// Location of a store path hash inside another store path
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HashOccurrence {
+    pub file: String,
+    pub offset: u64,
+    pub context: String,
+}
+
+/// Parses `nix path-info --json` output, accepting both the list format of
+/// Nix < 2.19 and the object-keyed-by-path format of later releases.
+pub fn parse_path_info(value: &Value) -> Result<Vec<PathInfo>, SystemAnalyzerError> {
+    match value {
+        Value::Array(entries) => entries
+            .iter()
+            .map(|entry| serde_json::from_value(entry.clone()).map_err(SystemAnalyzerError::from))
+            .collect(),
+        Value::Object(entries) => {
+            let mut infos = Vec::with_capacity(entries.len());
+            for (path, entry) in entries {
+                // Invalid paths are reported as `null`
+                if entry.is_null() {
+                    continue;
+                }
+                let mut info: PathInfo = serde_json::from_value(entry.clone())?;
+                info.path = path.clone();
+                infos.push(info);
+            }
+            Ok(infos)
+        }
+        _ => Err(SystemAnalyzerError::ParseError(
+            "Unexpected path-info output".to_string(),
+        )),
+    }
+}
+
+impl DependencyGraph {
+    /// Builds a graph from path-info entries, dropping self references
+    pub fn from_path_info(roots: Vec<String>, infos: &[PathInfo]) -> Self {
+        let mut nodes: Vec<String> = infos.iter().map(|info| info.path.clone()).collect();
+        nodes.sort();
+        nodes.dedup();
+
+        let mut edges = Vec::new();
+        for info in infos {
+            for reference in &info.references {
+                if reference != &info.path {
+                    edges.push((info.path.clone(), reference.clone()));
+                }
+            }
+        }
+        edges.sort();
+        edges.dedup();
+
+        let sizes = infos
+            .iter()
+            .map(|info| (info.path.clone(), info.nar_size))
+            .collect();
+
+        Self { roots, nodes, edges, sizes }
+    }
+
+    /// Total NAR size of the closure in bytes
+    pub fn total_size(&self) -> u64 {
+        self.sizes.values().sum()
+    }
+
+    /// Computes the dominator tree using the Cooper-Harvey-Kennedy algorithm.
+    /// A node dominates another if every reference chain from a root passes
+    /// through it, so its retained size is what dropping it would save.
+    pub fn dominators(&self) -> DominatorTree {
+        // Index 0 is a virtual root referencing every real root
+        let index: HashMap<&str, usize> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.as_str(), i + 1))
+            .collect();
+        let count = self.nodes.len() + 1;
+        let mut successors = vec![Vec::new(); count];
+        let mut predecessors = vec![Vec::new(); count];
+        for root in &self.roots {
+            if let Some(&r) = index.get(root.as_str()) {
+                successors[0].push(r);
+                predecessors[r].push(0);
+            }
+        }
+        for (from, to) in &self.edges {
+            if let (Some(&f), Some(&t)) = (index.get(from.as_str()), index.get(to.as_str())) {
+                successors[f].push(t);
+                predecessors[t].push(f);
+            }
+        }
+
+        // Iterative DFS for the postorder numbering
+        let mut postorder = Vec::with_capacity(count);
+        let mut visited = vec![false; count];
+        let mut stack = vec![(0usize, 0usize)];
+        visited[0] = true;
+        while let Some((node, child)) = stack.pop() {
+            if let Some(&next) = successors[node].get(child) {
+                stack.push((node, child + 1));
+                if !visited[next] {
+                    visited[next] = true;
+                    stack.push((next, 0));
+                }
+            } else {
+                postorder.push(node);
+            }
+        }
+        let mut order = vec![usize::MAX; count];
+        for (position, node) in postorder.iter().enumerate() {
+            order[*node] = position;
+        }
+
+        let mut idom = vec![usize::MAX; count];
+        idom[0] = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &node in postorder.iter().rev().skip(1) {
+                let mut new_idom = usize::MAX;
+                for &pred in &predecessors[node] {
+                    if idom[pred] == usize::MAX {
+                        continue;
+                    }
+                    new_idom = if new_idom == usize::MAX {
+                        pred
+                    } else {
+                        intersect(&idom, &order, pred, new_idom)
+                    };
+                }
+                if new_idom != usize::MAX && idom[node] != new_idom {
+                    idom[node] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        // Accumulate subtree sizes bottom-up, deepest nodes first
+        let mut retained = vec![0u64; count];
+        for (i, node) in self.nodes.iter().enumerate() {
+            retained[i + 1] = self.sizes.get(node).copied().unwrap_or(0);
+        }
+        let mut by_depth: Vec<usize> = postorder.iter().copied().filter(|n| *n != 0).collect();
+        by_depth.sort_by_key(|n| std::cmp::Reverse(dominator_depth(&idom, *n)));
+        for node in by_depth {
+            let parent = idom[node];
+            if parent != usize::MAX && parent != 0 {
+                retained[parent] += retained[node];
+            }
+        }
+
+        let mut tree = DominatorTree::default();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let n = i + 1;
+            if idom[n] == usize::MAX {
+                // Unreachable from the roots
+                continue;
+            }
+            let dominator = match idom[n] {
+                0 => None,
+                d => Some(self.nodes[d - 1].clone()),
+            };
+            tree.immediate.insert(node.clone(), dominator);
+            tree.retained_size.insert(node.clone(), retained[n]);
+        }
+        tree
+    }
+
+    /// Ranks closure members by self size and unique contribution
+    pub fn size_report(&self, params: &ClosureSizeParams) -> ClosureSizeReport {
+        let tree = self.dominators();
+        let top = params.top.unwrap_or(DEFAULT_TOP);
+
+        let members: Vec<ClosureMember> = self
+            .nodes
+            .iter()
+            .map(|path| ClosureMember {
+                path: path.clone(),
+                name: store_path_name(path).to_string(),
+                self_size: self.sizes.get(path).copied().unwrap_or(0),
+                unique_size: tree.retained_size.get(path).copied().unwrap_or(0),
+                dominator: tree.immediate.get(path).cloned().flatten(),
+            })
+            .collect();
+
+        let mut by_self_size = members.clone();
+        by_self_size.sort_by(|a, b| b.self_size.cmp(&a.self_size).then_with(|| a.path.cmp(&b.path)));
+        by_self_size.truncate(top);
+
+        let mut by_unique_size = members.clone();
+        by_unique_size.sort_by(|a, b| b.unique_size.cmp(&a.unique_size).then_with(|| a.path.cmp(&b.path)));
+
+        let offenders = match params.path_budget {
+            Some(limit) => by_unique_size
+                .iter()
+                .filter(|member| member.unique_size > limit)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        by_unique_size.truncate(top);
+
+        let mut groups: BTreeMap<&str, Vec<&ClosureMember>> = BTreeMap::new();
+        for member in &members {
+            groups
+                .entry(parse_drv_name(&member.name).0)
+                .or_default()
+                .push(member);
+        }
+        let mut by_package: Vec<PackageGroup> = groups
+            .into_iter()
+            .map(|(name, group)| {
+                let paths: HashSet<&str> = group.iter().map(|m| m.path.as_str()).collect();
+                // Skip members dominated by another member of the same
+                // group, their size is already part of that member.
+                let unique_size = group
+                    .iter()
+                    .filter(|member| !has_dominator_in(&tree, &member.path, &paths))
+                    .map(|member| member.unique_size)
+                    .sum();
+                PackageGroup {
+                    name: name.to_string(),
+                    paths: group.len(),
+                    self_size: group.iter().map(|m| m.self_size).sum(),
+                    unique_size,
+                }
+            })
+            .collect();
+        by_package.sort_by(|a, b| b.unique_size.cmp(&a.unique_size).then_with(|| a.name.cmp(&b.name)));
+        by_package.truncate(top);
+
+        let total_size = self.total_size();
+        ClosureSizeReport {
+            roots: self.roots.clone(),
+            paths: self.nodes.len(),
+            total_size,
+            budget: params.budget,
+            over_budget: params.budget.is_some_and(|budget| total_size > budget),
+            by_self_size,
+            by_unique_size,
+            by_package,
+            offenders,
+        }
+    }
+
+    fn adjacency(&self) -> HashMap<&str, Vec<&str>> {
+        let mut adjacency: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (from, to) in &self.edges {
+            adjacency.entry(from.as_str()).or_default().push(to.as_str());
+        }
+        adjacency
+    }
+
+    /// Resolves a target given as a store path, a store path name
+    /// (`glibc-2.39-52`) or a package name (`glibc`) to graph nodes.
+    pub fn resolve(&self, target: &str) -> Vec<&str> {
+        if let Some(node) = self.nodes.iter().find(|node| node.as_str() == target) {
+            return vec![node.as_str()];
+        }
+        self.nodes
+            .iter()
+            .filter(|node| {
+                let name = store_path_name(node);
+                name == target || parse_drv_name(name).0 == target
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Returns up to `k` shortest reference chains from `from` to any of
+    /// `targets`, in order of increasing length.
+    pub fn shortest_chains(&self, from: &str, targets: &HashSet<&str>, k: usize) -> Vec<Vec<String>> {
+        let adjacency = self.adjacency();
+
+        // Distance from every node to the nearest target, via reverse BFS
+        let mut reverse: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (src, dst) in &self.edges {
+            reverse.entry(dst.as_str()).or_default().push(src.as_str());
+        }
+        let mut distance: HashMap<&str, usize> = targets.iter().map(|t| (*t, 0)).collect();
+        let mut queue: std::collections::VecDeque<&str> = targets.iter().copied().collect();
+        while let Some(node) = queue.pop_front() {
+            let next = distance[node] + 1;
+            for parent in reverse.get(node).into_iter().flatten() {
+                if !distance.contains_key(parent) {
+                    distance.insert(parent, next);
+                    queue.push_back(parent);
+                }
+            }
+        }
+
+        let Some(&initial) = distance.get(from) else {
+            return Vec::new();
+        };
+
+        // Best-first search over partial chains; the distance heuristic is
+        // exact, so complete chains are produced in order of length.
+        let mut chains = Vec::new();
+        let mut heap = BinaryHeap::new();
+        let mut sequence = 0usize;
+        heap.push(Reverse((initial, sequence, vec![from])));
+
+        while let Some(Reverse((_, _, chain))) = heap.pop() {
+            if chains.len() >= k {
+                break;
+            }
+            let last = *chain.last().unwrap_or(&from);
+            if targets.contains(last) {
+                chains.push(chain.iter().map(|s| s.to_string()).collect());
+                continue;
+            }
+            let mut children: Vec<&str> = adjacency.get(last).cloned().unwrap_or_default();
+            children.sort_unstable();
+            for child in children {
+                if let Some(remaining) = distance.get(child) {
+                    let mut next = chain.clone();
+                    next.push(child);
+                    sequence += 1;
+                    heap.push(Reverse((next.len() - 1 + remaining, sequence, next)));
+                }
+            }
+        }
+
+        chains
+    }
+
+    /// Explains why the first root depends on `target`
+    pub fn why_depends(
+        &self,
+        target: &str,
+        k: Option<usize>,
+        precise: bool,
+    ) -> Result<WhyDepends, SystemAnalyzerError> {
+        let root = self.roots.first().cloned().ok_or_else(|| {
+            SystemAnalyzerError::GraphError("Dependency graph has no root".to_string())
+        })?;
+
+        let targets: HashSet<&str> = self.resolve(target).into_iter().collect();
+        let limit = k.unwrap_or(MAX_CHAINS).min(MAX_CHAINS);
+        let paths = if targets.is_empty() {
+            Vec::new()
+        } else {
+            self.shortest_chains(&root, &targets, limit + 1)
+        };
+        let truncated = paths.len() > limit;
+
+        let mut chains = Vec::new();
+        for paths in paths.into_iter().take(limit) {
+            let mut links = Vec::new();
+            for pair in paths.windows(2) {
+                let occurrences = if precise {
+                    find_hash_occurrences(Path::new(&pair[0]), &pair[1])
+                } else {
+                    Vec::new()
+                };
+                links.push(ReferenceLink {
+                    from: pair[0].clone(),
+                    to: pair[1].clone(),
+                    occurrences,
+                });
+            }
+            chains.push(DependencyChain { paths, links });
+        }
+
+        let dot_graph = chains_to_dot(&chains);
+        Ok(WhyDepends {
+            root,
+            target: target.to_string(),
+            depends: !chains.is_empty(),
+            truncated,
+            chains,
+            dot_graph,
+        })
+    }
+}
+
+fn intersect(idom: &[usize], order: &[usize], mut a: usize, mut b: usize) -> usize {
+    while a != b {
+        while order[a] < order[b] {
+            a = idom[a];
+        }
+        while order[b] < order[a] {
+            b = idom[b];
+        }
+    }
+    a
+}
+
+fn dominator_depth(idom: &[usize], mut node: usize) -> usize {
+    let mut depth = 0;
+    while node != 0 && idom[node] != usize::MAX {
+        node = idom[node];
+        depth += 1;
+    }
+    depth
+}
+
+fn has_dominator_in(tree: &DominatorTree, path: &str, group: &HashSet<&str>) -> bool {
+    let mut current = tree.immediate.get(path).cloned().flatten();
+    while let Some(dominator) = current {
+        if group.contains(dominator.as_str()) {
+            return true;
+        }
+        current = tree.immediate.get(&dominator).cloned().flatten();
+    }
+    false
+}
+
+/// Renders the union of all chains as a DOT graph
+pub fn chains_to_dot(chains: &[DependencyChain]) -> String {
+    let mut dot = String::from("digraph why_depends {\n  rankdir=LR;\n");
+    let mut seen_nodes = HashSet::new();
+    let mut seen_edges = HashSet::new();
+
+    for chain in chains {
+        for path in &chain.paths {
+            if seen_nodes.insert(path.as_str()) {
+                dot.push_str(&format!(
+                    "  \"{}\" [label=\"{}\"];\n",
+                    store_path_basename(path),
+                    store_path_name(path)
+                ));
+            }
+        }
+        for link in &chain.links {
+            if seen_edges.insert((link.from.as_str(), link.to.as_str())) {
+                let label = link
+                    .occurrences
+                    .first()
+                    .map(|occurrence| format!(" [label=\"{}\"]", occurrence.file))
+                    .unwrap_or_default();
+                dot.push_str(&format!(
+                    "  \"{}\" -> \"{}\"{};\n",
+                    store_path_basename(&link.from),
+                    store_path_basename(&link.to),
+                    label
+                ));
+            }
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+/// Scans the files below `root` for the hash of the store path `reference`,
+/// returning the file (relative to `root`) and byte offset of each match.
+/// Entries that cannot be read are skipped with a warning.
+pub fn find_hash_occurrences(root: &Path, reference: &str) -> Vec<HashOccurrence> {
+    let Some(hash) = store_path_hash(reference) else {
+        return Vec::new();
+    };
+    let mut occurrences = Vec::new();
+    scan_path(root, root, hash.as_bytes(), &mut occurrences);
+    occurrences
+}
+
+fn scan_path(root: &Path, path: &Path, hash: &[u8], occurrences: &mut Vec<HashOccurrence>) {
+    if occurrences.len() >= MAX_OCCURRENCES {
+        return;
+    }
+    if let Err(e) = scan_entry(root, path, hash, occurrences) {
+        log::warn!("Skipping {} while scanning for references: {}", path.display(), e);
+    }
+}
+
+fn scan_entry(root: &Path, path: &Path, hash: &[u8], occurrences: &mut Vec<HashOccurrence>) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file = path
+        .strip_prefix(root)
+        .ok()
+        .filter(|relative| !relative.as_os_str().is_empty())
+        .map(|relative| relative.to_string_lossy().to_string())
+        .unwrap_or_else(|| ".".to_string());
+
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        scan_bytes(&file, target.to_string_lossy().as_bytes(), hash, occurrences);
+    } else if metadata.is_dir() {
+        let mut entries: Vec<_> = fs::read_dir(path)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .collect();
+        entries.sort();
+        for entry in entries {
+            scan_path(root, &entry, hash, occurrences);
+        }
+    } else {
+        scan_file(&file, fs::File::open(path)?, hash, occurrences)?;
+    }
+
+    Ok(())
+}
+
+/// Scans a file chunk by chunk, keeping enough of the previous chunk to
+/// find hashes that cross a chunk boundary and to show their context
+fn scan_file(file: &str, mut reader: impl Read, hash: &[u8], occurrences: &mut Vec<HashOccurrence>) -> io::Result<()> {
+    let mut chunk = vec![0; SCAN_CHUNK_BYTES];
+    let mut window = Vec::new();
+    // File offset of `window[0]` and the first window index not yet searched
+    let mut window_offset = 0u64;
+    let mut next = 0;
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        window.extend_from_slice(&chunk[..read]);
+        // Until the end, matches need their trailing context in the window
+        let limit = if read == 0 {
+            window.len()
+        } else {
+            window.len().saturating_sub(hash.len() + CONTEXT_BYTES).max(next)
+        };
+        scan_window(file, &window, window_offset, next..limit, hash, occurrences);
+        if read == 0 || occurrences.len() >= MAX_OCCURRENCES {
+            return Ok(());
+        }
+        let keep_from = limit.saturating_sub(CONTEXT_BYTES);
+        window.drain(..keep_from);
+        window_offset += keep_from as u64;
+        next = limit - keep_from;
+    }
+}
+
+fn scan_bytes(file: &str, contents: &[u8], hash: &[u8], occurrences: &mut Vec<HashOccurrence>) {
+    scan_window(file, contents, 0, 0..contents.len(), hash, occurrences);
+}
+
+/// Reports matches starting within `starts`; `contents` begins at
+/// `offset` in the file
+fn scan_window(
+    file: &str,
+    contents: &[u8],
+    offset: u64,
+    starts: Range<usize>,
+    hash: &[u8],
+    occurrences: &mut Vec<HashOccurrence>,
+) {
+    for start in starts {
+        if start + hash.len() > contents.len() || occurrences.len() >= MAX_OCCURRENCES {
+            return;
+        }
+        if &contents[start..start + hash.len()] == hash {
+            let from = start.saturating_sub(CONTEXT_BYTES);
+            let to = (start + hash.len() + CONTEXT_BYTES).min(contents.len());
+            let context = contents[from..to]
+                .iter()
+                .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                .collect();
+            occurrences.push(HashOccurrence {
+                file: file.to_string(),
+                offset: offset + start as u64,
+                context,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const APP: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app-1.0";
+    const LIB: &str = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-libfoo-2.0";
+    const PY: &str = "/nix/store/cccccccccccccccccccccccccccccccc-python3-3.11.9";
+    const GCC: &str = "/nix/store/dddddddddddddddddddddddddddddddd-gcc-13.2.0-lib";
+    const GLIBC: &str = "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-glibc-2.39";
+
+    fn sample_graph() -> DependencyGraph {
+        let infos = parse_path_info(&json!({
+            APP: { "references": [APP, LIB, PY], "narSize": 100 },
+            LIB: { "references": [GCC, GLIBC], "narSize": 200 },
+            PY: { "references": [LIB, GLIBC], "narSize": 1000 },
+            GCC: { "references": [GLIBC], "narSize": 400 },
+            GLIBC: { "references": [GLIBC], "narSize": 800 },
+        }))
+        .unwrap();
+        DependencyGraph::from_path_info(vec![APP.to_string()], &infos)
+    }
+
+    #[test]
+    fn test_parse_path_info_list_format() {
+        let infos = parse_path_info(&json!([
+            { "path": APP, "references": [LIB] },
+            { "path": LIB, "references": [] },
+        ]))
+        .unwrap();
+        assert_eq!(infos.len(), 2);
+        assert_eq!(infos[0].path, APP);
+        assert_eq!(infos[0].references, vec![LIB.to_string()]);
+    }
+
+    #[test]
+    fn test_graph_drops_self_references() {
+        let graph = sample_graph();
+        assert_eq!(graph.nodes.len(), 5);
+        assert!(!graph.edges.iter().any(|(from, to)| from == to));
+    }
+
+    #[test]
+    fn test_shortest_chains_ordered_by_length() {
+        let graph = sample_graph();
+        let targets: HashSet<&str> = graph.resolve("gcc").into_iter().collect();
+        let chains = graph.shortest_chains(APP, &targets, 10);
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains[0], vec![APP, LIB, GCC]);
+        assert_eq!(chains[1], vec![APP, PY, LIB, GCC]);
+    }
+
+    #[test]
+    fn test_why_depends_k_and_missing_target() {
+        let graph = sample_graph();
+        let result = graph.why_depends("glibc", Some(1), false).unwrap();
+        assert!(result.depends);
+        assert!(result.truncated);
+        assert_eq!(result.chains.len(), 1);
+        assert_eq!(result.chains[0].paths, vec![APP, LIB, GLIBC]);
+        assert!(result.dot_graph.contains("-> \"eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-glibc-2.39\""));
+
+        let missing = graph.why_depends("perl", None, false).unwrap();
+        assert!(!missing.depends);
+        assert!(missing.chains.is_empty());
+    }
+
+    #[test]
+    fn test_dominators_and_retained_size() {
+        let graph = sample_graph();
+        let tree = graph.dominators();
+        assert_eq!(tree.immediate[APP], None);
+        // libfoo is reachable from both app and python3
+        assert_eq!(tree.immediate[LIB], Some(APP.to_string()));
+        assert_eq!(tree.immediate[GCC], Some(LIB.to_string()));
+        assert_eq!(tree.immediate[GLIBC], Some(APP.to_string()));
+        assert_eq!(tree.retained_size[LIB], 600);
+        assert_eq!(tree.retained_size[PY], 1000);
+        assert_eq!(tree.retained_size[APP], graph.total_size());
+    }
+
+    #[test]
+    fn test_size_report_budgets() {
+        let graph = sample_graph();
+        let report = graph.size_report(&ClosureSizeParams {
+            installable: ".".to_string(),
+            budget: Some(2000),
+            path_budget: Some(900),
+            top: Some(2),
+        });
+        assert_eq!(report.total_size, 2500);
+        assert!(report.over_budget);
+        assert_eq!(report.by_self_size.len(), 2);
+        assert_eq!(report.by_self_size[0].path, PY);
+        assert_eq!(report.by_unique_size[0].path, APP);
+        let offenders: Vec<&str> = report.offenders.iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(offenders, vec![APP, PY]);
+    }
+
+    #[test]
+    fn test_scan_bytes_reports_offsets() {
+        let mut occurrences = Vec::new();
+        let contents = format!("RPATH={}/lib", GLIBC);
+        scan_bytes("bin/app", contents.as_bytes(), store_path_hash(GLIBC).unwrap().as_bytes(), &mut occurrences);
+        assert_eq!(occurrences.len(), 1);
+        assert_eq!(occurrences[0].file, "bin/app");
+        assert_eq!(occurrences[0].offset, "RPATH=/nix/store/".len() as u64);
+    }
+
+    #[test]
+    fn test_scan_file_across_chunks() {
+        let hash = store_path_hash(GLIBC).unwrap();
+        let mut contents = vec![b'x'; 3 * SCAN_CHUNK_BYTES];
+        let offsets = [5, SCAN_CHUNK_BYTES - 10, 2 * SCAN_CHUNK_BYTES - CONTEXT_BYTES, contents.len() - hash.len()];
+        for offset in offsets {
+            contents[offset..offset + hash.len()].copy_from_slice(hash.as_bytes());
+        }
+        let mut occurrences = Vec::new();
+        scan_file("lib/libc.so", contents.as_slice(), hash.as_bytes(), &mut occurrences).unwrap();
+        let found: Vec<u64> = occurrences.iter().map(|o| o.offset).collect();
+        assert_eq!(found, offsets.map(|offset| offset as u64).to_vec());
+        assert_eq!(occurrences[1].context, format!("{}{}{}", "x".repeat(CONTEXT_BYTES), hash, "x".repeat(CONTEXT_BYTES)));
+        assert!(occurrences[3].context.ends_with(hash));
+    }
+
+    #[test]
+    fn test_unreadable_entries_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        // Opening a socket fails, whoever runs the test
+        let _socket = std::os::unix::net::UnixListener::bind(tmp.path().join("a.sock")).unwrap();
+        fs::write(tmp.path().join("b.conf"), format!("path={}", GLIBC)).unwrap();
+        let occurrences = find_hash_occurrences(tmp.path(), GLIBC);
+        assert_eq!(occurrences.len(), 1);
+        assert_eq!(occurrences[0].file, "b.conf");
+    }
+}
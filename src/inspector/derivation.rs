@@ -0,0 +1,566 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use super::aterm::STRUCTURED_ATTRS_KEY;
+use super::store::{store_path_name, STORE_HASH_LEN};
+use super::system::SystemAnalyzerError;
+
+/// Default recursion depth when diffing input derivations
+pub const DEFAULT_DIFF_DEPTH: usize = 8;
+
+/// Placeholder substituted for store path hashes when comparing values
+const HASH_PLACEHOLDER: &str = "<hash>";
+
+/// A store derivation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Derivation {
+    pub outputs: BTreeMap<String, DerivationOutput>,
+    pub input_srcs: BTreeSet<String>,
+    /// Input derivations and the outputs used from each of them
+    pub input_drvs: BTreeMap<String, BTreeSet<String>>,
+    pub system: String,
+    pub builder: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+}
+
+/// A derivation output; fixed-output derivations carry a hash
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DerivationOutput {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_algo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Parameters of the `diff_derivations` method
+#[derive(Debug, Deserialize)]
+pub struct DiffDerivationsParams {
+    pub left: String,
+    pub right: String,
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+}
+
+/// Recursive explanation of the difference between two derivations
+#[derive(Debug, Default, Serialize)]
+pub struct DerivationDiff {
+    pub left: String,
+    pub right: String,
+    pub identical: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<ValueChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<ValueChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub builder: Option<ValueChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<ListChange>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<ValueChange>,
+    /// Environment variables that only differ in store path hashes
+    pub env_hash_only: usize,
+    pub input_srcs: SetChange,
+    pub input_drvs: SetChange,
+    /// Inputs present on both sides under the same name but with different paths
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub divergent_inputs: Vec<DerivationDiff>,
+    /// Deepest derivation on the first diverging input chain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_divergence: Option<String>,
+    pub summary: String,
+}
+
+/// A changed scalar value; `None` means absent on that side
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValueChange {
+    pub name: String,
+    pub left: Option<String>,
+    pub right: Option<String>,
+}
+
+/// A changed list, reported with the elements present on one side only
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ListChange {
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+}
+
+/// Store paths added to, removed from or replaced in a set, compared by name
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SetChange {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// `[left, right]` pairs sharing a name but not a path
+    pub changed: Vec<(String, String)>,
+}
+
+impl SetChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Derivation {
+    /// Parses the output of `nix derivation show`, returning every derivation
+    /// keyed by its store path.
+    pub fn from_show_json(value: &Value, store_dir: &str) -> Result<BTreeMap<String, Derivation>, SystemAnalyzerError> {
+        let entries = value.as_object().ok_or_else(|| {
+            SystemAnalyzerError::ParseError("Expected an object of derivations".to_string())
+        })?;
+
+        let mut derivations = BTreeMap::new();
+        for (path, entry) in entries {
+            derivations.insert(
+                with_store_dir(path, store_dir),
+                Self::from_show_entry(entry, store_dir)?,
+            );
+        }
+        Ok(derivations)
+    }
+
+    fn from_show_entry(entry: &Value, store_dir: &str) -> Result<Self, SystemAnalyzerError> {
+        let field = |name: &str| entry.get(name).cloned().unwrap_or(Value::Null);
+        let string = |name: &str| field(name).as_str().unwrap_or_default().to_string();
+
+        let mut outputs = BTreeMap::new();
+        if let Some(entries) = field("outputs").as_object() {
+            for (name, output) in entries {
+                let get = |key: &str| output.get(key).and_then(Value::as_str).map(str::to_string);
+                outputs.insert(
+                    name.clone(),
+                    DerivationOutput {
+                        path: get("path").map(|p| with_store_dir(&p, store_dir)).unwrap_or_default(),
+                        hash_algo: get("hashAlgo"),
+                        hash: get("hash"),
+                    },
+                );
+            }
+        }
+
+        let mut input_drvs = BTreeMap::new();
+        if let Some(entries) = field("inputDrvs").as_object() {
+            for (path, used) in entries {
+                // Older releases list outputs directly, newer ones nest them
+                let names = used.get("outputs").unwrap_or(used);
+                let names: BTreeSet<String> = serde_json::from_value(names.clone())?;
+                input_drvs.insert(with_store_dir(path, store_dir), names);
+            }
+        }
+
+        let input_srcs: Vec<String> = serde_json::from_value(field("inputSrcs")).unwrap_or_default();
+
+        Ok(Self {
+            outputs,
+            input_srcs: input_srcs.iter().map(|p| with_store_dir(p, store_dir)).collect(),
+            input_drvs,
+            system: string("system"),
+            builder: string("builder"),
+            args: serde_json::from_value(field("args")).unwrap_or_default(),
+            env: serde_json::from_value(field("env")).unwrap_or_default(),
+        })
+    }
+
+    /// Name of the derivation, taken from its environment
+    pub fn name(&self) -> Option<&str> {
+        self.env.get("name").map(String::as_str)
+    }
+}
+
+/// Prefixes bare store path basenames, as printed by recent Nix releases
+fn with_store_dir(path: &str, store_dir: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/{}", store_dir.trim_end_matches('/'), path)
+    }
+}
+
+/// Replaces the hash of every path under `store_dir` in `value` with a
+/// placeholder
+fn mask_hashes(value: &str, store_dir: &str) -> String {
+    let prefix = format!("{}/", store_dir.trim_end_matches('/'));
+    let mut masked = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(position) = rest.find(&prefix) {
+        let start = position + prefix.len();
+        masked.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        let is_hash = candidate.as_bytes().get(STORE_HASH_LEN) == Some(&b'-')
+            && candidate.as_bytes()[..STORE_HASH_LEN].iter().all(u8::is_ascii_alphanumeric);
+        if is_hash {
+            masked.push_str(HASH_PLACEHOLDER);
+            rest = &candidate[STORE_HASH_LEN..];
+        } else {
+            rest = candidate;
+        }
+    }
+    masked.push_str(rest);
+    masked
+}
+
+fn compare_sets<'a>(left: impl Iterator<Item = &'a String>, right: impl Iterator<Item = &'a String>) -> SetChange {
+    let by_name = |paths: Vec<&'a String>| -> BTreeMap<&'a str, Vec<&'a String>> {
+        let mut map: BTreeMap<&str, Vec<&String>> = BTreeMap::new();
+        for path in paths {
+            map.entry(store_path_name(path)).or_default().push(path);
+        }
+        map
+    };
+    let left: Vec<&String> = left.collect();
+    let right: Vec<&String> = right.collect();
+    let left_set: HashSet<&String> = left.iter().copied().collect();
+    let right_set: HashSet<&String> = right.iter().copied().collect();
+    let left_only = by_name(left.into_iter().filter(|p| !right_set.contains(p)).collect());
+    let mut right_only = by_name(right.into_iter().filter(|p| !left_set.contains(p)).collect());
+
+    let mut change = SetChange::default();
+    for (name, mut paths) in left_only {
+        match right_only.get_mut(name) {
+            Some(others) if !others.is_empty() => {
+                while let (Some(l), Some(r)) = (paths.pop(), others.pop()) {
+                    change.changed.push((l.clone(), r.clone()));
+                }
+                change.removed.extend(paths.into_iter().cloned());
+            }
+            _ => change.removed.extend(paths.into_iter().cloned()),
+        }
+    }
+    for paths in right_only.into_values() {
+        change.added.extend(paths.into_iter().cloned());
+    }
+    change.added.sort();
+    change.removed.sort();
+    change.changed.sort();
+    change
+}
+
+fn scalar_change(name: &str, left: &str, right: &str) -> Option<ValueChange> {
+    (left != right).then(|| ValueChange {
+        name: name.to_string(),
+        left: Some(left.to_string()),
+        right: Some(right.to_string()),
+    })
+}
+
+/// Diffs two derivations, loading input derivations through `load` to
+/// explain divergent inputs recursively up to `max_depth` levels. Store
+/// path hashes under `store_dir` are ignored when comparing arguments and
+/// the environment.
+pub fn diff_derivations<F>(
+    left_path: &str,
+    right_path: &str,
+    store_dir: &str,
+    load: &mut F,
+    max_depth: usize,
+) -> Result<DerivationDiff, SystemAnalyzerError>
+where
+    F: FnMut(&str) -> Result<Derivation, SystemAnalyzerError>,
+{
+    let mut visited = HashSet::new();
+    let mut diff = diff_recursive(left_path, right_path, store_dir, load, max_depth, &mut visited)?;
+    diff.first_divergence = first_divergence(&diff);
+    diff.summary = summarize(&diff);
+    Ok(diff)
+}
+
+fn diff_recursive<F>(
+    left_path: &str,
+    right_path: &str,
+    store_dir: &str,
+    load: &mut F,
+    depth: usize,
+    visited: &mut HashSet<(String, String)>,
+) -> Result<DerivationDiff, SystemAnalyzerError>
+where
+    F: FnMut(&str) -> Result<Derivation, SystemAnalyzerError>,
+{
+    let left = load(left_path)?;
+    let right = load(right_path)?;
+    let mut diff = DerivationDiff {
+        left: left_path.to_string(),
+        right: right_path.to_string(),
+        identical: left == right,
+        ..Default::default()
+    };
+    if diff.identical {
+        return Ok(diff);
+    }
+
+    let names: BTreeSet<&String> = left.outputs.keys().chain(right.outputs.keys()).collect();
+    for name in names {
+        let l = left.outputs.get(name);
+        let r = right.outputs.get(name);
+        // Output paths always change along with the inputs; only report
+        // added or removed outputs and changed fixed-output hashes.
+        let changed = match (l, r) {
+            (Some(l), Some(r)) => l.hash != r.hash || l.hash_algo != r.hash_algo,
+            _ => true,
+        };
+        if changed {
+            diff.outputs.push(ValueChange {
+                name: name.clone(),
+                left: l.map(|o| o.hash.clone().unwrap_or_else(|| o.path.clone())),
+                right: r.map(|o| o.hash.clone().unwrap_or_else(|| o.path.clone())),
+            });
+        }
+    }
+
+    diff.system = scalar_change("system", &left.system, &right.system);
+    diff.builder = scalar_change("builder", &mask_hashes(&left.builder, store_dir), &mask_hashes(&right.builder, store_dir))
+        .map(|_| ValueChange {
+            name: "builder".to_string(),
+            left: Some(left.builder.clone()),
+            right: Some(right.builder.clone()),
+        });
+
+    let left_args: Vec<String> = left.args.iter().map(|a| mask_hashes(a, store_dir)).collect();
+    let right_args: Vec<String> = right.args.iter().map(|a| mask_hashes(a, store_dir)).collect();
+    if left_args != right_args {
+        diff.args = Some(ListChange {
+            left: left.args.iter().filter(|a| !right.args.contains(a)).cloned().collect(),
+            right: right.args.iter().filter(|a| !left.args.contains(a)).cloned().collect(),
+        });
+    }
+
+    let left_env = comparable_env(&left);
+    let right_env = comparable_env(&right);
+    let keys: BTreeSet<&String> = left_env.keys().chain(right_env.keys()).collect();
+    for key in keys {
+        let l = left_env.get(key);
+        let r = right_env.get(key);
+        if l == r {
+            continue;
+        }
+        // Output paths are implied by everything else
+        if left.outputs.contains_key(key) && right.outputs.contains_key(key) {
+            continue;
+        }
+        match (l, r) {
+            (Some(l), Some(r)) if mask_hashes(l, store_dir) == mask_hashes(r, store_dir) => diff.env_hash_only += 1,
+            _ => diff.env.push(ValueChange {
+                name: key.clone(),
+                left: l.cloned(),
+                right: r.cloned(),
+            }),
+        }
+    }
+
+    diff.input_srcs = compare_sets(left.input_srcs.iter(), right.input_srcs.iter());
+    diff.input_drvs = compare_sets(left.input_drvs.keys(), right.input_drvs.keys());
+
+    if depth > 0 {
+        for (l, r) in diff.input_drvs.changed.clone() {
+            if visited.insert((l.clone(), r.clone())) {
+                let child = diff_recursive(&l, &r, store_dir, load, depth - 1, visited)?;
+                if !child.identical {
+                    diff.divergent_inputs.push(child);
+                }
+            }
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Environment with structured attributes expanded into one entry per
+/// attribute, so that a change to one of them is reported individually.
+fn comparable_env(drv: &Derivation) -> BTreeMap<String, String> {
+    let mut env = drv.env.clone();
+    if let Some(attrs) = drv.structured_attrs() {
+        env.remove(STRUCTURED_ATTRS_KEY);
+        for (key, value) in attrs {
+            let value = match value {
+                Value::String(text) => text,
+                other => other.to_string(),
+            };
+            env.insert(format!("{}.{}", STRUCTURED_ATTRS_KEY, key), value);
+        }
+    }
+    env
+}
+
+/// Follows the first divergent input down to the derivation where the
+/// change originates.
+fn first_divergence(diff: &DerivationDiff) -> Option<String> {
+    let mut current = diff.divergent_inputs.first()?;
+    while let Some(next) = current.divergent_inputs.first() {
+        current = next;
+    }
+    Some(current.right.clone())
+}
+
+fn summarize(diff: &DerivationDiff) -> String {
+    if diff.identical {
+        return format!("{} and {} are identical", diff.left, diff.right);
+    }
+
+    let mut lines = vec![format!(
+        "{} differs from {}",
+        store_path_name(&diff.right),
+        store_path_name(&diff.left)
+    )];
+    describe(diff, &mut lines, 1);
+    if let Some(origin) = &diff.first_divergence {
+        lines.push(format!("First diverging input: {}", origin));
+    }
+    lines.join("\n")
+}
+
+fn describe(diff: &DerivationDiff, lines: &mut Vec<String>, indent: usize) {
+    let pad = "  ".repeat(indent);
+    let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "(unset)".to_string());
+
+    for change in diff.outputs.iter().chain(&diff.system).chain(&diff.builder) {
+        lines.push(format!("{}{} changed: {} -> {}", pad, change.name, show(&change.left), show(&change.right)));
+    }
+    if let Some(args) = &diff.args {
+        lines.push(format!("{}builder arguments changed: -{:?} +{:?}", pad, args.left, args.right));
+    }
+    for change in &diff.env {
+        lines.push(format!("{}env {} changed: {} -> {}", pad, change.name, show(&change.left), show(&change.right)));
+    }
+    for (kind, set) in [("input source", &diff.input_srcs), ("input derivation", &diff.input_drvs)] {
+        for path in &set.added {
+            lines.push(format!("{}{} added: {}", pad, kind, store_path_name(path)));
+        }
+        for path in &set.removed {
+            lines.push(format!("{}{} removed: {}", pad, kind, store_path_name(path)));
+        }
+        if kind == "input source" {
+            for (path, _) in &set.changed {
+                lines.push(format!("{}{} content changed: {}", pad, kind, store_path_name(path)));
+            }
+        }
+    }
+    for child in &diff.divergent_inputs {
+        lines.push(format!("{}input {} changed:", pad, store_path_name(&child.right)));
+        describe(child, lines, indent + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const STORE: &str = "/nix/store";
+
+    fn drv(hash: char, name: &str) -> String {
+        format!("{}/{}-{}.drv", STORE, hash.to_string().repeat(STORE_HASH_LEN), name)
+    }
+
+    fn sample(version: &str, openssl: &str, patch: &str) -> Derivation {
+        Derivation {
+            outputs: BTreeMap::from([(
+                "out".to_string(),
+                DerivationOutput { path: format!("/nix/store/{}-curl", version), ..Default::default() },
+            )]),
+            input_srcs: BTreeSet::from([patch.to_string()]),
+            input_drvs: BTreeMap::from([(openssl.to_string(), BTreeSet::from(["out".to_string()]))]),
+            system: "x86_64-linux".to_string(),
+            builder: "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-bash/bin/bash".to_string(),
+            args: vec!["-e".to_string()],
+            env: BTreeMap::from([
+                ("name".to_string(), "curl".to_string()),
+                ("version".to_string(), version.to_string()),
+                ("out".to_string(), format!("/nix/store/{}-curl", version)),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_from_show_json_formats() {
+        let value = json!({
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello.drv": {
+                "outputs": { "out": { "path": "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello" } },
+                "inputSrcs": ["cccccccccccccccccccccccccccccccc-builder.sh"],
+                "inputDrvs": {
+                    "dddddddddddddddddddddddddddddddd-bash.drv": { "outputs": ["out"], "dynamicOutputs": {} }
+                },
+                "system": "x86_64-linux",
+                "builder": "/bin/sh",
+                "args": ["-c", "true"],
+                "env": { "name": "hello" }
+            }
+        });
+        let parsed = Derivation::from_show_json(&value, STORE).unwrap();
+        let hello = &parsed[&drv('a', "hello")];
+        assert_eq!(hello.name(), Some("hello"));
+        assert_eq!(hello.outputs["out"].path, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello");
+        assert!(hello.input_drvs.contains_key(&drv('d', "bash")));
+        assert!(hello.input_srcs.contains("/nix/store/cccccccccccccccccccccccccccccccc-builder.sh"));
+
+        let legacy = json!({ drv('a', "hello"): { "inputDrvs": { drv('d', "bash"): ["out", "dev"] } } });
+        let parsed = Derivation::from_show_json(&legacy, STORE).unwrap();
+        assert_eq!(parsed[&drv('a', "hello")].input_drvs[&drv('d', "bash")].len(), 2);
+    }
+
+    #[test]
+    fn test_mask_hashes() {
+        assert_eq!(
+            mask_hashes("-I/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-zlib/include", STORE),
+            "-I/nix/store/<hash>-zlib/include"
+        );
+        assert_eq!(mask_hashes("/nix/store/short", STORE), "/nix/store/short");
+        let custom = "/srv/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-zlib";
+        assert_eq!(mask_hashes(custom, "/srv/store/"), "/srv/store/<hash>-zlib");
+        assert_eq!(mask_hashes(custom, STORE), custom);
+    }
+
+    #[test]
+    fn test_diff_finds_first_divergent_input() {
+        let patch = "/nix/store/pppppppppppppppppppppppppppppppp-fix.patch";
+        let left_ssl = drv('1', "openssl-3.0.13");
+        let right_ssl = drv('2', "openssl-3.0.13");
+        let mut store = BTreeMap::from([
+            (drv('l', "curl-8.6.0"), sample("8.6.0", &left_ssl, patch)),
+            (drv('r', "curl-8.6.0"), sample("8.7.1", &right_ssl, patch)),
+            (left_ssl.clone(), sample("3.0.13", &drv('3', "perl"), patch)),
+            (right_ssl.clone(), sample("3.0.13", &drv('3', "perl"), "/nix/store/qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqq-fix.patch")),
+        ]);
+        let mut load = |path: &str| {
+            store
+                .remove(path)
+                .ok_or_else(|| SystemAnalyzerError::ParseError(path.to_string()))
+        };
+
+        let diff = diff_derivations(&drv('l', "curl-8.6.0"), &drv('r', "curl-8.6.0"), STORE, &mut load, 4).unwrap();
+        assert!(!diff.identical);
+        assert_eq!(diff.env.len(), 1);
+        assert_eq!(diff.env[0].name, "version");
+        assert_eq!(diff.input_drvs.changed, vec![(left_ssl, right_ssl.clone())]);
+        assert_eq!(diff.divergent_inputs.len(), 1);
+        assert_eq!(diff.divergent_inputs[0].input_srcs.changed.len(), 1);
+        assert_eq!(diff.first_divergence.as_deref(), Some(right_ssl.as_str()));
+        assert!(diff.summary.contains("env version changed: 8.6.0 -> 8.7.1"));
+        assert!(diff.summary.contains("input source content changed: fix.patch"));
+    }
+
+    #[test]
+    fn test_diff_expands_structured_attrs() {
+        let structured = |version: &str| Derivation {
+            env: BTreeMap::from([(
+                STRUCTURED_ATTRS_KEY.to_string(),
+                json!({ "pname": "jq", "version": version, "doCheck": true }).to_string(),
+            )]),
+            ..Default::default()
+        };
+        let mut store = BTreeMap::from([
+            (drv('l', "jq"), structured("1.7")),
+            (drv('r', "jq"), structured("1.7.1")),
+        ]);
+        let mut load = |path: &str| {
+            store
+                .remove(path)
+                .ok_or_else(|| SystemAnalyzerError::ParseError(path.to_string()))
+        };
+
+        let diff = diff_derivations(&drv('l', "jq"), &drv('r', "jq"), STORE, &mut load, 0).unwrap();
+        assert_eq!(diff.env.len(), 1);
+        assert_eq!(diff.env[0].name, "__json.version");
+        assert_eq!(diff.env[0].right.as_deref(), Some("1.7.1"));
+    }
+}
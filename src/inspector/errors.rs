@@ -0,0 +1,260 @@
+use mcp_rust_sdk::error::{Error as McpError, ErrorCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fmt;
+
+use super::build::strip_ansi;
+use super::eval::{parse_eval_error, EvalError, Position, TraceFrame};
+use super::system::SystemAnalyzerError;
+
+/// Lines of stderr kept in error reports
+pub const STDERR_EXCERPT_LINES: usize = 40;
+
+/// Error codes documented in the API. They are sent as `error.data.code`
+/// next to a standard JSON-RPC `error.code`, so clients that do not know
+/// them can still tell a failed tool from bad parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ErrorKind {
+    NixCommandError,
+    ParseError,
+    MethodNotFound,
+    InvalidParams,
+    AccessDenied,
+    Unauthenticated,
+    RateLimited,
+    ConfirmationRequired,
+    IoError,
+    InternalError,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> u32 {
+        match self {
+            ErrorKind::NixCommandError => 1,
+            ErrorKind::ParseError => 2,
+            ErrorKind::MethodNotFound => 3,
+            ErrorKind::InvalidParams => 4,
+            ErrorKind::AccessDenied => 5,
+            ErrorKind::Unauthenticated => 6,
+            ErrorKind::RateLimited => 7,
+            ErrorKind::ConfirmationRequired => 8,
+            ErrorKind::IoError => 9,
+            ErrorKind::InternalError => 10,
+        }
+    }
+
+    /// JSON-RPC code sent as `error.code`
+    pub fn rpc_code(self) -> ErrorCode {
+        match self {
+            ErrorKind::MethodNotFound => ErrorCode::MethodNotFound,
+            ErrorKind::InvalidParams => ErrorCode::InvalidParams,
+            ErrorKind::ParseError | ErrorKind::IoError | ErrorKind::InternalError => ErrorCode::InternalError,
+            ErrorKind::NixCommandError
+            | ErrorKind::AccessDenied
+            | ErrorKind::Unauthenticated
+            | ErrorKind::RateLimited
+            | ErrorKind::ConfirmationRequired => ErrorCode::RequestFailed,
+        }
+    }
+}
+
+/// An MCP error with the documented code and kind in `error.data`, along
+/// with the fields of `details` if it is an object
+pub fn mcp_error(kind: ErrorKind, message: impl Into<String>, details: Value) -> McpError {
+    let mut data = json!({ "code": kind.code(), "kind": kind });
+    if let (Some(data), Value::Object(details)) = (data.as_object_mut(), details) {
+        for (key, value) in details {
+            data.entry(key).or_insert(value);
+        }
+    }
+    McpError::protocol(kind.rpc_code(), message).with_data(data)
+}
+
+/// A nix command that failed, with what it printed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NixFailure {
+    pub command: Vec<String>,
+    pub exit_status: Option<i32>,
+    /// Last lines of stderr
+    pub stderr: String,
+    /// The innermost `error:` message
+    pub message: String,
+    pub position: Option<Position>,
+    /// Evaluation trace, outermost frame first
+    pub trace: Vec<TraceFrame>,
+}
+
+impl NixFailure {
+    pub fn new(program: &str, args: &[&str], exit_status: Option<i32>, stderr: &[u8]) -> Self {
+        let stderr = strip_ansi(&String::from_utf8_lossy(stderr));
+        let lines: Vec<&str> = stderr.trim_end().lines().collect();
+        let excerpt = lines[lines.len().saturating_sub(STDERR_EXCERPT_LINES)..].join("\n");
+        let command = std::iter::once(program).chain(args.iter().copied()).map(str::to_string).collect();
+        if excerpt.trim().is_empty() {
+            let status = exit_status.map_or("a signal".to_string(), |code| format!("status {}", code));
+            return Self {
+                command,
+                exit_status,
+                stderr: excerpt,
+                message: format!("{} exited with {}", program, status),
+                position: None,
+                trace: Vec::new(),
+            };
+        }
+        let error = parse_eval_error(&stderr);
+        Self {
+            command,
+            exit_status,
+            stderr: excerpt,
+            message: error.message,
+            position: error.position,
+            trace: error.trace,
+        }
+    }
+
+    /// A failed evaluation run by `evaluate`
+    pub fn from_eval(command: Vec<String>, error: EvalError) -> Self {
+        Self {
+            command,
+            exit_status: None,
+            stderr: String::new(),
+            message: error.message,
+            position: error.position,
+            trace: error.trace,
+        }
+    }
+}
+
+impl fmt::Display for NixFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl SystemAnalyzerError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            SystemAnalyzerError::NixCommandError(_)
+            | SystemAnalyzerError::NixCommandFailed(_)
+            | SystemAnalyzerError::Which(_) => ErrorKind::NixCommandError,
+            SystemAnalyzerError::ParseError(_) | SystemAnalyzerError::Utf8(_) | SystemAnalyzerError::Json(_) => {
+                ErrorKind::ParseError
+            }
+            SystemAnalyzerError::GraphError(_) | SystemAnalyzerError::Config(_) => ErrorKind::InternalError,
+            SystemAnalyzerError::Io(_) => ErrorKind::IoError,
+            SystemAnalyzerError::ConfirmationRequired(_) => ErrorKind::ConfirmationRequired,
+            SystemAnalyzerError::AccessDenied(_) => ErrorKind::AccessDenied,
+            SystemAnalyzerError::Unauthenticated(_) => ErrorKind::Unauthenticated,
+            SystemAnalyzerError::RateLimited(_) => ErrorKind::RateLimited,
+        }
+    }
+
+    /// Fields added to `error.data`
+    pub fn details(&self) -> Value {
+        let details = match self {
+            SystemAnalyzerError::NixCommandFailed(failure) => serde_json::to_value(failure),
+            SystemAnalyzerError::AccessDenied(denial) => serde_json::to_value(denial),
+            SystemAnalyzerError::RateLimited(limited) => serde_json::to_value(limited),
+            _ => Ok(Value::Null),
+        };
+        details.unwrap_or_default()
+    }
+}
+
+impl From<SystemAnalyzerError> for McpError {
+    fn from(err: SystemAnalyzerError) -> Self {
+        mcp_error(err.kind(), err.to_string(), err.details())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspector::limits::{LimitScope, RateLimited};
+    use crate::inspector::policy::{AccessDenial, DenialReason};
+
+    const STDERR: &str = "\u{1b}[31;1merror:\u{1b}[0m
+       … while evaluating the attribute 'packages.x86_64-linux.default'
+
+       … while calling the 'derivationStrict' builtin
+         at /builtin/derivation.nix:9:12: (source not available)
+
+       error: undefined variable 'hello'
+       at /home/alice/app/flake.nix:7:45:
+            6|
+            7|     packages.x86_64-linux.default = pkgs.callPackage hello { };
+             |                                             ^
+";
+
+    fn wire(err: SystemAnalyzerError) -> (i32, Value) {
+        match McpError::from(err) {
+            McpError::Protocol { code, data, .. } => (code.into(), data.unwrap()),
+            other => panic!("not a protocol error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+        let json = serde_json::from_str::<Value>("{").unwrap_err();
+        let cases = [
+            (SystemAnalyzerError::NixCommandError("no flake.nix".into()), -32000, 1, "NixCommandError"),
+            (SystemAnalyzerError::ParseError("bad".into()), -32603, 2, "ParseError"),
+            (SystemAnalyzerError::Json(json), -32603, 2, "ParseError"),
+            (SystemAnalyzerError::GraphError("dot".into()), -32603, 10, "InternalError"),
+            (SystemAnalyzerError::Io(io), -32603, 9, "IoError"),
+            (SystemAnalyzerError::ConfirmationRequired("plan".into()), -32000, 8, "ConfirmationRequired"),
+            (SystemAnalyzerError::Unauthenticated("missing bearer token".into()), -32000, 6, "Unauthenticated"),
+        ];
+        for (err, rpc, code, kind) in cases {
+            let (wire_code, data) = wire(err);
+            assert_eq!((wire_code, &data["code"], &data["kind"]), (rpc, &json!(code), &json!(kind)));
+        }
+
+        let mcp = mcp_error(ErrorKind::MethodNotFound, "Method x not found", Value::Null);
+        assert!(matches!(mcp, McpError::Protocol { code: ErrorCode::MethodNotFound, .. }));
+        let mcp = mcp_error(ErrorKind::InvalidParams, "missing field", Value::Null);
+        assert!(matches!(mcp, McpError::Protocol { code: ErrorCode::InvalidParams, data: Some(ref data), .. } if data["code"] == 4));
+    }
+
+    #[test]
+    fn test_error_details() {
+        let denial = AccessDenial {
+            tool: "nix_build".to_string(),
+            reason: DenialReason::Mutating,
+            parameter: None,
+            value: None,
+        };
+        let (code, data) = wire(SystemAnalyzerError::AccessDenied(denial));
+        assert_eq!((code, &data["code"], &data["reason"], &data["tool"]), (-32000, &json!(5), &json!("mutating"), &json!("nix_build")));
+
+        let limited = RateLimited { scope: LimitScope::Session, tool: None, limit: 100, retry_after: 0.5 };
+        let (_, data) = wire(SystemAnalyzerError::RateLimited(limited));
+        assert_eq!((&data["code"], &data["scope"], &data["retry_after"]), (&json!(7), &json!("session"), &json!(0.5)));
+    }
+
+    #[test]
+    fn test_nix_failure() {
+        let failure = NixFailure::new("nix", &["build", ".#default"], Some(1), STDERR.as_bytes());
+        assert_eq!(failure.command, ["nix", "build", ".#default"]);
+        assert_eq!(failure.message, "undefined variable 'hello'");
+        assert_eq!(failure.position.as_ref().unwrap().line, 7);
+        assert_eq!(failure.trace.len(), 2);
+        assert_eq!(failure.trace[1].description, "while calling the 'derivationStrict' builtin");
+        assert!(failure.stderr.starts_with("error:"));
+
+        let (code, data) = wire(SystemAnalyzerError::NixCommandFailed(Box::new(failure)));
+        assert_eq!(code, -32000);
+        assert_eq!(data["code"], 1);
+        assert_eq!(data["exit_status"], 1);
+        assert_eq!(data["trace"][0]["description"], "while evaluating the attribute 'packages.x86_64-linux.default'");
+
+        let long = (0..100).map(|i| format!("line {}", i)).collect::<Vec<_>>().join("\n");
+        let failure = NixFailure::new("nix-store", &["--gc"], Some(1), long.as_bytes());
+        assert_eq!(failure.stderr.lines().count(), STDERR_EXCERPT_LINES);
+        assert!(failure.stderr.ends_with("line 99"));
+
+        let silent = NixFailure::new("nix", &["eval"], None, b"");
+        assert_eq!(silent.message, "nix exited with a signal");
+    }
+}
@@ -0,0 +1,423 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+
+use super::build::strip_ansi;
+use super::system::SystemAnalyzerError;
+
+/// Evaluation time limit unless the caller asks for less
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+pub const MAX_TIMEOUT_SECS: u64 = 300;
+
+/// Address space limit of the evaluator
+pub const DEFAULT_MAX_MEMORY_MB: u64 = 2048;
+pub const MAX_MEMORY_MB: u64 = 8192;
+
+/// Largest JSON value returned
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 64 * 1024;
+pub const MAX_OUTPUT_BYTES: usize = 4 * 1024 * 1024;
+
+/// Stderr kept for error reports
+const MAX_STDERR_BYTES: usize = 256 * 1024;
+
+/// Parameters of the `eval` method
+#[derive(Debug, Default, Deserialize)]
+pub struct EvalParams {
+    pub expression: String,
+    /// Flake whose locked outputs are in scope as `flake`, `inputs` and `pkgs`
+    #[serde(default)]
+    pub flake: Option<String>,
+    #[serde(default)]
+    pub system: Option<String>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub max_memory_mb: Option<u64>,
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+}
+
+/// Limits applied to one evaluation, clamped to the server maximums
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EvalLimits {
+    pub timeout: Duration,
+    pub max_memory_mb: u64,
+    pub max_output_bytes: usize,
+}
+
+impl EvalLimits {
+    pub fn from_params(params: &EvalParams) -> Self {
+        Self {
+            timeout: Duration::from_secs(params.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS).clamp(1, MAX_TIMEOUT_SECS)),
+            max_memory_mb: params.max_memory_mb.unwrap_or(DEFAULT_MAX_MEMORY_MB).clamp(64, MAX_MEMORY_MB),
+            max_output_bytes: params.max_output_bytes.unwrap_or(DEFAULT_MAX_OUTPUT_BYTES).min(MAX_OUTPUT_BYTES),
+        }
+    }
+}
+
+/// Why an evaluation produced no value
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvalErrorKind {
+    Evaluation,
+    Timeout,
+    OutOfMemory,
+    OutputTooLarge,
+}
+
+/// A source position in an evaluation error
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Position {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+/// One `… while evaluating` frame of an error trace, outermost first
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceFrame {
+    pub description: String,
+    pub position: Option<Position>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EvalError {
+    pub kind: EvalErrorKind,
+    pub message: String,
+    pub position: Option<Position>,
+    pub trace: Vec<TraceFrame>,
+}
+
+/// Result of the `eval` method: either a value or an error
+#[derive(Debug, Serialize)]
+pub struct EvalResult {
+    pub value: Option<Value>,
+    pub error: Option<EvalError>,
+    pub duration_ms: u64,
+}
+
+/// Quotes a string as a Nix string literal
+pub fn nix_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Wraps an expression so the locked flake is in scope. `pkgs` is the
+/// flake's nixpkgs input, falling back to its own `legacyPackages`.
+pub fn flake_expression(expression: &str, locked_url: &str, system: &str) -> String {
+    format!(
+        "let\n  flake = builtins.getFlake {url};\n  inputs = flake.inputs;\n  system = {system};\n  \
+         pkgs = inputs.nixpkgs.legacyPackages.${{system}} or flake.legacyPackages.${{system}} or {{ }};\n\
+         in\n{expression}\n",
+        url = nix_string(locked_url),
+        system = nix_string(system),
+        expression = expression,
+    )
+}
+
+/// Parses `<file>:<line>:<column>` at the end of an `at ...:` line
+fn parse_position(text: &str) -> Option<Position> {
+    let text = text.trim().strip_prefix("at ")?.trim_end_matches(':');
+    let mut parts = text.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next()?.to_string();
+    Some(Position { file, line, column })
+}
+
+/// Parses the stderr of a failed `nix eval` into a message and trace
+pub fn parse_eval_error(stderr: &str) -> EvalError {
+    let lines: Vec<String> = stderr.lines().map(strip_ansi).collect();
+    let mut trace: Vec<TraceFrame> = Vec::new();
+    let mut message: Vec<String> = Vec::new();
+    let mut position = None;
+    let mut in_message = false;
+
+    for line in &lines {
+        let trimmed = line.trim();
+        if let Some(description) = trimmed.strip_prefix('…') {
+            in_message = false;
+            trace.push(TraceFrame {
+                description: description.trim().to_string(),
+                position: None,
+            });
+        } else if let Some(pos) = parse_position(trimmed) {
+            in_message = false;
+            match trace.last_mut() {
+                Some(frame) if frame.position.is_none() && message.is_empty() => frame.position = Some(pos),
+                _ => position = Some(pos),
+            }
+        } else if let Some(rest) = trimmed.strip_prefix("error:") {
+            // The innermost `error:` carries the actual message
+            message.clear();
+            position = None;
+            if !rest.trim().is_empty() {
+                message.push(rest.trim().to_string());
+            }
+            in_message = true;
+        } else if in_message && !trimmed.is_empty() && !trimmed.contains('|') {
+            message.push(trimmed.to_string());
+        } else if in_message && trimmed.is_empty() && !message.is_empty() {
+            in_message = false;
+        }
+    }
+
+    let message = if message.is_empty() {
+        lines.iter().map(|l| l.trim()).find(|l| !l.is_empty()).unwrap_or("evaluation failed").to_string()
+    } else {
+        message.join("\n")
+    };
+    EvalError {
+        kind: EvalErrorKind::Evaluation,
+        message,
+        position,
+        trace,
+    }
+}
+
+/// Reads at most `limit + 1` bytes, enough to tell that the stream went over `limit`
+async fn read_limited<R: AsyncRead + Unpin>(reader: Option<R>, limit: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if let Some(reader) = reader {
+        reader.take(limit as u64 + 1).read_to_end(&mut buffer).await?;
+    }
+    Ok(buffer)
+}
+
+/// Keeps the first `limit` bytes and discards the rest until end of stream,
+/// so the child never blocks on a full pipe
+async fn read_draining<R: AsyncRead + Unpin>(reader: Option<R>, limit: usize) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if let Some(mut reader) = reader {
+        (&mut reader).take(limit as u64).read_to_end(&mut buffer).await?;
+        tokio::io::copy(&mut reader, &mut tokio::io::sink()).await?;
+    }
+    Ok(buffer)
+}
+
+/// Runs `nix eval --json` in pure, restricted mode within `limits`
+pub async fn evaluate(nix_cmd: &str, expression: &str, limits: EvalLimits) -> Result<EvalResult, SystemAnalyzerError> {
+    let started = Instant::now();
+    let max_memory = limits.max_memory_mb * 1024 * 1024;
+
+    let mut command = Command::new(nix_cmd);
+    command
+        .args(["eval", "--json", "--pure-eval", "--option", "restrict-eval", "true", "--expr", expression])
+        // Lets the evaluator's garbage collector fail cleanly before the rlimit hits
+        .env("GC_MAXIMUM_HEAP_SIZE", max_memory.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // SAFETY: setrlimit is async-signal-safe and touches no state of the parent
+    unsafe {
+        command.pre_exec(move || {
+            let limit = libc::rlimit {
+                rlim_cur: max_memory as libc::rlim_t,
+                rlim_max: max_memory as libc::rlim_t,
+            };
+            if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| SystemAnalyzerError::NixCommandError(e.to_string()))?;
+
+    let stdout = child.stdout.take();
+    let mut stderr = tokio::spawn(read_draining(child.stderr.take(), MAX_STDERR_BYTES));
+    // Yields no output once stdout goes over the limit, without waiting for the child
+    let run = async {
+        let stdout = read_limited(stdout, limits.max_output_bytes).await?;
+        if stdout.len() > limits.max_output_bytes {
+            return Ok(None);
+        }
+        let status = child.wait().await?;
+        let stderr = (&mut stderr).await.map_err(std::io::Error::other)??;
+        Ok::<_, std::io::Error>(Some((status, stdout, stderr)))
+    };
+
+    let failure = |kind, message: String| EvalResult {
+        value: None,
+        error: Some(EvalError { kind, message, position: None, trace: Vec::new() }),
+        duration_ms: started.elapsed().as_millis() as u64,
+    };
+
+    let (status, stdout, stderr) = match tokio::time::timeout(limits.timeout, run).await {
+        Ok(Ok(Some(output))) => output,
+        Ok(Ok(None)) => {
+            let _ = child.kill().await;
+            stderr.abort();
+            return Ok(failure(
+                EvalErrorKind::OutputTooLarge,
+                format!("result exceeds {} bytes", limits.max_output_bytes),
+            ));
+        }
+        Ok(Err(e)) => {
+            stderr.abort();
+            return Err(e.into());
+        }
+        Err(_) => {
+            let _ = child.kill().await;
+            stderr.abort();
+            return Ok(failure(
+                EvalErrorKind::Timeout,
+                format!("evaluation did not finish within {} seconds", limits.timeout.as_secs()),
+            ));
+        }
+    };
+    let stderr = String::from_utf8_lossy(&stderr);
+    if !status.success() {
+        if stderr.contains("out of memory") || stderr.contains("std::bad_alloc") {
+            return Ok(failure(
+                EvalErrorKind::OutOfMemory,
+                format!("evaluation exceeded {} MiB", limits.max_memory_mb),
+            ));
+        }
+        let mut error = parse_eval_error(&stderr);
+        if error.message.is_empty() {
+            error.message = format!("nix eval exited with {}", status);
+        }
+        return Ok(EvalResult {
+            value: None,
+            error: Some(error),
+            duration_ms: started.elapsed().as_millis() as u64,
+        });
+    }
+
+    let value = serde_json::from_slice(&stdout).map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
+    Ok(EvalResult {
+        value: Some(value),
+        error: None,
+        duration_ms: started.elapsed().as_millis() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "\
+error:
+       … while evaluating the attribute 'version'
+
+         at «string»:1:12:
+
+            1| let x = { version = builtins.throw \"not packaged\"; }; in x.version
+             |            ^
+
+       … while calling the 'throw' builtin
+
+         at «string»:1:22:
+
+            1| let x = { version = builtins.throw \"not packaged\"; }; in x.version
+             |                      ^
+
+       error: not packaged";
+
+    #[test]
+    fn test_nix_string() {
+        assert_eq!(nix_string("path:/tmp/a\"b"), "\"path:/tmp/a\\\"b\"");
+        assert_eq!(nix_string("${x} $y"), "\"\\${x} $y\"");
+    }
+
+    #[test]
+    fn test_flake_expression() {
+        let expr = flake_expression("pkgs.hello.version", "github:NixOS/nixpkgs/abc", "x86_64-linux");
+        assert!(expr.contains("builtins.getFlake \"github:NixOS/nixpkgs/abc\""));
+        assert!(expr.contains("legacyPackages.${system}"));
+        assert!(expr.trim_end().ends_with("pkgs.hello.version"));
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        let error = parse_eval_error(TRACE);
+        assert_eq!(error.kind, EvalErrorKind::Evaluation);
+        assert_eq!(error.message, "not packaged");
+        assert_eq!(error.trace.len(), 2);
+        assert_eq!(error.trace[0].description, "while evaluating the attribute 'version'");
+        assert_eq!(
+            error.trace[0].position,
+            Some(Position { file: "«string»".to_string(), line: 1, column: 12 })
+        );
+        assert_eq!(error.trace[1].position.as_ref().unwrap().column, 22);
+        assert_eq!(error.position, None);
+    }
+
+    #[test]
+    fn test_parse_single_error() {
+        let error = parse_eval_error("\u{1b}[31;1merror:\u{1b}[0m undefined variable 'hello'\n\n       at «string»:1:1:\n\n            1| hello\n             | ^\n");
+        assert_eq!(error.message, "undefined variable 'hello'");
+        assert_eq!(error.position.unwrap().line, 1);
+        assert!(error.trace.is_empty());
+    }
+
+    #[test]
+    fn test_limits_are_clamped() {
+        let limits = EvalLimits::from_params(&EvalParams {
+            timeout_secs: Some(100_000),
+            max_memory_mb: Some(1),
+            max_output_bytes: Some(usize::MAX),
+            ..Default::default()
+        });
+        assert_eq!(limits.timeout, Duration::from_secs(MAX_TIMEOUT_SECS));
+        assert_eq!(limits.max_memory_mb, 64);
+        assert_eq!(limits.max_output_bytes, MAX_OUTPUT_BYTES);
+    }
+
+    /// Writes a stand-in for `nix` that runs `script` and ignores its arguments
+    fn fake_nix(dir: &std::path::Path, script: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join("nix");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn test_limits() -> EvalLimits {
+        EvalLimits {
+            timeout: Duration::from_secs(20),
+            max_memory_mb: 1024,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_output_is_cut_short() {
+        let tmp = tempfile::tempdir().unwrap();
+        // 512 KiB on each stream, then stalls as a large evaluation would
+        let nix = fake_nix(
+            tmp.path(),
+            "head -c 524288 /dev/zero | tr '\\0' e >&2\nhead -c 524288 /dev/zero | tr '\\0' 1\nsleep 60",
+        );
+        let result = evaluate(&nix, "1", test_limits()).await.unwrap();
+        assert_eq!(result.error.unwrap().kind, EvalErrorKind::OutputTooLarge);
+        assert!(result.duration_ms < 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_large_stderr_is_drained() {
+        let tmp = tempfile::tempdir().unwrap();
+        let nix = fake_nix(tmp.path(), "head -c 524288 /dev/zero | tr '\\0' e >&2\necho 42");
+        let result = evaluate(&nix, "42", test_limits()).await.unwrap();
+        assert_eq!(result.value, Some(serde_json::json!(42)));
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub struct FlakeInspector {
     flake_path: PathBuf,
@@ -8,4 +8,103 @@ impl FlakeInspector {
     pub fn new(flake_path: PathBuf) -> Self {
         Self { flake_path }
     }
-} 
\ No newline at end of file
+
+    pub fn flake_path(&self) -> &Path {
+        &self.flake_path
+    }
+}
+
+/// Local path of a flake reference, resolved against `base`: plain paths
+/// and every scheme reading from the file system, such as `path:`,
+/// `git+file:` or `tarball+file:`. `None` for other flakes.
+pub fn local_flake_dir(flake: &str, base: &Path) -> Option<PathBuf> {
+    let path = match flake.split_once(':') {
+        Some((scheme, rest)) if scheme == "path" || scheme == "file" || scheme.ends_with("+file") => {
+            rest.strip_prefix("//").unwrap_or(rest)
+        }
+        _ if flake.starts_with('.') || flake.starts_with('/') => flake,
+        _ => return None,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    Some(base.join(if path.is_empty() { "." } else { path }))
+}
+
+/// Identifier of a DOT node statement or edge end, without attributes or
+/// quotes
+fn dot_id(statement: &str) -> &str {
+    statement.split('[').next().unwrap_or_default().trim().trim_matches('"')
+}
+
+/// The `label` attribute of a DOT node statement, if it has one
+fn dot_label(statement: &str) -> Option<&str> {
+    let start = statement.find("label=\"")? + "label=\"".len();
+    let len = statement[start..].find('"')?;
+    Some(&statement[start..start + len])
+}
+
+/// Renders parsed DOT nodes and edges as a Mermaid flowchart. Nodes get
+/// generated identifiers since DOT ones may contain characters Mermaid
+/// does not accept.
+pub fn mermaid_graph(nodes: &[String], edges: &[(String, String)]) -> String {
+    let mut ids: Vec<&str> = Vec::new();
+    let mut labels = std::collections::HashMap::new();
+    for node in nodes {
+        let id = dot_id(node);
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+        labels.insert(id, dot_label(node).unwrap_or(id));
+    }
+    for (from, to) in edges {
+        for id in [dot_id(from), dot_id(to)] {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+
+    let mut mermaid = String::from("flowchart LR\n");
+    for (i, id) in ids.iter().enumerate() {
+        let label = labels.get(id).copied().unwrap_or(id).replace('"', "#quot;");
+        mermaid.push_str(&format!("  n{}[\"{}\"]\n", i, label));
+    }
+    for (from, to) in edges {
+        let index = |id: &str| ids.iter().position(|known| *known == id).unwrap_or_default();
+        mermaid.push_str(&format!("  n{} --> n{}\n", index(dot_id(from)), index(dot_id(to))));
+    }
+    mermaid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_flake_dir() {
+        let base = Path::new("/home/alice");
+        let dir = |flake: &str| local_flake_dir(flake, base);
+        assert_eq!(dir("."), Some(PathBuf::from("/home/alice/.")));
+        assert_eq!(dir("./src/app#default"), Some(PathBuf::from("/home/alice/./src/app")));
+        assert_eq!(dir("path:app?dir=sub"), Some(PathBuf::from("/home/alice/app")));
+        assert_eq!(dir("git+file:///srv/app?ref=main#x"), Some(PathBuf::from("/srv/app")));
+        assert_eq!(dir("git+file:/srv/app"), Some(PathBuf::from("/srv/app")));
+        assert_eq!(dir("file:///srv/app.tar.gz"), Some(PathBuf::from("/srv/app.tar.gz")));
+        assert_eq!(dir("tarball+file://./app.tar.gz"), Some(PathBuf::from("/home/alice/./app.tar.gz")));
+        assert_eq!(dir("github:NixOS/nixpkgs"), None);
+        assert_eq!(dir("git+https://example.org/app"), None);
+        assert_eq!(dir("nixpkgs#hello"), None);
+    }
+
+    #[test]
+    fn test_mermaid_graph() {
+        let nodes = vec!["\"nixpkgs\" [label=\"nixpkgs/nixos-24.05\"]".to_string(), "root".to_string()];
+        let edges = vec![
+            ("root".to_string(), "\"nixpkgs\"".to_string()),
+            ("root".to_string(), "\"flake-utils\" [style=dashed]".to_string()),
+        ];
+        assert_eq!(
+            mermaid_graph(&nodes, &edges),
+            "flowchart LR\n  n0[\"nixpkgs/nixos-24.05\"]\n  n1[\"root\"]\n  n2[\"flake-utils\"]\n  n1 --> n0\n  n1 --> n2\n"
+        );
+    }
+}
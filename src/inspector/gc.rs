@@ -0,0 +1,590 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::closure::PathInfo;
+use super::profiles::{generation_number, Generation};
+use super::util::unix_now;
+
+/// Directory holding the garbage collector roots
+pub const GCROOTS_DIR: &str = "/nix/var/nix/gcroots";
+
+/// Age after which `result` links are reported as stale
+pub const DEFAULT_STALE_DAYS: u64 = 30;
+
+/// Number of entries in the largest-paths ranking of a plan
+const LARGEST_PATHS: usize = 20;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// What created a garbage collector root
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GcRootKind {
+    /// A generation of a profile
+    Profile,
+    /// A `result` link created by `nix build`
+    Result,
+    /// A root kept alive by nix-direnv
+    Direnv,
+    Other,
+}
+
+/// A garbage collector root
+#[derive(Debug, Clone, Serialize)]
+pub struct GcRoot {
+    /// The link that keeps the target alive, e.g. `/home/alice/src/app/result`
+    pub link: String,
+    pub target: Option<String>,
+    pub kind: GcRootKind,
+    pub owner: Option<String>,
+    /// Project directory of the link, or the profile for generation links
+    pub project: Option<String>,
+    pub age_days: u64,
+    /// Why the root is considered stale, if it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale: Option<String>,
+}
+
+/// Roots sharing an owner and a project
+#[derive(Debug, Serialize)]
+pub struct GcRootGroup {
+    pub owner: String,
+    pub project: Option<String>,
+    pub roots: Vec<GcRoot>,
+}
+
+/// Parameters of the `list_gc_roots` method
+#[derive(Debug, Default, Deserialize)]
+pub struct GcRootsParams {
+    #[serde(default)]
+    pub stale_after_days: Option<u64>,
+}
+
+/// All garbage collector roots, grouped by owner and project
+#[derive(Debug, Serialize)]
+pub struct GcRootsReport {
+    pub total: usize,
+    pub groups: Vec<GcRootGroup>,
+    pub stale: Vec<GcRoot>,
+}
+
+/// Parameters of the `plan_garbage_collection` method
+#[derive(Debug, Default, Deserialize)]
+pub struct GcPlanParams {
+    /// Remove generations older than this many days before collecting,
+    /// like `nix-collect-garbage --delete-older-than <days>d`
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+}
+
+/// Parameters of the `collect_garbage` method
+#[derive(Debug, Deserialize)]
+pub struct CollectGarbageParams {
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+    /// Identifier of the reviewed plan, as returned by `plan_garbage_collection`
+    pub plan_id: String,
+}
+
+/// What a garbage collection would delete, without deleting anything
+#[derive(Debug, Serialize)]
+pub struct GcPlan {
+    pub plan_id: String,
+    pub older_than_days: Option<u64>,
+    /// Profiles whose old generations are removed; no others are touched
+    pub profiles: Vec<String>,
+    pub removed_generations: Vec<Generation>,
+    /// Paths that are already unreachable from any root
+    pub dead_paths: usize,
+    pub freed_paths: usize,
+    pub freed_bytes: u64,
+    pub largest: Vec<(String, u64)>,
+}
+
+/// Result of an actual garbage collection
+#[derive(Debug, Serialize)]
+pub struct GcResult {
+    pub plan_id: String,
+    pub output: String,
+}
+
+/// Walks the garbage collector roots directory
+pub struct GcRootScanner {
+    gcroots_dir: PathBuf,
+    stale_after_days: u64,
+    now: i64,
+    users: BTreeMap<u32, String>,
+}
+
+impl GcRootScanner {
+    pub fn new(gcroots_dir: impl Into<PathBuf>, stale_after_days: u64) -> Self {
+        Self {
+            gcroots_dir: gcroots_dir.into(),
+            stale_after_days,
+            now: unix_now(),
+            users: read_users(Path::new("/etc/passwd")),
+        }
+    }
+
+    /// Returns every root below the roots directory, following indirect
+    /// roots (`auto/`) to the links they protect.
+    pub fn scan(&self) -> io::Result<Vec<GcRoot>> {
+        let mut roots = Vec::new();
+        let mut visited = HashSet::new();
+        self.scan_dir(&self.gcroots_dir, &mut roots, &mut visited)?;
+        roots.sort_by(|a, b| a.link.cmp(&b.link));
+        roots.dedup_by(|a, b| a.link == b.link);
+        Ok(roots)
+    }
+
+    fn scan_dir(&self, dir: &Path, roots: &mut Vec<GcRoot>, visited: &mut HashSet<PathBuf>) -> io::Result<()> {
+        let canonical = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        if !visited.insert(canonical) {
+            return Ok(());
+        }
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.filter_map(Result::ok).map(|e| e.path()).collect();
+        entries.sort();
+        for path in entries {
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                self.scan_dir(&path, roots, visited)?;
+                continue;
+            }
+            if !metadata.file_type().is_symlink() {
+                continue;
+            }
+
+            let target = resolve_link(&path)?;
+            if is_store_path(&target) {
+                roots.push(self.direct_root(&path, &target, &metadata));
+            } else if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.is_dir()) {
+                // e.g. `gcroots/profiles -> /nix/var/nix/profiles`. Links
+                // such as `result` are roots even when they point at a
+                // directory, so only real directories are entered.
+                self.scan_dir(&target, roots, visited)?;
+            } else if !is_profile_pointer(&path, &target) {
+                roots.push(self.indirect_root(&target));
+            }
+        }
+        Ok(())
+    }
+
+    fn direct_root(&self, link: &Path, target: &Path, metadata: &fs::Metadata) -> GcRoot {
+        let name = link.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let profile = link.parent().and_then(|dir| {
+            let (base, _) = name.strip_suffix("-link")?.rsplit_once('-')?;
+            generation_number(base, name)?;
+            Some(dir.join(base).to_string_lossy().to_string())
+        });
+        GcRoot {
+            link: link.to_string_lossy().to_string(),
+            target: Some(target.to_string_lossy().to_string()),
+            kind: if profile.is_some() { GcRootKind::Profile } else { GcRootKind::Other },
+            owner: self.users.get(&metadata.uid()).cloned(),
+            project: profile,
+            age_days: self.age_days(metadata.mtime()),
+            stale: (!target.exists()).then(|| "target no longer exists".to_string()),
+        }
+    }
+
+    /// Builds a root from an indirect root, i.e. a link outside the roots
+    /// directory (such as a `result` link) that is registered in `auto/`.
+    fn indirect_root(&self, link: &Path) -> GcRoot {
+        let link_str = link.to_string_lossy().to_string();
+        let kind = if link_str.contains("/.direnv/") {
+            GcRootKind::Direnv
+        } else if link.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("result")) {
+            GcRootKind::Result
+        } else {
+            GcRootKind::Other
+        };
+        let project = match kind {
+            GcRootKind::Direnv => link_str.split("/.direnv/").next().map(str::to_string),
+            _ => link.parent().map(|p| p.to_string_lossy().to_string()),
+        };
+
+        let Ok(metadata) = fs::symlink_metadata(link) else {
+            return GcRoot {
+                link: link_str,
+                target: None,
+                kind,
+                owner: None,
+                project,
+                age_days: 0,
+                stale: Some("link no longer exists".to_string()),
+            };
+        };
+
+        let target = fs::read_link(link).ok().map(|t| t.to_string_lossy().to_string());
+        let age_days = self.age_days(metadata.mtime());
+        let stale = if !link.exists() {
+            Some("target no longer exists".to_string())
+        } else if kind == GcRootKind::Result && age_days > self.stale_after_days {
+            Some(format!("older than {} days", self.stale_after_days))
+        } else {
+            None
+        };
+
+        GcRoot {
+            link: link_str,
+            target,
+            kind,
+            owner: self.users.get(&metadata.uid()).cloned(),
+            project,
+            age_days,
+            stale,
+        }
+    }
+
+    fn age_days(&self, mtime: i64) -> u64 {
+        ((self.now - mtime).max(0) / SECONDS_PER_DAY) as u64
+    }
+}
+
+/// Groups roots by owner and project and collects the stale ones
+pub fn group_roots(roots: Vec<GcRoot>) -> GcRootsReport {
+    let total = roots.len();
+    let stale = roots.iter().filter(|root| root.stale.is_some()).cloned().collect();
+    let mut groups: BTreeMap<(String, Option<String>), Vec<GcRoot>> = BTreeMap::new();
+    for root in roots {
+        let owner = root.owner.clone().unwrap_or_else(|| "unknown".to_string());
+        groups.entry((owner, root.project.clone())).or_default().push(root);
+    }
+    GcRootsReport {
+        total,
+        groups: groups
+            .into_iter()
+            .map(|((owner, project), roots)| GcRootGroup { owner, project, roots })
+            .collect(),
+        stale,
+    }
+}
+
+/// Selects the generations of a profile `--delete-older-than` would
+/// remove. Like Nix, this keeps the newest generation created before the
+/// cutoff, which was still the active one `days` days before `now`, and
+/// removes the ones older than it except the current one.
+pub fn expired_generations(generations: &[Generation], days: u64, now: i64) -> Vec<Generation> {
+    let cutoff = now - days as i64 * SECONDS_PER_DAY;
+    let mut newest_first: Vec<&Generation> = generations.iter().collect();
+    newest_first.sort_by_key(|generation| std::cmp::Reverse(generation.number));
+    let mut expired: Vec<Generation> = newest_first
+        .into_iter()
+        .skip_while(|generation| generation.created >= cutoff)
+        .skip(1)
+        .filter(|generation| !generation.current)
+        .cloned()
+        .collect();
+    expired.reverse();
+    expired
+}
+
+/// Profile a generation belongs to, e.g. `/nix/var/nix/profiles/system`
+/// for `system-42-link`
+pub fn generation_profile(generation: &Generation) -> Option<&str> {
+    generation.link.strip_suffix(&format!("-{}-link", generation.number))
+}
+
+/// Groups removed generations by profile, as arguments of
+/// `nix-env -p <profile> --delete-generations <numbers>`
+pub fn generations_by_profile(removed: &[Generation]) -> BTreeMap<&str, Vec<String>> {
+    let mut by_profile: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for generation in removed {
+        if let Some(profile) = generation_profile(generation) {
+            by_profile.entry(profile).or_default().push(generation.number.to_string());
+        }
+    }
+    by_profile
+}
+
+/// Whether a root is held by a running process, such as `/proc/<pid>/maps`
+/// or `{censored}`, rather than by a link on disk
+fn is_runtime_root(link: &str) -> bool {
+    link.starts_with("/proc/") || link.starts_with('{')
+}
+
+/// Identifier of a plan, used to confirm the deletion of exactly what was
+/// reviewed. It covers the removed generations, the paths that are
+/// already dead and the roots that are kept, so it changes whenever the
+/// store or the roots do. Runtime roots come and go with processes and are
+/// left out.
+pub fn plan_id(
+    older_than_days: Option<u64>,
+    removed: &[Generation],
+    dead: &[PathInfo],
+    kept_roots: &[(String, String)],
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    older_than_days.hash(&mut hasher);
+    for generation in removed {
+        generation.link.hash(&mut hasher);
+        generation.target.hash(&mut hasher);
+    }
+    let mut dead: Vec<&str> = dead.iter().map(|info| info.path.as_str()).collect();
+    dead.sort_unstable();
+    dead.hash(&mut hasher);
+    let mut kept: Vec<&(String, String)> = kept_roots.iter().filter(|(link, _)| !is_runtime_root(link)).collect();
+    kept.sort_unstable();
+    kept.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Computes what a collection would free: dead paths plus the closure of
+/// removed generations that is not reachable from any remaining root.
+pub fn compute_plan(
+    older_than_days: Option<u64>,
+    profiles: Vec<String>,
+    removed_generations: Vec<Generation>,
+    removed_closure: &[PathInfo],
+    kept_roots: &[(String, String)],
+    kept_closure: &HashSet<String>,
+    dead: &[PathInfo],
+) -> GcPlan {
+    let mut freed: BTreeMap<&str, u64> = BTreeMap::new();
+    for info in dead {
+        freed.insert(&info.path, info.nar_size);
+    }
+    for info in removed_closure {
+        if !kept_closure.contains(&info.path) {
+            freed.insert(&info.path, info.nar_size);
+        }
+    }
+
+    let mut largest: Vec<(String, u64)> = freed.iter().map(|(p, s)| (p.to_string(), *s)).collect();
+    largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    largest.truncate(LARGEST_PATHS);
+
+    GcPlan {
+        plan_id: plan_id(older_than_days, &removed_generations, dead, kept_roots),
+        older_than_days,
+        profiles,
+        removed_generations,
+        dead_paths: dead.len(),
+        freed_paths: freed.len(),
+        freed_bytes: freed.values().sum(),
+        largest,
+    }
+}
+
+/// Parses `nix-store --gc --print-roots` output into `(link, target)` pairs
+pub fn parse_print_roots(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once(" -> "))
+        .map(|(link, target)| (link.trim().to_string(), target.trim().to_string()))
+        .collect()
+}
+
+/// Whether `link` is a profile pointing at one of its own generations,
+/// e.g. `system -> system-42-link`; the generation is the actual root.
+fn is_profile_pointer(link: &Path, target: &Path) -> bool {
+    let name = |path: &Path| path.file_name().and_then(|n| n.to_str()).map(str::to_string);
+    match (name(link), name(target)) {
+        (Some(profile), Some(generation)) => {
+            link.parent() == target.parent() && generation_number(&profile, &generation).is_some()
+        }
+        _ => false,
+    }
+}
+
+fn is_store_path(path: &Path) -> bool {
+    path.starts_with("/nix/store")
+}
+
+fn resolve_link(link: &Path) -> io::Result<PathBuf> {
+    let target = fs::read_link(link)?;
+    Ok(match link.parent() {
+        Some(parent) if target.is_relative() => parent.join(target),
+        _ => target,
+    })
+}
+
+fn read_users(passwd: &Path) -> BTreeMap<u32, String> {
+    fs::read_to_string(passwd)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse().ok()?;
+            Some((uid, name.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn generation(number: u64, created: i64, current: bool) -> Generation {
+        Generation {
+            number,
+            link: format!("/nix/var/nix/profiles/system-{}-link", number),
+            target: format!("/nix/store/{}-nixos-system", number.to_string().repeat(32)),
+            created,
+            current,
+        }
+    }
+
+    fn info(path: &str, nar_size: u64) -> PathInfo {
+        PathInfo {
+            path: path.to_string(),
+            references: Vec::new(),
+            nar_size,
+            deriver: None,
+            nar_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_expired_generations() {
+        let now = 100 * SECONDS_PER_DAY;
+        let days = |days: i64| days * SECONDS_PER_DAY;
+        // Generations 1 and 2 are older than 30 days; 2 was active 30 days
+        // ago and stays
+        let generations = vec![generation(1, days(10), false), generation(2, days(20), false), generation(3, days(80), true)];
+        let numbers = |expired: Vec<Generation>| expired.iter().map(|g| g.number).collect::<Vec<_>>();
+        assert_eq!(numbers(expired_generations(&generations, 30, now)), vec![1]);
+        assert_eq!(numbers(expired_generations(&generations, 90, now)), Vec::<u64>::new());
+
+        // The current generation is never removed, even when it is older
+        let rolled_back = vec![
+            generation(1, days(5), false),
+            generation(2, days(10), true),
+            generation(3, days(20), false),
+            generation(4, days(80), false),
+        ];
+        assert_eq!(numbers(expired_generations(&rolled_back, 30, now)), vec![1]);
+        assert_eq!(numbers(expired_generations(&rolled_back, 1, now)), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_compute_plan() {
+        let removed = vec![generation(1, 0, false)];
+        let roots = vec![("/nix/var/nix/profiles/system-2-link".to_string(), "/nix/store/shared".to_string())];
+        let kept: HashSet<String> = ["/nix/store/shared".to_string()].into();
+        let dead = [info("/nix/store/dead", 50)];
+        let plan = compute_plan(
+            Some(30),
+            vec!["/nix/var/nix/profiles/system".to_string()],
+            removed.clone(),
+            &[info("/nix/store/old", 300), info("/nix/store/shared", 1000)],
+            &roots,
+            &kept,
+            &dead,
+        );
+        assert_eq!(plan.freed_paths, 2);
+        assert_eq!(plan.freed_bytes, 350);
+        assert_eq!(plan.largest[0], ("/nix/store/old".to_string(), 300));
+        assert_eq!(plan.plan_id, plan_id(Some(30), &removed, &dead, &roots));
+        assert_ne!(plan.plan_id, plan_id(Some(7), &removed, &dead, &roots));
+        assert_ne!(plan.plan_id, plan_id(Some(30), &removed, &[], &roots));
+        assert_ne!(plan.plan_id, plan_id(Some(30), &removed, &dead, &[]));
+
+        // A plain collection is tied to the dead paths it was reviewed with
+        let more_dead = [info("/nix/store/dead", 50), info("/nix/store/dead-2", 10)];
+        assert_ne!(plan_id(None, &[], &dead, &roots), plan_id(None, &[], &more_dead, &roots));
+    }
+
+    #[test]
+    fn test_plan_id_ignores_runtime_roots() {
+        let link = |link: &str, target: &str| (link.to_string(), target.to_string());
+        let roots = vec![
+            link("/nix/var/nix/profiles/system-2-link", "/nix/store/system"),
+            link("/proc/1234/maps", "/nix/store/glibc"),
+            link("{censored}", "/nix/store/bash"),
+        ];
+        let later = vec![
+            link("/proc/5678/exe", "/nix/store/coreutils"),
+            link("/nix/var/nix/profiles/system-2-link", "/nix/store/system"),
+        ];
+        assert_eq!(plan_id(None, &[], &[], &roots), plan_id(None, &[], &[], &later));
+        assert_ne!(plan_id(None, &[], &[], &roots), plan_id(None, &[], &[], &later[..1]));
+    }
+
+    #[test]
+    fn test_generations_by_profile() {
+        let mut home = generation(7, 0, false);
+        home.link = "/home/alice/.local/state/nix/profiles/home-manager-7-link".to_string();
+        let removed = vec![generation(1, 0, false), generation(2, 0, false), home];
+        let by_profile = generations_by_profile(&removed);
+        assert_eq!(by_profile["/nix/var/nix/profiles/system"], ["1", "2"]);
+        assert_eq!(by_profile["/home/alice/.local/state/nix/profiles/home-manager"], ["7"]);
+    }
+
+    #[test]
+    fn test_parse_print_roots() {
+        let roots = parse_print_roots(
+            "/home/alice/app/result -> /nix/store/aaaa-app\n/proc/12/maps -> /nix/store/bbbb-glibc\n",
+        );
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].0, "/home/alice/app/result");
+    }
+
+    #[test]
+    fn test_scanner_follows_indirect_roots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let gcroots = base.join("gcroots");
+        let project = base.join("project");
+        fs::create_dir_all(gcroots.join("auto")).unwrap();
+        fs::create_dir_all(project.join(".direnv")).unwrap();
+        symlink("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app", project.join("result")).unwrap();
+        symlink(
+            "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-shell",
+            project.join(".direnv/flake-profile-1-link"),
+        )
+        .unwrap();
+        symlink(project.join("result"), gcroots.join("auto/one")).unwrap();
+        symlink(project.join(".direnv/flake-profile-1-link"), gcroots.join("auto/two")).unwrap();
+        symlink(base.join("gone/result"), gcroots.join("auto/three")).unwrap();
+        fs::create_dir_all(base.join("profiles")).unwrap();
+        symlink("/nix/store/cccccccccccccccccccccccccccccccc-system", base.join("profiles/system-3-link")).unwrap();
+        symlink("system-3-link", base.join("profiles/system")).unwrap();
+        symlink(base.join("profiles"), gcroots.join("profiles")).unwrap();
+
+        let roots = GcRootScanner::new(&gcroots, DEFAULT_STALE_DAYS).scan().unwrap();
+        assert_eq!(roots.len(), 4);
+        let profile = roots.iter().find(|r| r.kind == GcRootKind::Profile).unwrap();
+        assert_eq!(profile.project.as_deref(), base.join("profiles/system").to_str());
+        let kinds: Vec<GcRootKind> = roots.iter().map(|r| r.kind).collect();
+        assert!(kinds.contains(&GcRootKind::Direnv));
+        let direnv = roots.iter().find(|r| r.kind == GcRootKind::Direnv).unwrap();
+        assert_eq!(direnv.project.as_deref(), Some(project.to_str().unwrap()));
+
+        let report = group_roots(roots);
+        // Store targets do not exist in the sandbox, so every root is stale
+        assert_eq!(report.stale.len(), 4);
+        assert!(report
+            .stale
+            .iter()
+            .any(|r| r.stale.as_deref() == Some("link no longer exists")));
+    }
+
+    #[test]
+    fn test_scanner_stops_at_result_links() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let gcroots = base.join("gcroots");
+        let package = base.join("store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app");
+        fs::create_dir_all(gcroots.join("auto")).unwrap();
+        fs::create_dir_all(package.join("bin")).unwrap();
+        fs::create_dir_all(base.join("project")).unwrap();
+        symlink("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-x", package.join("bin/x")).unwrap();
+        symlink(&package, base.join("project/result")).unwrap();
+        symlink(base.join("project/result"), gcroots.join("auto/x")).unwrap();
+
+        let roots = GcRootScanner::new(&gcroots, DEFAULT_STALE_DAYS).scan().unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].link, base.join("project/result").to_string_lossy());
+        assert_eq!(roots[0].kind, GcRootKind::Result);
+    }
+}
@@ -0,0 +1,281 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::profiles::Generation;
+use super::store::{parse_drv_name, store_path_name};
+
+/// Name of the Home Manager profile symlink
+pub const HOME_MANAGER_PROFILE: &str = "home-manager";
+
+/// Parameters of the `list_home_generations` method
+#[derive(Debug, Default, Deserialize)]
+pub struct HomeGenerationsParams {
+    /// Home Manager profile symlink, found in the usual places by default
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+/// Parameters of the `show_home_generation` method
+#[derive(Debug, Default, Deserialize)]
+pub struct ShowHomeGenerationParams {
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Generation number, the current generation by default
+    #[serde(default)]
+    pub generation: Option<u64>,
+}
+
+/// Parameters of the `diff_home_generations` method
+#[derive(Debug, Default, Deserialize)]
+pub struct DiffHomeGenerationsParams {
+    #[serde(default)]
+    pub profile: Option<String>,
+    pub from: u64,
+    /// Generation to compare with, the current generation by default
+    #[serde(default)]
+    pub to: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HomeGenerations {
+    pub profile: String,
+    pub generations: Vec<Generation>,
+}
+
+/// A file Home Manager links into the home directory
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ManagedFile {
+    /// Path relative to the home directory
+    pub path: String,
+    pub target: String,
+}
+
+/// A package of the generation's `home-path`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct HomePackage {
+    pub name: String,
+    pub version: Option<String>,
+    pub path: String,
+}
+
+impl HomePackage {
+    pub fn from_store_path(path: &str) -> Self {
+        let (name, version) = parse_drv_name(store_path_name(path));
+        Self {
+            name: name.to_string(),
+            version: version.map(str::to_string),
+            path: path.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HomeGeneration {
+    pub generation: Generation,
+    /// The activation package the generation links to
+    pub activation_package: String,
+    pub home_manager_version: Option<String>,
+    pub files: Vec<ManagedFile>,
+    pub packages: Vec<HomePackage>,
+}
+
+/// A package whose version differs between two generations
+#[derive(Debug, PartialEq, Serialize)]
+pub struct VersionChange {
+    pub name: String,
+    pub from: Vec<String>,
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct PackageDiff {
+    pub added: Vec<HomePackage>,
+    pub removed: Vec<HomePackage>,
+    pub changed: Vec<VersionChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HomeGenerationDiff {
+    pub from: u64,
+    pub to: u64,
+    pub packages: PackageDiff,
+    /// Managed files that were added, removed or now point elsewhere
+    pub files_added: Vec<String>,
+    pub files_removed: Vec<String>,
+    pub files_changed: Vec<String>,
+}
+
+/// Candidate locations of the Home Manager profile, newest layout first
+pub fn home_profile_candidates(home: Option<&Path>, user: Option<&str>) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(home) = home {
+        candidates.push(home.join(".local/state/nix/profiles").join(HOME_MANAGER_PROFILE));
+    }
+    if let Some(user) = user {
+        candidates.push(
+            Path::new("/nix/var/nix/profiles/per-user")
+                .join(user)
+                .join(HOME_MANAGER_PROFILE),
+        );
+    }
+    candidates
+}
+
+/// Lists the files a generation links into the home directory. Directories
+/// linked as a whole are reported as one entry.
+pub fn list_home_files(home_files: &Path) -> io::Result<Vec<ManagedFile>> {
+    fn walk(root: &Path, dir: &Path, files: &mut Vec<ManagedFile>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.file_type().is_symlink() {
+                files.push(ManagedFile {
+                    path: path.strip_prefix(root).unwrap_or(&path).to_string_lossy().to_string(),
+                    target: fs::read_link(&path)?.to_string_lossy().to_string(),
+                });
+            } else if metadata.is_dir() {
+                walk(root, &path, files)?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    if home_files.is_dir() {
+        walk(home_files, home_files, &mut files)?;
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Compares the packages of two generations by name
+pub fn diff_packages(from: &[HomePackage], to: &[HomePackage]) -> PackageDiff {
+    let by_name = |packages: &[HomePackage]| -> BTreeMap<String, Vec<HomePackage>> {
+        let mut map: BTreeMap<String, Vec<HomePackage>> = BTreeMap::new();
+        for package in packages {
+            map.entry(package.name.clone()).or_default().push(package.clone());
+        }
+        map
+    };
+    let from = by_name(from);
+    let to = by_name(to);
+
+    let mut diff = PackageDiff::default();
+    for (name, old) in &from {
+        match to.get(name) {
+            None => diff.removed.extend(old.iter().cloned()),
+            Some(new) => {
+                let versions = |packages: &[HomePackage]| -> BTreeSet<String> {
+                    packages.iter().map(|p| p.version.clone().unwrap_or_default()).collect()
+                };
+                let (old_versions, new_versions) = (versions(old), versions(new));
+                if old_versions != new_versions {
+                    diff.changed.push(VersionChange {
+                        name: name.clone(),
+                        from: old_versions.into_iter().collect(),
+                        to: new_versions.into_iter().collect(),
+                    });
+                }
+            }
+        }
+    }
+    for (name, new) in &to {
+        if !from.contains_key(name) {
+            diff.added.extend(new.iter().cloned());
+        }
+    }
+    diff
+}
+
+/// Compares the managed files of two generations, returning the paths that
+/// were added, removed and retargeted
+pub fn diff_files(from: &[ManagedFile], to: &[ManagedFile]) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let from: BTreeMap<&str, &str> = from.iter().map(|f| (f.path.as_str(), f.target.as_str())).collect();
+    let to: BTreeMap<&str, &str> = to.iter().map(|f| (f.path.as_str(), f.target.as_str())).collect();
+    let added = to.keys().filter(|path| !from.contains_key(*path)).map(|p| p.to_string()).collect();
+    let removed = from.keys().filter(|path| !to.contains_key(*path)).map(|p| p.to_string()).collect();
+    let changed = from
+        .iter()
+        .filter(|(path, target)| to.get(*path).is_some_and(|new| new != *target))
+        .map(|(path, _)| path.to_string())
+        .collect();
+    (added, removed, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn package(path: &str) -> HomePackage {
+        HomePackage::from_store_path(path)
+    }
+
+    #[test]
+    fn test_home_profile_candidates() {
+        let candidates = home_profile_candidates(Some(Path::new("/home/alice")), Some("alice"));
+        assert_eq!(
+            candidates,
+            vec![
+                PathBuf::from("/home/alice/.local/state/nix/profiles/home-manager"),
+                PathBuf::from("/nix/var/nix/profiles/per-user/alice/home-manager"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_home_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join(".config/git")).unwrap();
+        symlink("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hm_gitconfig", dir.join(".config/git/config")).unwrap();
+        symlink("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hm_.bashrc", dir.join(".bashrc")).unwrap();
+        symlink("/nix/store/cccccccccccccccccccccccccccccccc-nvim-config", dir.join(".config/nvim")).unwrap();
+
+        let files = list_home_files(dir).unwrap();
+        let paths: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec![".bashrc", ".config/git/config", ".config/nvim"]);
+        assert!(files[0].target.ends_with("hm_.bashrc"));
+        assert!(list_home_files(&dir.join("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_packages() {
+        let from = vec![
+            package("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-ripgrep-14.0.3"),
+            package("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-jq-1.7"),
+            package("/nix/store/cccccccccccccccccccccccccccccccc-htop-3.2.2"),
+        ];
+        let to = vec![
+            package("/nix/store/dddddddddddddddddddddddddddddddd-ripgrep-14.1.0"),
+            package("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-jq-1.7"),
+            package("/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-btop-1.3.0"),
+        ];
+        let diff = diff_packages(&from, &to);
+        assert_eq!(diff.added.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!["btop"]);
+        assert_eq!(diff.removed.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!["htop"]);
+        assert_eq!(
+            diff.changed,
+            vec![VersionChange {
+                name: "ripgrep".to_string(),
+                from: vec!["14.0.3".to_string()],
+                to: vec!["14.1.0".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let file = |path: &str, target: &str| ManagedFile { path: path.to_string(), target: target.to_string() };
+        let (added, removed, changed) = diff_files(
+            &[file(".bashrc", "/nix/store/a"), file(".vimrc", "/nix/store/b")],
+            &[file(".bashrc", "/nix/store/c"), file(".zshrc", "/nix/store/d")],
+        );
+        assert_eq!(added, vec![".zshrc"]);
+        assert_eq!(removed, vec![".vimrc"]);
+        assert_eq!(changed, vec![".bashrc"]);
+    }
+}
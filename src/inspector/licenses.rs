@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use super::closure::DependencyGraph;
+use super::lock::LockedInput;
+use super::system::SystemAnalyzerError;
+use super::util::default_input;
+use super::vulnerabilities::ScannedPackage;
+
+/// Policy below the state directory used when none is given
+pub const POLICY_FILE: &str = "license-policy.toml";
+
+/// Prefixes of copyleft licenses, as SPDX identifiers or nixpkgs short names
+const COPYLEFT_PREFIXES: &[&str] = &["gpl", "lgpl", "agpl", "mpl", "epl", "eupl", "cddl", "osl", "cecill", "cc-by-sa"];
+
+/// Licenses nixpkgs considers unfree that do not say so in their name
+const UNFREE_LICENSES: &[&str] = &["busl-1.1", "bsl11", "sspl-1.0", "sspl", "elastic-2.0", "elastic20"];
+
+/// Parameters of the `audit_licenses` method
+#[derive(Debug, Deserialize)]
+pub struct LicenseAuditParams {
+    pub installable: String,
+    /// Policy file, `license-policy.toml` in the state directory by default
+    #[serde(default)]
+    pub policy: Option<String>,
+    /// Flake whose nixpkgs supplies license metadata, the flake of the
+    /// installable by default
+    #[serde(default)]
+    pub metadata_flake: Option<String>,
+    #[serde(default = "default_input")]
+    pub input: String,
+}
+
+/// Classes of licenses, from least to most restrictive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LicenseClass {
+    Free,
+    Copyleft,
+    /// No license information
+    Unknown,
+    Unfree,
+}
+
+impl LicenseClass {
+    fn name(self) -> &'static str {
+        match self {
+            LicenseClass::Free => "free",
+            LicenseClass::Copyleft => "copyleft",
+            LicenseClass::Unknown => "unknown",
+            LicenseClass::Unfree => "unfree",
+        }
+    }
+}
+
+/// Which licenses a closure may contain. License names are compared
+/// without regard to case.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LicensePolicy {
+    /// Classes whose licenses are not allowed
+    pub deny_classes: Vec<LicenseClass>,
+    /// Licenses not allowed whatever their class
+    pub deny: Vec<String>,
+    /// Licenses allowed whatever their class
+    pub allow: Vec<String>,
+    /// Packages the policy does not apply to, by name
+    pub exempt_packages: Vec<String>,
+    /// Classes of licenses the built-in rules do not know or get wrong
+    pub classes: BTreeMap<String, LicenseClass>,
+}
+
+impl Default for LicensePolicy {
+    fn default() -> Self {
+        Self {
+            deny_classes: vec![LicenseClass::Unfree],
+            deny: Vec::new(),
+            allow: Vec::new(),
+            exempt_packages: Vec::new(),
+            classes: BTreeMap::new(),
+        }
+    }
+}
+
+fn contains(list: &[String], license: &str) -> bool {
+    list.iter().any(|entry| entry.eq_ignore_ascii_case(license))
+}
+
+impl LicensePolicy {
+    pub fn parse(contents: &str) -> Result<Self, SystemAnalyzerError> {
+        toml::from_str(contents).map_err(|e| SystemAnalyzerError::ParseError(format!("invalid license policy: {}", e)))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SystemAnalyzerError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn classify(&self, license: &str) -> LicenseClass {
+        if let Some((_, class)) = self.classes.iter().find(|(name, _)| name.eq_ignore_ascii_case(license)) {
+            return *class;
+        }
+        let lower = license.to_lowercase();
+        if lower.starts_with("unfree") || lower.starts_with("cc-by-nc") || UNFREE_LICENSES.contains(&lower.as_str()) {
+            LicenseClass::Unfree
+        } else if COPYLEFT_PREFIXES.iter().any(|prefix| lower.starts_with(prefix)) {
+            LicenseClass::Copyleft
+        } else {
+            LicenseClass::Free
+        }
+    }
+
+    /// Reasons a package breaks the policy; empty if it does not. Packages
+    /// under several licenses must satisfy the policy with each of them, as
+    /// nixpkgs does not say whether they apply together or as alternatives.
+    fn violations(&self, name: &str, licenses: &[String], unfree: bool) -> Vec<String> {
+        if contains(&self.exempt_packages, name) {
+            return Vec::new();
+        }
+        let mut reasons = Vec::new();
+        if licenses.is_empty() && self.deny_classes.contains(&LicenseClass::Unknown) {
+            reasons.push("no license information".to_string());
+        }
+        for license in licenses.iter().filter(|license| !contains(&self.allow, license)) {
+            let class = self.classify(license);
+            if contains(&self.deny, license) {
+                reasons.push(format!("{} is denied", license));
+            } else if self.deny_classes.contains(&class) {
+                reasons.push(format!("{} is {}", license, class.name()));
+            }
+        }
+        let allowed = licenses.iter().any(|license| contains(&self.allow, license));
+        if unfree && !allowed && reasons.is_empty() && self.deny_classes.contains(&LicenseClass::Unfree) {
+            reasons.push("marked unfree in meta".to_string());
+        }
+        reasons
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditedPackage {
+    pub name: String,
+    pub version: String,
+    pub store_path: String,
+    pub licenses: Vec<String>,
+    /// The most restrictive class of its licenses
+    pub class: LicenseClass,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LicenseViolation {
+    pub name: String,
+    pub version: String,
+    pub store_path: String,
+    pub licenses: Vec<String>,
+    pub reasons: Vec<String>,
+    /// References leading from the installable to the package
+    pub chain: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LicenseAudit {
+    pub installable: String,
+    /// The nixpkgs the licenses were looked up in
+    pub license_source: Option<LockedInput>,
+    /// Packages per license class
+    pub class_counts: BTreeMap<String, usize>,
+    pub packages: Vec<AuditedPackage>,
+    pub violations: Vec<LicenseViolation>,
+}
+
+/// Classifies the licenses of closure packages and checks them against a
+/// policy. `lookup` returns the licenses of a package and whether nixpkgs
+/// considers it unfree, or `None` if the package is unknown.
+pub fn audit_licenses(
+    installable: &str,
+    packages: &[ScannedPackage],
+    graph: &DependencyGraph,
+    policy: &LicensePolicy,
+    lookup: impl Fn(&str, &str) -> Option<(Vec<String>, bool)>,
+) -> LicenseAudit {
+    let mut audit = LicenseAudit {
+        installable: installable.to_string(),
+        license_source: None,
+        class_counts: BTreeMap::new(),
+        packages: Vec::new(),
+        violations: Vec::new(),
+    };
+    for package in packages {
+        let (licenses, unfree) = lookup(&package.pname, &package.version).unwrap_or_default();
+        let class = licenses
+            .iter()
+            .map(|license| policy.classify(license))
+            .max()
+            .map(|class| if unfree { LicenseClass::Unfree } else { class })
+            .unwrap_or(LicenseClass::Unknown);
+        *audit.class_counts.entry(class.name().to_string()).or_default() += 1;
+
+        let reasons = policy.violations(&package.pname, &licenses, unfree);
+        if !reasons.is_empty() {
+            let targets: HashSet<&str> = [package.store_path.as_str()].into_iter().collect();
+            let chain = graph
+                .roots
+                .iter()
+                .find_map(|root| graph.shortest_chains(root, &targets, 1).into_iter().next())
+                .unwrap_or_else(|| vec![package.store_path.clone()]);
+            audit.violations.push(LicenseViolation {
+                name: package.pname.clone(),
+                version: package.version.clone(),
+                store_path: package.store_path.clone(),
+                licenses: licenses.clone(),
+                reasons,
+                chain,
+            });
+        }
+        audit.packages.push(AuditedPackage {
+            name: package.pname.clone(),
+            version: package.version.clone(),
+            store_path: package.store_path.clone(),
+            licenses,
+            class,
+        });
+    }
+    audit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspector::closure::PathInfo;
+
+    const APP: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-app-1.0";
+    const CUDA: &str = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-cudatoolkit-12.2";
+    const NVIDIA: &str = "/nix/store/cccccccccccccccccccccccccccccccc-nvidia-x11-550.78";
+    const READLINE: &str = "/nix/store/dddddddddddddddddddddddddddddddd-readline-8.2p10";
+    const ZLIB: &str = "/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-zlib-1.3.1";
+    const MYSTERY: &str = "/nix/store/ffffffffffffffffffffffffffffffff-mystery-0.1";
+
+    const POLICY: &str = r#"
+deny_classes = ["unfree", "unknown"]
+deny = ["AGPL-3.0-only"]
+allow = ["LGPL-2.1-only"]
+exempt_packages = ["nvidia-x11"]
+
+[classes]
+"Commons-Clause" = "unfree"
+"#;
+
+    fn graph() -> DependencyGraph {
+        let info = |path: &str, references: &[&str]| PathInfo {
+            path: path.to_string(),
+            references: references.iter().map(|r| r.to_string()).collect(),
+            nar_size: 0,
+            deriver: None,
+            nar_hash: None,
+        };
+        let infos = vec![
+            info(APP, &[CUDA, READLINE, ZLIB]),
+            info(CUDA, &[NVIDIA, ZLIB]),
+            info(NVIDIA, &[]),
+            info(READLINE, &[]),
+            info(ZLIB, &[MYSTERY]),
+            info(MYSTERY, &[]),
+        ];
+        DependencyGraph::from_path_info(vec![APP.to_string()], &infos)
+    }
+
+    fn package(path: &str) -> ScannedPackage {
+        let (pname, version) = crate::inspector::store::parse_drv_name(crate::inspector::store::store_path_name(path));
+        ScannedPackage {
+            pname: pname.to_string(),
+            version: version.unwrap().to_string(),
+            store_path: path.to_string(),
+            patched: Default::default(),
+            known_vulnerabilities: Vec::new(),
+        }
+    }
+
+    fn lookup(name: &str, _version: &str) -> Option<(Vec<String>, bool)> {
+        let licenses = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        match name {
+            "app" => Some((licenses(&["MIT", "Commons-Clause"]), false)),
+            "cudatoolkit" => Some((licenses(&["CUDA EULA"]), true)),
+            "nvidia-x11" => Some((licenses(&["unfreeRedistributable"]), true)),
+            "readline" => Some((licenses(&["GPL-3.0-or-later"]), false)),
+            "zlib" => Some((licenses(&["Zlib"]), false)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        let policy = LicensePolicy::default();
+        assert_eq!(policy.classify("MIT"), LicenseClass::Free);
+        assert_eq!(policy.classify("GPL-2.0-only"), LicenseClass::Copyleft);
+        assert_eq!(policy.classify("gpl3Plus"), LicenseClass::Copyleft);
+        assert_eq!(policy.classify("MPL-2.0"), LicenseClass::Copyleft);
+        assert_eq!(policy.classify("unfreeRedistributable"), LicenseClass::Unfree);
+        assert_eq!(policy.classify("BUSL-1.1"), LicenseClass::Unfree);
+        let policy = LicensePolicy::parse(POLICY).unwrap();
+        assert_eq!(policy.classify("commons-clause"), LicenseClass::Unfree);
+        assert!(LicensePolicy::parse("deny_classes = [\"proprietary\"]").is_err());
+        assert!(LicensePolicy::parse("unknown_key = 1").is_err());
+    }
+
+    #[test]
+    fn test_audit_licenses() {
+        let packages: Vec<ScannedPackage> = [APP, CUDA, NVIDIA, READLINE, ZLIB, MYSTERY].iter().map(|p| package(p)).collect();
+        let policy = LicensePolicy::parse(POLICY).unwrap();
+        let audit = audit_licenses(".#default", &packages, &graph(), &policy, lookup);
+
+        assert_eq!(audit.class_counts.get("unfree"), Some(&3));
+        assert_eq!(audit.class_counts.get("copyleft"), Some(&1));
+        assert_eq!(audit.class_counts.get("unknown"), Some(&1));
+        assert_eq!(audit.packages[3].class, LicenseClass::Copyleft);
+
+        let violations: Vec<(&str, &Vec<String>)> = audit.violations.iter().map(|v| (v.name.as_str(), &v.reasons)).collect();
+        assert_eq!(
+            violations,
+            vec![
+                ("app", &vec!["Commons-Clause is unfree".to_string()]),
+                ("cudatoolkit", &vec!["marked unfree in meta".to_string()]),
+                ("mystery", &vec!["no license information".to_string()]),
+            ]
+        );
+        // The chain shows what pulled the offender in
+        assert_eq!(audit.violations[1].chain, vec![APP, CUDA]);
+        assert_eq!(audit.violations[2].chain, vec![APP, ZLIB, MYSTERY]);
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = LicensePolicy::default();
+        assert!(policy.violations("readline", &["GPL-3.0-or-later".to_string()], false).is_empty());
+        assert!(policy.violations("mystery", &[], false).is_empty());
+        assert_eq!(policy.violations("steam", &["unfreeRedistributable".to_string()], true).len(), 1);
+    }
+}
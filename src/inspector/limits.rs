@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Requests a session may make per minute unless configured otherwise
+pub const DEFAULT_REQUESTS_PER_MINUTE: u32 = 100;
+
+/// Nix processes that may run at once unless configured otherwise
+pub const DEFAULT_MAX_NIX_PROCESSES: usize = 4;
+
+/// How long a caller refused a nix process is asked to wait
+const PROCESS_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Rate and concurrency limits
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Requests per minute and session; 0 for no limit
+    pub requests_per_minute: u32,
+    /// Requests a session may make at once after being idle; the per
+    /// minute rate by default
+    pub burst: Option<u32>,
+    /// Requests per minute and session for single tools
+    pub tools: BTreeMap<String, u32>,
+    /// Nix processes running at once across sessions; 0 for no limit
+    pub max_nix_processes: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_minute: DEFAULT_REQUESTS_PER_MINUTE,
+            burst: None,
+            tools: BTreeMap::new(),
+            max_nix_processes: DEFAULT_MAX_NIX_PROCESSES,
+        }
+    }
+}
+
+/// What ran out
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitScope {
+    Session,
+    Tool,
+    NixProcesses,
+}
+
+/// A request refused by a limit
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RateLimited {
+    pub scope: LimitScope,
+    pub tool: Option<String>,
+    /// Requests per minute, or processes at once
+    pub limit: u64,
+    /// Seconds until the request would be let through
+    pub retry_after: f64,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scope {
+            LimitScope::Session => write!(f, "more than {} requests per minute", self.limit),
+            LimitScope::Tool => write!(
+                f,
+                "more than {} calls of {} per minute",
+                self.limit,
+                self.tool.as_deref().unwrap_or_default()
+            ),
+            LimitScope::NixProcesses => write!(f, "{} nix processes are running already", self.limit),
+        }?;
+        write!(f, ", retry after {:.1}s", self.retry_after)
+    }
+}
+
+/// Token bucket refilled continuously at a fixed rate
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32, burst: u32, now: Instant) -> Self {
+        Self {
+            capacity: burst.max(1) as f64,
+            tokens: burst.max(1) as f64,
+            per_second: per_minute as f64 / 60.0,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Whether the bucket has refilled, so it is no different from a new one
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    /// Time until a token is available, or `None` if one is now
+    fn wait(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        (self.tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - self.tokens) / self.per_second))
+    }
+}
+
+/// Per session and per tool request limits
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(String, Option<String>), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for a request of a session. Nothing is taken when the
+    /// request is refused, so waiting callers are not penalized.
+    pub fn check(&self, session: &str, tool: &str, now: Instant) -> Result<(), RateLimited> {
+        let mut limits = Vec::new();
+        if self.config.requests_per_minute > 0 {
+            let burst = self.config.burst.unwrap_or(self.config.requests_per_minute);
+            limits.push((None, self.config.requests_per_minute, burst));
+        }
+        if let Some(&rate) = self.config.tools.get(tool).filter(|&&rate| rate > 0) {
+            limits.push((Some(tool.to_string()), rate, rate));
+        }
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        // Buckets of idle sessions and tools have refilled and are
+        // dropped, so ended sessions do not pile up
+        buckets.retain(|_, bucket| !bucket.is_full(now));
+        for (scope, rate, burst) in &limits {
+            let bucket = buckets
+                .entry((session.to_string(), scope.clone()))
+                .or_insert_with(|| TokenBucket::new(*rate, *burst, now));
+            if let Some(wait) = bucket.wait(now) {
+                return Err(RateLimited {
+                    scope: if scope.is_some() { LimitScope::Tool } else { LimitScope::Session },
+                    tool: scope.clone(),
+                    limit: *rate as u64,
+                    retry_after: wait.as_secs_f64(),
+                });
+            }
+        }
+        for (scope, _, _) in limits {
+            if let Some(bucket) = buckets.get_mut(&(session.to_string(), scope)) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Caps the number of nix processes running at once
+#[derive(Debug, Clone)]
+pub struct ProcessSlots {
+    max: usize,
+    running: Arc<AtomicUsize>,
+}
+
+/// A running nix process; frees its slot when dropped
+#[derive(Debug)]
+pub struct ProcessSlot {
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for ProcessSlot {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ProcessSlots {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a slot, failing rather than waiting when all are in use
+    pub fn acquire(&self) -> Result<ProcessSlot, RateLimited> {
+        let taken = self.running.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+            (self.max == 0 || running < self.max).then_some(running + 1)
+        });
+        match taken {
+            Ok(_) => Ok(ProcessSlot { running: self.running.clone() }),
+            Err(_) => Err(RateLimited {
+                scope: LimitScope::NixProcesses,
+                tool: None,
+                limit: self.max as u64,
+                retry_after: PROCESS_RETRY_AFTER.as_secs_f64(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_limit() {
+        let limiter = RateLimiter::new(RateLimitConfig { requests_per_minute: 60, burst: Some(2), ..Default::default() });
+        let start = Instant::now();
+        assert!(limiter.check("a", "eval", start).is_ok());
+        assert!(limiter.check("a", "eval", start).is_ok());
+        let limited = limiter.check("a", "search_packages", start).unwrap_err();
+        assert_eq!(limited.scope, LimitScope::Session);
+        assert!((limited.retry_after - 1.0).abs() < 1e-6);
+        // Sessions have their own buckets
+        assert!(limiter.check("b", "eval", start).is_ok());
+        // One token per second comes back
+        assert!(limiter.check("a", "eval", start + Duration::from_millis(1000)).is_ok());
+        assert!(limiter.check("a", "eval", start + Duration::from_millis(1500)).is_err());
+    }
+
+    #[test]
+    fn test_idle_buckets_are_dropped() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            requests_per_minute: 60,
+            burst: Some(2),
+            tools: BTreeMap::from([("eval".to_string(), 1)]),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert!(limiter.check("a", "eval", start).is_ok());
+        assert!(limiter.check("b", "search_packages", start).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 3);
+        // The session buckets refill after a second, the tool bucket after a minute
+        assert!(limiter.check("c", "search_packages", start + Duration::from_secs(2)).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        assert!(limiter.check("c", "search_packages", start + Duration::from_secs(61)).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_tool_limit() {
+        let config = RateLimitConfig {
+            requests_per_minute: 100,
+            tools: BTreeMap::from([("eval".to_string(), 1)]),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(config);
+        let start = Instant::now();
+        assert!(limiter.check("a", "eval", start).is_ok());
+        let limited = limiter.check("a", "eval", start).unwrap_err();
+        assert_eq!((limited.scope, limited.tool.as_deref()), (LimitScope::Tool, Some("eval")));
+        assert!((limited.retry_after - 60.0).abs() < 1e-6);
+        assert_eq!(limited.to_string(), "more than 1 calls of eval per minute, retry after 60.0s");
+        assert!(limiter.check("a", "search_packages", start).is_ok());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(RateLimitConfig { requests_per_minute: 0, ..Default::default() });
+        let now = Instant::now();
+        assert!((0..1000).all(|_| limiter.check("a", "eval", now).is_ok()));
+    }
+
+    #[test]
+    fn test_process_slots() {
+        let slots = ProcessSlots::new(2);
+        let first = slots.acquire().unwrap();
+        let _second = slots.acquire().unwrap();
+        assert_eq!(slots.acquire().unwrap_err().scope, LimitScope::NixProcesses);
+        drop(first);
+        assert!(slots.acquire().is_ok());
+    }
+}
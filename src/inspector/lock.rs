@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::system::SystemAnalyzerError;
+
+/// A locked input of a flake, from `nix flake metadata --json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockedInput {
+    pub name: String,
+    /// Node of the input in `flake.lock`
+    pub node: String,
+    pub nar_hash: String,
+    pub rev: Option<String>,
+    pub last_modified: Option<i64>,
+}
+
+impl LockedInput {
+    /// Key identifying the locked contents, usable as a file name
+    pub fn cache_key(&self) -> String {
+        self.nar_hash.replace(['/', '+', '='], "_")
+    }
+}
+
+/// Resolves an input reference of a lock node: either a node name or a
+/// `follows` path of input names starting at the root
+fn resolve_reference(nodes: &Value, root: &str, reference: &Value) -> Option<String> {
+    match reference {
+        Value::String(node) => Some(node.clone()),
+        Value::Array(path) => {
+            let mut node = root.to_string();
+            for name in path {
+                let next = nodes.get(&node)?.get("inputs")?.get(name.as_str()?)?;
+                node = resolve_reference(nodes, root, next)?;
+            }
+            Some(node)
+        }
+        _ => None,
+    }
+}
+
+/// Finds a direct input of the flake described by `metadata`
+pub fn locked_input(metadata: &Value, input: &str) -> Result<LockedInput, SystemAnalyzerError> {
+    let locks = metadata
+        .get("locks")
+        .ok_or_else(|| SystemAnalyzerError::ParseError("flake metadata has no locks".to_string()))?;
+    let nodes = &locks["nodes"];
+    let root = locks.get("root").and_then(Value::as_str).unwrap_or("root");
+    let missing = || SystemAnalyzerError::ParseError(format!("flake has no locked input named {}", input));
+
+    let reference = nodes
+        .get(root)
+        .and_then(|node| node.get("inputs"))
+        .and_then(|inputs| inputs.get(input))
+        .ok_or_else(missing)?;
+    let node = resolve_reference(nodes, root, reference).ok_or_else(missing)?;
+    let locked = nodes.get(&node).and_then(|n| n.get("locked")).ok_or_else(missing)?;
+
+    Ok(LockedInput {
+        name: input.to_string(),
+        node,
+        nar_hash: locked
+            .get("narHash")
+            .and_then(Value::as_str)
+            .ok_or_else(|| SystemAnalyzerError::ParseError(format!("input {} has no narHash", input)))?
+            .to_string(),
+        rev: locked.get("rev").and_then(Value::as_str).map(str::to_string),
+        last_modified: locked.get("lastModified").and_then(Value::as_i64),
+    })
+}
+
+/// Locked URL of the flake itself, usable with `builtins.getFlake` in pure mode
+pub fn locked_url(metadata: &Value) -> Result<&str, SystemAnalyzerError> {
+    metadata
+        .get("url")
+        .and_then(Value::as_str)
+        .ok_or_else(|| SystemAnalyzerError::ParseError("flake metadata has no locked URL".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Value {
+        serde_json::json!({
+            "url": "path:/src/app?lastModified=1700000000&narHash=sha256-root",
+            "locks": {
+                "root": "root",
+                "version": 7,
+                "nodes": {
+                    "root": {"inputs": {"nixpkgs": "nixpkgs_2", "home-manager": "home-manager", "pinned": ["home-manager", "nixpkgs"]}},
+                    "home-manager": {
+                        "inputs": {"nixpkgs": ["nixpkgs"]},
+                        "locked": {"narHash": "sha256-hm", "rev": "bbbb", "lastModified": 1700000001}
+                    },
+                    "nixpkgs_2": {
+                        "locked": {"narHash": "sha256-abc/def+g=", "rev": "aaaa", "lastModified": 1700000002}
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_locked_input() {
+        let nixpkgs = locked_input(&metadata(), "nixpkgs").unwrap();
+        assert_eq!(nixpkgs.node, "nixpkgs_2");
+        assert_eq!(nixpkgs.rev.as_deref(), Some("aaaa"));
+        assert_eq!(nixpkgs.cache_key(), "sha256-abc_def_g_");
+
+        // `follows` paths resolve through other inputs
+        assert_eq!(locked_input(&metadata(), "pinned").unwrap().node, "nixpkgs_2");
+        assert!(locked_input(&metadata(), "missing").is_err());
+        assert!(locked_url(&metadata()).unwrap().starts_with("path:/src/app"));
+    }
+}
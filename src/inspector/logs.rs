@@ -0,0 +1,372 @@
+use bzip2::read::BzDecoder;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::build::strip_ansi;
+use super::store::store_path_basename;
+use super::system::SystemAnalyzerError;
+
+/// Directory holding compressed build logs, one per derivation
+pub const LOG_DIR: &str = "/nix/var/log/nix/drvs";
+
+/// Lines returned when no selection is given
+pub const DEFAULT_TAIL: usize = 100;
+
+/// Upper bound on returned lines, whatever the selection
+pub const MAX_LINES: usize = 1000;
+
+/// Lines of context kept around the first error
+const ERROR_CONTEXT: usize = 5;
+
+/// Parameters of the `get_build_log` method
+#[derive(Debug, Default, Deserialize)]
+pub struct BuildLogParams {
+    /// Store derivation or installable, e.g. `/nix/store/...-hello.drv` or `.#hello`
+    pub installable: String,
+    /// Return only the last `tail` selected lines
+    #[serde(default)]
+    pub tail: Option<usize>,
+    /// Return only lines matching this regular expression
+    #[serde(default)]
+    pub grep: Option<String>,
+    /// First line to return, 1-based
+    #[serde(default)]
+    pub start_line: Option<usize>,
+    /// Last line to return, inclusive
+    #[serde(default)]
+    pub end_line: Option<usize>,
+}
+
+/// Where a build log was read from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogSource {
+    LogDir,
+    NixLog,
+}
+
+/// A numbered line of a build log
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LogLine {
+    pub number: usize,
+    pub text: String,
+}
+
+/// Builders the failure heuristics know about
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuilderKind {
+    Cargo,
+    Cmake,
+    Meson,
+    Generic,
+}
+
+/// What went wrong in a failed build, as far as the log tells
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailureSummary {
+    pub builder: BuilderKind,
+    /// Last stdenv phase started before the log ends
+    pub phase: Option<String>,
+    pub first_error: Option<LogLine>,
+    /// Lines following the first error, e.g. the location of a compiler error
+    pub error_context: Vec<String>,
+    /// Dependencies the build looked for and did not find
+    pub missing_dependencies: Vec<String>,
+}
+
+/// A selection of a build log with its failure summary
+#[derive(Debug, Serialize)]
+pub struct BuildLog {
+    pub installable: String,
+    pub source: LogSource,
+    pub total_lines: usize,
+    pub lines: Vec<LogLine>,
+    pub truncated: bool,
+    pub summary: FailureSummary,
+}
+
+/// Location of the compressed log of a derivation, e.g.
+/// `/nix/var/log/nix/drvs/ab/cdef...-hello.drv.bz2`
+pub fn log_file_path(log_dir: &Path, drv_path: &str) -> Option<PathBuf> {
+    let name = store_path_basename(drv_path);
+    if !name.ends_with(".drv") || name.len() < 3 {
+        return None;
+    }
+    let (prefix, rest) = name.split_at(2);
+    Some(log_dir.join(prefix).join(format!("{}.bz2", rest)))
+}
+
+/// Reads a bzip2 compressed build log
+pub fn read_log_file(path: &Path) -> Result<String, SystemAnalyzerError> {
+    let mut contents = Vec::new();
+    BzDecoder::new(File::open(path)?).read_to_end(&mut contents)?;
+    Ok(String::from_utf8_lossy(&contents).to_string())
+}
+
+/// Applies the grep, range and tail selection of `params` to a log
+pub fn select_lines(log: &str, params: &BuildLogParams) -> Result<(Vec<LogLine>, bool), SystemAnalyzerError> {
+    let pattern = params
+        .grep
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(|e| SystemAnalyzerError::ParseError(format!("invalid grep pattern: {}", e)))?;
+    let start = params.start_line.unwrap_or(1);
+    let end = params.end_line.unwrap_or(usize::MAX);
+
+    let mut lines: Vec<LogLine> = log
+        .lines()
+        .enumerate()
+        .map(|(i, text)| LogLine { number: i + 1, text: strip_ansi(text) })
+        .filter(|line| line.number >= start && line.number <= end)
+        .filter(|line| pattern.as_ref().is_none_or(|p| p.is_match(&line.text)))
+        .collect();
+
+    let unselected = params.tail.is_none() && params.grep.is_none() && params.start_line.is_none() && params.end_line.is_none();
+    let tail = match params.tail {
+        Some(tail) => tail.min(MAX_LINES),
+        None if unselected => DEFAULT_TAIL,
+        None => MAX_LINES,
+    };
+    let truncated = lines.len() > tail;
+    if truncated {
+        lines.drain(..lines.len() - tail);
+    }
+    Ok((lines, truncated))
+}
+
+/// Guesses the build system from the log
+fn detect_builder(lines: &[&str]) -> BuilderKind {
+    let mentions = |needles: &[&str]| lines.iter().any(|line| needles.iter().any(|n| line.contains(n)));
+    if mentions(&["cargoBuildHook", "cargo build", "Compiling ", "error[E"]) && mentions(&["cargo", "rustc", "error[E"]) {
+        BuilderKind::Cargo
+    } else if mentions(&["mesonConfigurePhase", "The Meson build system", "meson.build:"]) {
+        BuilderKind::Meson
+    } else if mentions(&["cmake flags:", "CMake Error", "-- Configuring incomplete", "CMakeLists.txt"]) {
+        BuilderKind::Cmake
+    } else {
+        BuilderKind::Generic
+    }
+}
+
+/// Phase named by a stdenv phase header
+fn phase_of(line: &str) -> Option<String> {
+    // Recent stdenv prints `Running phase: buildPhase`
+    if let Some(phase) = line.strip_prefix("Running phase: ") {
+        return Some(phase.trim().to_string());
+    }
+    // Older stdenv only prints a header for some phases
+    const HEADERS: &[(&str, &str)] = &[
+        ("unpacking sources", "unpackPhase"),
+        ("patching sources", "patchPhase"),
+        ("configuring", "configurePhase"),
+        ("building", "buildPhase"),
+        ("running tests", "checkPhase"),
+        ("installing", "installPhase"),
+        ("post-installation fixup", "fixupPhase"),
+    ];
+    HEADERS
+        .iter()
+        .find(|(header, _)| line == *header)
+        .map(|(_, phase)| phase.to_string())
+}
+
+fn is_error(builder: BuilderKind, line: &str) -> bool {
+    let generic = line.contains(": error:") || line.contains(": fatal error:") || line.starts_with("error:");
+    match builder {
+        // `could not compile` only repeats the crate name after the real error
+        BuilderKind::Cargo => {
+            (line.starts_with("error") && !line.starts_with("error: could not compile")) || line.contains(": error:")
+        }
+        BuilderKind::Cmake => line.starts_with("CMake Error") || generic,
+        BuilderKind::Meson => line.contains("ERROR:") || generic,
+        BuilderKind::Generic => generic || line.starts_with("make: ***") || line.contains("command not found"),
+    }
+}
+
+lazy_static! {
+    /// Patterns whose first group names a dependency the build did not find
+    static ref MISSING_DEPENDENCY: Vec<Regex> = [
+        // CMake: `Could NOT find OpenSSL (missing: OPENSSL_CRYPTO_LIBRARY)`
+        r"Could NOT find (\S+)",
+        // CMake: `Could not find a package configuration file provided by "Qt5"`
+        r#"provided by "([^"]+)""#,
+        // Meson: `Dependency "glib-2.0" not found` / `Run-time dependency gtk4 found: NO`
+        r#"[Dd]ependency "?([\w.+-]+)"? (?:not found|found: NO)"#,
+        // pkg-config: `Package openssl was not found in the pkg-config search path`
+        r"Package '?([\w.+-]+)'?,? was not found",
+        // openssl-sys: `Could not find directory of OpenSSL installation`
+        r"Could not find directory of (\w+)",
+        r"could not find system library '([^']+)'",
+        // C compilers: `fatal error: zlib.h: No such file or directory`
+        r"fatal error: ([^:]+): No such file or directory",
+        // Linkers: `cannot find -lssl`
+        r"cannot find -l(\S+)",
+        // Shell: `sh: line 1: pkg-config: command not found`
+        r"([\w.+-]+): command not found",
+    ]
+    .iter()
+    .map(|pattern| Regex::new(pattern).expect("valid pattern"))
+    .collect();
+}
+
+fn missing_dependency(line: &str) -> Option<String> {
+    MISSING_DEPENDENCY
+        .iter()
+        .find_map(|pattern| pattern.captures(line))
+        .map(|captures| captures[1].to_string())
+}
+
+/// Extracts a failure summary from a build log
+pub fn summarize_failure(log: &str) -> FailureSummary {
+    let lines: Vec<String> = log.lines().map(strip_ansi).collect();
+    let plain: Vec<&str> = lines.iter().map(String::as_str).collect();
+    let builder = detect_builder(&plain);
+
+    let mut phase = None;
+    let mut first_error: Option<LogLine> = None;
+    let mut error_context = Vec::new();
+    let mut missing_dependencies: Vec<String> = Vec::new();
+    for (i, line) in plain.iter().enumerate() {
+        if let Some(p) = phase_of(line) {
+            phase = Some(p);
+        }
+        if first_error.is_none() && is_error(builder, line) {
+            first_error = Some(LogLine { number: i + 1, text: line.to_string() });
+            error_context = plain[i + 1..].iter().take(ERROR_CONTEXT).map(|l| l.to_string()).collect();
+        }
+        if let Some(dependency) = missing_dependency(line) {
+            if !missing_dependencies.contains(&dependency) {
+                missing_dependencies.push(dependency);
+            }
+        }
+    }
+
+    FailureSummary {
+        builder,
+        phase,
+        first_error,
+        error_context,
+        missing_dependencies,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO_LOG: &str = "\
+Running phase: unpackPhase
+unpacking source archive /nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-source
+Running phase: buildPhase
+Executing cargoBuildHook
+   Compiling openssl-sys v0.9.102
+error: failed to run custom build command for `openssl-sys v0.9.102`
+  Could not find directory of OpenSSL installation, and this `-sys` crate cannot
+  proceed without this knowledge.
+error: could not compile `app` due to previous error";
+
+    const CMAKE_LOG: &str = "\
+configuring
+cmake flags: -DCMAKE_BUILD_TYPE=Release
+-- The C compiler identification is GNU 13.2.0
+CMake Error at /nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-cmake/FindPackageHandleStandardArgs.cmake:230 (message):
+  Could NOT find ZLIB (missing: ZLIB_LIBRARY ZLIB_INCLUDE_DIR)
+-- Configuring incomplete, errors occurred!";
+
+    const MESON_LOG: &str = "\
+Running phase: configurePhase
+mesonConfigurePhase flags: --buildtype=plain
+The Meson build system
+Run-time dependency glib-2.0 found: NO (tried pkgconfig)
+../meson.build:12:0: ERROR: Dependency \"glib-2.0\" not found, tried pkgconfig";
+
+    #[test]
+    fn test_log_file_path() {
+        assert_eq!(
+            log_file_path(Path::new(LOG_DIR), "/nix/store/abcdefghijklmnopqrstuvwxyz012345-hello-2.12.1.drv"),
+            Some(PathBuf::from("/nix/var/log/nix/drvs/ab/cdefghijklmnopqrstuvwxyz012345-hello-2.12.1.drv.bz2"))
+        );
+        assert_eq!(log_file_path(Path::new(LOG_DIR), ".#hello"), None);
+    }
+
+    #[test]
+    fn test_select_lines() {
+        let log = (1..=300).map(|i| format!("line {}", i)).collect::<Vec<_>>().join("\n");
+
+        let (lines, truncated) = select_lines(&log, &BuildLogParams::default()).unwrap();
+        assert_eq!(lines.len(), DEFAULT_TAIL);
+        assert_eq!(lines[0].number, 201);
+        assert!(truncated);
+
+        let params = BuildLogParams { grep: Some(r"^line 1\d$".to_string()), ..Default::default() };
+        let (lines, truncated) = select_lines(&log, &params).unwrap();
+        assert_eq!(lines.len(), 10);
+        assert!(!truncated);
+
+        let params = BuildLogParams { start_line: Some(10), end_line: Some(20), tail: Some(3), ..Default::default() };
+        let (lines, _) = select_lines(&log, &params).unwrap();
+        assert_eq!(lines.iter().map(|l| l.number).collect::<Vec<_>>(), vec![18, 19, 20]);
+
+        let params = BuildLogParams { grep: Some("(".to_string()), ..Default::default() };
+        assert!(select_lines(&log, &params).is_err());
+    }
+
+    #[test]
+    fn test_summarize_cargo() {
+        let summary = summarize_failure(CARGO_LOG);
+        assert_eq!(summary.builder, BuilderKind::Cargo);
+        assert_eq!(summary.phase.as_deref(), Some("buildPhase"));
+        assert_eq!(summary.first_error.unwrap().number, 6);
+        assert!(summary.error_context[0].contains("OpenSSL"));
+        assert_eq!(summary.missing_dependencies, vec!["OpenSSL"]);
+    }
+
+    #[test]
+    fn test_summarize_cmake() {
+        let summary = summarize_failure(CMAKE_LOG);
+        assert_eq!(summary.builder, BuilderKind::Cmake);
+        assert_eq!(summary.phase.as_deref(), Some("configurePhase"));
+        assert!(summary.first_error.unwrap().text.starts_with("CMake Error"));
+        assert_eq!(summary.missing_dependencies, vec!["ZLIB"]);
+    }
+
+    #[test]
+    fn test_summarize_meson() {
+        let summary = summarize_failure(MESON_LOG);
+        assert_eq!(summary.builder, BuilderKind::Meson);
+        assert_eq!(summary.first_error.unwrap().number, 5);
+        assert_eq!(summary.missing_dependencies, vec!["glib-2.0"]);
+    }
+
+    #[test]
+    fn test_summarize_generic() {
+        let summary = summarize_failure("building\nmain.c:3:10: fatal error: zlib.h: No such file or directory\nmake: *** [Makefile:2: all] Error 1");
+        assert_eq!(summary.builder, BuilderKind::Generic);
+        assert_eq!(summary.phase.as_deref(), Some("buildPhase"));
+        assert_eq!(summary.first_error.unwrap().number, 2);
+        assert_eq!(summary.missing_dependencies, vec!["zlib.h"]);
+    }
+
+    #[test]
+    fn test_read_log_file() {
+        use bzip2::write::BzEncoder;
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let drv = "/nix/store/abcdefghijklmnopqrstuvwxyz012345-hello-2.12.1.drv";
+        let path = log_file_path(dir, drv).unwrap();
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut encoder = BzEncoder::new(File::create(&path).unwrap(), bzip2::Compression::default());
+        encoder.write_all(CMAKE_LOG.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        assert_eq!(read_log_file(&path).unwrap(), CMAKE_LOG);
+    }
+}
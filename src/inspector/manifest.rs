@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use super::system::SystemAnalyzerError;
+
+/// Profiles that are not package profiles and are skipped when listing
+pub const SKIPPED_PROFILES: &[&str] = &["home-manager", "channels"];
+
+/// System flake registry, pinned to the system nixpkgs on recent NixOS
+pub const SYSTEM_REGISTRY: &str = "/etc/nix/registry.json";
+
+/// Parameters of the `list_user_profiles` method
+#[derive(Debug, Default, Deserialize)]
+pub struct UserProfilesParams {
+    /// A single profile to inspect, all profiles of the user by default
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Also read the nixpkgs input of elements installed from other flakes,
+    /// which may need to fetch their lock files
+    #[serde(default)]
+    pub check_inputs: bool,
+}
+
+/// How a profile records its contents
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProfileKind {
+    /// `manifest.json`, written by `nix profile`
+    NixProfile,
+    /// `manifest.nix`, written by `nix-env`
+    NixEnv,
+}
+
+/// An element installed with `nix profile install`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProfileElement {
+    pub name: String,
+    pub active: bool,
+    pub attr_path: Option<String>,
+    pub original_url: Option<String>,
+    /// Locked flake reference
+    pub url: Option<String>,
+    pub priority: Option<i64>,
+    pub store_paths: Vec<String>,
+    /// Revision of nixpkgs the element was built from, if known
+    pub nixpkgs_rev: Option<String>,
+    /// Set when the element's nixpkgs differs from the system nixpkgs
+    pub outdated: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    pub name: String,
+    pub path: String,
+    pub kind: ProfileKind,
+    pub elements: Vec<ProfileElement>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserProfilesReport {
+    pub system_nixpkgs_rev: Option<String>,
+    pub profiles: Vec<UserProfile>,
+}
+
+/// Directories holding the user's profiles, newest layout first
+pub fn user_profile_dirs(home: Option<&Path>, user: Option<&str>) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(home) = home {
+        dirs.push(home.join(".local/state/nix/profiles"));
+    }
+    if let Some(user) = user {
+        dirs.push(Path::new("/nix/var/nix/profiles/per-user").join(user));
+    }
+    dirs
+}
+
+/// Whether a string looks like a full git revision
+fn is_rev(value: &str) -> bool {
+    value.len() == 40 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Extracts the revision from a locked flake reference, either a `rev`
+/// query parameter or the last path segment of `github:`-style references
+pub fn url_rev(url: &str) -> Option<String> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("rev="))
+        .filter(|rev| is_rev(rev))
+        .or_else(|| path.rsplit('/').next().filter(|rev| is_rev(rev)))
+        .map(str::to_string)
+}
+
+/// Whether an element was installed from nixpkgs itself
+fn is_nixpkgs(element: &ProfileElement) -> bool {
+    let refers_to_nixpkgs = |url: &Option<String>| {
+        url.as_deref().is_some_and(|url| {
+            url == "flake:nixpkgs" || url.starts_with("flake:nixpkgs/") || url.contains("/nixpkgs/") || url.ends_with("/nixpkgs")
+        })
+    };
+    refers_to_nixpkgs(&element.original_url) || refers_to_nixpkgs(&element.url)
+}
+
+fn parse_element(name: String, element: &Value) -> ProfileElement {
+    let string = |key: &str| element.get(key).and_then(Value::as_str).map(str::to_string);
+    let mut parsed = ProfileElement {
+        name,
+        active: element.get("active").and_then(Value::as_bool).unwrap_or(true),
+        attr_path: string("attrPath"),
+        original_url: string("originalUrl"),
+        url: string("url"),
+        priority: element.get("priority").and_then(Value::as_i64),
+        store_paths: element
+            .get("storePaths")
+            .and_then(Value::as_array)
+            .map(|paths| paths.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default(),
+        nixpkgs_rev: None,
+        outdated: None,
+    };
+    if is_nixpkgs(&parsed) {
+        parsed.nixpkgs_rev = parsed.url.as_deref().and_then(url_rev);
+    }
+    parsed
+}
+
+/// Parses `manifest.json`. Version 2 lists elements; version 3 names them.
+pub fn parse_manifest(value: &Value) -> Result<Vec<ProfileElement>, SystemAnalyzerError> {
+    let mut elements: Vec<ProfileElement> = match value.get("elements") {
+        Some(Value::Object(elements)) => elements
+            .iter()
+            .map(|(name, element)| parse_element(name.clone(), element))
+            .collect(),
+        Some(Value::Array(elements)) => elements
+            .iter()
+            .enumerate()
+            .map(|(i, element)| {
+                // Older manifests have no names; `nix profile` used the index
+                let name = element
+                    .get("attrPath")
+                    .and_then(Value::as_str)
+                    .and_then(|path| path.rsplit('.').next())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| i.to_string());
+                parse_element(name, element)
+            })
+            .collect(),
+        _ => return Err(SystemAnalyzerError::ParseError("manifest has no elements".to_string())),
+    };
+    elements.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(elements)
+}
+
+/// Revision of the nixpkgs entry of a flake registry
+pub fn registry_nixpkgs_rev(registry: &Value) -> Option<String> {
+    registry
+        .get("flakes")?
+        .as_array()?
+        .iter()
+        .find(|entry| entry.get("from").and_then(|from| from.get("id")).and_then(Value::as_str) == Some("nixpkgs"))?
+        .get("to")?
+        .get("rev")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Flags elements whose nixpkgs revision differs from the system's
+pub fn flag_outdated(elements: &mut [ProfileElement], system_rev: Option<&str>) {
+    let Some(system_rev) = system_rev else {
+        return;
+    };
+    for element in elements {
+        if let Some(rev) = &element.nixpkgs_rev {
+            element.outdated = Some(rev != system_rev);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REV: &str = "b06025f1533a1e07b6db3e75151caa155d1c7eb3";
+    const OLD_REV: &str = "057f9aecfb71c4437d2b27d3323df7f93c010b7e";
+
+    fn manifest() -> Value {
+        serde_json::json!({
+            "version": 3,
+            "elements": {
+                "hello": {
+                    "active": true,
+                    "attrPath": "legacyPackages.x86_64-linux.hello",
+                    "originalUrl": "flake:nixpkgs",
+                    "outputs": null,
+                    "priority": 5,
+                    "storePaths": ["/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12.1"],
+                    "url": format!("github:NixOS/nixpkgs/{}", OLD_REV)
+                },
+                "ripgrep": {
+                    "active": true,
+                    "attrPath": "legacyPackages.x86_64-linux.ripgrep",
+                    "originalUrl": "github:NixOS/nixpkgs/nixos-unstable",
+                    "priority": 5,
+                    "storePaths": ["/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-ripgrep-14.1.0"],
+                    "url": format!("github:NixOS/nixpkgs/{}?narHash=sha256-x", REV)
+                },
+                "tool": {
+                    "active": false,
+                    "attrPath": "packages.x86_64-linux.default",
+                    "originalUrl": "github:example/tool",
+                    "priority": 3,
+                    "storePaths": ["/nix/store/cccccccccccccccccccccccccccccccc-tool-0.1"],
+                    "url": "github:example/tool/0123456789abcdef0123456789abcdef01234567"
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_url_rev() {
+        assert_eq!(url_rev(&format!("github:NixOS/nixpkgs/{}", REV)).as_deref(), Some(REV));
+        assert_eq!(
+            url_rev(&format!("path:/nix/store/x-source?lastModified=1&narHash=sha256-x&rev={}", REV)).as_deref(),
+            Some(REV)
+        );
+        assert_eq!(url_rev("github:NixOS/nixpkgs/nixos-unstable"), None);
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let mut elements = parse_manifest(&manifest()).unwrap();
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[0].priority, Some(5));
+        assert_eq!(elements[0].nixpkgs_rev.as_deref(), Some(OLD_REV));
+        assert_eq!(elements[1].nixpkgs_rev.as_deref(), Some(REV));
+        // Other flakes do not reveal their nixpkgs from the URL
+        assert_eq!(elements[2].nixpkgs_rev, None);
+        assert!(!elements[2].active);
+
+        flag_outdated(&mut elements, Some(REV));
+        assert_eq!(elements[0].outdated, Some(true));
+        assert_eq!(elements[1].outdated, Some(false));
+        assert_eq!(elements[2].outdated, None);
+    }
+
+    #[test]
+    fn test_parse_manifest_v2() {
+        let manifest = serde_json::json!({
+            "version": 2,
+            "elements": [{
+                "active": true,
+                "attrPath": "legacyPackages.x86_64-linux.jq",
+                "originalUrl": "flake:nixpkgs",
+                "priority": 5,
+                "storePaths": ["/nix/store/dddddddddddddddddddddddddddddddd-jq-1.7"],
+                "url": format!("github:NixOS/nixpkgs/{}", REV)
+            }]
+        });
+        let elements = parse_manifest(&manifest).unwrap();
+        assert_eq!(elements[0].name, "jq");
+        assert!(parse_manifest(&serde_json::json!({"version": 1})).is_err());
+    }
+
+    #[test]
+    fn test_registry_nixpkgs_rev() {
+        let registry = serde_json::json!({
+            "version": 2,
+            "flakes": [{
+                "from": {"id": "nixpkgs", "type": "indirect"},
+                "to": {"type": "path", "path": "/nix/store/x-source", "rev": REV, "narHash": "sha256-x"}
+            }]
+        });
+        assert_eq!(registry_nixpkgs_rev(&registry).as_deref(), Some(REV));
+        assert_eq!(registry_nixpkgs_rev(&serde_json::json!({"flakes": []})), None);
+    }
+}
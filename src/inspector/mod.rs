@@ -2,10 +2,38 @@ pub mod system;
 pub mod flake;
 pub mod environment;
 pub mod validation;
+pub mod aterm;
+pub mod auth;
+pub mod build;
+pub mod cache;
+pub mod checks;
+pub mod closure;
+pub mod derivation;
+pub mod errors;
+pub mod eval;
+pub mod gc;
+pub mod home;
+pub mod licenses;
+pub mod limits;
+pub mod lock;
+pub mod logs;
+pub mod manifest;
+pub mod options;
+pub mod packages;
+pub mod policy;
+pub mod profiles;
+pub mod provenance;
+pub mod sbom;
+pub mod state;
+pub mod store;
+pub mod util;
+pub mod vulnerabilities;
+pub mod watch;
 
 use std::error::Error as StdError;
 
 pub use system::*;
+pub use closure::DependencyGraph;
 
 pub struct NixInspector {
     env_manager: environment::EnvironmentManager,
@@ -19,4 +47,12 @@ impl NixInspector {
             validator: validation::ConfigValidator::new()?,
         })
     }
-} 
\ No newline at end of file
+
+    pub fn environment(&self) -> &environment::EnvironmentManager {
+        &self.env_manager
+    }
+
+    pub fn validator(&self) -> &validation::ConfigValidator {
+        &self.validator
+    }
+}
@@ -0,0 +1,473 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::eval::nix_string;
+use super::lock::LockedInput;
+use super::system::SystemAnalyzerError;
+use super::util::default_flake;
+
+/// Results returned unless the caller asks for a different number
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// Format version of persisted indexes; bump to force a rebuild
+const INDEX_VERSION: u32 = 1;
+
+/// Module system whose options are searched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionSet {
+    #[default]
+    Nixos,
+    HomeManager,
+}
+
+impl OptionSet {
+    /// Flake input providing the modules, unless the caller names another
+    pub fn default_input(self) -> &'static str {
+        match self {
+            OptionSet::Nixos => "nixpkgs",
+            OptionSet::HomeManager => "home-manager",
+        }
+    }
+
+    /// Nix expression for the derivation holding `options.json`
+    pub fn docs_expression(self, locked_flake_url: &str, input: &str, system: &str) -> String {
+        let input = format!(
+            "(builtins.getFlake {}).inputs.{}",
+            nix_string(locked_flake_url),
+            nix_string(input)
+        );
+        match self {
+            OptionSet::Nixos => format!(
+                "(import ({}.outPath + \"/nixos/release.nix\") {{ supportedSystems = [ {} ]; }}).options",
+                input,
+                nix_string(system)
+            ),
+            OptionSet::HomeManager => format!("{}.packages.{}.docs-json", input, nix_string(system)),
+        }
+    }
+
+    /// Location of `options.json` in the output of the docs derivation
+    pub fn options_json(self) -> &'static str {
+        match self {
+            OptionSet::Nixos => "share/doc/nixos/options.json",
+            OptionSet::HomeManager => "share/doc/home-manager/options.json",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            OptionSet::Nixos => "nixos",
+            OptionSet::HomeManager => "home-manager",
+        }
+    }
+}
+
+/// Which options index a request refers to
+#[derive(Debug, Deserialize)]
+pub struct OptionSource {
+    #[serde(default = "default_flake")]
+    pub flake: String,
+    #[serde(default)]
+    pub set: OptionSet,
+    /// Flake input providing the modules
+    #[serde(default)]
+    pub input: Option<String>,
+    #[serde(default)]
+    pub system: Option<String>,
+    /// Regenerate the options even if an index exists
+    #[serde(default)]
+    pub rebuild: bool,
+}
+
+impl OptionSource {
+    pub fn input(&self) -> &str {
+        self.input.as_deref().unwrap_or(self.set.default_input())
+    }
+}
+
+/// Parameters of the `search_options` method
+#[derive(Debug, Deserialize)]
+pub struct OptionSearchParams {
+    pub query: String,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(flatten)]
+    pub source: OptionSource,
+}
+
+/// Parameters of the `get_option` method
+#[derive(Debug, Deserialize)]
+pub struct GetOptionParams {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: OptionSource,
+}
+
+/// Parameters of the `browse_options` method
+#[derive(Debug, Deserialize)]
+pub struct BrowseOptionsParams {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(flatten)]
+    pub source: OptionSource,
+}
+
+/// A module option as documented in `options.json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OptionEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub option_type: Option<String>,
+    pub default: Option<String>,
+    pub example: Option<String>,
+    pub description: Option<String>,
+    /// Files declaring the option, relative to the input
+    pub declarations: Vec<String>,
+    pub read_only: bool,
+}
+
+/// Options of one locked input
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OptionIndex {
+    pub version: u32,
+    pub set: OptionSet,
+    pub source: LockedInput,
+    /// Creation time in seconds since the epoch
+    pub created: i64,
+    pub options: Vec<OptionEntry>,
+}
+
+/// A search hit
+#[derive(Debug, Serialize)]
+pub struct OptionMatch {
+    pub score: u32,
+    #[serde(flatten)]
+    pub option: OptionEntry,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OptionSearchResult {
+    pub source: LockedInput,
+    pub total_options: usize,
+    pub matches: Vec<OptionMatch>,
+}
+
+/// Result of `get_option`; close matches are suggested when the name is unknown
+#[derive(Debug, Serialize)]
+pub struct OptionLookup {
+    pub source: LockedInput,
+    pub option: Option<OptionEntry>,
+    pub suggestions: Vec<String>,
+}
+
+/// An attribute below a browsed prefix
+#[derive(Debug, PartialEq, Serialize)]
+pub struct OptionChild {
+    pub name: String,
+    /// Set if the child is itself an option
+    pub option: Option<OptionEntry>,
+    /// Number of options below the child
+    pub options_below: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OptionBrowse {
+    pub source: LockedInput,
+    pub prefix: String,
+    pub children: Vec<OptionChild>,
+}
+
+/// Renders defaults and examples, which are either literal expressions or
+/// plain JSON values
+fn render_value(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::Object(object) if object.contains_key("_type") => {
+            object.get("text").and_then(Value::as_str).map(str::to_string)
+        }
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
+/// Parses `options.json` as written by `nixosOptionsDoc`
+pub fn parse_options_json(value: &Value) -> Result<Vec<OptionEntry>, SystemAnalyzerError> {
+    let entries = value
+        .as_object()
+        .ok_or_else(|| SystemAnalyzerError::ParseError("expected an object of options".to_string()))?;
+    let mut options: Vec<OptionEntry> = entries
+        .iter()
+        .map(|(name, entry)| OptionEntry {
+            name: name.clone(),
+            option_type: entry.get("type").and_then(Value::as_str).map(str::to_string),
+            default: render_value(entry.get("default")),
+            example: render_value(entry.get("example")),
+            // Plain text in older releases, `{ _type = "mdDoc"; text = ...; }` in newer ones
+            description: match entry.get("description") {
+                Some(Value::String(text)) => Some(text.clone()),
+                other => render_value(other),
+            },
+            declarations: entry
+                .get("declarations")
+                .and_then(Value::as_array)
+                .map(|declarations| {
+                    declarations
+                        .iter()
+                        .filter_map(|d| d.as_str().or_else(|| d.get("name").and_then(Value::as_str)))
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            read_only: entry.get("readOnly").and_then(Value::as_bool).unwrap_or_default(),
+        })
+        .collect();
+    options.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(options)
+}
+
+impl OptionIndex {
+    pub fn new(set: OptionSet, source: LockedInput, created: i64, options: Vec<OptionEntry>) -> Self {
+        Self {
+            version: INDEX_VERSION,
+            set,
+            source,
+            created,
+            options,
+        }
+    }
+
+    /// Location of the index of a locked input below `dir`
+    pub fn path(dir: &Path, set: OptionSet, source: &LockedInput) -> PathBuf {
+        dir.join("options").join(format!("{}-{}.json", set.name(), source.cache_key()))
+    }
+
+    /// Loads a persisted index, or `None` if there is none in a current format
+    pub fn load(path: &Path) -> Result<Option<Self>, SystemAnalyzerError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let index: Self = serde_json::from_slice(&fs::read(path)?)?;
+        Ok((index.version == INDEX_VERSION).then_some(index))
+    }
+
+    /// Persists the index, replacing any previous one atomically
+    pub fn save(&self, path: &Path) -> Result<(), SystemAnalyzerError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, serde_json::to_vec(self)?)?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&OptionEntry> {
+        self.options
+            .binary_search_by(|option| option.name.as_str().cmp(name))
+            .ok()
+            .map(|i| &self.options[i])
+    }
+
+    /// Full-text search over names and descriptions; every term must match
+    pub fn search(&self, query: &str, limit: usize) -> Vec<OptionMatch> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        let mut hits: Vec<(u32, &OptionEntry)> = self
+            .options
+            .iter()
+            .filter_map(|option| {
+                let name = option.name.to_lowercase();
+                let description = option.description.as_deref().unwrap_or_default().to_lowercase();
+                terms
+                    .iter()
+                    .map(|term| {
+                        if name == *term {
+                            Some(100)
+                        } else if name.ends_with(&format!(".{}", term)) {
+                            Some(60)
+                        } else if name.starts_with(term.as_str()) {
+                            Some(50)
+                        } else if name.contains(term.as_str()) {
+                            Some(30)
+                        } else if description.contains(term.as_str()) {
+                            Some(10)
+                        } else {
+                            None
+                        }
+                    })
+                    .sum::<Option<u32>>()
+                    .map(|score| (score, option))
+            })
+            .collect();
+        // Best score first, then shallower options
+        hits.sort_by(|(score_a, a), (score_b, b)| {
+            score_b
+                .cmp(score_a)
+                .then_with(|| a.name.len().cmp(&b.name.len()))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        hits.into_iter()
+            .take(limit)
+            .map(|(score, option)| OptionMatch { score, option: option.clone() })
+            .collect()
+    }
+
+    /// Options sharing the longest prefix with an unknown name
+    pub fn suggestions(&self, name: &str, limit: usize) -> Vec<String> {
+        let mut prefix = name;
+        loop {
+            let matches: Vec<String> = self
+                .options
+                .iter()
+                .filter(|option| option.name.starts_with(prefix))
+                .take(limit)
+                .map(|option| option.name.clone())
+                .collect();
+            if !matches.is_empty() || prefix.is_empty() {
+                return matches;
+            }
+            prefix = prefix.rsplit_once('.').map(|(parent, _)| parent).unwrap_or_default();
+        }
+    }
+
+    /// Direct children of an attribute path, e.g. `services.nginx`
+    pub fn browse(&self, prefix: &str) -> Vec<OptionChild> {
+        let prefix = prefix.trim_end_matches('.');
+        let mut children: BTreeMap<&str, OptionChild> = BTreeMap::new();
+        for option in &self.options {
+            let rest = if prefix.is_empty() {
+                option.name.as_str()
+            } else {
+                match option.name.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('.')) {
+                    Some(rest) => rest,
+                    None => continue,
+                }
+            };
+            let (child, below) = match rest.split_once('.') {
+                Some((child, _)) => (child, true),
+                None => (rest, false),
+            };
+            let entry = children.entry(child).or_insert_with(|| OptionChild {
+                name: child.to_string(),
+                option: None,
+                options_below: 0,
+            });
+            if below {
+                entry.options_below += 1;
+            } else {
+                entry.option = Some(option.clone());
+            }
+        }
+        children.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> OptionIndex {
+        let options = serde_json::json!({
+            "services.nginx.enable": {
+                "declarations": ["nixos/modules/services/web-servers/nginx/default.nix"],
+                "default": {"_type": "literalExpression", "text": "false"},
+                "description": "Whether to enable Nginx Web Server.",
+                "example": true,
+                "loc": ["services", "nginx", "enable"],
+                "readOnly": false,
+                "type": "boolean"
+            },
+            "services.nginx.virtualHosts.<name>.root": {
+                "declarations": [{"name": "<nixpkgs/nixos/modules/services/web-servers/nginx/vhost-options.nix>", "url": "https://example.invalid"}],
+                "default": null,
+                "description": {"_type": "mdDoc", "text": "The path of the web root directory."},
+                "type": "null or path"
+            },
+            "services.openssh.enable": {
+                "declarations": ["nixos/modules/services/networking/ssh/sshd.nix"],
+                "default": false,
+                "description": "Whether to enable the OpenSSH secure shell daemon.",
+                "type": "boolean"
+            },
+            "system.stateVersion": {
+                "declarations": ["nixos/modules/misc/version.nix"],
+                "default": {"_type": "literalExpression", "text": "\"24.05\""},
+                "readOnly": false,
+                "type": "string"
+            }
+        });
+        let source = LockedInput {
+            name: "nixpkgs".to_string(),
+            node: "nixpkgs".to_string(),
+            nar_hash: "sha256-abc".to_string(),
+            rev: None,
+            last_modified: None,
+        };
+        OptionIndex::new(OptionSet::Nixos, source, 0, parse_options_json(&options).unwrap())
+    }
+
+    #[test]
+    fn test_parse_options_json() {
+        let index = index();
+        let enable = index.get("services.nginx.enable").unwrap();
+        assert_eq!(enable.default.as_deref(), Some("false"));
+        assert_eq!(enable.example.as_deref(), Some("true"));
+        assert_eq!(enable.option_type.as_deref(), Some("boolean"));
+        let root = index.get("services.nginx.virtualHosts.<name>.root").unwrap();
+        assert_eq!(root.default, None);
+        assert_eq!(root.description.as_deref(), Some("The path of the web root directory."));
+        assert!(root.declarations[0].starts_with("<nixpkgs/"));
+        assert_eq!(index.get("system.stateVersion").unwrap().default.as_deref(), Some("\"24.05\""));
+        assert!(index.get("services.nginx").is_none());
+    }
+
+    #[test]
+    fn test_search() {
+        let index = index();
+        let names = |query: &str| -> Vec<String> {
+            index.search(query, DEFAULT_LIMIT).into_iter().map(|m| m.option.name).collect()
+        };
+        assert_eq!(names("nginx enable"), vec!["services.nginx.enable"]);
+        assert_eq!(names("enable"), vec!["services.nginx.enable", "services.openssh.enable"]);
+        assert_eq!(names("secure shell"), vec!["services.openssh.enable"]);
+        assert!(names("apache").is_empty());
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let index = index();
+        assert_eq!(
+            index.suggestions("services.nginx.enabled", DEFAULT_LIMIT),
+            vec!["services.nginx.enable", "services.nginx.virtualHosts.<name>.root"]
+        );
+    }
+
+    #[test]
+    fn test_browse() {
+        let index = index();
+        let top: Vec<(String, usize)> = index.browse("").into_iter().map(|c| (c.name, c.options_below)).collect();
+        assert_eq!(top, vec![("services".to_string(), 3), ("system".to_string(), 1)]);
+
+        let nginx = index.browse("services.nginx.");
+        assert_eq!(nginx.len(), 2);
+        assert_eq!(nginx[0].name, "enable");
+        assert!(nginx[0].option.is_some());
+        assert_eq!(nginx[1].name, "virtualHosts");
+        assert_eq!(nginx[1].options_below, 1);
+    }
+
+    #[test]
+    fn test_docs_expression() {
+        let expr = OptionSet::HomeManager.docs_expression("path:/src", "home-manager", "x86_64-linux");
+        assert_eq!(
+            expr,
+            "(builtins.getFlake \"path:/src\").inputs.\"home-manager\".packages.\"x86_64-linux\".docs-json"
+        );
+        assert!(OptionSet::Nixos.docs_expression("path:/src", "nixpkgs", "x86_64-linux").contains("/nixos/release.nix"));
+    }
+}
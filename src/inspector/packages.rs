@@ -0,0 +1,391 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::lock::LockedInput;
+use super::system::SystemAnalyzerError;
+use super::util::{default_flake, default_input};
+
+/// Results returned unless the caller asks for a different number
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// Format version of persisted indexes; bump to force a rebuild
+const INDEX_VERSION: u32 = 3;
+
+/// Parameters of the `search_packages` method
+#[derive(Debug, Deserialize)]
+pub struct PackageSearchParams {
+    pub query: String,
+    #[serde(default = "default_flake")]
+    pub flake: String,
+    /// Flake input providing nixpkgs
+    #[serde(default = "default_input")]
+    pub input: String,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Re-evaluate nixpkgs even if an index exists
+    #[serde(default)]
+    pub rebuild: bool,
+}
+
+/// A package of the index
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Package {
+    pub attr_path: String,
+    pub pname: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub license: Vec<String>,
+    /// Set when nixpkgs considers any of the licenses unfree
+    #[serde(default)]
+    pub unfree: bool,
+    /// `meta.knownVulnerabilities`, set when nixpkgs marks the package insecure
+    #[serde(default)]
+    pub known_vulnerabilities: Vec<String>,
+    pub platforms: Vec<String>,
+    pub main_program: Option<String>,
+}
+
+/// Packages of one locked nixpkgs revision
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PackageIndex {
+    pub version: u32,
+    pub source: LockedInput,
+    /// Creation time in seconds since the epoch
+    pub created: i64,
+    pub packages: Vec<Package>,
+    /// Lowercased search text per package, rebuilt on load
+    #[serde(skip)]
+    haystacks: Vec<Haystack>,
+    /// Positions of the packages of each `pname`, rebuilt on load
+    #[serde(skip)]
+    by_pname: HashMap<String, Vec<usize>>,
+}
+
+#[derive(Debug, Default)]
+struct Haystack {
+    attr_path: String,
+    pname: String,
+    main_program: String,
+    text: String,
+}
+
+/// A search hit
+#[derive(Debug, Serialize)]
+pub struct PackageMatch {
+    pub score: u32,
+    #[serde(flatten)]
+    pub package: Package,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageSearchResult {
+    pub source: LockedInput,
+    pub total_packages: usize,
+    pub matches: Vec<PackageMatch>,
+}
+
+/// Nix expression yielding the store path of a locked flake input
+pub fn input_path_expression(locked_flake_url: &str, input: &str) -> String {
+    format!(
+        "(builtins.getFlake {}).inputs.{}.outPath",
+        super::eval::nix_string(locked_flake_url),
+        super::eval::nix_string(input)
+    )
+}
+
+/// License names from the many shapes `meta.license` takes
+fn license_names(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(Value::Array(licenses)) => licenses.iter().flat_map(|l| license_names(Some(l))).collect(),
+        Some(Value::Object(license)) => ["spdxId", "shortName", "fullName"]
+            .iter()
+            .find_map(|key| license.get(*key).and_then(Value::as_str))
+            .map(|name| vec![name.to_string()])
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Whether `meta` marks a package unfree, directly or through a license
+fn is_unfree(meta: Option<&Value>) -> bool {
+    fn unfree_license(license: &Value) -> bool {
+        match license {
+            Value::String(name) => name.to_lowercase().starts_with("unfree"),
+            Value::Array(licenses) => licenses.iter().any(unfree_license),
+            Value::Object(license) => license.get("free").and_then(Value::as_bool) == Some(false),
+            _ => false,
+        }
+    }
+    meta.and_then(|m| m.get("unfree")).and_then(Value::as_bool).unwrap_or(false)
+        || meta.and_then(|m| m.get("license")).is_some_and(unfree_license)
+}
+
+/// Parses the output of `nix-env -qaP --json --meta`
+pub fn parse_nix_env(value: &Value) -> Result<Vec<Package>, SystemAnalyzerError> {
+    let entries = value
+        .as_object()
+        .ok_or_else(|| SystemAnalyzerError::ParseError("expected an object of packages".to_string()))?;
+    let mut packages: Vec<Package> = entries
+        .iter()
+        .map(|(attr_path, entry)| {
+            let meta = entry.get("meta");
+            let meta_str = |key: &str| meta.and_then(|m| m.get(key)).and_then(Value::as_str).map(str::to_string);
+            let name = entry.get("name").and_then(Value::as_str).unwrap_or(attr_path);
+            Package {
+                attr_path: attr_path.clone(),
+                pname: entry.get("pname").and_then(Value::as_str).unwrap_or(name).to_string(),
+                version: entry.get("version").and_then(Value::as_str).unwrap_or_default().to_string(),
+                description: meta_str("description"),
+                license: license_names(meta.and_then(|m| m.get("license"))),
+                unfree: is_unfree(meta),
+                known_vulnerabilities: meta
+                    .and_then(|m| m.get("knownVulnerabilities"))
+                    .and_then(Value::as_array)
+                    .map(|entries| entries.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                    .unwrap_or_default(),
+                platforms: meta
+                    .and_then(|m| m.get("platforms"))
+                    .and_then(Value::as_array)
+                    .map(|platforms| platforms.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                    .unwrap_or_default(),
+                main_program: meta_str("mainProgram"),
+            }
+        })
+        .collect();
+    packages.sort_by(|a, b| a.attr_path.cmp(&b.attr_path));
+    Ok(packages)
+}
+
+/// Whether the characters of `needle` appear in order in `haystack`
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+impl PackageIndex {
+    pub fn new(source: LockedInput, created: i64, packages: Vec<Package>) -> Self {
+        let mut index = Self {
+            version: INDEX_VERSION,
+            source,
+            created,
+            packages,
+            haystacks: Vec::new(),
+            by_pname: HashMap::new(),
+        };
+        index.prepare();
+        index
+    }
+
+    fn prepare(&mut self) {
+        self.haystacks = self
+            .packages
+            .iter()
+            .map(|p| Haystack {
+                attr_path: p.attr_path.to_lowercase(),
+                pname: p.pname.to_lowercase(),
+                main_program: p.main_program.as_deref().unwrap_or_default().to_lowercase(),
+                text: format!(
+                    "{} {} {} {}",
+                    p.attr_path,
+                    p.pname,
+                    p.main_program.as_deref().unwrap_or_default(),
+                    p.description.as_deref().unwrap_or_default()
+                )
+                .to_lowercase(),
+            })
+            .collect();
+        self.by_pname.clear();
+        for (i, package) in self.packages.iter().enumerate() {
+            self.by_pname.entry(package.pname.clone()).or_default().push(i);
+        }
+    }
+
+    /// Looks up a package by name, preferring the given version. Other
+    /// versions of the same package stand in when it is not in the index.
+    pub fn find(&self, pname: &str, version: Option<&str>) -> Option<&Package> {
+        let candidates = self.by_pname.get(pname)?;
+        candidates
+            .iter()
+            .map(|i| &self.packages[*i])
+            .find(|package| Some(package.version.as_str()) == version)
+            .or_else(|| candidates.first().map(|i| &self.packages[*i]))
+    }
+
+    /// Location of the index of a locked input below `dir`
+    pub fn path(dir: &Path, source: &LockedInput) -> PathBuf {
+        dir.join("packages").join(format!("{}.json", source.cache_key()))
+    }
+
+    /// Loads a persisted index, or `None` if there is none in a current format
+    pub fn load(path: &Path) -> Result<Option<Self>, SystemAnalyzerError> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut index: Self = serde_json::from_slice(&fs::read(path)?)?;
+        if index.version != INDEX_VERSION {
+            return Ok(None);
+        }
+        index.prepare();
+        Ok(Some(index))
+    }
+
+    /// Persists the index, replacing any previous one atomically
+    pub fn save(&self, path: &Path) -> Result<(), SystemAnalyzerError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, serde_json::to_vec(self)?)?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// Scores how well a package matches every term of the query; `None`
+    /// if a term matches nowhere
+    fn score(haystack: &Haystack, terms: &[String]) -> Option<u32> {
+        let mut total = 0;
+        for term in terms {
+            let score = if haystack.pname == *term || haystack.attr_path == *term {
+                100
+            } else if haystack.main_program == *term {
+                80
+            } else if haystack.pname.starts_with(term.as_str()) || haystack.attr_path.starts_with(term.as_str()) {
+                60
+            } else if haystack.attr_path.contains(term.as_str()) {
+                40
+            } else if haystack.text.contains(term.as_str()) {
+                15
+            } else if term.len() >= 3 && is_subsequence(term, &haystack.pname) {
+                5
+            } else {
+                return None;
+            };
+            total += score;
+        }
+        Some(total)
+    }
+
+    /// Full-text search with fuzzy matching on package names
+    pub fn search(&self, query: &str, limit: usize) -> Vec<PackageMatch> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        let mut hits: Vec<(u32, usize)> = self
+            .haystacks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, haystack)| Self::score(haystack, &terms).map(|score| (score, i)))
+            .collect();
+        // Best score first, then shorter attribute paths, which tend to be the canonical ones
+        hits.sort_by(|(score_a, a), (score_b, b)| {
+            score_b
+                .cmp(score_a)
+                .then_with(|| self.packages[*a].attr_path.len().cmp(&self.packages[*b].attr_path.len()))
+                .then_with(|| self.packages[*a].attr_path.cmp(&self.packages[*b].attr_path))
+        });
+        hits.into_iter()
+            .take(limit)
+            .map(|(score, i)| PackageMatch {
+                score,
+                package: self.packages[i].clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index() -> PackageIndex {
+        let nix_env = serde_json::json!({
+            "hello": {
+                "name": "hello-2.12.1", "pname": "hello", "version": "2.12.1",
+                "meta": {
+                    "description": "Program that produces a familiar, friendly greeting",
+                    "license": {"spdxId": "GPL-3.0-or-later", "shortName": "gpl3Plus"},
+                    "platforms": ["x86_64-linux", "aarch64-darwin"],
+                    "mainProgram": "hello"
+                }
+            },
+            "ripgrep": {
+                "name": "ripgrep-14.1.0", "pname": "ripgrep", "version": "14.1.0",
+                "meta": {
+                    "description": "Utility that combines the usability of The Silver Searcher with the raw speed of grep",
+                    "license": [{"spdxId": "MIT"}, {"spdxId": "Unlicense"}],
+                    "mainProgram": "rg"
+                }
+            },
+            "python3Packages.hello-world": {
+                "name": "hello-world-0.1", "pname": "hello-world", "version": "0.1",
+                "meta": {"license": "unfree", "knownVulnerabilities": ["CVE-2024-0001"]}
+            }
+        });
+        let source = LockedInput {
+            name: "nixpkgs".to_string(),
+            node: "nixpkgs".to_string(),
+            nar_hash: "sha256-abc".to_string(),
+            rev: Some("aaaa".to_string()),
+            last_modified: None,
+        };
+        PackageIndex::new(source, 0, parse_nix_env(&nix_env).unwrap())
+    }
+
+    #[test]
+    fn test_parse_nix_env() {
+        let index = index();
+        assert_eq!(index.packages.len(), 3);
+        let hello = &index.packages[0];
+        assert_eq!(hello.attr_path, "hello");
+        assert_eq!(hello.license, vec!["GPL-3.0-or-later"]);
+        assert_eq!(hello.platforms.len(), 2);
+        assert_eq!(index.packages[2].license, vec!["MIT", "Unlicense"]);
+        assert_eq!(index.packages[1].license, vec!["unfree"]);
+        assert!(index.packages[1].unfree);
+        assert!(!hello.unfree);
+        assert_eq!(index.packages[1].known_vulnerabilities, ["CVE-2024-0001"]);
+        assert!(hello.known_vulnerabilities.is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let index = index();
+        let names = |query: &str| -> Vec<String> {
+            index.search(query, DEFAULT_LIMIT).into_iter().map(|m| m.package.attr_path).collect()
+        };
+        assert_eq!(names("hello"), vec!["hello", "python3Packages.hello-world"]);
+        assert_eq!(names("silver searcher"), vec!["ripgrep"]);
+        // Fuzzy match on the package name
+        assert_eq!(names("rpgrp"), vec!["ripgrep"]);
+        assert!(names("nonexistent").is_empty());
+        assert!(names("  ").is_empty());
+        assert_eq!(index.search("hello", 1).len(), 1);
+    }
+
+    #[test]
+    fn test_find() {
+        let index = index();
+        assert_eq!(index.find("ripgrep", Some("14.1.0")).unwrap().attr_path, "ripgrep");
+        assert_eq!(index.find("ripgrep", Some("13.0.0")).unwrap().version, "14.1.0");
+        assert!(index.find("ripgrep-all", None).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let index = index();
+        let path = PackageIndex::path(dir, &index.source);
+        assert!(PackageIndex::load(&path).unwrap().is_none());
+
+        index.save(&path).unwrap();
+        let loaded = PackageIndex::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.packages, index.packages);
+        assert_eq!(loaded.search("rg", DEFAULT_LIMIT)[0].package.attr_path, "ripgrep");
+    }
+}
@@ -0,0 +1,392 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use super::flake::local_flake_dir;
+use super::system::SystemAnalyzerError;
+
+/// Default access policy file in the state directory
+pub const ACCESS_POLICY_FILE: &str = "access-policy.toml";
+
+/// Tools that build, delete or otherwise change state
+pub const MUTATING_TOOLS: &[&str] = &["nix_build", "flake_check", "collect_garbage", "invalidate_cache"];
+
+/// Parameters naming a flake or installable
+const INSTALLABLE_PARAMS: &[&str] = &["installable", "flake", "metadata_flake", "left", "right"];
+
+/// Parameters naming a file or directory to read
+const PATH_PARAMS: &[&str] = &["profile", "database", "whitelist", "policy", "uri"];
+
+/// Tools that use the flake in the working directory unless told otherwise
+const DEFAULT_FLAKE_TOOLS: &[&str] = &[
+    "analyze_dev_flake",
+    "generate_flake_graph",
+    "get_dependency_graph",
+    "flake_check",
+    "search_packages",
+    "search_options",
+    "get_option",
+    "browse_options",
+    "explain_option",
+    "watch_flake",
+    "unwatch_flake",
+];
+
+/// Why a call was refused
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenialReason {
+    /// The tool is not enabled
+    ToolDisabled,
+    /// The tool changes state and the policy is read-only
+    Mutating,
+    /// A local flake outside the allowed flake roots
+    FlakeOutsideRoots,
+    /// A flake that is not on the local file system
+    RemoteFlake,
+    /// A store path while store access is disabled
+    StoreAccess,
+    /// A file outside the allowed paths
+    PathNotAllowed,
+}
+
+/// A call refused by the access policy
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessDenial {
+    pub tool: String,
+    pub reason: DenialReason,
+    /// Parameter that was refused, if any
+    pub parameter: Option<String>,
+    pub value: Option<String>,
+}
+
+impl fmt::Display for AccessDenial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match (&self.parameter, &self.value) {
+            (Some(parameter), Some(value)) => format!("{} {:?}", parameter, value),
+            _ => String::new(),
+        };
+        match self.reason {
+            DenialReason::ToolDisabled => write!(f, "tool {} is not enabled", self.tool),
+            DenialReason::Mutating => write!(f, "tool {} changes state and the server is read-only", self.tool),
+            DenialReason::FlakeOutsideRoots => write!(f, "{} is outside the allowed flake roots", what),
+            DenialReason::RemoteFlake => write!(f, "{} is not a local flake", what),
+            DenialReason::StoreAccess => write!(f, "{} is a store path and store access is disabled", what),
+            DenialReason::PathNotAllowed => write!(f, "{} is outside the allowed paths", what),
+        }
+    }
+}
+
+/// What tools may run and what they may touch. Without a policy file
+/// everything is allowed; a policy file starts from read-only access.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Tools that may run; all of them when unset
+    pub tools: Option<Vec<String>>,
+    /// Tools that may not run whatever `tools` says
+    pub deny_tools: Vec<String>,
+    /// Whether tools that build or delete may run
+    pub allow_mutating: bool,
+    /// Whether `collect_garbage` may run; off even without a policy file,
+    /// and only effective together with `allow_mutating`
+    pub allow_collect_garbage: bool,
+    /// Directories local flakes must be in; anywhere when unset
+    pub flake_roots: Option<Vec<PathBuf>>,
+    /// Whether flakes such as `github:` or registry references may be used
+    pub remote_flakes: bool,
+    /// Whether store paths may be inspected directly
+    pub store: bool,
+    /// Directories other files such as profiles and advisory databases must
+    /// be in, besides the flake roots; anywhere when unset
+    pub paths: Option<Vec<PathBuf>>,
+    #[serde(skip)]
+    store_dir: PathBuf,
+}
+
+impl Default for AccessPolicy {
+    fn default() -> Self {
+        Self {
+            tools: None,
+            deny_tools: Vec::new(),
+            allow_mutating: false,
+            allow_collect_garbage: false,
+            flake_roots: None,
+            remote_flakes: true,
+            store: true,
+            paths: None,
+            store_dir: PathBuf::from("/nix/store"),
+        }
+    }
+}
+
+/// Absolute form of a path without following symlinks that do not exist
+fn absolute(path: &Path, base: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(base.join(path)) {
+        return path;
+    }
+    let mut absolute = PathBuf::new();
+    for component in base.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            Component::CurDir => {}
+            other => absolute.push(other),
+        }
+    }
+    absolute
+}
+
+impl AccessPolicy {
+    /// A policy allowing everything but garbage collection, used when no
+    /// policy file exists
+    pub fn unrestricted() -> Self {
+        Self {
+            allow_mutating: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, SystemAnalyzerError> {
+        toml::from_str(contents).map_err(|e| SystemAnalyzerError::ParseError(format!("invalid access policy: {}", e)))
+    }
+
+    /// Loads a policy file. Relative roots are taken relative to the file.
+    pub fn load(path: &Path) -> Result<Self, SystemAnalyzerError> {
+        let mut policy = Self::parse(&fs::read_to_string(path)?)?;
+        policy.resolve_dirs(path.parent().unwrap_or(Path::new("/")))?;
+        Ok(policy)
+    }
+
+    /// Makes relative flake roots and paths relative to `base`
+    pub(crate) fn resolve_dirs(&mut self, base: &Path) -> Result<(), SystemAnalyzerError> {
+        let base = absolute(base, &std::env::current_dir()?);
+        for dirs in [&mut self.flake_roots, &mut self.paths].into_iter().flatten() {
+            for dir in dirs.iter_mut() {
+                *dir = absolute(dir, &base);
+            }
+        }
+        Ok(())
+    }
+
+    /// Store directory store paths are recognized by
+    pub fn with_store_dir(mut self, store_dir: &str) -> Self {
+        self.store_dir = PathBuf::from(store_dir);
+        self
+    }
+
+    fn is_restricted(&self) -> bool {
+        self.tools.is_some()
+            || !self.deny_tools.is_empty()
+            || !self.allow_mutating
+            || !self.allow_collect_garbage
+            || self.flake_roots.is_some()
+            || !self.remote_flakes
+            || !self.store
+            || self.paths.is_some()
+    }
+
+    /// Whether a tool may run, before looking at its parameters
+    pub fn check_tool(&self, tool: &str, params: Option<&Value>) -> Result<(), AccessDenial> {
+        let deny = |reason| AccessDenial { tool: tool.to_string(), reason, parameter: None, value: None };
+        let enabled = self.tools.as_ref().is_none_or(|tools| tools.iter().any(|t| t == tool));
+        let opted_out = tool == "collect_garbage" && !self.allow_collect_garbage;
+        if !enabled || opted_out || self.deny_tools.iter().any(|t| t == tool) {
+            return Err(deny(DenialReason::ToolDisabled));
+        }
+        // Building a closure for its SBOM builds like `nix_build`. The option
+        // tools also build, but only the documentation of the pinned modules
+        // their index is made from; read-only policies allow that.
+        let builds = tool == "export_sbom" && params.and_then(|p| p.get("build_closure")).and_then(Value::as_bool) == Some(true);
+        if !self.allow_mutating && (MUTATING_TOOLS.contains(&tool) || builds) {
+            return Err(deny(DenialReason::Mutating));
+        }
+        Ok(())
+    }
+
+    fn is_store_path(&self, path: &Path) -> bool {
+        path.starts_with(&self.store_dir)
+    }
+
+    fn under(dirs: &[PathBuf], path: &Path) -> bool {
+        dirs.iter().any(|dir| path.starts_with(dir))
+    }
+
+    /// Whether a flake reference or installable may be used, with relative
+    /// paths resolved against `base`
+    pub fn check_installable(&self, installable: &str, base: &Path) -> Result<(), DenialReason> {
+        let flake = if installable.starts_with('#') || installable.is_empty() { "." } else { installable };
+        let Some(dir) = local_flake_dir(flake, base) else {
+            return if self.remote_flakes { Ok(()) } else { Err(DenialReason::RemoteFlake) };
+        };
+        let dir = absolute(&dir, base);
+        if self.is_store_path(&dir) {
+            return if self.store { Ok(()) } else { Err(DenialReason::StoreAccess) };
+        }
+        match &self.flake_roots {
+            Some(roots) if !Self::under(roots, &dir) => Err(DenialReason::FlakeOutsideRoots),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a file outside flakes, such as a profile, may be read
+    pub fn check_path(&self, path: &str, base: &Path) -> Result<(), DenialReason> {
+        let path = absolute(Path::new(path.strip_prefix("file://").unwrap_or(path)), base);
+        if self.is_store_path(&path) {
+            return if self.store { Ok(()) } else { Err(DenialReason::StoreAccess) };
+        }
+        let Some(paths) = &self.paths else {
+            return Ok(());
+        };
+        if Self::under(paths, &path) || self.flake_roots.as_ref().is_some_and(|roots| Self::under(roots, &path)) {
+            Ok(())
+        } else {
+            Err(DenialReason::PathNotAllowed)
+        }
+    }
+
+    /// Checks a tool call and every flake, store path and file it names
+    pub fn check(&self, tool: &str, params: Option<&Value>, base: &Path) -> Result<(), AccessDenial> {
+        if !self.is_restricted() {
+            return Ok(());
+        }
+        // Resources and the caller's identity are part of the protocol
+        // rather than tools
+        if !tool.starts_with("resources/") && tool != "whoami" {
+            self.check_tool(tool, params)?;
+        }
+        let deny = |parameter: &str, value: &str, reason| AccessDenial {
+            tool: tool.to_string(),
+            reason,
+            parameter: Some(parameter.to_string()),
+            value: Some(value.to_string()),
+        };
+        let param = |name: &str| params.and_then(|p| p.get(name)).and_then(Value::as_str);
+
+        if DEFAULT_FLAKE_TOOLS.contains(&tool) && param("flake").is_none() && param("installable").is_none() {
+            self.check_installable(".", base).map_err(|reason| deny("flake", ".", reason))?;
+        }
+        for name in INSTALLABLE_PARAMS {
+            if let Some(value) = param(name) {
+                self.check_installable(value, base).map_err(|reason| deny(name, value, reason))?;
+            }
+        }
+        for name in PATH_PARAMS {
+            if let Some(value) = param(name) {
+                self.check_path(value, base).map_err(|reason| deny(name, value, reason))?;
+            }
+        }
+        // A dependency is named by package name or by store path
+        if let Some(target) = param("target").filter(|target| target.starts_with('/')) {
+            self.check_path(target, base).map_err(|reason| deny("target", target, reason))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(contents: &str) -> AccessPolicy {
+        AccessPolicy::parse(contents).unwrap()
+    }
+
+    #[test]
+    fn test_unrestricted() {
+        let policy = AccessPolicy::unrestricted();
+        let params = json!({ "installable": "github:NixOS/nixpkgs#hello", "profile": "/etc/shadow" });
+        assert!(policy.check("nix_build", Some(&params), Path::new("/")).is_ok());
+        // Deleting garbage is opt-in even without a policy
+        let gc = json!({ "plan_id": "0123456789abcdef" });
+        let denial = policy.check("collect_garbage", Some(&gc), Path::new("/")).unwrap_err();
+        assert_eq!(denial.reason, DenialReason::ToolDisabled);
+
+        let opted_in = AccessPolicy::parse("allow_mutating = true\nallow_collect_garbage = true").unwrap();
+        assert!(opted_in.check("collect_garbage", Some(&gc), Path::new("/")).is_ok());
+        let read_only = AccessPolicy::parse("allow_collect_garbage = true").unwrap();
+        assert_eq!(read_only.check("collect_garbage", Some(&gc), Path::new("/")).unwrap_err().reason, DenialReason::Mutating);
+    }
+
+    #[test]
+    fn test_tools() {
+        let read_only = policy("");
+        assert!(read_only.check("get_system_info", None, Path::new("/")).is_ok());
+        let denial = read_only.check("nix_build", Some(&json!({ "installable": ".#default" })), Path::new("/")).unwrap_err();
+        assert_eq!(denial.reason, DenialReason::Mutating);
+        assert_eq!(denial.to_string(), "tool nix_build changes state and the server is read-only");
+        let sbom = json!({ "installable": "nixpkgs#hello", "build_closure": true });
+        assert_eq!(read_only.check("export_sbom", Some(&sbom), Path::new("/")).unwrap_err().reason, DenialReason::Mutating);
+
+        let listed = policy("tools = [\"eval\", \"nix_build\"]\ndeny_tools = [\"eval\"]\nallow_mutating = true");
+        assert!(listed.check("nix_build", None, Path::new("/")).is_ok());
+        assert_eq!(listed.check("eval", None, Path::new("/")).unwrap_err().reason, DenialReason::ToolDisabled);
+        assert_eq!(listed.check("search_options", None, Path::new("/")).unwrap_err().reason, DenialReason::ToolDisabled);
+
+        assert!(AccessPolicy::parse("allow_build = true").is_err());
+    }
+
+    #[test]
+    fn test_flakes() {
+        let policy = policy("flake_roots = [\"/home/alice/src\"]\nremote_flakes = false\nstore = false");
+        let check = |tool: &str, params: Value, base: &str| policy.check(tool, Some(&params), Path::new(base));
+
+        assert!(check("analyze_closure_size", json!({ "installable": ".#default" }), "/home/alice/src/app").is_ok());
+        assert!(check("analyze_closure_size", json!({ "installable": "path:/home/alice/src/app#x" }), "/").is_ok());
+        let denial = check("analyze_closure_size", json!({ "installable": "../../bob#x" }), "/home/alice/src/app").unwrap_err();
+        assert_eq!(denial.reason, DenialReason::FlakeOutsideRoots);
+        assert_eq!(denial.parameter.as_deref(), Some("installable"));
+        // Tools falling back to the working directory are checked too
+        let denial = check("analyze_dev_flake", Value::Null, "/tmp").unwrap_err();
+        assert_eq!((denial.reason, denial.value.as_deref()), (DenialReason::FlakeOutsideRoots, Some(".")));
+
+        let remote = check("export_sbom", json!({ "installable": "nixpkgs#hello" }), "/").unwrap_err();
+        assert_eq!(remote.reason, DenialReason::RemoteFlake);
+        let store = check("get_dependency_graph", json!({ "installable": "/nix/store/abc-hello" }), "/").unwrap_err();
+        assert_eq!(store.reason, DenialReason::StoreAccess);
+        let target = check("why_depends", json!({ "installable": ".", "target": "/nix/store/abc-glibc" }), "/home/alice/src").unwrap_err();
+        assert_eq!(target.reason, DenialReason::StoreAccess);
+        assert!(check("why_depends", json!({ "installable": ".", "target": "glibc" }), "/home/alice/src").is_ok());
+        // Option tools default to the working directory's flake
+        assert_eq!(check("search_options", json!({ "query": "nginx" }), "/tmp").unwrap_err().reason, DenialReason::FlakeOutsideRoots);
+        assert!(check("get_option", json!({ "name": "networking.hostName" }), "/home/alice/src").is_ok());
+    }
+
+    #[test]
+    fn test_file_schemes() {
+        let policy = policy("flake_roots = [\"/srv/flakes\"]");
+        let check = |installable: &str| policy.check_installable(installable, Path::new("/"));
+        assert!(check("git+file:///srv/flakes/app?ref=main#default").is_ok());
+        assert!(check("tarball+file:///srv/flakes/app.tar.gz").is_ok());
+        for outside in ["git+file:/home/bob/app#x", "file:///home/bob/app.tar.gz", "tarball+file://../home/bob/app.tar.gz", "path:/home/bob/app"] {
+            assert_eq!(check(outside), Err(DenialReason::FlakeOutsideRoots), "{}", outside);
+        }
+    }
+
+    #[test]
+    fn test_paths() {
+        let policy = policy("paths = [\"/nix/var/nix/profiles\"]\nflake_roots = [\"/etc/nixos\"]");
+        let check = |params: Value| policy.check("list_home_generations", Some(&params), Path::new("/"));
+        assert!(check(json!({ "profile": "/nix/var/nix/profiles/per-user/alice/home-manager" })).is_ok());
+        assert!(check(json!({ "uri": "file:///etc/nixos/flake.nix" })).is_ok());
+        assert_eq!(check(json!({ "profile": "/home/alice/.local/state/nix/profiles/home-manager" })).unwrap_err().reason, DenialReason::PathNotAllowed);
+        assert_eq!(check(json!({ "profile": "/nix/var/nix/profiles/../../../etc" })).unwrap_err().reason, DenialReason::PathNotAllowed);
+    }
+
+    #[test]
+    fn test_load_relative_roots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("flakes")).unwrap();
+        let file = dir.join(ACCESS_POLICY_FILE);
+        fs::write(&file, "flake_roots = [\"flakes\"]").unwrap();
+        let policy = AccessPolicy::load(&file).unwrap();
+        let root = fs::canonicalize(dir.join("flakes")).unwrap();
+        assert_eq!(policy.flake_roots, Some(vec![root.clone()]));
+        assert!(policy.check_installable(&format!("{}#x", root.join("app").display()), Path::new("/")).is_ok());
+    }
+}
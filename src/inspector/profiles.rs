@@ -0,0 +1,117 @@
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// System-wide profile directory
+pub const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+
+/// A single generation of a profile, e.g. `system-42-link`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Generation {
+    pub number: u64,
+    pub link: String,
+    pub target: String,
+    /// Creation time of the generation link in seconds since the epoch
+    pub created: i64,
+    pub current: bool,
+}
+
+/// Parses the generation number out of a `<profile>-<number>-link` name
+pub fn generation_number(profile_name: &str, link_name: &str) -> Option<u64> {
+    link_name
+        .strip_prefix(profile_name)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+/// Lists the generations of a profile, oldest first. `profile` is the
+/// profile symlink itself, e.g. `/nix/var/nix/profiles/system`.
+pub fn list_generations(profile: &Path) -> io::Result<Vec<Generation>> {
+    let (Some(dir), Some(name)) = (profile.parent(), profile.file_name().and_then(|n| n.to_str())) else {
+        return Ok(Vec::new());
+    };
+    let current = fs::read_link(profile).ok();
+
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let link_name = entry.file_name();
+        let Some(number) = link_name.to_str().and_then(|n| generation_number(name, n)) else {
+            continue;
+        };
+        let link = entry.path();
+        let metadata = fs::symlink_metadata(&link)?;
+        let target = fs::read_link(&link)?;
+        let is_current = current.as_deref().is_some_and(|c| {
+            c == Path::new(&link_name) || c == link.as_path()
+        });
+        generations.push(Generation {
+            number,
+            link: link.to_string_lossy().to_string(),
+            target: target.to_string_lossy().to_string(),
+            created: metadata.mtime(),
+            current: is_current,
+        });
+    }
+
+    generations.sort_by_key(|generation| generation.number);
+    Ok(generations)
+}
+
+/// Finds the profile symlinks in a directory, i.e. the entries that have
+/// at least one `<name>-<number>-link` sibling.
+pub fn find_profiles(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut names = Vec::new();
+    let mut links = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with("-link") {
+            links.push(name);
+        } else if entry.file_type()?.is_symlink() {
+            names.push(name);
+        }
+    }
+
+    let mut profiles: Vec<PathBuf> = names
+        .into_iter()
+        .filter(|name| links.iter().any(|link| generation_number(name, link).is_some()))
+        .map(|name| dir.join(name))
+        .collect();
+    profiles.sort();
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_generation_number() {
+        assert_eq!(generation_number("system", "system-42-link"), Some(42));
+        assert_eq!(generation_number("home-manager", "home-manager-7-link"), Some(7));
+        assert_eq!(generation_number("system", "system-profiles"), None);
+        assert_eq!(generation_number("system", "system-x-link"), None);
+    }
+
+    #[test]
+    fn test_list_generations() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        symlink("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos-system", dir.join("system-1-link")).unwrap();
+        symlink("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos-system", dir.join("system-2-link")).unwrap();
+        symlink("system-2-link", dir.join("system")).unwrap();
+
+        let generations = list_generations(&dir.join("system")).unwrap();
+        assert_eq!(generations.len(), 2);
+        assert_eq!(generations[0].number, 1);
+        assert!(!generations[0].current);
+        assert!(generations[1].current);
+        assert_eq!(find_profiles(dir).unwrap(), vec![dir.join("system")]);
+    }
+}
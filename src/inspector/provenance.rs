@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::eval::nix_string;
+use super::system::SystemAnalyzerError;
+use super::util::default_flake;
+
+/// Priority of definitions without `mkOverride`
+pub const DEFAULT_PRIORITY: i64 = 100;
+
+/// Priority of option defaults
+pub const OPTION_DEFAULT_PRIORITY: i64 = 1500;
+
+/// Parameters of the `explain_option` method
+#[derive(Debug, Deserialize)]
+pub struct ExplainOptionParams {
+    #[serde(default = "default_flake")]
+    pub flake: String,
+    /// Name in `nixosConfigurations`
+    pub host: String,
+    /// Option path, e.g. `services.nginx.enable` or `users.users."alice".shell`
+    pub option: String,
+}
+
+/// A definition that contributes to the final value. All of them share
+/// the option's highest priority.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OptionDefinition {
+    /// Position in merge order, starting at 0
+    pub order: usize,
+    pub file: String,
+    pub value: Value,
+}
+
+/// Where the value of an option comes from
+#[derive(Debug, Serialize)]
+pub struct OptionExplanation {
+    pub host: String,
+    pub option: String,
+    pub value: Value,
+    #[serde(rename = "type")]
+    pub option_type: Option<String>,
+    pub declarations: Vec<String>,
+    pub is_defined: bool,
+    pub highest_priority: i64,
+    pub priority_name: String,
+    /// Definitions at the highest priority, in merge order
+    pub definitions: Vec<OptionDefinition>,
+    /// Definitions at a lower priority, including the option default, are
+    /// discarded by the module system before they can be inspected; this is
+    /// set when some may exist
+    pub lower_priority_definitions_hidden: bool,
+}
+
+/// Splits an option path into attribute names, honoring quoted names
+pub fn option_path(option: &str) -> Result<Vec<String>, SystemAnalyzerError> {
+    let mut path = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in option.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => path.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    path.push(current);
+    if quoted || path.iter().any(String::is_empty) {
+        return Err(SystemAnalyzerError::ParseError(format!("invalid option path: {}", option)));
+    }
+    Ok(path)
+}
+
+/// Name of the `lib.mkOverride` helper producing a priority
+pub fn priority_name(priority: i64) -> String {
+    match priority {
+        10 => "mkVMOverride".to_string(),
+        50 => "mkForce".to_string(),
+        60 => "mkImageMediaOverride".to_string(),
+        DEFAULT_PRIORITY => "default".to_string(),
+        1000 => "mkDefault".to_string(),
+        OPTION_DEFAULT_PRIORITY => "mkOptionDefault".to_string(),
+        other => format!("mkOverride {}", other),
+    }
+}
+
+/// Nix expression describing an option of a NixOS configuration as JSON.
+/// Values are made serializable: derivations and functions become
+/// placeholders, and deep values are cut off.
+pub fn explain_expression(locked_flake_url: &str, host: &str, path: &[String]) -> String {
+    let path = path.iter().map(|name| nix_string(name)).collect::<Vec<_>>().join(" ");
+    format!(
+        r#"let
+  flake = builtins.getFlake {url};
+  system = flake.nixosConfigurations.{host};
+  lib = system.pkgs.lib;
+  option = lib.getAttrFromPath [ {path} ] system.options;
+  sanitize = depth: value:
+    if depth > 8 then "<...>"
+    else if lib.isDerivation value then "<derivation ${{value.name or "?"}}>"
+    else if builtins.isFunction value then "<function>"
+    else if builtins.isPath value then toString value
+    else if builtins.isAttrs value then lib.mapAttrs (_: sanitize (depth + 1)) value
+    else if builtins.isList value then map (sanitize (depth + 1)) value
+    else value;
+  safe = value:
+    let
+      sanitized = sanitize 0 value;
+      result = builtins.tryEval (builtins.deepSeq sanitized sanitized);
+    in if result.success then result.value else "<error>";
+in {{
+  value = if option.isDefined then safe option.value else null;
+  type = option.type.description or null;
+  declarations = map toString (option.declarations or [ ]);
+  isDefined = option.isDefined;
+  highestPrio = option.highestPrio or {default};
+  definitions = map (def: {{ file = toString def.file; value = safe def.value; }}) (option.definitionsWithLocations or [ ]);
+}}"#,
+        url = nix_string(locked_flake_url),
+        host = nix_string(host),
+        path = path,
+        default = OPTION_DEFAULT_PRIORITY,
+    )
+}
+
+/// Builds the explanation from the output of `explain_expression`
+pub fn parse_explanation(host: &str, option: &str, value: &Value) -> Result<OptionExplanation, SystemAnalyzerError> {
+    let highest_priority = value
+        .get("highestPrio")
+        .and_then(Value::as_i64)
+        .ok_or_else(|| SystemAnalyzerError::ParseError("missing highestPrio".to_string()))?;
+    let strings = |key: &str| -> Vec<String> {
+        value
+            .get(key)
+            .and_then(Value::as_array)
+            .map(|items| items.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let definitions = value
+        .get("definitions")
+        .and_then(Value::as_array)
+        .map(|definitions| {
+            definitions
+                .iter()
+                .enumerate()
+                .map(|(order, definition)| OptionDefinition {
+                    order,
+                    file: definition.get("file").and_then(Value::as_str).unwrap_or_default().to_string(),
+                    value: definition.get("value").cloned().unwrap_or(Value::Null),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(OptionExplanation {
+        host: host.to_string(),
+        option: option.to_string(),
+        value: value.get("value").cloned().unwrap_or(Value::Null),
+        option_type: value.get("type").and_then(Value::as_str).map(str::to_string),
+        declarations: strings("declarations"),
+        is_defined: value.get("isDefined").and_then(Value::as_bool).unwrap_or_default(),
+        highest_priority,
+        priority_name: priority_name(highest_priority),
+        definitions,
+        // Anything with a priority above (numerically below) the option default may shadow others
+        lower_priority_definitions_hidden: highest_priority < OPTION_DEFAULT_PRIORITY,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_option_path() {
+        assert_eq!(option_path("services.nginx.enable").unwrap(), vec!["services", "nginx", "enable"]);
+        assert_eq!(
+            option_path("users.users.\"alice.b\".shell").unwrap(),
+            vec!["users", "users", "alice.b", "shell"]
+        );
+        assert!(option_path("services..enable").is_err());
+        assert!(option_path("users.\"open").is_err());
+    }
+
+    #[test]
+    fn test_priority_name() {
+        assert_eq!(priority_name(50), "mkForce");
+        assert_eq!(priority_name(1000), "mkDefault");
+        assert_eq!(priority_name(100), "default");
+        assert_eq!(priority_name(900), "mkOverride 900");
+    }
+
+    #[test]
+    fn test_explain_expression() {
+        let path = option_path("networking.hostName").unwrap();
+        let expr = explain_expression("path:/src", "web-1", &path);
+        assert!(expr.contains("flake.nixosConfigurations.\"web-1\""));
+        assert!(expr.contains("lib.getAttrFromPath [ \"networking\" \"hostName\" ]"));
+        assert!(expr.contains("${value.name or \"?\"}"));
+    }
+
+    #[test]
+    fn test_parse_explanation() {
+        let output = serde_json::json!({
+            "value": "web-1",
+            "type": "string",
+            "declarations": ["/nix/store/aaaa-source/nixos/modules/tasks/network-interfaces.nix"],
+            "isDefined": true,
+            "highestPrio": 50,
+            "definitions": [
+                {"file": "/nix/store/bbbb-source/hosts/web-1.nix", "value": "web-1"}
+            ]
+        });
+        let explanation = parse_explanation("web-1", "networking.hostName", &output).unwrap();
+        assert_eq!((explanation.highest_priority, explanation.priority_name.as_str()), (50, "mkForce"));
+        assert_eq!(explanation.definitions.len(), 1);
+        assert_eq!(explanation.definitions[0].file, "/nix/store/bbbb-source/hosts/web-1.nix");
+        assert!(explanation.lower_priority_definitions_hidden);
+
+        let undefined = serde_json::json!({"value": null, "isDefined": false, "highestPrio": 1500, "definitions": []});
+        let explanation = parse_explanation("web-1", "services.foo.bar", &undefined).unwrap();
+        assert!(!explanation.is_defined);
+        assert!(!explanation.lower_priority_definitions_hidden);
+    }
+}
@@ -0,0 +1,430 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use super::closure::PathInfo;
+use super::store::{parse_drv_name, store_path_hash, store_path_name};
+use super::util::default_input;
+
+/// Alphabet of Nix's base-32 hash encoding
+const NIX_BASE32: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Parameters of the `export_sbom` method
+#[derive(Debug, Deserialize)]
+pub struct SbomParams {
+    pub installable: String,
+    #[serde(default)]
+    pub format: SbomFormat,
+    /// Describe the derivations needed to build the installable rather than
+    /// its runtime closure
+    #[serde(default)]
+    pub build_closure: bool,
+    /// Flake whose nixpkgs supplies license metadata, the flake of the
+    /// installable by default
+    #[serde(default)]
+    pub metadata_flake: Option<String>,
+    #[serde(default = "default_input")]
+    pub input: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+pub enum SbomFormat {
+    /// CycloneDX 1.5 JSON
+    #[default]
+    #[serde(rename = "cyclonedx")]
+    CycloneDx,
+    /// SPDX 2.3 JSON
+    #[serde(rename = "spdx")]
+    Spdx,
+}
+
+/// A store path of the closure
+#[derive(Debug, Clone, PartialEq)]
+pub struct SbomComponent {
+    pub store_path: String,
+    pub name: String,
+    pub version: Option<String>,
+    /// Hex-encoded SHA-256 of the NAR serialisation
+    pub sha256: Option<String>,
+    pub licenses: Vec<String>,
+    /// Other members of the closure this path refers to
+    pub dependencies: Vec<String>,
+}
+
+impl SbomComponent {
+    pub fn purl(&self) -> String {
+        let mut purl = format!("pkg:nix/{}", percent_encode(&self.name));
+        if let Some(version) = &self.version {
+            purl.push('@');
+            purl.push_str(&percent_encode(version));
+        }
+        purl
+    }
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => (b as char).to_string(),
+            other => format!("%{:02X}", other),
+        })
+        .collect()
+}
+
+fn decode_nix_base32(encoded: &str, size: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0u8; size];
+    for (n, c) in encoded.bytes().rev().enumerate() {
+        let digit = NIX_BASE32.iter().position(|d| *d == c)? as u16;
+        let (i, j) = (n * 5 / 8, n * 5 % 8);
+        *bytes.get_mut(i)? |= (digit << j) as u8;
+        let carry = digit >> (8 - j);
+        if let Some(next) = bytes.get_mut(i + 1) {
+            *next |= carry as u8;
+        } else if carry != 0 {
+            return None;
+        }
+    }
+    Some(bytes)
+}
+
+fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+/// Hex encoding of a `sha256:` NAR hash in Nix base-32, hex or SRI form
+pub fn sha256_hex(hash: &str) -> Option<String> {
+    let bytes = if let Some(sri) = hash.strip_prefix("sha256-") {
+        decode_base64(sri)?
+    } else {
+        let digest = hash.strip_prefix("sha256:")?;
+        match digest.len() {
+            64 => return Some(digest.to_lowercase()),
+            52 => decode_nix_base32(digest, 32)?,
+            _ => return None,
+        }
+    };
+    (bytes.len() == 32).then(|| {
+        bytes.iter().fold(String::with_capacity(64), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+    })
+}
+
+/// Components of a closure, named after their store paths. `licenses`
+/// looks up the licenses of a package name and version.
+pub fn sbom_components(
+    infos: &[PathInfo],
+    licenses: impl Fn(&str, Option<&str>) -> Vec<String>,
+) -> Vec<SbomComponent> {
+    let members: BTreeSet<&str> = infos.iter().map(|info| info.path.as_str()).collect();
+    let mut components: Vec<SbomComponent> = infos
+        .iter()
+        .map(|info| {
+            let (name, version) = parse_drv_name(store_path_name(&info.path).trim_end_matches(".drv"));
+            let mut dependencies: Vec<String> = info
+                .references
+                .iter()
+                .filter(|reference| **reference != info.path && members.contains(reference.as_str()))
+                .cloned()
+                .collect();
+            dependencies.sort();
+            dependencies.dedup();
+            SbomComponent {
+                store_path: info.path.clone(),
+                name: name.to_string(),
+                version: version.map(str::to_string),
+                sha256: info.nar_hash.as_deref().and_then(sha256_hex),
+                licenses: licenses(name, version),
+                dependencies,
+            }
+        })
+        .collect();
+    components.sort_by(|a, b| a.store_path.cmp(&b.store_path));
+    components
+}
+
+/// Whether a license name is an SPDX identifier rather than a nixpkgs short
+/// name such as `gpl3Plus` or `unfree`
+fn is_spdx_id(license: &str) -> bool {
+    license.starts_with(|c: char| c.is_ascii_uppercase() || c.is_ascii_digit())
+        && license.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+'))
+}
+
+/// Identifier of a component in SPDX documents
+fn spdx_id(component: &SbomComponent) -> String {
+    let hash = store_path_hash(&component.store_path).unwrap_or_default();
+    let name: String = component
+        .name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '-' })
+        .collect();
+    format!("SPDXRef-{}-{}", hash, name)
+}
+
+fn tool_name() -> String {
+    format!("{}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// CycloneDX 1.5 document of a closure. Roots become the metadata component
+/// and the remaining paths library components.
+pub fn cyclonedx(roots: &[String], components: &[SbomComponent], serial: &str, timestamp: &str) -> Value {
+    let component = |c: &SbomComponent, kind: &str| {
+        let mut value = json!({
+            "type": kind,
+            "bom-ref": c.store_path,
+            "name": c.name,
+            "purl": c.purl(),
+            "properties": [{"name": "nix:store_path", "value": c.store_path}],
+        });
+        if let Some(version) = &c.version {
+            value["version"] = json!(version);
+        }
+        if let Some(sha256) = &c.sha256 {
+            value["hashes"] = json!([{"alg": "SHA-256", "content": sha256}]);
+        }
+        if !c.licenses.is_empty() {
+            let licenses: Vec<Value> = c
+                .licenses
+                .iter()
+                .map(|license| {
+                    if is_spdx_id(license) {
+                        json!({"license": {"id": license}})
+                    } else {
+                        json!({"license": {"name": license}})
+                    }
+                })
+                .collect();
+            value["licenses"] = json!(licenses);
+        }
+        value
+    };
+
+    let mut document = json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", serial),
+        "version": 1,
+        "metadata": {
+            "timestamp": timestamp,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                }]
+            }
+        },
+        "components": components
+            .iter()
+            .filter(|c| !roots.contains(&c.store_path))
+            .map(|c| component(c, "library"))
+            .collect::<Vec<_>>(),
+        "dependencies": components
+            .iter()
+            .map(|c| json!({"ref": c.store_path, "dependsOn": c.dependencies}))
+            .collect::<Vec<_>>(),
+    });
+    let root_components: Vec<&SbomComponent> = components.iter().filter(|c| roots.contains(&c.store_path)).collect();
+    match root_components.as_slice() {
+        [root] => document["metadata"]["component"] = component(root, "application"),
+        // Several roots, e.g. the outputs of one derivation, stay listed as components
+        _ => {
+            let roots: Vec<Value> = root_components.iter().map(|c| component(c, "application")).collect();
+            document["components"].as_array_mut().unwrap().splice(0..0, roots);
+        }
+    }
+    document
+}
+
+/// SPDX 2.3 document of a closure
+pub fn spdx(name: &str, roots: &[String], components: &[SbomComponent], namespace: &str, timestamp: &str) -> Value {
+    let mut extracted = BTreeSet::new();
+    let packages: Vec<Value> = components
+        .iter()
+        .map(|c| {
+            let declared = if c.licenses.is_empty() {
+                "NOASSERTION".to_string()
+            } else {
+                let ids: Vec<String> = c
+                    .licenses
+                    .iter()
+                    .map(|license| {
+                        if is_spdx_id(license) {
+                            return license.clone();
+                        }
+                        let id = format!("LicenseRef-{}", percent_encode(license).replace(['%', '_', '~'], "-"));
+                        extracted.insert((id.clone(), license.clone()));
+                        id
+                    })
+                    .collect();
+                // Packages under several licenses may be used under any of them
+                ids.join(" OR ")
+            };
+            let mut package = json!({
+                "SPDXID": spdx_id(c),
+                "name": c.name,
+                "versionInfo": c.version.clone().unwrap_or_default(),
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": declared,
+                "copyrightText": "NOASSERTION",
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": c.purl(),
+                }],
+                "comment": c.store_path,
+            });
+            if let Some(sha256) = &c.sha256 {
+                package["checksums"] = json!([{"algorithm": "SHA256", "checksumValue": sha256}]);
+            }
+            package
+        })
+        .collect();
+
+    let ids: std::collections::HashMap<&str, String> =
+        components.iter().map(|c| (c.store_path.as_str(), spdx_id(c))).collect();
+    let mut relationships: Vec<Value> = roots
+        .iter()
+        .filter_map(|root| ids.get(root.as_str()))
+        .map(|root| json!({"spdxElementId": "SPDXRef-DOCUMENT", "relationshipType": "DESCRIBES", "relatedSpdxElement": root}))
+        .collect();
+    for c in components {
+        for dependency in &c.dependencies {
+            relationships.push(json!({
+                "spdxElementId": ids[c.store_path.as_str()],
+                "relationshipType": "DEPENDS_ON",
+                "relatedSpdxElement": ids[dependency.as_str()],
+            }));
+        }
+    }
+
+    let mut document = json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!("https://spdx.org/spdxdocs/{}-{}", env!("CARGO_PKG_NAME"), namespace),
+        "creationInfo": {
+            "created": timestamp,
+            "creators": [format!("Tool: {}", tool_name())],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    });
+    if !extracted.is_empty() {
+        document["hasExtractedLicensingInfos"] = extracted
+            .into_iter()
+            .map(|(id, name)| json!({"licenseId": id, "name": name, "extractedText": name}))
+            .collect();
+    }
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const HELLO: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello-2.12.1";
+    const GLIBC: &str = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc-2.39-52";
+    const LIBIDN: &str = "/nix/store/cccccccccccccccccccccccccccccccc-libidn2-2.3.7";
+
+    fn components() -> Vec<SbomComponent> {
+        let info = |path: &str, references: &[&str]| PathInfo {
+            path: path.to_string(),
+            references: references.iter().map(|r| r.to_string()).collect(),
+            nar_size: 0,
+            deriver: None,
+            nar_hash: Some("sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73".to_string()),
+        };
+        let infos = vec![
+            info(HELLO, &[HELLO, GLIBC]),
+            info(GLIBC, &[GLIBC, LIBIDN]),
+            info(LIBIDN, &[]),
+        ];
+        sbom_components(&infos, |name, _| match name {
+            "hello" => vec!["GPL-3.0-or-later".to_string()],
+            "glibc" => vec!["LGPL-2.1-or-later".to_string(), "gpl2Plus".to_string()],
+            _ => Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex("sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73").as_deref(),
+            Some(EMPTY_SHA256)
+        );
+        assert_eq!(
+            sha256_hex("sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=").as_deref(),
+            Some(EMPTY_SHA256)
+        );
+        assert_eq!(sha256_hex(&format!("sha256:{}", EMPTY_SHA256)).as_deref(), Some(EMPTY_SHA256));
+        assert_eq!(sha256_hex("sha512-abc"), None);
+        assert_eq!(sha256_hex("sha256:not-a-hash"), None);
+    }
+
+    #[test]
+    fn test_sbom_components() {
+        let components = components();
+        assert_eq!(components.len(), 3);
+        let hello = &components[0];
+        assert_eq!((hello.name.as_str(), hello.version.as_deref()), ("hello", Some("2.12.1")));
+        assert_eq!(hello.purl(), "pkg:nix/hello@2.12.1");
+        assert_eq!(hello.dependencies, vec![GLIBC]);
+        assert_eq!(hello.sha256.as_deref(), Some(EMPTY_SHA256));
+        assert_eq!(components[1].purl(), "pkg:nix/glibc@2.39-52");
+    }
+
+    #[test]
+    fn test_cyclonedx() {
+        let document = cyclonedx(&[HELLO.to_string()], &components(), "0000", "2024-01-01T00:00:00Z");
+        assert_eq!(document["specVersion"], "1.5");
+        assert_eq!(document["metadata"]["component"]["name"], "hello");
+        assert_eq!(document["metadata"]["component"]["licenses"][0]["license"]["id"], "GPL-3.0-or-later");
+        let components = document["components"].as_array().unwrap();
+        assert_eq!(components.len(), 2);
+        assert_eq!(components[0]["hashes"][0]["content"], EMPTY_SHA256);
+        assert_eq!(components[0]["licenses"][1]["license"]["name"], "gpl2Plus");
+        assert_eq!(document["dependencies"][0]["dependsOn"], json!([GLIBC]));
+        assert_eq!(document["dependencies"][2]["dependsOn"], json!([]));
+    }
+
+    #[test]
+    fn test_spdx() {
+        let document = spdx("hello", &[HELLO.to_string()], &components(), "0000", "2024-01-01T00:00:00Z");
+        assert_eq!(document["spdxVersion"], "SPDX-2.3");
+        let packages = document["packages"].as_array().unwrap();
+        assert_eq!(packages[0]["SPDXID"], "SPDXRef-aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-hello");
+        assert_eq!(packages[1]["licenseDeclared"], "LGPL-2.1-or-later OR LicenseRef-gpl2Plus");
+        assert_eq!(packages[2]["licenseDeclared"], "NOASSERTION");
+        assert_eq!(document["hasExtractedLicensingInfos"][0]["licenseId"], "LicenseRef-gpl2Plus");
+        let relationships = document["relationships"].as_array().unwrap();
+        assert_eq!(relationships[0]["relationshipType"], "DESCRIBES");
+        assert_eq!(relationships.len(), 3);
+        assert_eq!(relationships[1]["relatedSpdxElement"], "SPDXRef-bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-glibc");
+    }
+}
@@ -0,0 +1,52 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Name of the state directory below the XDG state home
+const APP_NAME: &str = "nix-inspector-mcp";
+
+/// Directory for persistent data such as search indexes.
+///
+/// Uses the `StateDirectory` systemd provides to the service, then
+/// `$XDG_STATE_HOME`, then `~/.local/state`.
+pub fn state_dir() -> PathBuf {
+    state_dir_from(
+        env::var_os("STATE_DIRECTORY").map(PathBuf::from),
+        env::var_os("XDG_STATE_HOME").map(PathBuf::from),
+        env::var_os("HOME").map(PathBuf::from),
+    )
+}
+
+fn state_dir_from(systemd: Option<PathBuf>, xdg: Option<PathBuf>, home: Option<PathBuf>) -> PathBuf {
+    // systemd separates multiple state directories with colons
+    if let Some(dir) = systemd.and_then(|dirs| dirs.to_str()?.split(':').next().map(PathBuf::from)) {
+        if !dir.as_os_str().is_empty() {
+            return dir;
+        }
+    }
+    if let Some(xdg) = xdg.filter(|dir| dir.is_absolute()) {
+        return xdg.join(APP_NAME);
+    }
+    match home {
+        Some(home) => home.join(".local/state").join(APP_NAME),
+        None => env::temp_dir().join(APP_NAME),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_dir_precedence() {
+        let path = |s: &str| Some(PathBuf::from(s));
+        assert_eq!(
+            state_dir_from(path("/var/lib/nix-inspector-mcp:/var/lib/other"), path("/xdg"), path("/home/u")),
+            PathBuf::from("/var/lib/nix-inspector-mcp")
+        );
+        assert_eq!(state_dir_from(None, path("/xdg"), path("/home/u")), PathBuf::from("/xdg/nix-inspector-mcp"));
+        assert_eq!(
+            state_dir_from(None, path("relative"), path("/home/u")),
+            PathBuf::from("/home/u/.local/state/nix-inspector-mcp")
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::Path;
+
+/// Length of the hash part of a store path basename (`<hash>-<name>`)
+pub const STORE_HASH_LEN: usize = 32;
+
+/// Returns the basename of a store path, e.g. `abc...-hello-2.12.1`
+pub fn store_path_basename(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+/// Returns the hash part of a store path, or `None` if the path is not a store path
+pub fn store_path_hash(path: &str) -> Option<&str> {
+    let base = store_path_basename(path);
+    let hash = base.get(..STORE_HASH_LEN)?;
+    if base.as_bytes().get(STORE_HASH_LEN) != Some(&b'-') {
+        return None;
+    }
+    Some(hash)
+}
+
+/// Returns the name part of a store path, e.g. `hello-2.12.1`
+pub fn store_path_name(path: &str) -> &str {
+    let base = store_path_basename(path);
+    match store_path_hash(path) {
+        Some(_) => &base[STORE_HASH_LEN + 1..],
+        None => base,
+    }
+}
+
+/// Splits a derivation name into package name and version, following the
+/// rules of `builtins.parseDrvName`: the version starts at the first dash
+/// that is followed by something other than a letter.
+pub fn parse_drv_name(name: &str) -> (&str, Option<&str>) {
+    let bytes = name.as_bytes();
+    for (i, b) in bytes.iter().enumerate() {
+        if *b == b'-' {
+            if let Some(next) = bytes.get(i + 1) {
+                if !next.is_ascii_alphabetic() {
+                    return (&name[..i], Some(&name[i + 1..]));
+                }
+            }
+        }
+    }
+    (name, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "/nix/store/4h0mfrlhb6qrqk4kqb0ffdnbbsx4bzyb-hello-2.12.1";
+
+    #[test]
+    fn test_store_path_parts() {
+        assert_eq!(store_path_hash(HELLO), Some("4h0mfrlhb6qrqk4kqb0ffdnbbsx4bzyb"));
+        assert_eq!(store_path_name(HELLO), "hello-2.12.1");
+        assert_eq!(store_path_hash("/etc/hosts"), None);
+        assert_eq!(store_path_name("/etc/hosts"), "hosts");
+    }
+
+    #[test]
+    fn test_parse_drv_name() {
+        assert_eq!(parse_drv_name("hello-2.12.1"), ("hello", Some("2.12.1")));
+        assert_eq!(parse_drv_name("gcc-wrapper-13.2.0"), ("gcc-wrapper", Some("13.2.0")));
+        assert_eq!(parse_drv_name("python3-3.11.9-env"), ("python3", Some("3.11.9-env")));
+        assert_eq!(parse_drv_name("source"), ("source", None));
+    }
+}
@@ -2,41 +2,89 @@ use async_trait::async_trait;
 use mcp_rust_sdk::{
     error::{Error as McpError, ErrorCode},
     server::ServerHandler,
-    types::{ClientCapabilities, Implementation, ServerCapabilities},
+    types::{ClientCapabilities, Implementation, Progress, ServerCapabilities},
+    Notification,
 };
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 use std::process::Command;
 use thiserror::Error;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use which;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::path::PathBuf;
-use lazy_static::lazy_static;
-use std::sync::RwLock;
-use std::sync::Arc;
-use uuid;
+use crate::config::{Args, Config, ConfigError, FileConfig};
 use crate::transport::stdio::StdioTransport;
 use serde_json::{json, Value};
 
-lazy_static! {
-    static ref SERVER_STATE: RwLock<ServerState> = RwLock::new(ServerState {
-        initialized: false,
-        implementation: None,
-        capabilities: None,
-    });
-}
+use super::closure::{
+    parse_path_info, ClosureSizeParams, PathInfo, ClosureSizeReport, DependencyGraph, WhyDepends,
+    WhyDependsParams,
+};
+use super::aterm::read_derivation;
+use super::build::{stream_build, BuildParams, BuildResult, BuiltDerivation, DEFAULT_LOG_LINES};
+use super::gc::{
+    compute_plan, expired_generations, generations_by_profile, group_roots, parse_print_roots, CollectGarbageParams,
+    GcPlan, GcPlanParams, GcResult, GcRootScanner, GcRootsParams, GcRootsReport,
+    DEFAULT_STALE_DAYS, GCROOTS_DIR,
+};
+use super::cache::{
+    flake_fingerprint, CacheInvalidation, CacheStats, InvalidateCacheParams, ResponseCache,
+};
+use super::checks::{check_report, select_checks, FlakeCheckParams, FlakeCheckReport, CHECK_DRV_PATHS};
+use super::eval::{evaluate, flake_expression, EvalLimits, EvalParams, EvalResult};
+use super::lock::{locked_input, locked_url, LockedInput};
+use super::manifest::{
+    flag_outdated, parse_manifest, registry_nixpkgs_rev, user_profile_dirs, ProfileElement, ProfileKind,
+    UserProfile, UserProfilesParams, UserProfilesReport, SKIPPED_PROFILES, SYSTEM_REGISTRY,
+};
+use super::options::{
+    parse_options_json, BrowseOptionsParams, GetOptionParams, OptionBrowse, OptionIndex, OptionLookup,
+    OptionSearchParams, OptionSearchResult, OptionSource, DEFAULT_LIMIT as OPTIONS_LIMIT,
+};
+use super::provenance::{
+    explain_expression, option_path, parse_explanation, ExplainOptionParams, OptionExplanation,
+};
+use super::packages::{
+    input_path_expression, parse_nix_env, PackageIndex, PackageSearchParams, PackageSearchResult, DEFAULT_LIMIT,
+};
+use super::sbom::{cyclonedx, sbom_components, spdx, SbomFormat, SbomParams};
+use super::store::store_path_name;
+use super::util::{today, unix_now, utc_timestamp};
+use super::vulnerabilities::{
+    closure_packages, scan, AdvisoryDatabase, VulnerabilityReport, VulnerabilityScanParams, Whitelist,
+    ADVISORY_DIR, CURRENT_SYSTEM, SYSTEM_PROFILE, WHITELIST_FILE,
+};
+use super::licenses::{audit_licenses, LicenseAudit, LicenseAuditParams, LicensePolicy, POLICY_FILE};
+use super::logs::{
+    log_file_path, read_log_file, select_lines, summarize_failure, BuildLog, BuildLogParams, LogSource, LOG_DIR,
+};
+use super::home::{
+    diff_files, diff_packages, home_profile_candidates, list_home_files, DiffHomeGenerationsParams, HomeGeneration,
+    HomeGenerationDiff, HomeGenerations, HomeGenerationsParams, HomePackage, ShowHomeGenerationParams,
+};
+use super::profiles::{find_profiles, list_generations, Generation, PROFILES_DIR};
+use super::auth::{AuditLog, Authenticator, Identity, Session, AUDIT_LOG};
+use super::limits::{ProcessSlot, ProcessSlots, RateLimitConfig, RateLimited, RateLimiter};
+use super::errors::{mcp_error, ErrorKind, NixFailure};
+use super::policy::{AccessDenial, AccessPolicy};
+use super::watch::{file_uri, uri_path, ResourceKind, ResourceList, ResourceParams, WatchFlakeParams, Watcher};
+use super::flake::mermaid_graph;
+use super::derivation::{diff_derivations, Derivation, DerivationDiff, DiffDerivationsParams, DEFAULT_DIFF_DEPTH};
 
-#[derive(Debug, Clone)]
-struct ServerState {
-    initialized: bool,
-    implementation: Option<Implementation>,
-    capabilities: Option<ClientCapabilities>,
-}
+/// Evaluating a whole NixOS configuration takes longer than typical expressions
+const EXPLAIN_TIMEOUT_SECS: u64 = 120;
+
+/// Nodes and edges parsed from a DOT graph
+type DotGraphParts = (Vec<String>, Vec<(String, String)>);
 
 #[derive(Error, Debug)]
 pub enum SystemAnalyzerError {
     #[error("Failed to execute nix command: {0}")]
     NixCommandError(String),
+    #[error("Failed to execute nix command: {0}")]
+    NixCommandFailed(Box<NixFailure>),
     #[error("Failed to parse nix output: {0}")]
     ParseError(String),
     #[error("Failed to generate graph: {0}")]
@@ -49,6 +97,16 @@ pub enum SystemAnalyzerError {
     Json(#[from] serde_json::Error),
     #[error("Which error: {0}")]
     Which(#[from] which::Error),
+    #[error("Confirmation required: {0}")]
+    ConfirmationRequired(String),
+    #[error("Access denied: {0}")]
+    AccessDenied(AccessDenial),
+    #[error("Authentication failed: {0}")]
+    Unauthenticated(String),
+    #[error("Rate limited: {0}")]
+    RateLimited(RateLimited),
+    #[error(transparent)]
+    Config(Box<ConfigError>),
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -57,7 +115,8 @@ pub struct SystemInfo {
     pub nix_cmd: String,
     pub system_flake: Option<String>,
     pub current_system: Option<String>,
-    pub store_path: Option<String>,
+    /// Store directory of this nix, read once at startup
+    pub store_path: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -72,24 +131,47 @@ pub struct FlakeInfo {
 pub struct FlakeGraph {
     dot_graph: String,
     svg_output: Option<String>,
+    mermaid_output: Option<String>,
     nodes: Vec<String>,
     edges: Vec<(String, String)>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct DependencyGraph {
-    nodes: Vec<String>,
-    edges: Vec<(String, String)>,
-}
-
-impl From<SystemAnalyzerError> for McpError {
-    fn from(err: SystemAnalyzerError) -> Self {
-        McpError::protocol(ErrorCode::InternalError, err.to_string())
-    }
+/// What an analyzer is set up with, as resolved by [`Config`]
+pub struct AnalyzerSettings {
+    /// The nix binary; found on PATH when unset
+    pub nix_path: Option<String>,
+    /// Holds search indexes, advisories, policies and the audit log
+    pub state_dir: PathBuf,
+    /// `None` disables caching
+    pub cache: Option<ResponseCache>,
+    pub policy: AccessPolicy,
+    /// Bearer tokens and roles of network clients, if any
+    pub authenticator: Option<Authenticator>,
+    pub rate_limits: RateLimitConfig,
 }
 
 pub struct SystemAnalyzer {
     info: SystemInfo,
+    state_dir: PathBuf,
+    /// Package indexes loaded in this session, by locked input
+    package_indexes: Mutex<HashMap<String, Arc<PackageIndex>>>,
+    /// Option indexes loaded in this session, by index file
+    option_indexes: Mutex<HashMap<String, Arc<OptionIndex>>>,
+    /// Persisted outputs of flake commands, by flake fingerprint
+    cache: Option<ResponseCache>,
+    /// Watches flakes and profiles once the client has initialized
+    watcher: std::sync::OnceLock<Watcher>,
+    /// Which tools may run and what they may touch
+    policy: AccessPolicy,
+    /// Bearer tokens and roles of network clients
+    authenticator: Option<Authenticator>,
+    /// Session of the client connected over stdio; network transports
+    /// keep a session per connection instead
+    local_session: std::sync::OnceLock<Session>,
+    audit: AuditLog,
+    rate_limiter: RateLimiter,
+    /// Shared by every nix process the server starts
+    nix_processes: ProcessSlots,
 }
 
 impl SystemAnalyzer {
@@ -97,8 +179,19 @@ impl SystemAnalyzer {
         Self::with_nix_path(None)
     }
 
+    /// An analyzer set up as the binary is without flags or a config file
     pub fn with_nix_path(nix_path: Option<String>) -> Result<Self, SystemAnalyzerError> {
-        let nix_cmd = match nix_path {
+        let args = Args { nix_path: nix_path.map(PathBuf::from), ..Args::default() };
+        Config::resolve(args, FileConfig::default())
+            .and_then(|config| config.system_analyzer())
+            .map_err(|e| match e {
+                ConfigError::Analyzer(e) => e,
+                e => SystemAnalyzerError::Config(Box::new(e)),
+            })
+    }
+
+    pub fn with_settings(settings: AnalyzerSettings) -> Result<Self, SystemAnalyzerError> {
+        let nix_cmd = match settings.nix_path {
             Some(path) => path,
             None => which::which("nix")
                 .map_err(SystemAnalyzerError::Which)?
@@ -111,6 +204,9 @@ impl SystemAnalyzer {
             .output()?;
 
         let nix_version = String::from_utf8(version_output.stdout)?;
+        let store_path = read_store_dir(&nix_cmd)?;
+
+        let limits = settings.rate_limits;
 
         Ok(Self {
             info: SystemInfo {
@@ -118,8 +214,79 @@ impl SystemAnalyzer {
                 nix_cmd,
                 system_flake: None,
                 current_system: None,
-                store_path: None,
+                store_path: store_path.clone(),
             },
+            state_dir: settings.state_dir.clone(),
+            package_indexes: Mutex::new(HashMap::new()),
+            option_indexes: Mutex::new(HashMap::new()),
+            cache: settings.cache,
+            watcher: std::sync::OnceLock::new(),
+            policy: settings.policy.with_store_dir(&store_path),
+            authenticator: settings.authenticator.map(|auth| auth.with_store_dir(&store_path)),
+            local_session: std::sync::OnceLock::new(),
+            audit: AuditLog::new(settings.state_dir.join(AUDIT_LOG)),
+            nix_processes: ProcessSlots::new(limits.max_nix_processes),
+            rate_limiter: RateLimiter::new(limits),
+        })
+    }
+
+    /// Counts a request against the caller's session and tool limits
+    fn check_rate(&self, session: &Session, method: &str) -> Result<(), SystemAnalyzerError> {
+        self.rate_limiter.check(&session.id, method, std::time::Instant::now()).map_err(|limited| {
+            log::warn!("Rate limited {}: {}", method, limited);
+            SystemAnalyzerError::RateLimited(limited)
+        })
+    }
+
+    /// Reserves one of the slots for running nix processes. A tool call
+    /// holds its slot throughout, so one running several nix commands is
+    /// never refused halfway.
+    fn nix_slot(&self) -> Result<ProcessSlot, SystemAnalyzerError> {
+        self.nix_processes.acquire().map_err(SystemAnalyzerError::RateLimited)
+    }
+
+    /// Starts the session of a client connecting over the network, given
+    /// its `Authorization` header. Only clients with a known token get in.
+    /// The transport passes the session to [`Self::call`] for each request
+    /// on the connection.
+    pub fn start_session(&self, authorization: Option<&str>) -> Result<Session, SystemAnalyzerError> {
+        let identity = self.authenticator.as_ref().zip(authorization).and_then(|(auth, header)| auth.authenticate(header));
+        let Some(identity) = identity else {
+            let reason = if authorization.is_some() { "invalid bearer token" } else { "missing bearer token" };
+            log::warn!("Refused session: {}", reason);
+            self.audit.record(None, None, reason);
+            return Err(SystemAnalyzerError::Unauthenticated(reason.to_string()));
+        };
+        Ok(Self::log_session(Session::new(identity)))
+    }
+
+    /// Starts the session of a local client, such as the command line
+    pub fn start_local_session(&self) -> Session {
+        Self::log_session(Session::new(Identity::local()))
+    }
+
+    fn log_session(session: Session) -> Session {
+        log::info!("Session {} started for {}", session.id, session.identity.client);
+        session
+    }
+
+    /// Session of the client connected over stdio, started on first use
+    fn stdio_session(&self) -> &Session {
+        self.local_session.get_or_init(|| self.start_local_session())
+    }
+
+    /// Checks a call against the server's access policy and the policy of
+    /// the session's role before it runs. Refused calls are audited.
+    fn authorize(&self, session: &Session, method: &str, params: Option<&Value>) -> Result<(), SystemAnalyzerError> {
+        let base = std::env::current_dir()?;
+        let result = self.policy.check(method, params, &base).and_then(|()| match &self.authenticator {
+            Some(authenticator) => authenticator.check(session, method, params, &base),
+            None => Ok(()),
+        });
+        result.map_err(|denial| {
+            log::warn!("Denied {}: {}", method, denial);
+            self.audit.record(Some(session), Some(method), &denial.to_string());
+            SystemAnalyzerError::AccessDenied(denial)
         })
     }
 
@@ -135,10 +302,6 @@ impl SystemAnalyzer {
         let current_system = self.run_nix_command(&["eval", "--raw", "system"])?;
         info.current_system = Some(current_system);
 
-        // Get store path
-        let store_path = self.run_nix_command(&["eval", "--raw", "storeDir"])?;
-        info.store_path = Some(store_path);
-
         Ok(info)
     }
 
@@ -152,12 +315,12 @@ impl SystemAnalyzer {
         }
 
         // Get locked inputs
-        let locked_inputs = self.run_nix_command(&["flake", "metadata", "--json"])?;
+        let locked_inputs = self.cached_nix_command(".", &["flake", "metadata", "--json"])?;
         let inputs: Vec<String> = serde_json::from_str(&locked_inputs)
             .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
 
         // Get outputs
-        let outputs = self.run_nix_command(&["flake", "show", "--json"])?;
+        let outputs = self.cached_nix_command(".", &["flake", "show", "--json"])?;
         let output_list: Vec<String> = serde_json::from_str(&outputs)
             .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
 
@@ -194,16 +357,18 @@ impl SystemAnalyzer {
         // Parse nodes and edges from DOT output
         let (nodes, edges) = self.parse_dot_graph(&dot_graph)?;
 
-        // Generate SVG if requested
+        // Generate SVG or Mermaid if requested
         let svg_output = if output_format == Some("svg") {
             Some(self.dot_to_svg(&dot_graph)?)
         } else {
             None
         };
+        let mermaid_output = (output_format == Some("mermaid")).then(|| mermaid_graph(&nodes, &edges));
 
         Ok(FlakeGraph {
             dot_graph,
             svg_output,
+            mermaid_output,
             nodes,
             edges,
         })
@@ -211,26 +376,158 @@ impl SystemAnalyzer {
 
     // Helper methods
     fn run_nix_command(&self, args: &[&str]) -> Result<String, SystemAnalyzerError> {
-        let output = Command::new(&self.info.nix_cmd)
+        self.run_command(&self.info.nix_cmd, args)
+    }
+
+    /// Watches the flake in the working directory, the system profiles and
+    /// the user's profiles, notifying the client about changes
+    fn start_watcher(&self) -> Result<(), SystemAnalyzerError> {
+        if self.watcher.get().is_some() {
+            return Ok(());
+        }
+        let notify = Arc::new(|notification: Notification| {
+            if let Err(e) = StdioTransport::notify(&notification) {
+                log::warn!("Failed to send {} notification: {}", notification.method, e);
+            }
+        });
+        let watcher = Watcher::start(self.cache.clone(), notify)?;
+
+        let cwd = std::env::current_dir()?;
+        if cwd.join("flake.nix").is_file() && self.policy.check_installable(".", &cwd).is_ok() {
+            watcher.watch_flake(&cwd)?;
+        }
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        let user = std::env::var("USER").ok();
+        let profile_dirs = std::iter::once(PathBuf::from(PROFILES_DIR)).chain(user_profile_dirs(home.as_deref(), user.as_deref()));
+        for dir in profile_dirs.filter(|dir| dir.is_dir() && self.policy.check_path(&dir.to_string_lossy(), &cwd).is_ok()) {
+            if let Err(e) = watcher.watch_profiles(&dir) {
+                log::warn!("Failed to watch profiles in {}: {}", dir.display(), e);
+            }
+        }
+        let _ = self.watcher.set(watcher);
+        Ok(())
+    }
+
+    fn watcher(&self) -> Result<&Watcher, SystemAnalyzerError> {
+        self.watcher.get().ok_or_else(|| {
+            SystemAnalyzerError::Io(std::io::Error::new(std::io::ErrorKind::NotConnected, "file watcher is not running"))
+        })
+    }
+
+    /// Watched flake files and profiles
+    pub async fn list_resources(&self) -> Result<ResourceList, SystemAnalyzerError> {
+        Ok(ResourceList { resources: self.watcher()?.resources() })
+    }
+
+    /// Contents of a watched resource: the text of a flake file, or the
+    /// generations of a profile
+    pub async fn read_resource(&self, params: &ResourceParams) -> Result<Value, SystemAnalyzerError> {
+        let not_found = || {
+            SystemAnalyzerError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("unknown resource {}", params.uri),
+            ))
+        };
+        let kind = self.watcher()?.resource_kind(&params.uri).ok_or_else(not_found)?;
+        let path = uri_path(&params.uri).ok_or_else(not_found)?;
+        let (mime_type, text) = match kind {
+            ResourceKind::FlakeFile => {
+                let mime_type = if path.extension().is_some_and(|ext| ext == "nix") { "text/x-nix" } else { "application/json" };
+                (mime_type, std::fs::read_to_string(&path)?)
+            }
+            ResourceKind::Profile => ("application/json", serde_json::to_string_pretty(&list_generations(&path)?)?),
+        };
+        Ok(json!({ "contents": [{ "uri": params.uri, "mimeType": mime_type, "text": text }] }))
+    }
+
+    /// Start watching a local flake; it is then listed as resources
+    pub async fn watch_flake(&self, params: &WatchFlakeParams) -> Result<Value, SystemAnalyzerError> {
+        let dir = std::fs::canonicalize(&params.flake)?;
+        let added = self.watcher()?.watch_flake(&dir)?;
+        Ok(json!({ "flake": file_uri(&dir), "watching": true, "added": added }))
+    }
+
+    /// Stop watching a local flake
+    pub async fn unwatch_flake(&self, params: &WatchFlakeParams) -> Result<Value, SystemAnalyzerError> {
+        let dir = std::fs::canonicalize(&params.flake)?;
+        let removed = self.watcher()?.unwatch_flake(&dir)?;
+        Ok(json!({ "flake": file_uri(&dir), "watching": false, "removed": removed }))
+    }
+
+    /// Run a nix command whose output only depends on the contents of a
+    /// flake, answering from the response cache when the flake is unchanged
+    fn cached_nix_command(&self, flake: &str, args: &[&str]) -> Result<String, SystemAnalyzerError> {
+        let Some(cache) = &self.cache else {
+            return self.run_nix_command(args);
+        };
+        let Some(fingerprint) = flake_fingerprint(flake, &std::env::current_dir()?) else {
+            return self.run_nix_command(args);
+        };
+        let command = [&[self.info.nix_cmd.as_str()], args].concat();
+        if let Some(output) = cache.get(&fingerprint, &command) {
+            return Ok(output);
+        }
+        let output = self.run_nix_command(args)?;
+        if let Err(e) = cache.put(&fingerprint, &command, &output) {
+            log::warn!("Failed to cache output of nix {}: {}", args.join(" "), e);
+        }
+        Ok(output)
+    }
+
+    /// Drop cached responses for a flake, or all of them
+    pub async fn invalidate_cache(&self, params: &InvalidateCacheParams) -> Result<CacheInvalidation, SystemAnalyzerError> {
+        let Some(cache) = &self.cache else {
+            return Ok(CacheInvalidation { removed: 0, stats: CacheStats::default() });
+        };
+        let removed = match &params.flake {
+            Some(flake) => match flake_fingerprint(flake, &std::env::current_dir()?) {
+                Some(fingerprint) => cache.invalidate(Some(&fingerprint))?,
+                None => 0,
+            },
+            None => cache.invalidate(None)?,
+        };
+        Ok(CacheInvalidation { removed, stats: cache.stats()? })
+    }
+
+    /// Size and location of the response cache
+    pub async fn cache_stats(&self) -> Result<CacheStats, SystemAnalyzerError> {
+        match &self.cache {
+            Some(cache) => cache.stats(),
+            None => Ok(CacheStats::default()),
+        }
+    }
+
+    /// Run one of the legacy tools shipped next to `nix`, e.g. `nix-store`
+    fn run_nix_tool(&self, tool: &str, args: &[&str]) -> Result<String, SystemAnalyzerError> {
+        let sibling = std::path::Path::new(&self.info.nix_cmd).with_file_name(tool);
+        let program = if sibling.exists() {
+            sibling.to_string_lossy().to_string()
+        } else {
+            tool.to_string()
+        };
+        self.run_command(&program, args)
+    }
+
+    fn run_command(&self, program: &str, args: &[&str]) -> Result<String, SystemAnalyzerError> {
+        let output = Command::new(program)
             .args(args)
             .output()
             .map_err(|e| SystemAnalyzerError::NixCommandError(e.to_string()))?;
 
         if !output.status.success() {
-            return Err(SystemAnalyzerError::NixCommandError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+            return Err(SystemAnalyzerError::NixCommandFailed(Box::new(NixFailure::new(
+                program,
+                args,
+                output.status.code(),
+                &output.stderr,
+            ))));
         }
 
         Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
     }
 
-    fn get_system_flake(&self) -> Result<String, SystemAnalyzerError> {
-        self.run_nix_command(&["eval", "--raw", "nixosConfig.system.build.toplevel.drvPath"])
-    }
-
     fn get_dev_shells(&self) -> Result<Vec<String>, SystemAnalyzerError> {
-        let shells = self.run_nix_command(&["flake", "show", "--json", "--allow-import-from-derivation"])?;
+        let shells = self.cached_nix_command(".", &["flake", "show", "--json", "--allow-import-from-derivation"])?;
         let shell_data: serde_json::Value = serde_json::from_str(&shells)
             .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
         
@@ -248,7 +545,7 @@ impl SystemAnalyzer {
     }
 
     // Helper method to parse DOT graph
-    fn parse_dot_graph(&self, dot_graph: &str) -> Result<(Vec<String>, Vec<(String, String)>), SystemAnalyzerError> {
+    fn parse_dot_graph(&self, dot_graph: &str) -> Result<DotGraphParts, SystemAnalyzerError> {
         let mut nodes = Vec::new();
         let mut edges = Vec::new();
 
@@ -297,20 +594,788 @@ impl SystemAnalyzer {
         Ok(String::from_utf8_lossy(&output.stdout).to_string())
     }
 
-    async fn get_dependency_graph(&self) -> Result<DependencyGraph, McpError> {
-        // Implement dependency graph generation
-        Ok(DependencyGraph {
-            nodes: vec![],
-            edges: vec![],
+    /// Build the runtime closure reference graph of an installable
+    pub async fn get_dependency_graph(&self, installable: &str) -> Result<DependencyGraph, SystemAnalyzerError> {
+        let roots = self
+            .run_nix_command(&["path-info", installable])?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect();
+
+        let infos = self.path_infos(&["path-info", "--json", "--recursive", installable])?;
+
+        Ok(DependencyGraph::from_path_info(roots, &infos))
+    }
+
+    /// Explain why an installable's closure contains a target path
+    pub async fn why_depends(&self, params: &WhyDependsParams) -> Result<WhyDepends, SystemAnalyzerError> {
+        let graph = self.get_dependency_graph(&params.installable).await?;
+        graph.why_depends(&params.target, params.k, params.precise)
+    }
+
+    /// Rank the members of an installable's closure by size
+    pub async fn analyze_closure_size(&self, params: &ClosureSizeParams) -> Result<ClosureSizeReport, SystemAnalyzerError> {
+        let graph = self.get_dependency_graph(&params.installable).await?;
+        Ok(graph.size_report(params))
+    }
+
+    /// Load a derivation by store path or installable. Readable `.drv`
+    /// files are parsed directly; anything else goes through `nix derivation show`.
+    pub fn load_derivation(&self, path: &str) -> Result<(String, Derivation), SystemAnalyzerError> {
+        let file = std::path::Path::new(path);
+        if path.ends_with(".drv") && file.is_file() {
+            return Ok((path.to_string(), read_derivation(file)?));
+        }
+
+        let output = self.run_nix_command(&["derivation", "show", path])?;
+        let value: Value = serde_json::from_str(&output)
+            .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
+        Derivation::from_show_json(&value, self.store_dir())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| SystemAnalyzerError::ParseError(format!("No derivation found for {}", path)))
+    }
+
+    /// Explain the difference between two derivations
+    pub async fn diff_derivations(&self, params: &DiffDerivationsParams) -> Result<DerivationDiff, SystemAnalyzerError> {
+        let mut cache = HashMap::new();
+        let (left, left_drv) = self.load_derivation(&params.left)?;
+        let (right, right_drv) = self.load_derivation(&params.right)?;
+        cache.insert(left.clone(), left_drv);
+        cache.insert(right.clone(), right_drv);
+
+        let mut load = |path: &str| match cache.get(path) {
+            Some(drv) => Ok(drv.clone()),
+            None => {
+                let (_, drv) = self.load_derivation(path)?;
+                cache.insert(path.to_string(), drv.clone());
+                Ok(drv)
+            }
+        };
+        diff_derivations(&left, &right, self.store_dir(), &mut load, params.max_depth.unwrap_or(DEFAULT_DIFF_DEPTH))
+    }
+
+    /// List garbage collector roots grouped by owner and project
+    pub async fn list_gc_roots(&self, params: &GcRootsParams) -> Result<GcRootsReport, SystemAnalyzerError> {
+        let scanner = GcRootScanner::new(GCROOTS_DIR, params.stale_after_days.unwrap_or(DEFAULT_STALE_DAYS));
+        Ok(group_roots(scanner.scan()?))
+    }
+
+    /// Compute what a garbage collection would free without deleting anything
+    pub async fn plan_garbage_collection(&self, params: &GcPlanParams) -> Result<GcPlan, SystemAnalyzerError> {
+        let now = unix_now();
+
+        // The system profiles and the caller's own; generations are removed
+        // profile by profile, so nothing outside the plan is touched
+        let mut profiles = Vec::new();
+        let mut removed = Vec::new();
+        if let Some(days) = params.older_than_days {
+            let home = std::env::var_os("HOME").map(PathBuf::from);
+            let user = std::env::var("USER").ok();
+            let dirs = std::iter::once(PathBuf::from(PROFILES_DIR)).chain(user_profile_dirs(home.as_deref(), user.as_deref()));
+            for dir in dirs.filter(|dir| dir.is_dir()) {
+                for profile in find_profiles(&dir)? {
+                    removed.extend(expired_generations(&list_generations(&profile)?, days, now));
+                    profiles.push(profile.to_string_lossy().to_string());
+                }
+            }
+        }
+        let removed_links: HashSet<&str> = removed.iter().map(|g| g.link.as_str()).collect();
+
+        let removed_closure = if removed.is_empty() {
+            Vec::new()
+        } else {
+            let mut args = vec!["path-info", "--json", "--recursive"];
+            args.extend(removed.iter().map(|g| g.target.as_str()));
+            self.path_infos(&args)?
+        };
+
+        let roots = parse_print_roots(&self.run_nix_tool("nix-store", &["--gc", "--print-roots"])?);
+        let kept_roots: Vec<(String, String)> = roots
+            .into_iter()
+            .filter(|(link, _)| !removed_links.contains(link.as_str()))
+            .collect();
+        let kept_targets: Vec<&str> = kept_roots.iter().map(|(_, target)| target.as_str()).collect();
+        let kept_closure: HashSet<String> = if kept_targets.is_empty() {
+            HashSet::new()
+        } else {
+            let mut args = vec!["--query", "--requisites"];
+            args.extend(kept_targets);
+            self.run_nix_tool("nix-store", &args)?
+                .lines()
+                .map(str::to_string)
+                .collect()
+        };
+
+        let dead_output = self.run_nix_tool("nix-store", &["--gc", "--print-dead"])?;
+        let dead_paths: Vec<&str> = dead_output.lines().filter(|l| !l.is_empty()).collect();
+        let dead = if dead_paths.is_empty() {
+            Vec::new()
+        } else {
+            let mut args = vec!["path-info", "--json"];
+            args.extend(dead_paths);
+            self.path_infos(&args)?
+        };
+
+        Ok(compute_plan(
+            params.older_than_days,
+            profiles,
+            removed,
+            &removed_closure,
+            &kept_roots,
+            &kept_closure,
+            &dead,
+        ))
+    }
+
+    /// Collect garbage, provided the caller confirms a plan that still matches
+    pub async fn collect_garbage(&self, params: &CollectGarbageParams) -> Result<GcResult, SystemAnalyzerError> {
+        let plan = self
+            .plan_garbage_collection(&GcPlanParams { older_than_days: params.older_than_days })
+            .await?;
+        if plan.plan_id != params.plan_id {
+            return Err(SystemAnalyzerError::ConfirmationRequired(format!(
+                "plan {} does not match the current plan {}, review it with plan_garbage_collection",
+                params.plan_id, plan.plan_id
+            )));
+        }
+
+        // Delete exactly the reviewed generations, then collect
+        let mut output = String::new();
+        for (profile, numbers) in generations_by_profile(&plan.removed_generations) {
+            let mut args = vec!["-p", profile, "--delete-generations"];
+            args.extend(numbers.iter().map(String::as_str));
+            output.push_str(&self.run_nix_tool("nix-env", &args)?);
+            output.push('\n');
+        }
+        output.push_str(&self.run_nix_tool("nix-store", &["--gc"])?);
+        Ok(GcResult { plan_id: plan.plan_id, output: output.trim().to_string() })
+    }
+
+    /// Build an installable, reporting progress to `progress_token` if the
+    /// client asked for it
+    pub async fn nix_build(
+        &self,
+        params: &BuildParams,
+        progress_token: Option<Value>,
+    ) -> Result<BuildResult, SystemAnalyzerError> {
+        let max_log_lines = params.max_log_lines.unwrap_or(DEFAULT_LOG_LINES);
+        let args = ["build", "--json", "--no-link", params.installable.as_str()];
+        let (success, stdout, tracker) = stream_build(&self.info.nix_cmd, &args, max_log_lines, |progress| {
+            if let Some(token) = &progress_token {
+                send_progress(token, progress);
+            }
+        })
+        .await?;
+
+        let outputs: Vec<BuiltDerivation> = if success {
+            serde_json::from_str(stdout.trim()).map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?
+        } else {
+            Vec::new()
+        };
+        Ok(tracker.finish(success, outputs))
+    }
+
+    /// Build the checks of a flake, reporting the status of each one
+    pub async fn flake_check(
+        &self,
+        params: &FlakeCheckParams,
+        progress_token: Option<Value>,
+    ) -> Result<FlakeCheckReport, SystemAnalyzerError> {
+        let system = match &params.system {
+            Some(system) => system.clone(),
+            None => self.current_system()?,
+        };
+        let attr = format!("{}#checks.{}", params.flake, system);
+        let output = self.run_nix_command(&["eval", "--json", &attr, "--apply", CHECK_DRV_PATHS])?;
+        let available: BTreeMap<String, String> = serde_json::from_str(&output)
+            .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
+        let checks = select_checks(available, params.checks.as_deref()).map_err(|unknown| {
+            SystemAnalyzerError::NixCommandError(format!("{} has no checks named {}", attr, unknown.join(", ")))
+        })?;
+
+        let installables: Vec<String> = checks.keys().map(|name| format!("{}.{}", attr, name)).collect();
+        let mut args = vec!["build", "--json", "--no-link", "--keep-going"];
+        args.extend(installables.iter().map(String::as_str));
+        let max_log_lines = params.max_log_lines.unwrap_or(DEFAULT_LOG_LINES);
+        let (success, _, tracker) = stream_build(&self.info.nix_cmd, &args, max_log_lines, |progress| {
+            if let Some(token) = &progress_token {
+                send_progress(token, progress);
+            }
+        })
+        .await?;
+
+        Ok(check_report(&params.flake, &system, &checks, tracker.finish(success, Vec::new())))
+    }
+
+    /// Evaluate a Nix expression in pure, restricted mode within resource
+    /// limits, optionally with a locked flake in scope
+    pub async fn eval(&self, params: &EvalParams) -> Result<EvalResult, SystemAnalyzerError> {
+        let expression = match &params.flake {
+            Some(flake) => {
+                let metadata = self.flake_metadata(flake)?;
+                let locked_url = locked_url(&metadata)?;
+                let system = match &params.system {
+                    Some(system) => system.clone(),
+                    None => self.current_system()?,
+                };
+                flake_expression(&params.expression, locked_url, &system)
+            }
+            None => params.expression.clone(),
+        };
+        evaluate(&self.info.nix_cmd, &expression, EvalLimits::from_params(params)).await
+    }
+
+    /// Explain where the value of an option of a NixOS configuration comes from
+    pub async fn explain_option(&self, params: &ExplainOptionParams) -> Result<OptionExplanation, SystemAnalyzerError> {
+        let path = option_path(&params.option)?;
+        let metadata = self.flake_metadata(&params.flake)?;
+        let expression = explain_expression(locked_url(&metadata)?, &params.host, &path);
+        let limits = EvalLimits::from_params(&EvalParams {
+            timeout_secs: Some(EXPLAIN_TIMEOUT_SECS),
+            ..Default::default()
+        });
+        let result = evaluate(&self.info.nix_cmd, &expression, limits).await?;
+        match (result.value, result.error) {
+            (Some(value), _) => parse_explanation(&params.host, &params.option, &value),
+            (None, Some(error)) => {
+                let command = [self.info.nix_cmd.as_str(), "eval", "--json", "--expr", &expression];
+                let command = command.iter().map(|part| part.to_string()).collect();
+                Err(SystemAnalyzerError::NixCommandFailed(Box::new(NixFailure::from_eval(command, error))))
+            }
+            (None, None) => Err(SystemAnalyzerError::NixCommandError("evaluation returned no value".to_string())),
+        }
+    }
+
+    /// List the generations of the Home Manager profile
+    pub async fn list_home_generations(&self, params: &HomeGenerationsParams) -> Result<HomeGenerations, SystemAnalyzerError> {
+        let profile = home_profile(params.profile.as_deref())?;
+        Ok(HomeGenerations {
+            profile: profile.to_string_lossy().to_string(),
+            generations: list_generations(&profile)?,
+        })
+    }
+
+    /// Show the activation package, managed files and packages of a Home
+    /// Manager generation
+    pub async fn show_home_generation(&self, params: &ShowHomeGenerationParams) -> Result<HomeGeneration, SystemAnalyzerError> {
+        let profile = home_profile(params.profile.as_deref())?;
+        let generation = find_generation(&profile, params.generation)?;
+        self.home_generation(generation)
+    }
+
+    /// Compare the packages and managed files of two Home Manager generations
+    pub async fn diff_home_generations(&self, params: &DiffHomeGenerationsParams) -> Result<HomeGenerationDiff, SystemAnalyzerError> {
+        let profile = home_profile(params.profile.as_deref())?;
+        let from = self.home_generation(find_generation(&profile, Some(params.from))?)?;
+        let to = self.home_generation(find_generation(&profile, params.to)?)?;
+        let (files_added, files_removed, files_changed) = diff_files(&from.files, &to.files);
+        Ok(HomeGenerationDiff {
+            from: from.generation.number,
+            to: to.generation.number,
+            packages: diff_packages(&from.packages, &to.packages),
+            files_added,
+            files_removed,
+            files_changed,
         })
     }
+
+    fn home_generation(&self, generation: Generation) -> Result<HomeGeneration, SystemAnalyzerError> {
+        let activation = Path::new(&generation.target).to_path_buf();
+        let home_manager_version = std::fs::read_to_string(activation.join("hm-version"))
+            .ok()
+            .map(|version| version.trim().to_string());
+        let files = list_home_files(&activation.join("home-files"))?;
+
+        let home_path = activation.join("home-path");
+        let home_path = std::fs::read_link(&home_path).unwrap_or(home_path).to_string_lossy().to_string();
+        let mut packages: Vec<HomePackage> = self
+            .path_infos(&["path-info", "--json", &home_path])?
+            .into_iter()
+            .flat_map(|info| info.references)
+            .filter(|reference| *reference != home_path)
+            .map(|reference| HomePackage::from_store_path(&reference))
+            .collect();
+        packages.sort();
+
+        Ok(HomeGeneration {
+            activation_package: generation.target.clone(),
+            generation,
+            home_manager_version,
+            files,
+            packages,
+        })
+    }
+
+    /// List the user's profiles and their elements, flagging elements built
+    /// from a different nixpkgs than the system
+    pub async fn list_user_profiles(&self, params: &UserProfilesParams) -> Result<UserProfilesReport, SystemAnalyzerError> {
+        let system_nixpkgs_rev = self.system_nixpkgs_rev();
+
+        let profiles = match &params.profile {
+            Some(profile) => vec![PathBuf::from(profile)],
+            None => {
+                let home = std::env::var_os("HOME").map(PathBuf::from);
+                let user = std::env::var("USER").ok();
+                let mut profiles = Vec::new();
+                for dir in user_profile_dirs(home.as_deref(), user.as_deref()) {
+                    if dir.is_dir() {
+                        profiles.extend(find_profiles(&dir)?);
+                    }
+                }
+                profiles
+            }
+        };
+
+        let mut report = UserProfilesReport {
+            system_nixpkgs_rev,
+            profiles: Vec::new(),
+        };
+        let mut seen = HashSet::new();
+        for profile in profiles {
+            let name = profile.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            if SKIPPED_PROFILES.contains(&name.as_str()) || !seen.insert(std::fs::canonicalize(&profile)?) {
+                continue;
+            }
+
+            let manifest = profile.join("manifest.json");
+            let (kind, mut elements) = if manifest.exists() {
+                let value: Value = serde_json::from_slice(&std::fs::read(&manifest)?)
+                    .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
+                (ProfileKind::NixProfile, parse_manifest(&value)?)
+            } else {
+                // nix-env profiles only record their packages as references
+                let target = std::fs::canonicalize(&profile)?.to_string_lossy().to_string();
+                let elements = self
+                    .path_infos(&["path-info", "--json", &target])?
+                    .into_iter()
+                    .flat_map(|info| info.references)
+                    .filter(|reference| *reference != target)
+                    .map(|reference| ProfileElement {
+                        name: store_path_name(&reference).to_string(),
+                        active: true,
+                        attr_path: None,
+                        original_url: None,
+                        url: None,
+                        priority: None,
+                        store_paths: vec![reference],
+                        nixpkgs_rev: None,
+                        outdated: None,
+                    })
+                    .collect();
+                (ProfileKind::NixEnv, elements)
+            };
+
+            if params.check_inputs {
+                for element in elements.iter_mut().filter(|e| e.nixpkgs_rev.is_none()) {
+                    let Some(url) = &element.url else { continue };
+                    if let Ok(input) = self.flake_metadata(url).and_then(|m| locked_input(&m, "nixpkgs")) {
+                        element.nixpkgs_rev = input.rev;
+                    }
+                }
+            }
+            flag_outdated(&mut elements, report.system_nixpkgs_rev.as_deref());
+
+            report.profiles.push(UserProfile {
+                name,
+                path: profile.to_string_lossy().to_string(),
+                kind,
+                elements,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Match the closure of an installable or system generation against a
+    /// local NVD dataset, skipping CVEs fixed by patches or whitelisted
+    pub async fn scan_vulnerabilities(&self, params: &VulnerabilityScanParams) -> Result<VulnerabilityReport, SystemAnalyzerError> {
+        let target = match (&params.installable, params.generation) {
+            (Some(installable), _) => installable.clone(),
+            (None, Some(generation)) => format!("{}-{}-link", SYSTEM_PROFILE, generation),
+            (None, None) => CURRENT_SYSTEM.to_string(),
+        };
+        let database = match &params.database {
+            Some(database) => PathBuf::from(database),
+            None => self.state_dir.join(ADVISORY_DIR),
+        };
+        let database = AdvisoryDatabase::load(&database)?;
+        let whitelist = match &params.whitelist {
+            Some(whitelist) => Whitelist::load(Path::new(whitelist))?,
+            None => {
+                let default = self.state_dir.join(WHITELIST_FILE);
+                if default.exists() {
+                    Whitelist::load(&default)?
+                } else {
+                    Whitelist::default()
+                }
+            }
+        };
+
+        let infos = self.path_infos(&["path-info", "--json", "--recursive", &target])?;
+        let mut packages = closure_packages(&infos, |deriver| read_derivation(Path::new(deriver)).ok());
+
+        // Packages nixpkgs marks insecure, from the same index as licenses
+        if let Some((_, index)) = self.metadata_index(&target, params.metadata_flake.as_deref(), &params.input).await {
+            for package in &mut packages {
+                if let Some(indexed) = index.find(&package.pname, Some(&package.version)).filter(|p| p.version == package.version) {
+                    package.known_vulnerabilities = indexed.known_vulnerabilities.clone();
+                }
+            }
+        }
+
+        // Only installables carry meta; store paths and generations do not
+        let known_vulnerabilities = match &params.installable {
+            Some(installable) if !installable.starts_with('/') => self
+                .run_nix_command(&["eval", "--json", installable, "--apply", "p: p.meta.knownVulnerabilities or [ ]"])
+                .ok()
+                .and_then(|output| serde_json::from_str(&output).ok())
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+
+        Ok(scan(&target, &packages, &database, &whitelist, known_vulnerabilities, &today()))
+    }
+
+    /// Describe the runtime or build closure of an installable as a
+    /// CycloneDX or SPDX document. Licenses come from the package index of
+    /// the nixpkgs the installable's flake pins.
+    pub async fn export_sbom(&self, params: &SbomParams) -> Result<Value, SystemAnalyzerError> {
+        let mut args = vec!["path-info"];
+        if params.build_closure {
+            args.push("--derivation");
+        }
+        let roots: Vec<String> = self
+            .run_nix_command(&[args.as_slice(), &[&params.installable]].concat())?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect();
+        let infos = self.path_infos(&[args.as_slice(), &["--json", "--recursive", &params.installable]].concat())?;
+
+        let index = self
+            .metadata_index(&params.installable, params.metadata_flake.as_deref(), &params.input)
+            .await;
+        let components = sbom_components(&infos, |name, version| {
+            index
+                .as_ref()
+                .and_then(|(_, index)| index.find(name, version))
+                .map(|package| package.license.clone())
+                .unwrap_or_default()
+        });
+
+        let now = unix_now();
+        let id = uuid::Uuid::new_v4().to_string();
+        Ok(match params.format {
+            SbomFormat::CycloneDx => cyclonedx(&roots, &components, &id, &utc_timestamp(now)),
+            SbomFormat::Spdx => spdx(&params.installable, &roots, &components, &id, &utc_timestamp(now)),
+        })
+    }
+
+    /// Check the licenses of the packages in an installable's runtime
+    /// closure against a policy, with the chain that pulled in each offender
+    pub async fn audit_licenses(&self, params: &LicenseAuditParams) -> Result<LicenseAudit, SystemAnalyzerError> {
+        let policy = match &params.policy {
+            Some(policy) => LicensePolicy::load(Path::new(policy))?,
+            None => {
+                let default = self.state_dir.join(POLICY_FILE);
+                if default.exists() {
+                    LicensePolicy::load(&default)?
+                } else {
+                    LicensePolicy::default()
+                }
+            }
+        };
+
+        let roots = self
+            .run_nix_command(&["path-info", &params.installable])?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect();
+        let infos = self.path_infos(&["path-info", "--json", "--recursive", &params.installable])?;
+        let graph = DependencyGraph::from_path_info(roots, &infos);
+        let packages = closure_packages(&infos, |_| None);
+
+        let index = self
+            .metadata_index(&params.installable, params.metadata_flake.as_deref(), &params.input)
+            .await;
+        let mut audit = audit_licenses(&params.installable, &packages, &graph, &policy, |name, version| {
+            let (_, index) = index.as_ref()?;
+            index.find(name, Some(version)).map(|package| (package.license.clone(), package.unfree))
+        });
+        audit.license_source = index.map(|(source, _)| source);
+        Ok(audit)
+    }
+
+    /// Package index providing `meta` for the packages of an installable:
+    /// that of the given flake, or else of the installable's own flake.
+    /// Installables without a flake, and flakes that cannot be indexed, have none.
+    async fn metadata_index(&self, installable: &str, flake: Option<&str>, input: &str) -> Option<(LockedInput, Arc<PackageIndex>)> {
+        let flake = match flake {
+            Some(flake) => flake.to_string(),
+            None => match installable.split_once('#')? {
+                ("", _) => ".".to_string(),
+                (flake, _) => flake.to_string(),
+            },
+        };
+        match self.package_index(&flake, input, false).await {
+            Ok(index) => Some(index),
+            Err(e) => {
+                log::warn!("No package metadata from {}: {}", flake, e);
+                None
+            }
+        }
+    }
+
+    /// Revision of the nixpkgs the running system was built from
+    fn system_nixpkgs_rev(&self) -> Option<String> {
+        self.run_command("nixos-version", &["--json"])
+            .ok()
+            .and_then(|output| serde_json::from_str::<Value>(&output).ok())
+            .and_then(|version| version.get("nixpkgsRevision")?.as_str().map(str::to_string))
+            .or_else(|| {
+                let registry: Value = serde_json::from_slice(&std::fs::read(SYSTEM_REGISTRY).ok()?).ok()?;
+                registry_nixpkgs_rev(&registry)
+            })
+    }
+
+    /// Search the packages of the nixpkgs revision a flake pins. The index is
+    /// built by evaluating nixpkgs once and persisted in the state directory.
+    pub async fn search_packages(&self, params: &PackageSearchParams) -> Result<PackageSearchResult, SystemAnalyzerError> {
+        let (source, index) = self.package_index(&params.flake, &params.input, params.rebuild).await?;
+        Ok(PackageSearchResult {
+            source,
+            total_packages: index.packages.len(),
+            matches: index.search(&params.query, params.limit.unwrap_or(DEFAULT_LIMIT)),
+        })
+    }
+
+    /// Package index of a flake input, from memory, the state directory, or
+    /// by evaluating the input with `nix-env`
+    async fn package_index(&self, flake: &str, input: &str, rebuild: bool) -> Result<(LockedInput, Arc<PackageIndex>), SystemAnalyzerError> {
+        let metadata = self.flake_metadata(flake)?;
+        let source = locked_input(&metadata, input)?;
+        let key = source.cache_key();
+
+        let mut indexes = self.package_indexes.lock().await;
+        if let Some(index) = indexes.get(&key).filter(|_| !rebuild) {
+            return Ok((source, index.clone()));
+        }
+        let path = PackageIndex::path(&self.state_dir, &source);
+        let loaded = if rebuild { None } else { PackageIndex::load(&path)? };
+        let index = match loaded {
+            Some(index) => index,
+            None => {
+                let expression = input_path_expression(locked_url(&metadata)?, input);
+                let nixpkgs = self.run_nix_command(&["eval", "--raw", "--expr", &expression])?;
+                let output = self.run_nix_tool("nix-env", &["-f", &nixpkgs, "-qaP", "--json", "--meta"])?;
+                let value: Value = serde_json::from_str(&output)
+                    .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
+                let now = unix_now();
+                let index = PackageIndex::new(source.clone(), now, parse_nix_env(&value)?);
+                index.save(&path)?;
+                index
+            }
+        };
+        let index = Arc::new(index);
+        indexes.insert(key, index.clone());
+        Ok((source, index))
+    }
+
+    /// Fetch the build log of a derivation with a failure summary. Logs are
+    /// read from the log directory when possible and from `nix log` otherwise.
+    pub async fn get_build_log(&self, params: &BuildLogParams) -> Result<BuildLog, SystemAnalyzerError> {
+        let log_file = log_file_path(Path::new(LOG_DIR), &params.installable).filter(|path| path.is_file());
+        let (source, log) = match log_file {
+            Some(path) => (LogSource::LogDir, read_log_file(&path)?),
+            None => (LogSource::NixLog, self.run_nix_command(&["log", &params.installable])?),
+        };
+
+        let (lines, truncated) = select_lines(&log, params)?;
+        Ok(BuildLog {
+            installable: params.installable.clone(),
+            source,
+            total_lines: log.lines().count(),
+            lines,
+            truncated,
+            summary: summarize_failure(&log),
+        })
+    }
+
+    fn current_system(&self) -> Result<String, SystemAnalyzerError> {
+        self.run_nix_command(&["eval", "--impure", "--raw", "--expr", "builtins.currentSystem"])
+    }
+
+    /// Loads the options index of a locked input, generating `options.json`
+    /// for it on first use
+    async fn option_index(&self, source: &OptionSource) -> Result<Arc<OptionIndex>, SystemAnalyzerError> {
+        let metadata = self.flake_metadata(&source.flake)?;
+        let input = locked_input(&metadata, source.input())?;
+        let path = OptionIndex::path(&self.state_dir, source.set, &input);
+        let key = path.to_string_lossy().to_string();
+
+        let mut indexes = self.option_indexes.lock().await;
+        if let Some(index) = indexes.get(&key).filter(|_| !source.rebuild) {
+            return Ok(index.clone());
+        }
+        let loaded = if source.rebuild { None } else { OptionIndex::load(&path)? };
+        let index = match loaded {
+            Some(index) => index,
+            None => {
+                let system = match &source.system {
+                    Some(system) => system.clone(),
+                    None => self.current_system()?,
+                };
+                let expression = source.set.docs_expression(locked_url(&metadata)?, source.input(), &system);
+                let docs = self.run_nix_command(&["build", "--no-link", "--print-out-paths", "--expr", &expression])?;
+                let options_json = Path::new(docs.lines().next().unwrap_or_default()).join(source.set.options_json());
+                let value: Value = serde_json::from_slice(&std::fs::read(&options_json)?)
+                    .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
+                let now = unix_now();
+                let index = OptionIndex::new(source.set, input, now, parse_options_json(&value)?);
+                index.save(&path)?;
+                index
+            }
+        };
+        let index = Arc::new(index);
+        indexes.insert(key, index.clone());
+        Ok(index)
+    }
+
+    /// Search NixOS or Home Manager options of the locked inputs of a flake
+    pub async fn search_options(&self, params: &OptionSearchParams) -> Result<OptionSearchResult, SystemAnalyzerError> {
+        let index = self.option_index(&params.source).await?;
+        Ok(OptionSearchResult {
+            source: index.source.clone(),
+            total_options: index.options.len(),
+            matches: index.search(&params.query, params.limit.unwrap_or(OPTIONS_LIMIT)),
+        })
+    }
+
+    /// Look up a single option by its full name
+    pub async fn get_option(&self, params: &GetOptionParams) -> Result<OptionLookup, SystemAnalyzerError> {
+        let index = self.option_index(&params.source).await?;
+        let option = index.get(&params.name).cloned();
+        let suggestions = match option {
+            Some(_) => Vec::new(),
+            None => index.suggestions(&params.name, OPTIONS_LIMIT),
+        };
+        Ok(OptionLookup {
+            source: index.source.clone(),
+            option,
+            suggestions,
+        })
+    }
+
+    /// List the attributes directly below an option prefix
+    pub async fn browse_options(&self, params: &BrowseOptionsParams) -> Result<OptionBrowse, SystemAnalyzerError> {
+        let index = self.option_index(&params.source).await?;
+        Ok(OptionBrowse {
+            source: index.source.clone(),
+            prefix: params.prefix.clone(),
+            children: index.browse(&params.prefix),
+        })
+    }
+
+    fn flake_metadata(&self, flake: &str) -> Result<Value, SystemAnalyzerError> {
+        serde_json::from_str(&self.cached_nix_command(flake, &["flake", "metadata", "--json", flake])?)
+            .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))
+    }
+
+    fn path_infos(&self, args: &[&str]) -> Result<Vec<PathInfo>, SystemAnalyzerError> {
+        let output = self.run_nix_command(args)?;
+        let value: Value = serde_json::from_str(&output)
+            .map_err(|e| SystemAnalyzerError::ParseError(e.to_string()))?;
+        parse_path_info(&value)
+    }
+
+    fn store_dir(&self) -> &str {
+        &self.info.store_path
+    }
+}
+
+/// Asks nix for its store directory, which need not be `/nix/store`
+fn read_store_dir(nix_cmd: &str) -> Result<String, SystemAnalyzerError> {
+    let args = ["eval", "--raw", "--expr", "builtins.storeDir"];
+    let output = Command::new(nix_cmd)
+        .args(args)
+        .output()
+        .map_err(|e| SystemAnalyzerError::NixCommandError(e.to_string()))?;
+    if !output.status.success() {
+        return Err(SystemAnalyzerError::NixCommandFailed(Box::new(NixFailure::new(
+            nix_cmd,
+            &args,
+            output.status.code(),
+            &output.stderr,
+        ))));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
-#[derive(Debug, Deserialize)]
-struct InitializeParams {
-    #[serde(default)]
-    capabilities: Value,
-    implementation: Option<Implementation>,
+/// Extracts `_meta.progressToken` from request parameters
+fn progress_token(params: &Option<Value>) -> Option<Value> {
+    params.as_ref()?.get("_meta")?.get("progressToken").cloned()
+}
+
+/// Sends a `notifications/progress` message for a pending request
+fn send_progress(token: &Value, progress: Progress) {
+    let mut params = json!({ "progressToken": token, "progress": progress.progress });
+    if let Some(total) = progress.total {
+        params["total"] = json!(total);
+    }
+    if let Some(message) = progress.message {
+        params["message"] = json!(message);
+    }
+    let notification = Notification::new("notifications/progress", Some(params));
+    if let Err(e) = StdioTransport::notify(&notification) {
+        log::warn!("Failed to send progress notification: {}", e);
+    }
+}
+
+/// Resolves the Home Manager profile, looking in the usual places unless
+/// the caller names one
+fn home_profile(profile: Option<&str>) -> Result<PathBuf, SystemAnalyzerError> {
+    if let Some(profile) = profile {
+        return Ok(PathBuf::from(profile));
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let user = std::env::var("USER").ok();
+    let candidates = home_profile_candidates(home.as_deref(), user.as_deref());
+    candidates
+        .iter()
+        .find(|candidate| candidate.exists())
+        .cloned()
+        .ok_or_else(|| {
+            let searched: Vec<String> = candidates.iter().map(|c| c.to_string_lossy().to_string()).collect();
+            SystemAnalyzerError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no Home Manager profile found, looked in {}", searched.join(", ")),
+            ))
+        })
+}
+
+/// Finds a generation by number, or the current one
+fn find_generation(profile: &Path, number: Option<u64>) -> Result<Generation, SystemAnalyzerError> {
+    list_generations(profile)?
+        .into_iter()
+        .find(|generation| match number {
+            Some(number) => generation.number == number,
+            None => generation.current,
+        })
+        .ok_or_else(|| {
+            let which = number.map(|n| format!("generation {}", n)).unwrap_or_else(|| "current generation".to_string());
+            SystemAnalyzerError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} has no {}", profile.display(), which),
+            ))
+        })
+}
+
+/// Deserializes method parameters, reporting failures as invalid params
+fn parse_params<T: DeserializeOwned>(params: Option<Value>) -> Result<T, McpError> {
+    serde_json::from_value(params.unwrap_or_else(|| json!({})))
+        .map_err(|e| mcp_error(ErrorKind::InvalidParams, e.to_string(), Value::Null))
 }
 
 #[async_trait]
@@ -320,9 +1385,16 @@ impl ServerHandler for SystemAnalyzer {
         _implementation: Implementation,
         _capabilities: ClientCapabilities,
     ) -> Result<ServerCapabilities, McpError> {
+        self.stdio_session();
         let mut custom = HashMap::new();
         custom.insert("supports_nix_flakes".to_string(), json!(true));
         custom.insert("supports_nix_packages".to_string(), json!(true));
+        match self.start_watcher() {
+            Ok(()) => {
+                custom.insert("resources".to_string(), json!({ "subscribe": true, "listChanged": true }));
+            }
+            Err(e) => log::warn!("Failed to start file watcher: {}", e),
+        }
         
         Ok(ServerCapabilities {
             custom: Some(custom)
@@ -341,6 +1413,18 @@ impl ServerHandler for SystemAnalyzer {
         if !self.is_initialized() && method != "initialize" {
             return Err(McpError::protocol(ErrorCode::ServerNotInitialized, "Server not initialized".to_string()));
         }
+        self.call(self.stdio_session(), method, params).await
+    }
+}
+
+impl SystemAnalyzer {
+    /// Runs a tool the way the server does once a client has initialized,
+    /// checking the access policy and limits first. The command line calls
+    /// tools through this as well so both give the same output.
+    pub async fn call(&self, session: &Session, method: &str, params: Option<Value>) -> Result<Value, McpError> {
+        self.authorize(session, method, params.as_ref())?;
+        self.check_rate(session, method)?;
+        let _slot = self.nix_slot()?;
 
         match method {
             "get_system_info" => {
@@ -353,7 +1437,7 @@ impl ServerHandler for SystemAnalyzer {
             }
             "generate_flake_graph" => {
                 let params: Option<HashMap<String, String>> = params
-                    .map(|v| serde_json::from_value(v))
+                    .map(serde_json::from_value)
                     .transpose()?;
                 
                 let format_str = params
@@ -364,10 +1448,221 @@ impl ServerHandler for SystemAnalyzer {
                 Ok(serde_json::to_value(graph)?)
             }
             "get_dependency_graph" => {
-                let graph = self.get_dependency_graph().await?;
+                let params: HashMap<String, String> = parse_params(params)?;
+                let installable = params.get("installable").map(String::as_str).unwrap_or(".");
+                let graph = self.get_dependency_graph(installable).await?;
                 Ok(serde_json::to_value(graph)?)
             }
-            _ => Err(McpError::protocol(ErrorCode::MethodNotFound, format!("Method {} not found", method))),
+            "why_depends" => {
+                let params: WhyDependsParams = parse_params(params)?;
+                let explanation = self.why_depends(&params).await?;
+                Ok(serde_json::to_value(explanation)?)
+            }
+            "analyze_closure_size" => {
+                let params: ClosureSizeParams = parse_params(params)?;
+                let report = self.analyze_closure_size(&params).await?;
+                Ok(serde_json::to_value(report)?)
+            }
+            "diff_derivations" => {
+                let params: DiffDerivationsParams = parse_params(params)?;
+                let diff = self.diff_derivations(&params).await?;
+                Ok(serde_json::to_value(diff)?)
+            }
+            "list_gc_roots" => {
+                let params: GcRootsParams = parse_params(params)?;
+                let report = self.list_gc_roots(&params).await?;
+                Ok(serde_json::to_value(report)?)
+            }
+            "plan_garbage_collection" => {
+                let params: GcPlanParams = parse_params(params)?;
+                let plan = self.plan_garbage_collection(&params).await?;
+                Ok(serde_json::to_value(plan)?)
+            }
+            "collect_garbage" => {
+                let params: CollectGarbageParams = parse_params(params)?;
+                let result = self.collect_garbage(&params).await?;
+                Ok(serde_json::to_value(result)?)
+            }
+            "nix_build" => {
+                let token = progress_token(&params);
+                let params: BuildParams = parse_params(params)?;
+                let result = self.nix_build(&params, token).await?;
+                Ok(serde_json::to_value(result)?)
+            }
+            "flake_check" => {
+                let token = progress_token(&params);
+                let params: FlakeCheckParams = parse_params(params)?;
+                let report = self.flake_check(&params, token).await?;
+                Ok(serde_json::to_value(report)?)
+            }
+            "eval" => {
+                let params: EvalParams = parse_params(params)?;
+                let result = self.eval(&params).await?;
+                Ok(serde_json::to_value(result)?)
+            }
+            "explain_option" => {
+                let params: ExplainOptionParams = parse_params(params)?;
+                let explanation = self.explain_option(&params).await?;
+                Ok(serde_json::to_value(explanation)?)
+            }
+            "list_home_generations" => {
+                let params: HomeGenerationsParams = parse_params(params)?;
+                let generations = self.list_home_generations(&params).await?;
+                Ok(serde_json::to_value(generations)?)
+            }
+            "show_home_generation" => {
+                let params: ShowHomeGenerationParams = parse_params(params)?;
+                let generation = self.show_home_generation(&params).await?;
+                Ok(serde_json::to_value(generation)?)
+            }
+            "diff_home_generations" => {
+                let params: DiffHomeGenerationsParams = parse_params(params)?;
+                let diff = self.diff_home_generations(&params).await?;
+                Ok(serde_json::to_value(diff)?)
+            }
+            "list_user_profiles" => {
+                let params: UserProfilesParams = parse_params(params)?;
+                let report = self.list_user_profiles(&params).await?;
+                Ok(serde_json::to_value(report)?)
+            }
+            "scan_vulnerabilities" => {
+                let params: VulnerabilityScanParams = parse_params(params)?;
+                let report = self.scan_vulnerabilities(&params).await?;
+                Ok(serde_json::to_value(report)?)
+            }
+            "export_sbom" => {
+                let params: SbomParams = parse_params(params)?;
+                let sbom = self.export_sbom(&params).await?;
+                Ok(sbom)
+            }
+            "audit_licenses" => {
+                let params: LicenseAuditParams = parse_params(params)?;
+                let audit = self.audit_licenses(&params).await?;
+                Ok(serde_json::to_value(audit)?)
+            }
+            "invalidate_cache" => {
+                let params: InvalidateCacheParams = parse_params(params)?;
+                let invalidation = self.invalidate_cache(&params).await?;
+                Ok(serde_json::to_value(invalidation)?)
+            }
+            "cache_stats" => {
+                let stats = self.cache_stats().await?;
+                Ok(serde_json::to_value(stats)?)
+            }
+            "whoami" => Ok(json!({ "session": session.id, "identity": session.identity })),
+            "resources/list" => {
+                let resources = self.list_resources().await?;
+                Ok(serde_json::to_value(resources)?)
+            }
+            "resources/read" => {
+                let params: ResourceParams = parse_params(params)?;
+                Ok(self.read_resource(&params).await?)
+            }
+            "resources/subscribe" => {
+                let params: ResourceParams = parse_params(params)?;
+                self.watcher()?.subscribe(&params.uri);
+                Ok(json!({}))
+            }
+            "resources/unsubscribe" => {
+                let params: ResourceParams = parse_params(params)?;
+                self.watcher()?.unsubscribe(&params.uri);
+                Ok(json!({}))
+            }
+            "watch_flake" => {
+                let params: WatchFlakeParams = parse_params(params)?;
+                Ok(self.watch_flake(&params).await?)
+            }
+            "unwatch_flake" => {
+                let params: WatchFlakeParams = parse_params(params)?;
+                Ok(self.unwatch_flake(&params).await?)
+            }
+            "search_packages" => {
+                let params: PackageSearchParams = parse_params(params)?;
+                let result = self.search_packages(&params).await?;
+                Ok(serde_json::to_value(result)?)
+            }
+            "search_options" => {
+                let params: OptionSearchParams = parse_params(params)?;
+                let result = self.search_options(&params).await?;
+                Ok(serde_json::to_value(result)?)
+            }
+            "get_option" => {
+                let params: GetOptionParams = parse_params(params)?;
+                let lookup = self.get_option(&params).await?;
+                Ok(serde_json::to_value(lookup)?)
+            }
+            "browse_options" => {
+                let params: BrowseOptionsParams = parse_params(params)?;
+                let browse = self.browse_options(&params).await?;
+                Ok(serde_json::to_value(browse)?)
+            }
+            "get_build_log" => {
+                let params: BuildLogParams = parse_params(params)?;
+                let log = self.get_build_log(&params).await?;
+                Ok(serde_json::to_value(log)?)
+            }
+            _ => Err(mcp_error(ErrorKind::MethodNotFound, format!("Method {} not found", method), Value::Null)),
+        }
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    const TOKENS: &str = r#"
+[roles.reader]
+tools = ["cache_stats"]
+
+[[tokens]]
+client = "assistant"
+role = "reader"
+token = "assistant-secret"
+"#;
+
+    /// An analyzer whose `nix` only answers the questions asked at startup
+    fn analyzer(dir: &Path) -> SystemAnalyzer {
+        let nix = dir.join("nix");
+        fs::write(&nix, "#!/bin/sh\ncase \"$1\" in\n--version) echo 'nix (Nix) 2.24.0' ;;\n*) printf /nix/store ;;\nesac\n").unwrap();
+        fs::set_permissions(&nix, fs::Permissions::from_mode(0o755)).unwrap();
+        SystemAnalyzer::with_settings(AnalyzerSettings {
+            nix_path: Some(nix.to_string_lossy().to_string()),
+            state_dir: dir.to_path_buf(),
+            cache: None,
+            policy: AccessPolicy::unrestricted(),
+            authenticator: Some(Authenticator::parse(TOKENS).unwrap()),
+            rate_limits: RateLimitConfig::default(),
+        })
+        .unwrap()
+    }
+
+    fn error_kind(err: McpError) -> Value {
+        match err {
+            McpError::Protocol { data, .. } => data.unwrap()["kind"].clone(),
+            other => panic!("not a protocol error: {:?}", other),
         }
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_call_in_authenticated_session() {
+        let tmp = tempfile::tempdir().unwrap();
+        let analyzer = analyzer(tmp.path());
+        assert_eq!(analyzer.store_dir(), "/nix/store");
+
+        assert!(matches!(analyzer.start_session(None), Err(SystemAnalyzerError::Unauthenticated(_))));
+        assert!(analyzer.start_session(Some("Bearer wrong")).is_err());
+
+        let session = analyzer.start_session(Some("Bearer assistant-secret")).unwrap();
+        assert_eq!(session.identity.role.as_deref(), Some("reader"));
+        assert!(analyzer.call(&session, "cache_stats", None).await.is_ok());
+        let err = analyzer.call(&session, "eval", Some(json!({ "expression": "1" }))).await.unwrap_err();
+        assert_eq!(error_kind(err), "AccessDenied");
+
+        let audit = fs::read_to_string(tmp.path().join(AUDIT_LOG)).unwrap();
+        let entries: Vec<Value> = audit.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2]["client"], "assistant");
+        assert_eq!(entries[2]["tool"], "eval");
+    }
+}
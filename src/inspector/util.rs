@@ -0,0 +1,66 @@
+//! Small helpers shared by the tools
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Flake the tools work on unless given one, the current directory
+pub fn default_flake() -> String {
+    ".".to_string()
+}
+
+/// Flake input providing nixpkgs unless given one
+pub fn default_input() -> String {
+    "nixpkgs".to_string()
+}
+
+/// Seconds since the epoch
+pub fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+/// Formats seconds since the epoch as an RFC 3339 UTC timestamp
+pub fn utc_timestamp(secs: i64) -> String {
+    let (days, time) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`
+pub fn today() -> String {
+    utc_timestamp(unix_now())[..10].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(951_827_696), "2000-02-29T12:34:56Z");
+        assert_eq!(utc_timestamp(1_735_689_599), "2024-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn test_today() {
+        let today = today();
+        assert_eq!(today.len(), 10);
+        assert!(today.as_str() > "2024-01-01");
+    }
+}
@@ -0,0 +1,803 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use super::closure::PathInfo;
+use super::derivation::Derivation;
+use super::store::{parse_drv_name, store_path_name};
+use super::system::SystemAnalyzerError;
+use super::util::default_input;
+
+/// Profile holding the system generations
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// The running system generation
+pub const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// Directory below the state directory holding NVD feeds by default
+pub const ADVISORY_DIR: &str = "advisories";
+
+/// Whitelist below the state directory used when none is given
+pub const WHITELIST_FILE: &str = "vulnix-whitelist.toml";
+
+/// Parameters of the `scan_vulnerabilities` method
+#[derive(Debug, Default, Deserialize)]
+pub struct VulnerabilityScanParams {
+    /// Installable or store path to scan
+    #[serde(default)]
+    pub installable: Option<String>,
+    /// System generation to scan when no installable is given, the running
+    /// system by default
+    #[serde(default)]
+    pub generation: Option<u64>,
+    /// NVD JSON feed, or a directory of feeds
+    #[serde(default)]
+    pub database: Option<String>,
+    /// Whitelist in vulnix's TOML format
+    #[serde(default)]
+    pub whitelist: Option<String>,
+    /// Flake whose nixpkgs supplies `meta.knownVulnerabilities` of the
+    /// closure's packages, the flake of the installable by default
+    #[serde(default)]
+    pub metadata_flake: Option<String>,
+    #[serde(default = "default_input")]
+    pub input: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Unknown,
+    None,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl Severity {
+    fn parse(value: &str) -> Self {
+        match value.to_ascii_uppercase().as_str() {
+            "NONE" => Severity::None,
+            "LOW" => Severity::Low,
+            "MEDIUM" => Severity::Medium,
+            "HIGH" => Severity::High,
+            "CRITICAL" => Severity::Critical,
+            _ => Severity::Unknown,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Unknown => "unknown",
+            Severity::None => "none",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// A product and version range named by a CPE match of an advisory
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AffectedProduct {
+    pub product: String,
+    /// Exact version, `None` for any version within the bounds
+    pub version: Option<String>,
+    pub start_including: Option<String>,
+    pub start_excluding: Option<String>,
+    pub end_including: Option<String>,
+    pub end_excluding: Option<String>,
+}
+
+impl AffectedProduct {
+    fn from_cpe(cpe: &str, bounds: &Value) -> Option<Self> {
+        // cpe:2.3:<part>:<vendor>:<product>:<version>:...
+        let fields: Vec<&str> = cpe.split(':').collect();
+        let product = fields.get(4)?.to_lowercase();
+        let version = fields
+            .get(5)
+            .filter(|version| !matches!(**version, "*" | "-" | ""))
+            .map(|version| version.replace('\\', ""));
+        let bound = |key: &str| bounds.get(key).and_then(Value::as_str).map(str::to_string);
+        Some(Self {
+            product,
+            version,
+            start_including: bound("versionStartIncluding"),
+            start_excluding: bound("versionStartExcluding"),
+            end_including: bound("versionEndIncluding"),
+            end_excluding: bound("versionEndExcluding"),
+        })
+    }
+
+    pub fn affects(&self, version: &str) -> bool {
+        if let Some(exact) = &self.version {
+            return compare_versions(version, exact) == Ordering::Equal;
+        }
+        let within = |bound: &Option<String>, accept: &[Ordering]| {
+            bound.as_deref().is_none_or(|bound| accept.contains(&compare_versions(version, bound)))
+        };
+        within(&self.start_including, &[Ordering::Greater, Ordering::Equal])
+            && within(&self.start_excluding, &[Ordering::Greater])
+            && within(&self.end_including, &[Ordering::Less, Ordering::Equal])
+            && within(&self.end_excluding, &[Ordering::Less])
+    }
+}
+
+/// A CVE entry of the advisory database
+#[derive(Debug, Clone, PartialEq)]
+pub struct Advisory {
+    pub id: String,
+    pub description: Option<String>,
+    pub severity: Severity,
+    pub score: Option<f64>,
+    pub affected: Vec<AffectedProduct>,
+}
+
+/// Advisories loaded from NVD JSON feeds, indexed by product name
+#[derive(Debug, Default)]
+pub struct AdvisoryDatabase {
+    pub advisories: Vec<Advisory>,
+    by_product: HashMap<String, Vec<usize>>,
+}
+
+/// Collects the vulnerable CPE matches of configuration nodes, descending
+/// into child nodes. `key` is `cpe_match` in 1.1 feeds and `cpeMatch` in 2.0.
+fn affected_products(nodes: Option<&Value>, key: &str, uri: &str, products: &mut Vec<AffectedProduct>) {
+    for node in nodes.and_then(Value::as_array).into_iter().flatten() {
+        for cpe_match in node.get(key).and_then(Value::as_array).into_iter().flatten() {
+            if cpe_match.get("vulnerable").and_then(Value::as_bool) == Some(false) {
+                continue;
+            }
+            if let Some(product) = cpe_match
+                .get(uri)
+                .and_then(Value::as_str)
+                .and_then(|cpe| AffectedProduct::from_cpe(cpe, cpe_match))
+            {
+                products.push(product);
+            }
+        }
+        affected_products(node.get("children"), key, uri, products);
+    }
+}
+
+/// English description of a list of `{lang, value}` entries
+fn english(descriptions: Option<&Value>) -> Option<String> {
+    let descriptions = descriptions?.as_array()?;
+    descriptions
+        .iter()
+        .find(|d| d.get("lang").and_then(Value::as_str) == Some("en"))
+        .or_else(|| descriptions.first())?
+        .get("value")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Advisory of a `CVE_Items` entry of a 1.1 feed
+fn parse_nvd_item(item: &Value) -> Option<Advisory> {
+    let cve = item.get("cve")?;
+    let id = cve.get("CVE_data_meta")?.get("ID")?.as_str()?.to_string();
+    let impact = item.get("impact");
+    let v3 = impact.and_then(|i| i.get("baseMetricV3")).and_then(|m| m.get("cvssV3"));
+    let v2 = impact.and_then(|i| i.get("baseMetricV2"));
+    let (severity, score) = match (v3, v2) {
+        (Some(v3), _) => (
+            v3.get("baseSeverity").and_then(Value::as_str).map(Severity::parse),
+            v3.get("baseScore").and_then(Value::as_f64),
+        ),
+        (None, Some(v2)) => (
+            v2.get("severity").and_then(Value::as_str).map(Severity::parse),
+            v2.get("cvssV2").and_then(|c| c.get("baseScore")).and_then(Value::as_f64),
+        ),
+        (None, None) => (None, None),
+    };
+    let mut affected = Vec::new();
+    affected_products(
+        item.get("configurations").and_then(|c| c.get("nodes")),
+        "cpe_match",
+        "cpe23Uri",
+        &mut affected,
+    );
+    Some(Advisory {
+        id,
+        description: english(cve.get("description").and_then(|d| d.get("description_data"))),
+        severity: severity.unwrap_or(Severity::Unknown),
+        score,
+        affected,
+    })
+}
+
+/// Advisory of a `vulnerabilities` entry of the 2.0 API
+fn parse_nvd_vulnerability(item: &Value) -> Option<Advisory> {
+    let cve = item.get("cve")?;
+    let id = cve.get("id")?.as_str()?.to_string();
+    let metrics = cve.get("metrics");
+    let (severity, score) = ["cvssMetricV31", "cvssMetricV30", "cvssMetricV2"]
+        .iter()
+        .find_map(|key| metrics?.get(*key)?.as_array()?.first())
+        .map(|metric| {
+            let data = metric.get("cvssData");
+            let severity = data
+                .and_then(|d| d.get("baseSeverity"))
+                .or_else(|| metric.get("baseSeverity"))
+                .and_then(Value::as_str)
+                .map(Severity::parse);
+            (severity, data.and_then(|d| d.get("baseScore")).and_then(Value::as_f64))
+        })
+        .unwrap_or((None, None));
+    let mut affected = Vec::new();
+    for configuration in cve.get("configurations").and_then(Value::as_array).into_iter().flatten() {
+        affected_products(configuration.get("nodes"), "cpeMatch", "criteria", &mut affected);
+    }
+    Some(Advisory {
+        id,
+        description: english(cve.get("descriptions")),
+        severity: severity.unwrap_or(Severity::Unknown),
+        score,
+        affected,
+    })
+}
+
+/// Parses an NVD feed, either a 1.1 data feed or a 2.0 API response
+pub fn parse_nvd_feed(value: &Value) -> Result<Vec<Advisory>, SystemAnalyzerError> {
+    if let Some(items) = value.get("CVE_Items").and_then(Value::as_array) {
+        Ok(items.iter().filter_map(parse_nvd_item).collect())
+    } else if let Some(items) = value.get("vulnerabilities").and_then(Value::as_array) {
+        Ok(items.iter().filter_map(parse_nvd_vulnerability).collect())
+    } else {
+        Err(SystemAnalyzerError::ParseError(
+            "not an NVD feed: expected `CVE_Items` or `vulnerabilities`".to_string(),
+        ))
+    }
+}
+
+impl AdvisoryDatabase {
+    pub fn new(advisories: Vec<Advisory>) -> Self {
+        let mut by_product: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, advisory) in advisories.iter().enumerate() {
+            let products: BTreeSet<&str> = advisory.affected.iter().map(|a| a.product.as_str()).collect();
+            for product in products {
+                by_product.entry(product.to_string()).or_default().push(i);
+            }
+        }
+        Self { advisories, by_product }
+    }
+
+    /// Loads a decompressed NVD feed, or every `.json` feed of a directory
+    pub fn load(path: &Path) -> Result<Self, SystemAnalyzerError> {
+        let files = if path.is_dir() {
+            let mut files: Vec<_> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|file| file.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            files.sort();
+            files
+        } else if path.exists() {
+            vec![path.to_path_buf()]
+        } else {
+            Vec::new()
+        };
+        if files.is_empty() {
+            return Err(SystemAnalyzerError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no NVD JSON feeds found at {}", path.display()),
+            )));
+        }
+
+        let mut advisories = Vec::new();
+        for file in files {
+            let value: Value = serde_json::from_slice(&fs::read(&file)?)
+                .map_err(|e| SystemAnalyzerError::ParseError(format!("{}: {}", file.display(), e)))?;
+            advisories.extend(parse_nvd_feed(&value)?);
+        }
+        Ok(Self::new(advisories))
+    }
+
+    /// Advisories affecting a package version. CPE product names use
+    /// underscores where Nix package names tend to use dashes.
+    pub fn matching(&self, pname: &str, version: &str) -> Vec<&Advisory> {
+        let pname = pname.to_lowercase();
+        let mut names = vec![pname.clone()];
+        if pname.contains('-') {
+            names.push(pname.replace('-', "_"));
+        }
+        let mut matches: Vec<&Advisory> = Vec::new();
+        for name in &names {
+            for i in self.by_product.get(name).into_iter().flatten() {
+                let advisory = &self.advisories[*i];
+                let affected = advisory
+                    .affected
+                    .iter()
+                    .any(|a| a.product == *name && a.affects(version));
+                if affected && !matches.iter().any(|m| m.id == advisory.id) {
+                    matches.push(advisory);
+                }
+            }
+        }
+        matches
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WhitelistEntry {
+    #[serde(default)]
+    cve: Vec<String>,
+    /// Date after which the entry no longer applies
+    #[serde(default)]
+    until: Option<toml::Value>,
+    #[serde(default)]
+    comment: Option<String>,
+}
+
+/// Suppressions in vulnix's TOML whitelist format. Sections are named after
+/// a package or a package version and may be limited to some CVEs.
+#[derive(Debug, Default)]
+pub struct Whitelist {
+    entries: BTreeMap<String, WhitelistEntry>,
+}
+
+impl Whitelist {
+    pub fn parse(contents: &str) -> Result<Self, SystemAnalyzerError> {
+        let entries = toml::from_str(contents)
+            .map_err(|e| SystemAnalyzerError::ParseError(format!("invalid whitelist: {}", e)))?;
+        Ok(Self { entries })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SystemAnalyzerError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// The reason a finding is whitelisted, if it is. `today` is an ISO
+    /// date compared against the `until` of entries.
+    pub fn suppression(&self, pname: &str, version: &str, cve: &str, today: &str) -> Option<String> {
+        [format!("{}-{}", pname, version), pname.to_string()]
+            .iter()
+            .find_map(|key| {
+                let entry = self.entries.get(key)?;
+                let until = entry.until.as_ref().map(|until| match until {
+                    toml::Value::String(date) => date.clone(),
+                    other => other.to_string(),
+                });
+                let expired = until.is_some_and(|until| until.as_str() < today);
+                let applies = entry.cve.is_empty() || entry.cve.iter().any(|id| id == cve);
+                (!expired && applies).then(|| entry.comment.clone().unwrap_or_else(|| format!("whitelisted by [{}]", key)))
+            })
+    }
+}
+
+lazy_static! {
+    static ref CVE_ID: Regex = Regex::new(r"(?i)CVE-\d{4}-\d{4,}").unwrap();
+}
+
+/// CVEs named by the patches a derivation applies
+pub fn patched_cves(drv: &Derivation) -> BTreeSet<String> {
+    let mut patches: Vec<String> = drv
+        .env
+        .get("patches")
+        .map(|patches| patches.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+    if let Some(Value::Array(structured)) = drv.structured_attrs().as_ref().and_then(|attrs| attrs.get("patches")) {
+        patches.extend(structured.iter().filter_map(Value::as_str).map(str::to_string));
+    }
+    patches
+        .iter()
+        .flat_map(|patch| CVE_ID.find_iter(store_path_name(patch)))
+        .map(|id| id.as_str().to_uppercase())
+        .collect()
+}
+
+/// A package of the scanned closure
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScannedPackage {
+    pub pname: String,
+    pub version: String,
+    pub store_path: String,
+    pub patched: BTreeSet<String>,
+    /// `meta.knownVulnerabilities` of the package in nixpkgs
+    pub known_vulnerabilities: Vec<String>,
+}
+
+/// Packages of a closure, one per derivation. Names come from the deriver
+/// so that outputs such as `-dev` or `-lib` are not mistaken for versions;
+/// paths without a version, like sources and configuration files, are skipped.
+pub fn closure_packages(
+    infos: &[PathInfo],
+    mut load: impl FnMut(&str) -> Option<Derivation>,
+) -> Vec<ScannedPackage> {
+    let mut by_name: BTreeMap<String, ScannedPackage> = BTreeMap::new();
+    for info in infos {
+        let key = info.deriver.clone().unwrap_or_else(|| info.path.clone());
+        if let Some(package) = by_name.get_mut(&key) {
+            package.store_path = package.store_path.clone().min(info.path.clone());
+            continue;
+        }
+        let name = store_path_name(&key).trim_end_matches(".drv");
+        let (pname, Some(version)) = parse_drv_name(name) else {
+            continue;
+        };
+        let (pname, version) = (pname.to_string(), version.to_string());
+        let patched = info
+            .deriver
+            .as_deref()
+            .and_then(&mut load)
+            .map(|drv| patched_cves(&drv))
+            .unwrap_or_default();
+        by_name.insert(
+            key,
+            ScannedPackage {
+                pname,
+                version,
+                store_path: info.path.clone(),
+                patched,
+                known_vulnerabilities: Vec::new(),
+            },
+        );
+    }
+    by_name.into_values().collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub cve: String,
+    pub package: String,
+    pub version: String,
+    pub store_path: String,
+    pub severity: Severity,
+    pub score: Option<f64>,
+    pub description: Option<String>,
+    /// Listed in the package's `meta.knownVulnerabilities`. Entries there
+    /// without a matching advisory are findings of unknown severity.
+    pub known: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressionReason {
+    /// The derivation applies a patch named after the CVE
+    Patched,
+    Whitelisted,
+}
+
+/// A match that is not reported as a finding
+#[derive(Debug, Serialize)]
+pub struct SuppressedFinding {
+    pub cve: String,
+    pub package: String,
+    pub version: String,
+    pub reason: SuppressionReason,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VulnerabilityReport {
+    pub target: String,
+    pub advisories: usize,
+    pub scanned_packages: usize,
+    /// Most severe first
+    pub findings: Vec<Finding>,
+    pub suppressed: Vec<SuppressedFinding>,
+    /// Findings per severity
+    pub severity_counts: BTreeMap<String, usize>,
+    /// `meta.knownVulnerabilities` of the installable
+    pub known_vulnerabilities: Vec<String>,
+}
+
+/// Matches packages against the database, applying patches and the
+/// whitelist, and reports what nixpkgs marks insecure. `known_vulnerabilities`
+/// are those of the installable itself.
+pub fn scan(
+    target: &str,
+    packages: &[ScannedPackage],
+    database: &AdvisoryDatabase,
+    whitelist: &Whitelist,
+    known_vulnerabilities: Vec<String>,
+    today: &str,
+) -> VulnerabilityReport {
+    let mut report = VulnerabilityReport {
+        target: target.to_string(),
+        advisories: database.advisories.len(),
+        scanned_packages: packages.len(),
+        ..Default::default()
+    };
+    for package in packages {
+        let known = |id: &str| {
+            package.known_vulnerabilities.iter().chain(&known_vulnerabilities).any(|known| known.contains(id))
+        };
+        let mut matched = HashSet::new();
+        for advisory in database.matching(&package.pname, &package.version) {
+            matched.insert(advisory.id.as_str());
+            let suppressed = |reason, comment| SuppressedFinding {
+                cve: advisory.id.clone(),
+                package: package.pname.clone(),
+                version: package.version.clone(),
+                reason,
+                comment,
+            };
+            if package.patched.contains(&advisory.id) {
+                report.suppressed.push(suppressed(SuppressionReason::Patched, None));
+            } else if let Some(comment) = whitelist.suppression(&package.pname, &package.version, &advisory.id, today) {
+                report.suppressed.push(suppressed(SuppressionReason::Whitelisted, Some(comment)));
+            } else {
+                *report.severity_counts.entry(advisory.severity.name().to_string()).or_default() += 1;
+                report.findings.push(Finding {
+                    cve: advisory.id.clone(),
+                    package: package.pname.clone(),
+                    version: package.version.clone(),
+                    store_path: package.store_path.clone(),
+                    severity: advisory.severity,
+                    score: advisory.score,
+                    description: advisory.description.clone(),
+                    known: known(&advisory.id),
+                });
+            }
+        }
+
+        // Insecure according to nixpkgs without an advisory in the database
+        for entry in &package.known_vulnerabilities {
+            let id = CVE_ID.find(entry).map_or_else(|| entry.clone(), |id| id.as_str().to_uppercase());
+            if matched.contains(id.as_str()) {
+                continue;
+            }
+            if let Some(comment) = whitelist.suppression(&package.pname, &package.version, &id, today) {
+                report.suppressed.push(SuppressedFinding {
+                    cve: id,
+                    package: package.pname.clone(),
+                    version: package.version.clone(),
+                    reason: SuppressionReason::Whitelisted,
+                    comment: Some(comment),
+                });
+                continue;
+            }
+            *report.severity_counts.entry(Severity::Unknown.name().to_string()).or_default() += 1;
+            report.findings.push(Finding {
+                cve: id,
+                package: package.pname.clone(),
+                version: package.version.clone(),
+                store_path: package.store_path.clone(),
+                severity: Severity::Unknown,
+                score: None,
+                description: Some(entry.clone()),
+                known: true,
+            });
+        }
+    }
+    report.findings.sort_by(|a, b| {
+        b.severity
+            .cmp(&a.severity)
+            .then_with(|| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal))
+            .then_with(|| a.cve.cmp(&b.cve))
+    });
+    report.known_vulnerabilities = known_vulnerabilities;
+    report
+}
+
+/// Splits a version into components the way `builtins.compareVersions` does
+fn version_components(version: &str) -> Vec<&str> {
+    let mut components = Vec::new();
+    let mut rest = version;
+    loop {
+        rest = rest.trim_start_matches(['.', '-']);
+        if rest.is_empty() {
+            return components;
+        }
+        let digits = rest.starts_with(|c: char| c.is_ascii_digit());
+        let end = rest
+            .find(|c: char| c == '.' || c == '-' || c.is_ascii_digit() != digits)
+            .unwrap_or(rest.len());
+        components.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+}
+
+fn component_less(a: &str, b: &str) -> bool {
+    let (na, nb) = (a.parse::<u64>().ok(), b.parse::<u64>().ok());
+    match (na, nb) {
+        (Some(na), Some(nb)) => na < nb,
+        _ if a.is_empty() && nb.is_some() => true,
+        _ if a == "pre" && b != "pre" => true,
+        _ if b == "pre" => false,
+        // `2.3a` sorts before `2.3.1`
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => a < b,
+    }
+}
+
+/// Compares versions following `builtins.compareVersions`
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, b) = (version_components(a), version_components(b));
+    for i in 0..a.len().max(b.len()) {
+        let (x, y) = (a.get(i).copied().unwrap_or(""), b.get(i).copied().unwrap_or(""));
+        if component_less(x, y) {
+            return Ordering::Less;
+        }
+        if component_less(y, x) {
+            return Ordering::Greater;
+        }
+    }
+    Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NVD: &str = include_str!("../../tests/fixtures/nvd-sample.json");
+    const WHITELIST: &str = include_str!("../../tests/fixtures/vulnix-whitelist.toml");
+
+    fn database() -> AdvisoryDatabase {
+        AdvisoryDatabase::new(parse_nvd_feed(&serde_json::from_str(NVD).unwrap()).unwrap())
+    }
+
+    fn package(name: &str, patches: &[&str]) -> ScannedPackage {
+        let (pname, version) = parse_drv_name(name);
+        ScannedPackage {
+            pname: pname.to_string(),
+            version: version.unwrap().to_string(),
+            store_path: format!("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-{}", name),
+            patched: patches.iter().map(|p| p.to_string()).collect(),
+            known_vulnerabilities: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("1.2.11", "1.2.12"), Ordering::Less);
+        assert_eq!(compare_versions("3.0.7", "3.0.7"), Ordering::Equal);
+        assert_eq!(compare_versions("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0pre1", "1.0"), Ordering::Less);
+        assert_eq!(compare_versions("2.3a", "2.3.1"), Ordering::Less);
+        assert_eq!(compare_versions("2.3", "2.3.0"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_parse_nvd_feed() {
+        let database = database();
+        assert_eq!(database.advisories.len(), 5);
+        let openssl = &database.advisories[0];
+        assert_eq!(openssl.id, "CVE-2022-3602");
+        assert_eq!(openssl.severity, Severity::High);
+        assert_eq!(openssl.affected[0].start_including.as_deref(), Some("3.0.0"));
+        // CVSS v2 only
+        assert_eq!(database.advisories[2].severity, Severity::Medium);
+        assert_eq!(database.advisories[2].score, Some(6.8));
+        // Non-vulnerable platform matches are not products
+        assert_eq!(database.advisories[2].affected.len(), 1);
+
+        assert_eq!(database.matching("openssl", "3.0.5").len(), 1);
+        assert!(database.matching("openssl", "3.0.7").is_empty());
+        assert!(database.matching("openssl", "1.1.1w").is_empty());
+        assert_eq!(database.matching("cortex-xdr-agent", "7.5.0")[0].id, "CVE-2023-0001");
+        assert!(parse_nvd_feed(&serde_json::json!({})).is_err());
+    }
+
+    #[test]
+    fn test_parse_nvd_api() {
+        let response = serde_json::json!({
+            "vulnerabilities": [{
+                "cve": {
+                    "id": "CVE-2024-3094",
+                    "descriptions": [{"lang": "en", "value": "Malicious code was discovered in the upstream tarballs of xz."}],
+                    "metrics": {
+                        "cvssMetricV31": [{"cvssData": {"baseScore": 10.0, "baseSeverity": "CRITICAL"}}]
+                    },
+                    "configurations": [{
+                        "nodes": [{
+                            "operator": "OR",
+                            "cpeMatch": [
+                                {"vulnerable": true, "criteria": "cpe:2.3:a:tukaani:xz:5.6.0:*:*:*:*:*:*:*"},
+                                {"vulnerable": true, "criteria": "cpe:2.3:a:tukaani:xz:5.6.1:*:*:*:*:*:*:*"}
+                            ]
+                        }]
+                    }]
+                }
+            }]
+        });
+        let database = AdvisoryDatabase::new(parse_nvd_feed(&response).unwrap());
+        assert_eq!(database.matching("xz", "5.6.1")[0].severity, Severity::Critical);
+        assert!(database.matching("xz", "5.4.6").is_empty());
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let whitelist = Whitelist::parse(WHITELIST).unwrap();
+        let today = "2024-06-01";
+        assert!(whitelist.suppression("zlib", "1.2.11", "CVE-2018-25032", today).is_some());
+        // Only the listed CVEs of the listed version
+        assert!(whitelist.suppression("zlib", "1.2.11", "CVE-2022-37434", today).is_none());
+        assert!(whitelist.suppression("zlib", "1.2.10", "CVE-2018-25032", today).is_none());
+        // Entries without CVEs cover every CVE of the package
+        assert_eq!(
+            whitelist.suppression("lz4", "1.9.2", "CVE-2021-3520", today).as_deref(),
+            Some("Only used for reading our own archives")
+        );
+        // Expired entries no longer apply
+        assert!(whitelist.suppression("openssl", "3.0.5", "CVE-2022-3602", today).is_none());
+        assert!(whitelist.suppression("openssl", "3.0.5", "CVE-2022-3602", "2019-12-31").is_some());
+        assert!(Whitelist::parse("[broken").is_err());
+    }
+
+    #[test]
+    fn test_closure_packages() {
+        let drv_env = |patches: &str| Derivation {
+            env: [("patches".to_string(), patches.to_string())].into_iter().collect(),
+            ..Default::default()
+        };
+        let info = |path: &str, deriver: Option<&str>| PathInfo {
+            path: path.to_string(),
+            references: Vec::new(),
+            nar_size: 0,
+            deriver: deriver.map(str::to_string),
+            nar_hash: None,
+        };
+        let curl_drv = "/nix/store/dddddddddddddddddddddddddddddddd-curl-8.1.2.drv";
+        let infos = vec![
+            info("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-curl-8.1.2-dev", Some(curl_drv)),
+            info("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-curl-8.1.2", Some(curl_drv)),
+            info("/nix/store/cccccccccccccccccccccccccccccccc-source", None),
+            info("/nix/store/eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee-zlib-1.2.11", None),
+        ];
+        let packages = closure_packages(&infos, |path| {
+            (path == curl_drv).then(|| {
+                drv_env("/nix/store/ffffffffffffffffffffffffffffffff-cve-2023-38545.patch /nix/store/gggggggggggggggggggggggggggggggg-fix-build.patch")
+            })
+        });
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages[0].pname, "curl");
+        assert_eq!(packages[0].version, "8.1.2");
+        assert_eq!(packages[0].store_path, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-curl-8.1.2");
+        assert_eq!(packages[0].patched, ["CVE-2023-38545".to_string()].into_iter().collect());
+        assert_eq!(packages[1].pname, "zlib");
+        assert!(packages[1].patched.is_empty());
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut packages = vec![
+            package("openssl-3.0.5", &[]),
+            package("curl-8.1.2", &["CVE-2023-38545"]),
+            package("zlib-1.2.11", &[]),
+            package("lz4-1.9.2", &[]),
+            package("zstd-1.5.5", &[]),
+        ];
+        packages[0].known_vulnerabilities = vec!["CVE-2022-3602: buffer overrun in punycode decoding".to_string()];
+        packages[4].known_vulnerabilities = vec!["Unmaintained upstream, see cve-2024-99999".to_string()];
+        let report = scan(
+            "/run/current-system",
+            &packages,
+            &database(),
+            &Whitelist::parse(WHITELIST).unwrap(),
+            Vec::new(),
+            "2024-06-01",
+        );
+        assert_eq!(report.scanned_packages, 5);
+        assert_eq!(report.findings.len(), 2);
+        let finding = &report.findings[0];
+        assert_eq!((finding.cve.as_str(), finding.package.as_str()), ("CVE-2022-3602", "openssl"));
+        assert_eq!(finding.severity, Severity::High);
+        assert!(finding.known);
+        assert_eq!(report.severity_counts.get("high"), Some(&1));
+        // Marked insecure in nixpkgs without an advisory in the database
+        let insecure = &report.findings[1];
+        assert_eq!((insecure.cve.as_str(), insecure.package.as_str()), ("CVE-2024-99999", "zstd"));
+        assert_eq!((insecure.severity, insecure.known), (Severity::Unknown, true));
+        assert_eq!(report.severity_counts.get("unknown"), Some(&1));
+
+        let reasons: Vec<(&str, SuppressionReason)> =
+            report.suppressed.iter().map(|s| (s.cve.as_str(), s.reason)).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                ("CVE-2023-38545", SuppressionReason::Patched),
+                ("CVE-2018-25032", SuppressionReason::Whitelisted),
+                ("CVE-2021-3520", SuppressionReason::Whitelisted),
+            ]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use mcp_rust_sdk::Notification;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use super::cache::{flake_fingerprint, ResponseCache};
+use super::profiles::find_profiles;
+use super::util::default_flake;
+
+/// Files of a flake whose changes are reported
+pub const FLAKE_FILES: &[&str] = &["flake.nix", "flake.lock"];
+
+/// Sends a notification to the client
+pub type Notifier = Arc<dyn Fn(Notification) + Send + Sync>;
+
+/// Parameters of the `resources/subscribe`, `resources/unsubscribe` and
+/// `resources/read` methods
+#[derive(Debug, Deserialize)]
+pub struct ResourceParams {
+    pub uri: String,
+}
+
+/// Parameters of the `watch_flake` and `unwatch_flake` methods
+#[derive(Debug, Deserialize)]
+pub struct WatchFlakeParams {
+    /// Local flake directory
+    #[serde(default = "default_flake")]
+    pub flake: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Resource {
+    pub uri: String,
+    pub name: String,
+    pub description: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResourceList {
+    pub resources: Vec<Resource>,
+}
+
+/// What a watched location is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResourceKind {
+    FlakeFile,
+    Profile,
+}
+
+/// `file://` URI of a path
+pub fn file_uri(path: &Path) -> String {
+    format!("file://{}", path.display())
+}
+
+/// Path of a `file://` URI
+pub fn uri_path(uri: &str) -> Option<PathBuf> {
+    uri.strip_prefix("file://").map(PathBuf::from)
+}
+
+/// Name of the profile a directory entry belongs to: the profile symlink
+/// itself, or one of its `<profile>-<n>-link` generations. Temporary links
+/// Nix creates while switching are ignored.
+pub fn profile_of(name: &str) -> Option<&str> {
+    if name.starts_with('.') || name.contains(".tmp") {
+        return None;
+    }
+    match name.strip_suffix("-link").and_then(|rest| rest.rsplit_once('-')) {
+        Some((profile, number)) if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) => Some(profile),
+        _ => Some(name),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Watched {
+    Flake(PathBuf),
+    Profiles(PathBuf),
+}
+
+#[derive(Default)]
+struct WatchState {
+    directories: HashMap<WatchDescriptor, Watched>,
+    /// Fingerprint of each watched flake when it was last seen
+    flakes: BTreeMap<PathBuf, Option<String>>,
+    /// Files of the watched flakes that exist
+    flake_files: BTreeSet<PathBuf>,
+    /// Profiles of each watched profile directory
+    profiles: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    subscriptions: HashSet<String>,
+}
+
+impl WatchState {
+    fn resources(&self) -> Vec<Resource> {
+        let mut resources = Vec::new();
+        for file in &self.flake_files {
+            let name = file.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let dir = file.parent().unwrap_or(Path::new("/"));
+            resources.push(Resource {
+                uri: file_uri(file),
+                description: format!("{} of the flake in {}", name, dir.display()),
+                name,
+                mime_type: if file.extension().is_some_and(|ext| ext == "nix") { "text/x-nix" } else { "application/json" }
+                    .to_string(),
+            });
+        }
+        for profile in self.profiles.values().flatten() {
+            resources.push(Resource {
+                uri: file_uri(profile),
+                name: profile.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                description: format!("Generations of the profile {}", profile.display()),
+                mime_type: "application/json".to_string(),
+            });
+        }
+        resources
+    }
+
+    /// Updates the state after a change in a watched directory, returning
+    /// the URIs that changed, whether the resource list changed, and the
+    /// outdated fingerprint of a changed flake
+    fn handle(&mut self, watched: &Watched, name: &str) -> (Vec<String>, bool, Option<String>) {
+        match watched {
+            Watched::Flake(dir) => {
+                if !FLAKE_FILES.contains(&name) {
+                    return (Vec::new(), false, None);
+                }
+                let file = dir.join(name);
+                let list_changed = if file.exists() { self.flake_files.insert(file) } else { self.flake_files.remove(&file) };
+                let fingerprint = flake_fingerprint(&dir.to_string_lossy(), Path::new("/"));
+                let outdated = self.flakes.insert(dir.clone(), fingerprint.clone()).flatten().filter(|old| Some(old) != fingerprint.as_ref());
+                (vec![file_uri(&dir.join(name))], list_changed, outdated)
+            }
+            Watched::Profiles(dir) => {
+                let Some(profile) = profile_of(name) else {
+                    return (Vec::new(), false, None);
+                };
+                let found: BTreeSet<PathBuf> = find_profiles(dir).unwrap_or_default().into_iter().collect();
+                let list_changed = self.profiles.get(dir) != Some(&found);
+                self.profiles.insert(dir.clone(), found);
+                (vec![file_uri(&dir.join(profile))], list_changed, None)
+            }
+        }
+    }
+}
+
+/// Watches flakes and profile directories with inotify. Changes invalidate
+/// cached responses and are reported to the client as
+/// `notifications/resources/updated` for subscribed resources and
+/// `notifications/resources/list_changed` when resources come or go.
+pub struct Watcher {
+    watches: Mutex<Watches>,
+    state: Arc<Mutex<WatchState>>,
+    notify: Notifier,
+}
+
+impl Watcher {
+    /// Starts a thread reading inotify events
+    pub fn start(cache: Option<ResponseCache>, notify: Notifier) -> io::Result<Self> {
+        let mut inotify = Inotify::init()?;
+        let watches = inotify.watches();
+        let state = Arc::new(Mutex::new(WatchState::default()));
+
+        let thread_state = state.clone();
+        let thread_notify = notify.clone();
+        std::thread::Builder::new().name("inotify".to_string()).spawn(move || {
+            let mut buffer = [0u8; 4096];
+            loop {
+                let events = match inotify.read_events_blocking(&mut buffer) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        log::error!("Failed to read inotify events: {}", e);
+                        return;
+                    }
+                };
+                for event in events {
+                    if event.mask.contains(EventMask::IGNORED) {
+                        continue;
+                    }
+                    let Some(name) = event.name.map(|name| name.to_string_lossy().to_string()) else {
+                        continue;
+                    };
+                    let mut state = thread_state.lock().unwrap_or_else(|e| e.into_inner());
+                    let Some(watched) = state.directories.get(&event.wd).cloned() else {
+                        continue;
+                    };
+                    let (updated, list_changed, outdated) = state.handle(&watched, &name);
+                    let updated: Vec<String> =
+                        updated.into_iter().filter(|uri| state.subscriptions.contains(uri)).collect();
+                    drop(state);
+
+                    if let (Some(cache), Some(fingerprint)) = (&cache, outdated) {
+                        if let Err(e) = cache.invalidate(Some(&fingerprint)) {
+                            log::warn!("Failed to invalidate cached responses: {}", e);
+                        }
+                    }
+                    for uri in updated {
+                        thread_notify(Notification::new("notifications/resources/updated", Some(json!({ "uri": uri }))));
+                    }
+                    if list_changed {
+                        thread_notify(Notification::new("notifications/resources/list_changed", None));
+                    }
+                }
+            }
+        })?;
+
+        Ok(Self {
+            watches: Mutex::new(watches),
+            state,
+            notify,
+        })
+    }
+
+    fn add(&self, dir: &Path, watched: Watched) -> io::Result<()> {
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM;
+        let wd = self.watches.lock().unwrap_or_else(|e| e.into_inner()).add(dir, mask)?;
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).directories.insert(wd, watched);
+        Ok(())
+    }
+
+    fn list_changed(&self) {
+        (self.notify)(Notification::new("notifications/resources/list_changed", None));
+    }
+
+    /// Starts watching a local flake, returning `false` if it already was
+    pub fn watch_flake(&self, dir: &Path) -> io::Result<bool> {
+        let dir = std::fs::canonicalize(dir)?;
+        if !dir.join("flake.nix").is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no flake.nix in {}", dir.display())));
+        }
+        if self.state.lock().unwrap_or_else(|e| e.into_inner()).flakes.contains_key(&dir) {
+            return Ok(false);
+        }
+        self.add(&dir, Watched::Flake(dir.clone()))?;
+        let fingerprint = flake_fingerprint(&dir.to_string_lossy(), Path::new("/"));
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.flake_files.extend(FLAKE_FILES.iter().map(|file| dir.join(file)).filter(|file| file.exists()));
+        state.flakes.insert(dir, fingerprint);
+        drop(state);
+        self.list_changed();
+        Ok(true)
+    }
+
+    /// Stops watching a flake, returning `false` if it was not watched
+    pub fn unwatch_flake(&self, dir: &Path) -> io::Result<bool> {
+        let dir = std::fs::canonicalize(dir)?;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.flakes.remove(&dir).is_none() {
+            return Ok(false);
+        }
+        state.flake_files.retain(|file| file.parent() != Some(dir.as_path()));
+        let watched = Watched::Flake(dir);
+        let wds: Vec<WatchDescriptor> = state.directories.iter().filter(|(_, w)| **w == watched).map(|(wd, _)| wd.clone()).collect();
+        for wd in wds {
+            state.directories.remove(&wd);
+            // The watch is gone already if the directory was removed
+            let _ = self.watches.lock().unwrap_or_else(|e| e.into_inner()).remove(wd);
+        }
+        drop(state);
+        self.list_changed();
+        Ok(true)
+    }
+
+    /// Starts watching a directory of profiles such as `/nix/var/nix/profiles`
+    pub fn watch_profiles(&self, dir: &Path) -> io::Result<()> {
+        let profiles = find_profiles(dir)?.into_iter().collect();
+        self.add(dir, Watched::Profiles(dir.to_path_buf()))?;
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).profiles.insert(dir.to_path_buf(), profiles);
+        Ok(())
+    }
+
+    pub fn subscribe(&self, uri: &str) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).subscriptions.insert(uri.to_string());
+    }
+
+    pub fn unsubscribe(&self, uri: &str) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).subscriptions.remove(uri);
+    }
+
+    pub fn resources(&self) -> Vec<Resource> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).resources()
+    }
+
+    /// What a watched resource is, or `None` for unknown URIs
+    pub fn resource_kind(&self, uri: &str) -> Option<ResourceKind> {
+        let path = uri_path(uri)?;
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.profiles.values().any(|profiles| profiles.contains(&path)) {
+            Some(ResourceKind::Profile)
+        } else if state.flake_files.contains(&path) {
+            Some(ResourceKind::FlakeFile)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::time::{Duration, Instant};
+
+    /// A watcher that records the notifications it sends
+    fn watcher(cache: Option<ResponseCache>) -> (Watcher, Arc<Mutex<Vec<Notification>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let recorder = sent.clone();
+        let watcher = Watcher::start(cache, Arc::new(move |n| recorder.lock().unwrap().push(n))).unwrap();
+        (watcher, sent)
+    }
+
+    /// Waits until a notification with the method and URI arrives
+    fn wait_for(sent: &Mutex<Vec<Notification>>, method: &str, uri: Option<&str>) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let found = sent.lock().unwrap().iter().any(|n| {
+                n.method == method && uri.is_none_or(|uri| n.params.as_ref().and_then(|p| p["uri"].as_str()) == Some(uri))
+            });
+            if found {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn test_profile_of() {
+        assert_eq!(profile_of("system"), Some("system"));
+        assert_eq!(profile_of("system-42-link"), Some("system"));
+        assert_eq!(profile_of("home-manager-7-link"), Some("home-manager"));
+        assert_eq!(profile_of("per-user"), Some("per-user"));
+        assert_eq!(profile_of("system.tmp-1234-5678"), None);
+        assert_eq!(profile_of(".system-42-link"), None);
+    }
+
+    #[test]
+    fn test_flake_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(tmp.path()).unwrap();
+        fs::write(dir.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        let cache_tmp = tempfile::tempdir().unwrap();
+        let cache_dir = fs::canonicalize(cache_tmp.path()).unwrap();
+        let cache = ResponseCache::new(cache_dir.clone(), 1024 * 1024);
+        let fingerprint = flake_fingerprint(&dir.to_string_lossy(), Path::new("/")).unwrap();
+        cache.put(&fingerprint, &["nix", "flake", "show"], "{}").unwrap();
+
+        let (watcher, sent) = watcher(Some(cache.clone()));
+        assert!(watcher.watch_flake(&dir).unwrap());
+        assert!(!watcher.watch_flake(&dir).unwrap());
+        assert!(wait_for(&sent, "notifications/resources/list_changed", None));
+        let flake_nix = file_uri(&dir.join("flake.nix"));
+        assert_eq!(watcher.resources().len(), 1);
+        assert_eq!(watcher.resource_kind(&flake_nix), Some(ResourceKind::FlakeFile));
+        watcher.subscribe(&flake_nix);
+
+        sent.lock().unwrap().clear();
+        fs::write(dir.join("flake.nix"), "{ outputs = _: { packages = { }; }; }").unwrap();
+        assert!(wait_for(&sent, "notifications/resources/updated", Some(&flake_nix)));
+        // Responses for the old contents are dropped
+        assert!(cache.get(&fingerprint, &["nix", "flake", "show"]).is_none());
+
+        // A new lock file is a new resource; it is not subscribed to
+        sent.lock().unwrap().clear();
+        fs::write(dir.join("flake.lock"), "{}").unwrap();
+        assert!(wait_for(&sent, "notifications/resources/list_changed", None));
+        assert!(!wait_for(&sent, "notifications/resources/updated", None));
+        assert_eq!(watcher.resources().len(), 2);
+
+        assert!(watcher.unwatch_flake(&dir).unwrap());
+        assert!(watcher.resources().is_empty());
+    }
+
+    #[test]
+    fn test_profile_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(tmp.path()).unwrap();
+        symlink("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixos-system", dir.join("system-1-link")).unwrap();
+        symlink("system-1-link", dir.join("system")).unwrap();
+
+        let (watcher, sent) = watcher(None);
+        watcher.watch_profiles(&dir).unwrap();
+        let system = file_uri(&dir.join("system"));
+        assert_eq!(watcher.resource_kind(&system), Some(ResourceKind::Profile));
+        watcher.subscribe(&system);
+
+        // A new generation updates the profile without changing the list
+        symlink("/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixos-system", dir.join("system-2-link")).unwrap();
+        assert!(wait_for(&sent, "notifications/resources/updated", Some(&system)));
+        assert!(!sent.lock().unwrap().iter().any(|n| n.method == "notifications/resources/list_changed"));
+
+        // A new profile changes the list
+        symlink("/nix/store/cccccccccccccccccccccccccccccccc-user-environment", dir.join("tools-1-link")).unwrap();
+        symlink("tools-1-link", dir.join("tools")).unwrap();
+        assert!(wait_for(&sent, "notifications/resources/list_changed", None));
+        assert_eq!(watcher.resources().len(), 2);
+    }
+}
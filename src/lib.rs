@@ -1,3 +1,5 @@
+pub mod cli;
+pub mod config;
 pub mod inspector;
 pub mod transport;
 
@@ -54,6 +56,8 @@ impl ServerBuilder {
         // Create the server with the first provider
         if let Some((_, provider)) = self.providers.into_iter().next() {
             Ok(Server {
+                name: self.name,
+                version: self.version,
                 inner: McpServer::new(Arc::new(transport) as Arc<dyn McpTransport>, provider),
             })
         } else {
@@ -62,20 +66,28 @@ impl ServerBuilder {
     }
 }
 
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// A wrapper around the MCP server that provides a simpler interface
 pub struct Server {
+    name: String,
+    version: String,
     inner: McpServer,
 }
 
 impl Server {
     /// Returns the name of the server
     pub fn name(&self) -> &str {
-        "nix-inspector-mcp"
+        &self.name
     }
 
     /// Returns the version of the server
     pub fn version(&self) -> &str {
-        "0.2.0"
+        &self.version
     }
 
     /// Starts the server and runs until completion
@@ -1,28 +1,57 @@
-use std::sync::Arc;
-use env_logger;
+use clap::Parser;
 use log::info;
-use nix_inspector_mcp::{ServerBuilder, inspector::system::SystemAnalyzer};
-use nix_inspector_mcp::transport::stdio::StdioTransport;
+use nix_inspector_mcp::cli;
+use nix_inspector_mcp::config::{Args, Config, LogLevel, Transport};
+use nix_inspector_mcp::ServerBuilder;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    env_logger::init();
-    info!("Starting nix-inspector-mcp server");
+    let mut args = Args::parse();
+    let command = args.command.take();
+    // Configuration mistakes are reported plainly rather than as a Debug dump
+    let mut config = match Config::load(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("nix-inspector-mcp: {}", e);
+            std::process::exit(cli::EXIT_USAGE);
+        }
+    };
+
+    // A single tool run from the shell prints only its result
+    if let Some(command) = command {
+        if config.log_level.is_none() && std::env::var_os("RUST_LOG").is_none() {
+            config.log_level = Some(LogLevel::Warn);
+        }
+        config.init_logging()?;
+        let analyzer = match config.system_analyzer() {
+            Ok(analyzer) => analyzer,
+            Err(e) => {
+                eprintln!("nix-inspector-mcp: {}", e);
+                std::process::exit(cli::EXIT_TOOL_FAILED);
+            }
+        };
+        std::process::exit(cli::run(&command, &analyzer).await);
+    }
 
-    // Create system analyzer
-    let system_analyzer = SystemAnalyzer::new()?;
-    info!("System analyzer initialized");
+    config.init_logging()?;
+    info!("Starting nix-inspector-mcp server");
 
     // Create and start server using builder
-    let server = ServerBuilder::new()
-        .name("nix-inspector-mcp")
-        .version("0.2.0")
-        .add_provider("system", system_analyzer)
-        .build()?;
+    let mut builder = ServerBuilder::new()
+        .name(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"));
+    for provider in &config.providers {
+        if provider == "system" {
+            builder = builder.add_provider("system", config.system_analyzer()?);
+            info!("System analyzer initialized");
+        }
+    }
+    let server = match config.transport {
+        Transport::Stdio => builder.build()?,
+    };
 
     info!("Server starting...");
     server.run().await?;
 
     Ok(())
-} 
\ No newline at end of file
+}
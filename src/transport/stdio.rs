@@ -3,8 +3,7 @@ use futures::{Stream, stream};
 use log::{error, debug};
 use mcp_rust_sdk::error::{Error as McpError, ErrorCode};
 use mcp_rust_sdk::transport::{Message, Transport as McpTransport};
-use mcp_rust_sdk::{Request, Response, Notification, protocol::RequestId};
-use serde_json::Value;
+use mcp_rust_sdk::{Request, Response, Notification};
 use std::io::{self, BufRead, Write};
 use std::pin::Pin;
 use tokio::sync::broadcast;
@@ -130,6 +129,21 @@ impl StdioTransport {
     pub fn is_initialized() -> bool {
         INITIALIZED.load(Ordering::SeqCst)
     }
+
+    /// Writes a server-initiated notification to stdout
+    ///
+    /// Handlers have no access to the transport instance, so long running
+    /// methods use this to report progress while the request is pending.
+    pub fn notify(notification: &Notification) -> Result<(), StdioError> {
+        let json = serde_json::to_string(notification)?;
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        debug!("Sending notification: {}", json);
+        writeln!(handle, "{}", json)?;
+        handle.flush()?;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -196,6 +210,8 @@ impl Default for StdioTransport {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mcp_rust_sdk::protocol::RequestId;
+    use serde_json::Value;
     use tokio::test;
 
     #[test]